@@ -9,22 +9,138 @@
 //! 
 //! Expressions also have escape hatches (using groups and blocks) in order to recurse up to the
 //! other levels.
+//!
+//! Commands and expressions also carry the span of source they were parsed from, so that errors
+//! can point at them.
 
-#[derive(Debug)]
+use std::fmt;
+
+#[derive(Debug, Default)]
 pub struct Ast {
     pub items: Vec<Bind>,
+    /// Source line of each item. May be shorter than `items` for a hand-built AST, in which case
+    /// the remaining items get the line of the item before them.
+    pub lines: Vec<usize>,
 }
 
 impl Ast {
     pub fn new() -> Self {
-        Self { items: Vec::new() }
+        Self {
+            items: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+}
+
+impl Ast {
+    /// Visit each top-level item in turn.
+    pub fn visit(&self, visitor: &mut impl AstVisitor) {
+        for item in &self.items {
+            visitor.visit_bind(item);
+        }
+    }
+}
+
+/// Walks an AST, e.g. to collect the names a script uses. Every node is visited by default, so an
+/// implementation only overrides the methods for the nodes it cares about, and calls the matching
+/// `walk_*` function from there to keep going into the children of the node.
+pub trait AstVisitor {
+    fn visit_bind(&mut self, bind: &Bind) {
+        walk_bind(self, bind);
+    }
+
+    fn visit_cmd(&mut self, cmd: &Cmd) {
+        walk_cmd(self, cmd);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
     }
 }
 
+/// Visit the commands and expressions that a binding is made of.
+pub fn walk_bind<V: AstVisitor + ?Sized>(visitor: &mut V, bind: &Bind) {
+    match bind {
+        Bind::Def(def_bind) => walk_def(visitor, def_bind),
+        Bind::Let(let_bind) | Bind::Const(let_bind) | Bind::Assign(let_bind) => {
+            visitor.visit_cmd(&let_bind.init);
+        }
+        Bind::Destruct(destruct_bind) => visitor.visit_cmd(&destruct_bind.init),
+        Bind::Cmd(cmd) => visitor.visit_cmd(cmd),
+    }
+}
+
+/// Visit the expressions that a command is made of.
+pub fn walk_cmd<V: AstVisitor + ?Sized>(visitor: &mut V, cmd: &Cmd) {
+    match &cmd.kind {
+        CmdKind::Print(expr) | CmdKind::Yield(expr) | CmdKind::Expr(expr) => {
+            visitor.visit_expr(expr);
+        }
+        CmdKind::Create(_) => {}
+        CmdKind::Resume(co, args) => {
+            visitor.visit_expr(co);
+            args.iter().for_each(|arg| visitor.visit_expr(arg));
+        }
+        CmdKind::While(cond, body) | CmdKind::For(_, cond, body) => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(body);
+        }
+        CmdKind::If(cond, then, alt) => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(then);
+            visitor.visit_expr(alt);
+        }
+    }
+}
+
+/// Visit the sub-expressions of an expression, and the bindings of blocks and functions.
+pub fn walk_expr<V: AstVisitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    use ExprKind::*;
+    match &expr.kind {
+        Cond(cond, then, alt) => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(then);
+            visitor.visit_expr(alt);
+        }
+        Lt(lhs, rhs) | Gt(lhs, rhs) | Le(lhs, rhs) | Ge(lhs, rhs) | Eq(lhs, rhs)
+        | Range(lhs, rhs) | Add(lhs, rhs) | Sub(lhs, rhs) | Mul(lhs, rhs) | Div(lhs, rhs)
+        | IDiv(lhs, rhs) | BitAnd(lhs, rhs) | BitOr(lhs, rhs) | BitXor(lhs, rhs) | Shl(lhs, rhs)
+        | Shr(lhs, rhs) | Index(lhs, rhs) => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        Neg(inner) | Not(inner) | BitNot(inner) => visitor.visit_expr(inner),
+        Slice(list, from, to) => {
+            visitor.visit_expr(list);
+            from.iter().chain(to).for_each(|bound| visitor.visit_expr(bound));
+        }
+        Block(binds, _) => binds.iter().for_each(|bind| visitor.visit_bind(bind)),
+        Group(cmd) => visitor.visit_cmd(cmd),
+        List(items) => items.iter().for_each(|item| visitor.visit_expr(item)),
+        Lambda(def_bind) => walk_def(visitor, def_bind),
+        Call(callee, args) => {
+            visitor.visit_expr(callee);
+            args.iter().for_each(|arg| visitor.visit_expr(arg));
+        }
+        Ident(_) | Bool(_) | Num(_) | Str(_) | Unit => {}
+    }
+}
+
+// Default values of parameters, then the body.
+fn walk_def<V: AstVisitor + ?Sized>(visitor: &mut V, def_bind: &DefBind) {
+    def_bind.defaults.iter().flatten().for_each(|expr| visitor.visit_expr(expr));
+    visitor.visit_cmd(&def_bind.body);
+}
+
 #[derive(Debug)]
 pub enum Bind {
     Def(DefBind),
     Let(LetBind),
+    Const(LetBind),
+    /// Compound assignment, e.g. `x += 1`, which is kept as `x = x + 1`. Unlike `let`, this updates
+    /// a name bound in an enclosing function rather than binding a new one.
+    Assign(LetBind),
+    Destruct(DestructBind),
     Cmd(Cmd),
 }
 
@@ -32,12 +148,17 @@ pub enum Bind {
 pub struct DefBind {
     pub name: String,
     pub params: Vec<String>,
+    /// Default value for each parameter, if any. Same length as `params`.
+    pub defaults: Vec<Option<Expr>>,
+    /// Name of the trailing parameter that collects extra arguments, if any.
+    pub rest: Option<String>,
     pub body: Cmd,
 }
 
 impl DefBind {
     pub fn new(name: String, params: Vec<String>, body: Cmd) -> Self {
-        Self { name, params, body }
+        let defaults = params.iter().map(|_| None).collect();
+        Self { name, params, defaults, rest: None, body }
     }
 }
 
@@ -53,29 +174,120 @@ impl LetBind {
     }
 }
 
+/// Binds each name to the corresponding element of a list, e.g. `let a, b = pair`. Elements
+/// named `_` are skipped.
 #[derive(Debug)]
-pub enum Cmd {
+pub struct DestructBind {
+    pub names: Vec<String>,
+    pub init: Cmd,
+}
+
+impl DestructBind {
+    pub fn new(names: Vec<String>, init: Cmd) -> Self {
+        Self { names, init }
+    }
+}
+
+/// Byte range of the source that a node was parsed from, with `end` being exclusive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// The span from the start of this one to the end of `other`.
+    pub fn to(self, other: Span) -> Self {
+        Self::new(self.start, other.end)
+    }
+}
+
+pub struct Cmd {
+    pub kind: CmdKind,
+    pub span: Span,
+}
+
+impl Cmd {
+    pub fn new(kind: CmdKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+// Spans are left out, so that trees parsed from differently spaced source look the same.
+impl fmt::Debug for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+#[derive(Debug)]
+pub enum CmdKind {
     Print(Expr),
     Create(String),
     Resume(Expr, Vec<Expr>),
     Yield(Expr),
     While(Expr, Expr),
+    /// Loop over the items of a list or range, binding each to the name in turn.
+    For(String, Expr, Expr),
     If(Expr, Expr, Expr),
     Expr(Expr),
 }
 
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
 #[derive(Debug)]
-pub enum Expr {
+pub enum ExprKind {
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
     Lt(Box<Expr>, Box<Expr>),
+    Gt(Box<Expr>, Box<Expr>),
+    Le(Box<Expr>, Box<Expr>),
+    Ge(Box<Expr>, Box<Expr>),
     Eq(Box<Expr>, Box<Expr>),
+    Range(Box<Expr>, Box<Expr>),
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
+    IDiv(Box<Expr>, Box<Expr>),
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    BitXor(Box<Expr>, Box<Expr>),
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
     Neg(Box<Expr>),
     Not(Box<Expr>),
-    Block(Vec<Bind>),
+    BitNot(Box<Expr>),
+    /// Item of a list, e.g. `xs[i]`.
+    Index(Box<Expr>, Box<Expr>),
+    /// Copy of part of a list, e.g. `xs[a:b]`. Either bound can be left out.
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    /// Bindings of the block, with the source line of each one, same as `Ast`.
+    Block(Vec<Bind>, Vec<usize>),
     Group(Box<Cmd>),
+    List(Vec<Expr>),
+    /// Anonymous function, which has an empty name.
+    Lambda(Box<DefBind>),
+    /// Call of a function with the given arguments, e.g. `f(a, b)`.
+    Call(Box<Expr>, Vec<Expr>),
     Ident(String),
     Bool(bool),
     Num(f64),
@@ -0,0 +1,1752 @@
+//! This module provides the built-in functions that are available to all Coro code.
+//!
+//! Built-ins are `NativeFn` values that live in a table separate from any coroutine env. When a
+//! name is not bound in the env, the VM falls back to looking it up here, so user code can still
+//! shadow a built-in with its own binding.
+//!
+//! Some built-ins give scripts access to the host, e.g. its files, or pull in extra dependencies,
+//! e.g. JSON support, so they are only visible to coroutines whose config opts in to them.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::panic;
+use std::process;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use rand::Rng;
+
+use crate::value::range_nth;
+use crate::value::slice_range;
+use crate::value::Lazy;
+use crate::value::NativeFn;
+use crate::value::Value;
+use crate::vm;
+use crate::vm::CoStatus;
+use crate::vm::CoVM;
+use crate::vm::Coro;
+
+/// Longest string that `str_repeat` will build, in bytes.
+pub const MAX_REPEAT_LEN: usize = 1 << 24;
+
+/// Longest list that `to_list` will make from a range.
+pub const MAX_RANGE_LIST_LEN: usize = 1 << 24;
+
+/// Most decimal places that `fmt_num` will show.
+pub const MAX_FMT_PLACES: usize = 100;
+
+thread_local! {
+    static BUILTINS: RefCell<HashMap<String, Value>> = RefCell::new(table());
+}
+
+/// How `exit` unwinds out of the VM when the host has set an exit handler. The outermost resume
+/// catches this and gives back `CoError::Exit` instead.
+#[derive(Debug)]
+pub(crate) struct ScriptExit(pub(crate) i32);
+
+/// Find the built-in function with the given name.
+pub fn lookup(name: &str) -> Option<Value> {
+    BUILTINS.with(|builtins| builtins.borrow().get(name).cloned())
+}
+
+/// Add a built-in function, replacing any existing one with the same name.
+pub fn register(native: NativeFn) {
+    let name = native.name().to_owned();
+    let val = Value::NativeFn(Rc::new(native));
+    BUILTINS.with(|builtins| builtins.borrow_mut().insert(name, val));
+}
+
+/// The built-ins that read and write files, which need `CoVMBuilder::with_file_io`.
+pub(crate) const FILE_IO: [&str; 2] = ["read_file", "write_file"];
+
+/// The built-ins that convert between values and JSON, which need `CoVMBuilder::with_json`.
+pub(crate) const JSON: [&str; 2] = ["parse_json", "to_json"];
+
+fn table() -> HashMap<String, Value> {
+    let natives = vec![
+        NativeFn::new("sort", 1, sort),
+        NativeFn::new("sort_by", 2, sort_by),
+        NativeFn::new("map", 2, map),
+        NativeFn::new("filter", 2, filter),
+        NativeFn::new("fold", 3, fold),
+        NativeFn::new("lazy", 1, lazy),
+        NativeFn::new("force", 1, force),
+        NativeFn::new("memo", 1, memo),
+        NativeFn::new("infinite", 2, infinite),
+        NativeFn::new("take", 2, take),
+        NativeFn::new("first", 1, first),
+        NativeFn::new("last", 2, last),
+        NativeFn::new("skip", 2, skip),
+        NativeFn::new("skip_while", 2, skip_while),
+        NativeFn::new("any", 2, any),
+        NativeFn::new("all", 2, all),
+        NativeFn::new("count", 2, count),
+        NativeFn::new("flat_map_co", 2, flat_map_co),
+        NativeFn::new("flat", 1, flat),
+        NativeFn::new("concat_co", 1, concat_co),
+        NativeFn::ranged("chain_co", 1, usize::MAX, chain_co),
+        NativeFn::new("interleave_co", 2, interleave_co),
+        NativeFn::new("fmt_num", 2, fmt_num),
+        NativeFn::new("fmt_sci", 1, fmt_sci),
+        NativeFn::new("fmt_hex", 1, fmt_hex),
+        NativeFn::new("input", 1, input),
+        NativeFn::new("env_var", 1, env_var),
+        NativeFn::new("args", 0, args),
+        NativeFn::new("clock", 0, clock),
+        NativeFn::new("epoch_ms", 0, epoch_ms),
+        NativeFn::new("rand", 0, rand),
+        NativeFn::ranged("exit", 0, 1, exit),
+        NativeFn::new("rand_int", 2, rand_int),
+        NativeFn::new("read_file", 1, read_file),
+        NativeFn::new("write_file", 2, write_file),
+        NativeFn::new("parse_json", 1, parse_json),
+        NativeFn::new("to_json", 1, to_json),
+        NativeFn::new("to_bool", 1, to_bool),
+        NativeFn::variadic("format", 1, format),
+        NativeFn::new("range", 3, range),
+        NativeFn::new("to_list", 1, to_list),
+        NativeFn::new("list_push", 2, list_push),
+        NativeFn::new("list_pop", 1, list_pop),
+        NativeFn::new("list_len", 1, list_len),
+        NativeFn::new("list_get", 2, list_get),
+        NativeFn::new("list_set", 3, list_set),
+        NativeFn::new("list_slice", 3, list_slice),
+        NativeFn::new("list_reverse", 1, list_reverse),
+        NativeFn::new("list_concat", 2, list_concat),
+        NativeFn::new("map_new", 0, map_new),
+        NativeFn::new("map_get", 2, map_get),
+        NativeFn::new("map_set", 3, map_set),
+        NativeFn::new("map_has", 2, map_has),
+        NativeFn::new("map_del", 2, map_del),
+        NativeFn::new("map_keys", 1, map_keys),
+        NativeFn::new("map_values", 1, map_values),
+        NativeFn::new("map_len", 1, map_len),
+        NativeFn::new("contains", 2, contains),
+        NativeFn::new("index_of", 2, index_of),
+        NativeFn::new("ord", 1, ord),
+        NativeFn::new("chr", 1, chr),
+        NativeFn::new("split", 2, split),
+        NativeFn::new("join", 2, join),
+        // Short names for the most common string built-ins.
+        NativeFn::new("upper", 1, |args| str_to_upper("upper", &args)),
+        NativeFn::new("lower", 1, |args| str_to_lower("lower", &args)),
+        NativeFn::new("trim", 1, |args| str_trim("trim", &args)),
+        NativeFn::new("str_trim", 1, |args| str_trim("str_trim", &args)),
+        NativeFn::new("str_trim_start", 1, str_trim_start),
+        NativeFn::new("str_trim_end", 1, str_trim_end),
+        NativeFn::new("str_to_upper", 1, |args| str_to_upper("str_to_upper", &args)),
+        NativeFn::new("str_to_lower", 1, |args| str_to_lower("str_to_lower", &args)),
+        NativeFn::new("str_repeat", 2, str_repeat),
+        NativeFn::new("str_replace", 3, |args| str_replace("str_replace", &args, 1)),
+        NativeFn::new("str_replace_all", 3, |args| {
+            str_replace("str_replace_all", &args, usize::MAX)
+        }),
+    ];
+    natives
+        .into_iter()
+        .map(|native| (native.name().to_owned(), Value::NativeFn(Rc::new(native))))
+        .collect()
+}
+
+/// Sort a list in place, using the ordering of `Value`.
+fn sort(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("sort", &args[0])?;
+    list.borrow_mut().sort();
+    Ok(Value::Unit)
+}
+
+/// Sort a list in place, using a function that compares two values and returns a negative number,
+/// zero, or a positive number.
+fn sort_by(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("sort_by", &args[0])?;
+    let cmp = &args[1];
+
+    // The comparison function may look at the list, so sort a copy and write it back after.
+    let mut items = list.borrow().clone();
+    let mut error = None;
+    items.sort_by(|a, b| {
+        if error.is_some() {
+            return Ordering::Equal;
+        }
+        match CoVM::call(cmp, vec![a.clone(), b.clone()]) {
+            Ok(Value::Num(n)) if n < 0.0 => Ordering::Less,
+            Ok(Value::Num(n)) if n > 0.0 => Ordering::Greater,
+            Ok(Value::Num(_)) => Ordering::Equal,
+            Ok(_) => {
+                error = Some("sort_by comparison must return a number".to_owned());
+                Ordering::Equal
+            }
+            Err(e) => {
+                error = Some(e.message().to_owned());
+                Ordering::Equal
+            }
+        }
+    });
+    if let Some(msg) = error {
+        return Err(msg);
+    }
+    *list.borrow_mut() = items;
+    Ok(Value::Unit)
+}
+
+/// Make a new list by calling a function on each item of a list.
+fn map(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("map", &args[0])?;
+    // The function may change the list, so go over a copy.
+    let items = list.borrow().clone();
+    let mapped = items
+        .into_iter()
+        .map(|item| CoVM::call(&args[1], vec![item]).map_err(|e| e.message().to_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::list(mapped))
+}
+
+/// Make a new list with the items of a list that a function returns a truthy value for.
+fn filter(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("filter", &args[0])?;
+    let items = list.borrow().clone();
+    let mut kept = Vec::new();
+    for item in items {
+        let keep = CoVM::call(&args[1], vec![item.clone()]).map_err(|e| e.message().to_owned())?;
+        if keep.is_truthy() {
+            kept.push(item);
+        }
+    }
+    Ok(Value::list(kept))
+}
+
+/// Combine the items of a list into one value, starting from `init` and calling a function with
+/// the value so far and each item in turn.
+fn fold(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("fold", &args[0])?;
+    let items = list.borrow().clone();
+    items.into_iter().try_fold(args[1].clone(), |acc, item| {
+        CoVM::call(&args[2], vec![acc, item]).map_err(|e| e.message().to_owned())
+    })
+}
+
+/// Wrap a function taking no arguments as a lazy value, which calls it when first forced.
+fn lazy(args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Fn(_) | Value::NativeFn(_) => Ok(Value::lazy(args[0].clone())),
+        val => Err(format!("lazy expects a function but got {}", val)),
+    }
+}
+
+/// Get the value of a lazy value, calling its function the first time only. Values that are not
+/// lazy are given back as they are.
+fn force(args: Vec<Value>) -> Result<Value, String> {
+    let lazy = match &args[0] {
+        Value::Lazy(lazy) => lazy,
+        val => return Ok(val.clone()),
+    };
+    // Let go of the borrow while the function runs, since it may force other lazy values.
+    let fun = match lazy.replace(Lazy::Forcing) {
+        Lazy::Pending(fun) => fun,
+        Lazy::Forced(val) => {
+            *lazy.borrow_mut() = Lazy::Forced(val.clone());
+            return Ok(val);
+        }
+        Lazy::Forcing => return Err("lazy value depends on itself".to_owned()),
+    };
+    match CoVM::call(&fun, Vec::new()) {
+        Ok(val) => {
+            *lazy.borrow_mut() = Lazy::Forced(val.clone());
+            Ok(val)
+        }
+        Err(err) => {
+            // Leave it to be tried again.
+            *lazy.borrow_mut() = Lazy::Pending(fun);
+            Err(err.message().to_owned())
+        }
+    }
+}
+
+/// Wrap a function so that it only runs once for each distinct list of arguments, giving back the
+/// saved result after that. A recursive function should be passed the wrapper to call, e.g.
+/// `let f = memo(fib)` and then `f(f, n)`, so that its inner calls are saved too.
+fn memo(args: Vec<Value>) -> Result<Value, String> {
+    let fun = args[0].clone();
+    let (name, min, max) = match &fun {
+        Value::Fn(def) => {
+            let max = if def.is_variadic() { usize::MAX } else { def.arity() };
+            (def.name().to_owned(), def.min_arity(), max)
+        }
+        Value::NativeFn(native) => {
+            (native.name().to_owned(), native.min_arity(), native.max_arity())
+        }
+        val => return Err(format!("memo expects a function but got {}", val)),
+    };
+    let cache: RefCell<HashMap<Vec<Value>, Value>> = RefCell::default();
+    let wrapper = NativeFn::ranged(&name, min, max, move |args| {
+        if let Some(val) = cache.borrow().get(&args) {
+            return Ok(val.clone());
+        }
+        // Copy the key, so that changing a list argument later does not change the key.
+        let key = args.iter().map(Value::deep_clone).collect();
+        let val = CoVM::call(&fun, args).map_err(|e| e.message().to_owned())?;
+        cache.borrow_mut().insert(key, val.clone());
+        Ok(val)
+    });
+    Ok(Value::NativeFn(Rc::new(wrapper)))
+}
+
+/// Make a coroutine that never finishes, e.g. for the natural numbers. Each resume calls a function
+/// with the current state, which starts out as `seed`. The function returns a list of the next
+/// state and the value to yield, e.g. `infinite(fn n -> [n + 1, n], 0)`.
+fn infinite(args: Vec<Value>) -> Result<Value, String> {
+    let next_fn = match &args[0] {
+        Value::Fn(_) | Value::NativeFn(_) => args[0].clone(),
+        val => return Err(format!("infinite expects a function but got {}", val)),
+    };
+    let mut state = args[1].clone();
+    let step = move || {
+        let next = CoVM::call(&next_fn, vec![state.clone()]).map_err(|e| e.message().to_owned())?;
+        let next = match &next {
+            Value::List(list) if list.borrow().len() == 2 => list.borrow().clone(),
+            val => {
+                let msg = "infinite expects the function to give back [state, value] but got";
+                return Err(format!("{} {}", msg, val));
+            }
+        };
+        state = next[0].clone();
+        Ok(Some(next[1].clone()))
+    };
+    Ok(stream("infinite", Box::new(step)))
+}
+
+/// Make a coroutine that calls a function with each value another coroutine yields, and yields
+/// everything the coroutine it returns yields in turn, e.g. to chain generators together.
+fn flat_map_co(args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Fn(_) | Value::NativeFn(_) => {}
+        val => return Err(format!("flat_map_co expects a function but got {}", val)),
+    }
+    expect_co("flat_map_co", &args[1])?;
+    Ok(flatten("flat_map_co", Some(args[0].clone()), args[1].clone()))
+}
+
+/// Make a coroutine that yields everything yielded by each coroutine another coroutine yields.
+fn flat(args: Vec<Value>) -> Result<Value, String> {
+    expect_co("flat", &args[0])?;
+    Ok(flatten("flat", None, args[0].clone()))
+}
+
+/// Same as `flat`, for symmetry with `chain_co`, which takes the coroutines up front instead.
+fn concat_co(args: Vec<Value>) -> Result<Value, String> {
+    expect_co("concat_co", &args[0])?;
+    Ok(flatten("concat_co", None, args[0].clone()))
+}
+
+/// Make a coroutine that yields everything the first coroutine yields, then everything the next one
+/// yields, and so on. The coroutines are passed either as arguments, e.g. `chain_co(a, b)`, or as
+/// one list, e.g. `chain_co([a, b, c])`.
+fn chain_co(args: Vec<Value>) -> Result<Value, String> {
+    let cos = match args.as_slice() {
+        [Value::List(list)] => list.borrow().clone(),
+        _ => args,
+    };
+    for co in &cos {
+        expect_co("chain_co", co)?;
+    }
+    let mut idx = 0;
+    let step = move || {
+        while let Some(co) = cos.get(idx) {
+            if let Some(val) = next_item("chain_co", expect_co("chain_co", co)?)? {
+                return Ok(Some(val));
+            }
+            idx += 1;
+        }
+        Ok(None)
+    };
+    Ok(stream("chain_co", Box::new(step)))
+}
+
+/// Make a coroutine that takes turns yielding a value from each of two coroutines. Once one is
+/// done, the rest of the other is yielded in order.
+fn interleave_co(args: Vec<Value>) -> Result<Value, String> {
+    expect_co("interleave_co", &args[0])?;
+    expect_co("interleave_co", &args[1])?;
+    let mut turn = 0;
+    let step = move || {
+        for _ in 0..args.len() {
+            let co = expect_co("interleave_co", &args[turn])?;
+            turn = (turn + 1) % args.len();
+            if let Some(val) = next_item("interleave_co", co)? {
+                return Ok(Some(val));
+            }
+        }
+        Ok(None)
+    };
+    Ok(stream("interleave_co", Box::new(step)))
+}
+
+// A coroutine that yields everything yielded by the coroutine `fun` gives back for each value
+// `outer` yields, or by each value itself without a `fun`.
+fn flatten(name: &'static str, fun: Option<Value>, outer: Value) -> Value {
+    let mut inner: Option<Value> = None;
+    let step = move || loop {
+        if let Some(co) = &inner {
+            if let Some(val) = next_item(name, expect_co(name, co)?)? {
+                return Ok(Some(val));
+            }
+        }
+        let item = match next_item(name, expect_co(name, &outer)?)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        inner = Some(match &fun {
+            Some(fun) => CoVM::call(fun, vec![item]).map_err(|e| e.message().to_owned())?,
+            None => item,
+        });
+    };
+    stream(name, Box::new(step))
+}
+
+// The coroutine is made as if the code that called the native had created it, so it runs with the
+// same limits, e.g. `max_steps` stops `last` on an `infinite` coroutine.
+fn stream(name: &str, step: vm::NativeStep) -> Value {
+    Value::Co(Rc::new(RefCell::new(vm::spawn_native(name, step))))
+}
+
+/// Resume a coroutine up to `n` times and collect what it yields into a list. This stops early
+/// once the coroutine is done, leaving out the value it returns.
+fn take(args: Vec<Value>) -> Result<Value, String> {
+    let num = match args[0].as_int() {
+        Some(num) if num >= 0 => num as usize,
+        _ => return Err(format!("take expects a non-negative whole number but got {}", args[0])),
+    };
+    let co = expect_co("take", &args[1])?;
+    let mut items = Vec::new();
+    while items.len() < num {
+        match next_item("take", co)? {
+            Some(val) => items.push(val),
+            None => break,
+        }
+    }
+    Ok(Value::list(items))
+}
+
+/// Resume a coroutine once and give back what it yields, or unit if it is already done.
+fn first(args: Vec<Value>) -> Result<Value, String> {
+    let co = expect_co("first", &args[0])?;
+    Ok(next_item("first", co)?.unwrap_or(Value::Unit))
+}
+
+/// Run a coroutine until it is done and give back the last `n` values it yields, oldest first.
+/// Only those are kept while running, but a coroutine that never finishes, e.g. one made by
+/// `infinite`, keeps this from ever returning.
+fn last(args: Vec<Value>) -> Result<Value, String> {
+    let num = match args[0].as_int() {
+        Some(num) if num >= 0 => num as usize,
+        _ => return Err(format!("last expects a non-negative whole number but got {}", args[0])),
+    };
+    let co = expect_co("last", &args[1])?;
+    let mut items = VecDeque::new();
+    while let Some(val) = next_item("last", co)? {
+        if num == 0 {
+            continue;
+        }
+        if items.len() == num {
+            items.pop_front();
+        }
+        items.push_back(val);
+    }
+    Ok(Value::list(items.into()))
+}
+
+/// Resume a coroutine `n` times and drop what it yields. The same coroutine is given back, so the
+/// rest can be resumed from there, e.g. `take(2, skip(3, co))`.
+fn skip(args: Vec<Value>) -> Result<Value, String> {
+    let num = match args[0].as_int() {
+        Some(num) if num >= 0 => num,
+        _ => return Err(format!("skip expects a non-negative whole number but got {}", args[0])),
+    };
+    let co = expect_co("skip", &args[1])?;
+    for _ in 0..num {
+        if next_item("skip", co)?.is_none() {
+            break;
+        }
+    }
+    Ok(args[1].clone())
+}
+
+/// Drop the values a coroutine yields while a function returns a truthy value for them. This gives
+/// back a coroutine that yields the first value the function rejected, and then the rest.
+fn skip_while(args: Vec<Value>) -> Result<Value, String> {
+    let co = expect_co("skip_while", &args[1])?;
+    let mut held = None;
+    while let Some(val) = next_item("skip_while", co)? {
+        if !call_pred(&args[0], val.clone())? {
+            held = Some(val);
+            break;
+        }
+    }
+    let rest = co.clone();
+    let step = move || match held.take() {
+        Some(val) => Ok(Some(val)),
+        None => next_item("skip_while", &rest),
+    };
+    Ok(stream("skip_while", Box::new(step)))
+}
+
+/// Whether a function returns a truthy value for any value a coroutine yields. This stops at the
+/// first one that does, leaving the rest of the coroutine to be resumed later.
+fn any(args: Vec<Value>) -> Result<Value, String> {
+    let co = expect_co("any", &args[1])?;
+    while let Some(val) = next_item("any", co)? {
+        if call_pred(&args[0], val)? {
+            return Ok(Value::Bool(true));
+        }
+    }
+    Ok(Value::Bool(false))
+}
+
+/// Whether a function returns a truthy value for every value a coroutine yields. This stops at the
+/// first one that does not, same as `any`.
+fn all(args: Vec<Value>) -> Result<Value, String> {
+    let co = expect_co("all", &args[1])?;
+    while let Some(val) = next_item("all", co)? {
+        if !call_pred(&args[0], val)? {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+/// Count the values a coroutine yields that a function returns a truthy value for. This runs the
+/// coroutine until it is done, so it never returns for one made by `infinite`.
+fn count(args: Vec<Value>) -> Result<Value, String> {
+    let co = expect_co("count", &args[1])?;
+    let mut num = 0;
+    while let Some(val) = next_item("count", co)? {
+        if call_pred(&args[0], val)? {
+            num += 1;
+        }
+    }
+    Ok(Value::Num(num as f64))
+}
+
+// Resume a coroutine for the next value it yields, or `None` once it is done. The value it returns
+// at the end is left out. The coroutine is only borrowed while it runs, so callbacks can resume it
+// too.
+fn next_item(name: &str, co: &RefCell<Coro>) -> Result<Option<Value>, String> {
+    let mut co = co
+        .try_borrow_mut()
+        .map_err(|_| format!("{} cannot resume a running coroutine", name))?;
+    if co.status() == &CoStatus::Done {
+        return Ok(None);
+    }
+    let val = co.resume(Vec::new()).map_err(|e| e.message().to_owned())?;
+    if co.status() == &CoStatus::Done {
+        return Ok(None);
+    }
+    Ok(Some(val))
+}
+
+fn call_pred(pred: &Value, val: Value) -> Result<bool, String> {
+    let res = CoVM::call(pred, vec![val]).map_err(|e| e.message().to_owned())?;
+    Ok(res.is_truthy())
+}
+
+/// Format a number with a fixed number of decimal places.
+fn fmt_num(args: Vec<Value>) -> Result<Value, String> {
+    let num = expect_num("fmt_num", &args[0])?;
+    let places = match args[1].as_int().and_then(|places| usize::try_from(places).ok()) {
+        Some(places) if places <= MAX_FMT_PLACES => places,
+        _ => {
+            let msg = format!("fmt_num expects a whole number of places up to {}", MAX_FMT_PLACES);
+            return Err(format!("{} but got {}", msg, args[1]));
+        }
+    };
+    Ok(Value::Str(format!("{:.prec$}", num, prec = places).into()))
+}
+
+/// Format a number in scientific notation, e.g. `1.5e3`.
+fn fmt_sci(args: Vec<Value>) -> Result<Value, String> {
+    let num = expect_num("fmt_sci", &args[0])?;
+    Ok(Value::Str(format!("{:e}", num).into()))
+}
+
+/// Format the integer part of a number in lowercase hex, with a leading `-` if it is negative.
+fn fmt_hex(args: Vec<Value>) -> Result<Value, String> {
+    let num = expect_num("fmt_hex", &args[0])?;
+    let int = match Value::Num(num.trunc()).as_int() {
+        Some(int) => int,
+        None => {
+            let msg = "fmt_hex expects a number that fits in 64 bits but got";
+            return Err(format!("{} {}", msg, args[0]));
+        }
+    };
+    let sign = if int < 0 { "-" } else { "" };
+    Ok(Value::Str(format!("{}{:x}", sign, int.unsigned_abs()).into()))
+}
+
+/// Print a prompt and read a line of input, without the line ending. Returns unit at the end of
+/// input. This blocks the whole thread until a line is read, so it should not be used in a
+/// coroutine that the host expects to resume quickly, e.g. one driven from an event loop.
+fn input(args: Vec<Value>) -> Result<Value, String> {
+    let prompt = expect_str("input", &args[0])?;
+    vm::write_output(prompt).map_err(|e| format!("cannot write output: {}", e))?;
+
+    let mut line = String::new();
+    let len = match &vm::caller_limits().input {
+        Some(reader) => reader.0.borrow_mut().read_line(&mut line),
+        None => io::stdin().lock().read_line(&mut line),
+    };
+    let len = len.map_err(|e| format!("cannot read input: {}", e))?;
+    if len == 0 {
+        return Ok(Value::Unit);
+    }
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    Ok(Value::Str(line.into()))
+}
+
+/// Build a string from a template, replacing each `{}` with the next argument in its printed form.
+/// A literal `{` can be written as `\{`.
+fn format(args: Vec<Value>) -> Result<Value, String> {
+    let template = expect_str("format", &args[0])?;
+    let args = &args[1..];
+    let holes = template.replace("\\{", "").matches("{}").count();
+    if holes != args.len() {
+        let msg = format!("format template has {} placeholders but got", holes);
+        return Err(format!("{} {} arguments", msg, args.len()));
+    }
+
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'{') => out.push(chars.next().unwrap()),
+            '{' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push_str(&args.next().unwrap().to_string());
+            }
+            _ => out.push(c),
+        }
+    }
+    Ok(Value::Str(out.into()))
+}
+
+/// Add a value to the end of a list.
+fn list_push(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("list_push", &args[0])?;
+    list.borrow_mut().push(args[1].clone());
+    Ok(Value::Unit)
+}
+
+/// Remove and return the last value of a list, or unit if it is empty.
+fn list_pop(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("list_pop", &args[0])?;
+    let last = list.borrow_mut().pop();
+    Ok(last.unwrap_or(Value::Unit))
+}
+
+fn list_len(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("list_len", &args[0])?;
+    let len = list.borrow().len();
+    Ok(Value::Num(len as f64))
+}
+
+/// Get the value at an index, which must be within the list.
+fn list_get(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("list_get", &args[0])?;
+    let items = list.borrow();
+    let idx = expect_index("list_get", &args[1], items.len())?;
+    Ok(items[idx].clone())
+}
+
+/// Replace the value at an index, which must be within the list.
+fn list_set(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("list_set", &args[0])?;
+    let mut items = list.borrow_mut();
+    let idx = expect_index("list_set", &args[1], items.len())?;
+    items[idx] = args[2].clone();
+    Ok(Value::Unit)
+}
+
+/// Copy the values from index `from` up to but not including `to` into a new list. Negative
+/// indices count from the end, and indices past either end are clamped, same as in Python.
+fn list_slice(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("list_slice", &args[0])?;
+    let items = list.borrow();
+    let bound = |val: &Value| match val.as_int() {
+        Some(idx) => Ok(idx),
+        None => Err(format!("list_slice expects a whole number index but got {}", val)),
+    };
+    let range = slice_range(items.len(), Some(bound(&args[1])?), Some(bound(&args[2])?));
+    Ok(Value::list(items[range].to_vec()))
+}
+
+/// Reverse a list in place.
+fn list_reverse(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("list_reverse", &args[0])?;
+    list.borrow_mut().reverse();
+    Ok(Value::Unit)
+}
+
+/// Make a new list with the values of the first list followed by those of the second.
+fn list_concat(args: Vec<Value>) -> Result<Value, String> {
+    let first = expect_list("list_concat", &args[0])?;
+    let second = expect_list("list_concat", &args[1])?;
+    let mut items = first.borrow().clone();
+    items.extend(second.borrow().iter().cloned());
+    Ok(Value::list(items))
+}
+
+/// Make a new empty map. There is no literal syntax for maps, so this is how scripts get one.
+fn map_new(_: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::map(BTreeMap::new()))
+}
+
+/// Get the value for a key, or unit if the key is not in the map.
+fn map_get(args: Vec<Value>) -> Result<Value, String> {
+    let map = expect_map("map_get", &args[0])?;
+    let key = expect_str("map_get", &args[1])?;
+    let val = map.borrow().get(key).cloned();
+    Ok(val.unwrap_or(Value::Unit))
+}
+
+/// Set the value for a key, replacing any value it had.
+fn map_set(args: Vec<Value>) -> Result<Value, String> {
+    let map = expect_map("map_set", &args[0])?;
+    let key = expect_str("map_set", &args[1])?;
+    map.borrow_mut().insert(key.into(), args[2].clone());
+    Ok(Value::Unit)
+}
+
+fn map_has(args: Vec<Value>) -> Result<Value, String> {
+    let map = expect_map("map_has", &args[0])?;
+    let key = expect_str("map_has", &args[1])?;
+    let has = map.borrow().contains_key(key);
+    Ok(Value::Bool(has))
+}
+
+/// Remove a key, returning the value it had or unit if it was not in the map.
+fn map_del(args: Vec<Value>) -> Result<Value, String> {
+    let map = expect_map("map_del", &args[0])?;
+    let key = expect_str("map_del", &args[1])?;
+    let old = map.borrow_mut().remove(key);
+    Ok(old.unwrap_or(Value::Unit))
+}
+
+/// The keys of a map as a list of strings, in sorted order.
+fn map_keys(args: Vec<Value>) -> Result<Value, String> {
+    let map = expect_map("map_keys", &args[0])?;
+    let keys = map.borrow().keys().map(|key| Value::Str(key.clone())).collect();
+    Ok(Value::list(keys))
+}
+
+/// The values of a map as a list, in the order of their keys.
+fn map_values(args: Vec<Value>) -> Result<Value, String> {
+    let map = expect_map("map_values", &args[0])?;
+    let vals = map.borrow().values().cloned().collect();
+    Ok(Value::list(vals))
+}
+
+fn map_len(args: Vec<Value>) -> Result<Value, String> {
+    let map = expect_map("map_len", &args[0])?;
+    let len = map.borrow().len();
+    Ok(Value::Num(len as f64))
+}
+
+fn contains(args: Vec<Value>) -> Result<Value, String> {
+    let s = expect_str("contains", &args[0])?;
+    let sub = expect_str("contains", &args[1])?;
+    Ok(Value::Bool(s.contains(sub)))
+}
+
+/// Find the first place a substring appears in a string, as an index of chars rather than bytes.
+/// Returns unit if it does not appear, same as other look-ups that find nothing.
+fn index_of(args: Vec<Value>) -> Result<Value, String> {
+    let s = expect_str("index_of", &args[0])?;
+    let sub = expect_str("index_of", &args[1])?;
+    match s.find(sub) {
+        Some(byte) => Ok(Value::Num(s[..byte].chars().count() as f64)),
+        None => Ok(Value::Unit),
+    }
+}
+
+/// The Unicode code point of a string with a single char in it.
+fn ord(args: Vec<Value>) -> Result<Value, String> {
+    let s = expect_str("ord", &args[0])?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Value::Num(c as u32 as f64)),
+        _ => Err(format!("ord expects a single char string but got \"{}\"", s)),
+    }
+}
+
+/// The string with just the char for a Unicode code point, the reverse of `ord`.
+fn chr(args: Vec<Value>) -> Result<Value, String> {
+    let c = args[0]
+        .as_int()
+        .and_then(|code| u32::try_from(code).ok())
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("chr expects a valid code point but got {}", args[0]))?;
+    Ok(Value::Str(c.to_string().into()))
+}
+
+/// Split a string into a list of the parts between each separator.
+fn split(args: Vec<Value>) -> Result<Value, String> {
+    let s = expect_str("split", &args[0])?;
+    let sep = expect_str("split", &args[1])?;
+    if sep.is_empty() {
+        return Err("split separator must not be empty".to_owned());
+    }
+    Ok(Value::list(s.split(sep).map(|part| Value::Str(part.into())).collect()))
+}
+
+/// Join a list of strings into one, with the separator between each.
+fn join(args: Vec<Value>) -> Result<Value, String> {
+    let list = expect_list("join", &args[0])?;
+    let sep = expect_str("join", &args[1])?;
+    let parts = list
+        .borrow()
+        .iter()
+        .map(|item| match item {
+            Value::Str(s) => Ok(s.clone()),
+            _ => Err(format!("join expects a list of strings but got {}", item)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::Str(parts.join(sep).into()))
+}
+
+/// Remove whitespace from both ends of a string.
+fn str_trim(name: &str, args: &[Value]) -> Result<Value, String> {
+    let s = expect_str(name, &args[0])?;
+    Ok(Value::Str(s.trim().into()))
+}
+
+/// Remove whitespace from the start of a string.
+fn str_trim_start(args: Vec<Value>) -> Result<Value, String> {
+    let s = expect_str("str_trim_start", &args[0])?;
+    Ok(Value::Str(s.trim_start().into()))
+}
+
+/// Remove whitespace from the end of a string.
+fn str_trim_end(args: Vec<Value>) -> Result<Value, String> {
+    let s = expect_str("str_trim_end", &args[0])?;
+    Ok(Value::Str(s.trim_end().into()))
+}
+
+/// Case conversion follows Unicode, so it can change the length of the string.
+fn str_to_upper(name: &str, args: &[Value]) -> Result<Value, String> {
+    let s = expect_str(name, &args[0])?;
+    Ok(Value::Str(s.to_uppercase().into()))
+}
+
+fn str_to_lower(name: &str, args: &[Value]) -> Result<Value, String> {
+    let s = expect_str(name, &args[0])?;
+    Ok(Value::Str(s.to_lowercase().into()))
+}
+
+/// Repeat a string a whole number of times, up to `MAX_REPEAT_LEN` bytes in total.
+fn str_repeat(args: Vec<Value>) -> Result<Value, String> {
+    let s = expect_str("str_repeat", &args[0])?;
+    let times = match args[1].as_int() {
+        Some(times) if times >= 0 => times as usize,
+        _ => {
+            let msg = "str_repeat expects a non-negative whole number of times but got";
+            return Err(format!("{} {}", msg, args[1]));
+        }
+    };
+    repeat_str(s, times).map(|s| Value::Str(s.into()))
+}
+
+/// Repeat a string, as long as the result is at most `MAX_REPEAT_LEN` bytes. This is shared with
+/// the `*` operator on strings.
+pub fn repeat_str(s: &str, times: usize) -> Result<String, String> {
+    match s.len().checked_mul(times) {
+        Some(len) if len <= MAX_REPEAT_LEN => Ok(s.repeat(times)),
+        _ => Err(format!("repeated string would be longer than {} bytes", MAX_REPEAT_LEN)),
+    }
+}
+
+// Replace up to `count` occurrences of a substring, from the start.
+fn str_replace(name: &str, args: &[Value], count: usize) -> Result<Value, String> {
+    let s = expect_str(name, &args[0])?;
+    let from = expect_str(name, &args[1])?;
+    let to = expect_str(name, &args[2])?;
+    Ok(Value::Str(s.replacen(from, to, count).into()))
+}
+
+/// Convert a value to a bool, i.e. whether it is truthy. Calls that are not shadowed when
+/// compiling become the `OpBool` instruction instead.
+fn to_bool(args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Bool(args[0].is_truthy()))
+}
+
+/// Look up an environment variable, returning unit if it is not set or is not valid unicode.
+fn env_var(args: Vec<Value>) -> Result<Value, String> {
+    let name = expect_str("env_var", &args[0])?;
+    match env::var(name) {
+        Ok(val) => Ok(Value::Str(val.into())),
+        Err(_) => Ok(Value::Unit),
+    }
+}
+
+/// The command-line arguments given to the script, as a list of strings.
+fn args(_: Vec<Value>) -> Result<Value, String> {
+    let limits = vm::caller_limits();
+    Ok(Value::list(limits.args.iter().map(|arg| Value::Str(arg.as_str().into())).collect()))
+}
+
+/// Seconds since the VM was set up, for timing how long code takes.
+fn clock(_: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Num(vm::caller_limits().started.elapsed().as_secs_f64()))
+}
+
+/// Milliseconds since the Unix epoch, according to the system clock.
+fn epoch_ms(_: Vec<Value>) -> Result<Value, String> {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => Ok(Value::Num(since.as_millis() as f64)),
+        Err(e) => Err(format!("system clock is before the Unix epoch: {}", e)),
+    }
+}
+
+/// A random number from 0 up to but not including 1.
+fn rand(_: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Num(vm::caller_limits().rng.borrow_mut().gen()))
+}
+
+/// A random whole number from `lo` up to and including `hi`.
+fn rand_int(args: Vec<Value>) -> Result<Value, String> {
+    let bound = |val: &Value| {
+        val.as_int()
+            .ok_or_else(|| format!("rand_int expects a whole number but got {}", val))
+    };
+    let (lo, hi) = (bound(&args[0])?, bound(&args[1])?);
+    if lo > hi {
+        return Err(format!("rand_int range is empty since {} is greater than {}", lo, hi));
+    }
+    Ok(Value::Num(vm::caller_limits().rng.borrow_mut().gen_range(lo..=hi) as f64))
+}
+
+/// End the script with an exit code, which is 0 if not given. This ends the whole process, unless
+/// the host set an exit handler.
+fn exit(args: Vec<Value>) -> Result<Value, String> {
+    let code = match args.first() {
+        None => 0,
+        Some(val) => match val.as_int().and_then(|code| i32::try_from(code).ok()) {
+            Some(code) => code,
+            None => return Err(format!("exit expects a whole number exit code but got {}", val)),
+        },
+    };
+    match vm::caller_limits().exit_handler.clone() {
+        Some(handler) => {
+            handler.call(code);
+            // Not a panic, so the panic hook does not report it as one.
+            panic::resume_unwind(Box::new(ScriptExit(code)))
+        }
+        None => process::exit(code),
+    }
+}
+
+/// Read the whole file at the path as a string.
+fn read_file(args: Vec<Value>) -> Result<Value, String> {
+    let path = expect_str("read_file", &args[0])?;
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Value::Str(contents.into())),
+        Err(e) => Err(format!("cannot read file '{}': {}", path, e)),
+    }
+}
+
+/// Write a string to the file at the path, replacing anything already there.
+fn write_file(args: Vec<Value>) -> Result<Value, String> {
+    let path = expect_str("write_file", &args[0])?;
+    let contents = expect_str("write_file", &args[1])?;
+    match fs::write(path, contents) {
+        Ok(()) => Ok(Value::Unit),
+        Err(e) => Err(format!("cannot write file '{}': {}", path, e)),
+    }
+}
+
+/// Parse a JSON string into a value. Objects become maps, arrays become lists, and null becomes
+/// unit.
+fn parse_json(args: Vec<Value>) -> Result<Value, String> {
+    let src = expect_str("parse_json", &args[0])?;
+    match serde_json::from_str(src) {
+        Ok(json) => Ok(from_json(json)),
+        Err(e) => Err(format!("cannot parse json: {}", e)),
+    }
+}
+
+/// Serialize a value as a JSON string. Values with no JSON form, such as functions, coroutines
+/// and numbers that are not finite, become null.
+fn to_json(args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Str(into_json(&args[0]).to_string().into()))
+}
+
+/// Make a range that counts by something other than 1, which `start..end` always does.
+fn range(args: Vec<Value>) -> Result<Value, String> {
+    let start = expect_num("range", &args[0])?;
+    let end = expect_num("range", &args[1])?;
+    let step = expect_num("range", &args[2])?;
+    if step == 0.0 || step.is_nan() {
+        return Err(format!("range step must be a non-zero number but got {}", args[2]));
+    }
+    Ok(Value::range(start, end, step))
+}
+
+/// Make a new list with the numbers of a range, or the items of a list.
+fn to_list(args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::List(list) => Ok(Value::list(list.borrow().clone())),
+        Value::Range { start, end, step } => range_items(*start, *end, *step).map(Value::list),
+        val => Err(format!("to_list expects a list or a range but got {}", val)),
+    }
+}
+
+// Ranges are lazy, so they can be too long, or even infinite, to make a list of.
+fn range_items(start: f64, end: f64, step: f64) -> Result<Vec<Value>, String> {
+    if (end - start) / step > MAX_RANGE_LIST_LEN as f64 {
+        return Err(format!("range has more than {} numbers to list", MAX_RANGE_LIST_LEN));
+    }
+    Ok((0..).map_while(|idx| range_nth(start, end, step, idx)).map(Value::Num).collect())
+}
+
+fn from_json(json: serde_json::Value) -> Value {
+    use serde_json::Value as Json;
+    match json {
+        Json::Null => Value::Unit,
+        Json::Bool(b) => Value::Bool(b),
+        Json::Number(n) => Value::Num(n.as_f64().unwrap_or(f64::NAN)),
+        Json::String(s) => Value::Str(s.into()),
+        Json::Array(items) => Value::list(items.into_iter().map(from_json).collect()),
+        Json::Object(entries) => {
+            let entries: BTreeMap<Rc<str>, Value> =
+                entries.into_iter().map(|(k, v)| (k.into(), from_json(v))).collect();
+            Value::map(entries)
+        }
+    }
+}
+
+fn into_json(val: &Value) -> serde_json::Value {
+    use serde_json::Value as Json;
+    match val {
+        Value::Unit => Json::Null,
+        Value::Bool(b) => Json::Bool(*b),
+        // Whole numbers are written without a fraction, e.g. `1` rather than `1.0`.
+        Value::Num(n) => match val.as_int() {
+            Some(int) => Json::from(int),
+            None => serde_json::Number::from_f64(*n).map_or(Json::Null, Json::Number),
+        },
+        Value::Str(s) => Json::String(s.to_string()),
+        Value::List(list) => Json::Array(list.borrow().iter().map(into_json).collect()),
+        Value::Map(map) => {
+            let entries = map.borrow();
+            Json::Object(entries.iter().map(|(k, v)| (k.to_string(), into_json(v))).collect())
+        }
+        Value::Range { start, end, step } => match range_items(*start, *end, *step) {
+            Ok(items) => Json::Array(items.iter().map(into_json).collect()),
+            Err(_) => Json::Null,
+        },
+        Value::Fn(_) | Value::NativeFn(_) | Value::Co(_) | Value::Lazy(_) => Json::Null,
+    }
+}
+
+fn expect_str<'a>(name: &str, val: &'a Value) -> Result<&'a str, String> {
+    match val {
+        Value::Str(s) => Ok(s),
+        _ => Err(format!("{} expects a string but got {}", name, val)),
+    }
+}
+
+fn expect_num(name: &str, val: &Value) -> Result<f64, String> {
+    match val {
+        Value::Num(num) => Ok(*num),
+        _ => Err(format!("{} expects a number but got {}", name, val)),
+    }
+}
+
+fn expect_index(name: &str, val: &Value, len: usize) -> Result<usize, String> {
+    match val.as_int() {
+        Some(idx) if idx >= 0 && (idx as usize) < len => Ok(idx as usize),
+        Some(idx) => Err(format!("{} index {} is out of bounds for length {}", name, idx, len)),
+        None => Err(format!("{} expects a whole number index but got {}", name, val)),
+    }
+}
+
+type Entries = RefCell<BTreeMap<Rc<str>, Value>>;
+
+fn expect_map<'a>(name: &str, val: &'a Value) -> Result<&'a Entries, String> {
+    match val {
+        Value::Map(map) => Ok(map),
+        _ => Err(format!("{} expects a map but got {}", name, val)),
+    }
+}
+
+fn expect_co<'a>(name: &str, val: &'a Value) -> Result<&'a Rc<RefCell<Coro>>, String> {
+    match val {
+        Value::Co(co) => Ok(co),
+        _ => Err(format!("{} expects a coroutine but got {}", name, val)),
+    }
+}
+
+fn expect_list<'a>(name: &str, val: &'a Value) -> Result<&'a Rc<RefCell<Vec<Value>>>, String> {
+    match val {
+        Value::List(list) => Ok(list),
+        _ => Err(format!("{} expects a list but got {}", name, val)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::error::CoError;
+    use crate::vm::CoRes;
+    use crate::vm::SharedBuf;
+
+    fn eval(src: &str) -> Result<Value, String> {
+        let mut co = CoVM::build(src).map_err(|e| e.message().to_owned())?;
+        CoVM::run(&mut co).map_err(|e| e.message().to_owned())
+    }
+
+    // Makes a coroutine that yields the whole numbers from 0 up to but not including `n`.
+    const UPTO: &str = "def upto n = { \
+        def gen = { let i = 0; while i < n do { yield i; i += 1 } end }; create gen }\n";
+
+    #[test]
+    fn lookup_builtin() {
+        assert!(lookup("sort").unwrap().is_native_fn());
+        assert!(lookup("nope").is_none());
+    }
+
+    #[test]
+    fn sort_numbers() {
+        let val = eval("let xs = [3, 1, 2]\nsort(xs)\nxs").unwrap();
+        assert_eq!("[1, 2, 3]", format!("{}", val));
+    }
+
+    #[test]
+    fn sort_mixed() {
+        let val = eval("let xs = [\"b\", 2, (), \"a\", true, 1]\nsort(xs)\nxs").unwrap();
+        assert_eq!("[(), true, 1, 2, \"a\", \"b\"]", format!("{}", val));
+    }
+
+    #[test]
+    fn sort_strings() {
+        let val = eval("let xs = [\"pear\", \"apple\", \"fig\"]\nsort(xs)\nxs").unwrap();
+        assert_eq!("[\"apple\", \"fig\", \"pear\"]", format!("{}", val));
+    }
+
+    #[test]
+    fn sort_by_function() {
+        let src = "let xs = [1, 3, 2]\nsort_by(xs, fn a b -> b - a)\nxs";
+        assert_eq!("[3, 2, 1]", format!("{}", eval(src).unwrap()));
+
+        let src = "sort_by([2, 1], fn a b -> true)";
+        assert_eq!("sort_by comparison must return a number", eval(src).unwrap_err());
+    }
+
+    #[test]
+    fn fmt_numbers() {
+        assert_eq!(Value::Bool(true), eval("fmt_num(3.14159, 2.0) == \"3.14\"").unwrap());
+        assert_eq!("2", format!("{}", eval("fmt_num(1.5, 0)").unwrap()));
+        assert_eq!("1.5e3", format!("{}", eval("fmt_sci(1500)").unwrap()));
+        assert_eq!(Value::Bool(true), eval("fmt_hex(255.0) == \"ff\"").unwrap());
+        assert_eq!("-1a", format!("{}", eval("fmt_hex(-26.9)").unwrap()));
+
+        assert_eq!("fmt_sci expects a number but got a", eval("fmt_sci(\"a\")").unwrap_err());
+        assert!(eval("fmt_num(1, -1)").is_err());
+        assert!(eval("fmt_num(1, 0.5)").is_err());
+        assert_eq!(102, format!("{}", eval("fmt_num(1, 100)").unwrap()).len());
+        let err = eval("fmt_num(1, 100000)").unwrap_err();
+        assert_eq!("fmt_num expects a whole number of places up to 100 but got 100000", err);
+        assert!(eval("fmt_hex(0x7fffffffffffffff * 4)").unwrap_err().contains("fits in 64 bits"));
+    }
+
+    #[test]
+    fn input_lines() {
+        let builder = CoVM::with_input(Box::new(io::Cursor::new("alice\r\nbob")));
+        let out = SharedBuf::default();
+        let mut co = builder.build("[input(\"name? \"), input(\"\"), input(\"again? \")]").unwrap();
+        co.set_output(Box::new(out.clone()));
+        let val = CoVM::run(&mut co).unwrap();
+        assert_eq!("[\"alice\", \"bob\", ()]", format!("{}", val));
+        // The prompts go to the same place as `print`.
+        assert_eq!(b"name? again? ", &out.0.borrow()[..]);
+
+        assert_eq!("input expects a string but got 1", eval("input(1)").unwrap_err());
+    }
+
+    #[test]
+    fn env_and_args() {
+        env::set_var("CORO_TEST_ENV_VAR", "on");
+        assert_eq!(Value::Str("on".into()), eval("env_var(\"CORO_TEST_ENV_VAR\")").unwrap());
+        assert_eq!(Value::Unit, eval("env_var(\"CORO_TEST_UNSET_VAR\")").unwrap());
+
+        assert_eq!("[]", format!("{}", eval("args()").unwrap()));
+        let builder = CoVM::with_args(vec!["a".to_owned(), "b c".to_owned()]);
+        let val = CoVM::run(&mut builder.build("args()").unwrap()).unwrap();
+        assert_eq!("[\"a\", \"b c\"]", format!("{}", val));
+        // Callbacks see the args of the coroutine that called the native.
+        let val = CoVM::run(&mut builder.build("map([1], fn _ -> args())").unwrap()).unwrap();
+        assert_eq!("[[\"a\", \"b c\"]]", format!("{}", val));
+        assert_eq!("[]", format!("{}", eval("args()").unwrap()));
+    }
+
+    #[test]
+    fn clock_and_epoch() {
+        let src = "let a = clock()\nlet b = clock()\n[a, b]";
+        let times = match eval(src).unwrap() {
+            Value::List(list) => list.borrow().clone(),
+            val => panic!("expected a list but got {}", val),
+        };
+        match (&times[0], &times[1]) {
+            (Value::Num(a), Value::Num(b)) => assert!(0.0 <= *a && a <= b, "{} then {}", a, b),
+            _ => panic!("expected numbers but got {:?}", times),
+        }
+
+        // The clock starts when the config is made, not when the coroutine is built.
+        let builder = CoVM::builder();
+        thread::sleep(Duration::from_millis(10));
+        match CoVM::run(&mut builder.build("clock()").unwrap()).unwrap() {
+            Value::Num(secs) => assert!(secs >= 0.01, "{}", secs),
+            val => panic!("expected a number but got {}", val),
+        }
+
+        // Between 2020 and 2100.
+        match eval("epoch_ms()").unwrap() {
+            Value::Num(ms) => assert!(1.5e12 < ms && ms < 4.2e12, "{}", ms),
+            val => panic!("expected a number but got {}", val),
+        }
+    }
+
+    #[test]
+    fn seeded_random() {
+        let src = "[rand(), rand(), rand_int(1, 6), rand_int(1, 6), rand_int(1, 6), \
+            rand_int(-2, -2)]";
+        let exp = "[0.15935863614561085, 0.30424576388446034, 5, 4, 1, -2]";
+        let builder = CoVM::with_random_seed(42);
+        let eval_seeded = |src: &str| CoVM::run(&mut builder.build(src).unwrap()).unwrap();
+        assert_eq!(exp, format!("{}", eval_seeded(src)));
+        // Each coroutine built from the config starts the generator over.
+        assert_eq!(exp, format!("{}", eval_seeded(src)));
+
+        // Coroutines draw from the same generator, which carries on across yields.
+        let src = "let f = fn -> { yield rand(); rand_int(1, 6) }\nlet c = create f\n\
+            let a = (resume c)\nlet b = rand()\n[a, b, (resume c)]";
+        let exp = "[0.15935863614561085, 0.30424576388446034, 5]";
+        assert_eq!(exp, format!("{}", eval_seeded(src)));
+
+        let err = eval("rand_int(0.5, 1)").unwrap_err();
+        assert_eq!("rand_int expects a whole number but got 0.5", err);
+        let err = eval("rand_int(2, 1)").unwrap_err();
+        assert_eq!("rand_int range is empty since 2 is greater than 1", err);
+    }
+
+    #[test]
+    fn exit_handler() {
+        let codes = Rc::new(RefCell::new(Vec::new()));
+        let seen = codes.clone();
+        let builder = CoVM::with_exit_handler(Box::new(move |code| seen.borrow_mut().push(code)));
+
+        assert!(matches!(builder.eval("exit(3)\nlet x = 1 / [2]"), CoRes::Exit(3)));
+        assert!(matches!(builder.eval("let f = fn -> exit()\nf()\n1"), CoRes::Exit(0)));
+
+        // Hosts running the coroutine themselves get it back as an error, even from deep inside
+        // natives.
+        let mut co = builder.build("exit(5)").unwrap();
+        assert_eq!(Err(CoError::Exit(5)), CoVM::run(&mut co));
+        let src = "def gen = { exit(6) }\nlet c = create gen\ntake(1, c)";
+        let mut co = builder.build(src).unwrap();
+        assert_eq!(Err(CoError::Exit(6)), CoVM::run(&mut co));
+        let mut co = builder.build("map([1], fn x -> exit(7))").unwrap();
+        assert_eq!(Err(CoError::Exit(7)), co.resume(Vec::new()));
+        assert_eq!(vec![3, 0, 5, 6, 7], *codes.borrow());
+
+        let err = eval("exit(0.5)").unwrap_err();
+        assert_eq!("exit expects a whole number exit code but got 0.5", err);
+        let err = eval("exit(1, 2)").unwrap_err();
+        assert_eq!("expected 0 to 1 arguments but got 2 when calling 'exit'", err);
+    }
+
+    #[test]
+    fn map_and_filter() {
+        let src = "let xs = [1, 2, 3, 4]\nlet ys = map(xs, fn x -> x * 2)\n[xs, ys]";
+        assert_eq!("[[1, 2, 3, 4], [2, 4, 6, 8]]", format!("{}", eval(src).unwrap()));
+        let src = "filter([5, 1, 4, 2, 3], fn x -> x > 2)";
+        assert_eq!("[5, 4, 3]", format!("{}", eval(src).unwrap()));
+        let src = "map(filter([\"b\", \"a\", \"c\"], fn s -> s > \"a\"), upper)";
+        assert_eq!("[\"B\", \"C\"]", format!("{}", eval(src).unwrap()));
+        assert_eq!("[]", format!("{}", eval("map([], fn x -> x + 1)").unwrap()));
+
+        assert_eq!("map expects a list but got 1", eval("map(1, upper)").unwrap_err());
+        let err = eval("filter([1], fn x -> x + \"a\")").unwrap_err();
+        assert_eq!("operands must be numbers", err);
+    }
+
+    #[test]
+    fn fold_list() {
+        assert_eq!(Value::Num(10.0), eval("fold([1, 2, 3, 4], 0, fn acc x -> acc + x)").unwrap());
+        assert_eq!(Value::Str("init".into()), eval("fold([], \"init\", fn acc x -> x)").unwrap());
+        let src = "fold([\"a\", \"b\"], [], fn acc x -> list_concat([x], acc))";
+        assert_eq!("[\"b\", \"a\"]", format!("{}", eval(src).unwrap()));
+
+        assert_eq!("fold expects a list but got ()", eval("fold((), 0, upper)").unwrap_err());
+        let err = eval("fold([1], 0, fn acc -> acc)").unwrap_err();
+        assert_eq!("expected 1 arguments but got 2 when resuming coroutine", err);
+    }
+
+    #[test]
+    fn lazy_values() {
+        let src = "let l = lazy(fn -> 1 + 2)\nlet before = format(\"{}\", l)\n\
+            [before, force(l), force(l), l]";
+        assert_eq!("[\"<lazy>\", 3, 3, <lazy 3>]", format!("{}", eval(src).unwrap()));
+        assert_eq!(Value::Num(1.0), eval("force(1)").unwrap());
+        assert_eq!("lazy expects a function but got 1", eval("lazy(1)").unwrap_err());
+        let err = eval("force(lazy(fn x -> x))").unwrap_err();
+        assert_eq!("expected 1 arguments but got 0 when resuming coroutine", err);
+
+        // The function runs on the first force only.
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let fun = NativeFn::new("count", 0, move |_| {
+            counter.set(counter.get() + 1);
+            Ok(Value::Num(counter.get() as f64))
+        });
+        let val = Value::lazy(Value::NativeFn(Rc::new(fun)));
+        for _ in 0..3 {
+            assert_eq!(Value::Num(1.0), force(vec![val.clone()]).unwrap());
+        }
+        assert_eq!(1, calls.get());
+    }
+
+    #[test]
+    fn lazy_depends_on_itself() {
+        let slot: Rc<RefCell<Option<Value>>> = Rc::default();
+        let inner = slot.clone();
+        let fun = NativeFn::new("loop", 0, move |_| force(vec![inner.borrow().clone().unwrap()]));
+        let val = Value::lazy(Value::NativeFn(Rc::new(fun)));
+        *slot.borrow_mut() = Some(val.clone());
+        assert_eq!("lazy value depends on itself", force(vec![val.clone()]).unwrap_err());
+        // A failed force can be tried again.
+        match &val {
+            Value::Lazy(lazy) => assert!(matches!(&*lazy.borrow(), Lazy::Pending(_))),
+            _ => unreachable!(),
+        }
+        slot.borrow_mut().take();
+    }
+
+    #[test]
+    fn memo_fib() {
+        // Without the cache this makes over a trillion calls.
+        let src = "def fib f n = n < 2 ? n : f(f, n - 1) + f(f, n - 2)\n\
+            let f = memo(fib)\n[f(f, 70), f(f, 10)]";
+        assert_eq!("[190392490709135, 55]", format!("{}", eval(src).unwrap()));
+
+        // Each number is worked out once, rather than an exponential number of times.
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let fib = NativeFn::new("fib", 2, move |args| {
+            counter.set(counter.get() + 1);
+            let n = args[1].as_int().unwrap();
+            if n < 2 {
+                return Ok(args[1].clone());
+            }
+            let call = |n| {
+                let val = CoVM::call(&args[0], vec![args[0].clone(), Value::Num(n as f64)]);
+                val.unwrap().as_int().unwrap()
+            };
+            Ok(Value::Num((call(n - 1) + call(n - 2)) as f64))
+        });
+        let f = memo(vec![Value::NativeFn(Rc::new(fib))]).unwrap();
+        let val = CoVM::call(&f, vec![f.clone(), Value::Num(30.0)]).unwrap();
+        assert_eq!(Value::Num(832040.0), val);
+        assert_eq!(31, calls.get());
+    }
+
+    #[test]
+    fn memo_args() {
+        let src = "let f = memo(fn a (b = 1) -> [a, b])\n[f(1), f(1), f(1, 2)]";
+        assert_eq!("[[1, 1], [1, 1], [1, 2]]", format!("{}", eval(src).unwrap()));
+
+        // Keys are copies, so a list can be changed after being passed without a stale hit.
+        let src = "let f = memo(list_len)\nlet xs = [1]\nlet a = f(xs)\nlist_push(xs, 2)\n\
+            [a, f(xs), f([1])]";
+        assert_eq!("[1, 2, 1]", format!("{}", eval(src).unwrap()));
+
+        assert_eq!("memo expects a function but got 1", eval("memo(1)").unwrap_err());
+        let err = eval("let f = memo(list_len)\nf()").unwrap_err();
+        assert_eq!("expected 1 arguments but got 0 when calling 'list_len'", err);
+    }
+
+    #[test]
+    fn infinite_take() {
+        let src = "let nats = infinite(fn n -> [n + 1, n], 0)
+[take(5, nats), take(2, nats)]";
+        assert_eq!("[[0, 1, 2, 3, 4], [5, 6]]", format!("{}", eval(src).unwrap()));
+
+        let src = "def fib st = [[st[1], st[0] + st[1]], st[0]]
+take(7, infinite(fib, [0, 1]))";
+        assert_eq!("[0, 1, 1, 2, 3, 5, 8]", format!("{}", eval(src).unwrap()));
+
+        // Coroutines that finish give back fewer values.
+        let src = "def two = { yield 1; yield 2; 3 }\nlet co = create two\ntake(5, co)";
+        assert_eq!("[1, 2]", format!("{}", eval(src).unwrap()));
+
+        assert_eq!("infinite expects a function but got 1", eval("infinite(1, 0)").unwrap_err());
+        let err = eval("take(1, infinite(fn n -> n, 0))").unwrap_err();
+        assert_eq!("infinite expects the function to give back [state, value] but got 0", err);
+        let err = eval("take(-1, infinite(fn n -> [n, n], 0))").unwrap_err();
+        assert_eq!("take expects a non-negative whole number but got -1", err);
+
+        // The coroutine runs with the limits of the code that made it.
+        let builder = CoVM::builder().with_max_steps(10_000);
+        let run = |src: &str| {
+            CoVM::run(&mut builder.build(src).unwrap()).map_err(|e| e.message().to_owned())
+        };
+        let src = "last(1, infinite(fn n -> [n + 1, n], 0))";
+        assert_eq!("step limit of 10000 exceeded", run(src).unwrap_err());
+        let src = "take(1000000, chain_co(infinite(fn n -> [n, n], 0)))";
+        assert_eq!("step limit of 10000 exceeded", run(src).unwrap_err());
+        let src = "take(3, infinite(fn n -> [n + 1, n], 0))";
+        assert_eq!("[0, 1, 2]", format!("{}", run(src).unwrap()));
+    }
+
+    #[test]
+    fn flat_map_co_chains() {
+        let src = format!("{}take(10, flat_map_co(fn n -> upto(n), upto(4)))", UPTO);
+        assert_eq!("[0, 0, 1, 0, 1, 2]", format!("{}", eval(&src).unwrap()));
+
+        // Stops pulling from the outer coroutine once enough values are taken.
+        let src = format!("{}take(3, flat_map_co(upto, infinite(fn n -> [n + 1, n], 2)))", UPTO);
+        assert_eq!("[0, 1, 0]", format!("{}", eval(&src).unwrap()));
+
+        let src = format!("{}def both = {{ yield upto(2); yield upto(3) }}\n\
+            let co = create both\ntake(10, flat(co))", UPTO);
+        assert_eq!("[0, 1, 0, 1, 2]", format!("{}", eval(&src).unwrap()));
+
+        let src = format!("{}take(1, flat_map_co(fn n -> n, upto(2)))", UPTO);
+        assert_eq!("flat_map_co expects a coroutine but got 0", eval(&src).unwrap_err());
+    }
+
+    #[test]
+    fn chain_interleave() {
+        let src = format!("{}take(10, chain_co(upto(2), upto(3)))", UPTO);
+        assert_eq!("[0, 1, 0, 1, 2]", format!("{}", eval(&src).unwrap()));
+        let src = format!("{}take(10, chain_co([upto(3), upto(0), upto(1)]))", UPTO);
+        assert_eq!("[0, 1, 2, 0]", format!("{}", eval(&src).unwrap()));
+
+        let src = format!("{}take(10, interleave_co(upto(2), upto(4)))", UPTO);
+        assert_eq!("[0, 0, 1, 1, 2, 3]", format!("{}", eval(&src).unwrap()));
+        let src = format!("{}take(10, interleave_co(upto(3), upto(1)))", UPTO);
+        assert_eq!("[0, 0, 1, 2]", format!("{}", eval(&src).unwrap()));
+        let src = "take(5, interleave_co(infinite(fn n -> [n + 1, n], 0), \
+            infinite(fn n -> [n - 1, n], 0)))";
+        assert_eq!("[0, 0, 1, -1, 2]", format!("{}", eval(src).unwrap()));
+
+        let src = format!("{}def both = {{ yield upto(2); yield upto(1) }}\n\
+            let co = create both\ntake(10, concat_co(co))", UPTO);
+        assert_eq!("[0, 1, 0]", format!("{}", eval(&src).unwrap()));
+
+        let err = eval("chain_co([1])").unwrap_err();
+        assert_eq!("chain_co expects a coroutine but got 1", err);
+    }
+
+    #[test]
+    fn first_last() {
+        let src = format!("{}let co = upto(10)\n[first(co), first(co), first(upto(0))]", UPTO);
+        assert_eq!("[0, 1, ()]", format!("{}", eval(&src).unwrap()));
+
+        let src = format!("{}[last(3, upto(10)), last(1, upto(10)), last(0, upto(10))]", UPTO);
+        assert_eq!("[[7, 8, 9], [9], []]", format!("{}", eval(&src).unwrap()));
+        let src = format!("{}[last(5, upto(2)), last(2, upto(0))]", UPTO);
+        assert_eq!("[[0, 1], []]", format!("{}", eval(&src).unwrap()));
+
+        let err = eval("last(-1, infinite(fn n -> [n, n], 0))").unwrap_err();
+        assert_eq!("last expects a non-negative whole number but got -1", err);
+    }
+
+    #[test]
+    fn skip_prefix() {
+        let src = format!("{}let co = upto(6)\n[take(2, skip(3, co)), take(2, co)]", UPTO);
+        assert_eq!("[[3, 4], [5]]", format!("{}", eval(&src).unwrap()));
+        let src = format!("{}take(2, skip(9, upto(3)))", UPTO);
+        assert_eq!("[]", format!("{}", eval(&src).unwrap()));
+
+        let src = format!("{}let co = skip_while(fn n -> n < 4, upto(6))\n[take(9, co)]", UPTO);
+        assert_eq!("[[4, 5]]", format!("{}", eval(&src).unwrap()));
+        let src = "let evens = infinite(fn n -> [n + 2, n], 0)\n\
+            take(2, skip_while(fn n -> n < 5, evens))";
+        assert_eq!("[6, 8]", format!("{}", eval(src).unwrap()));
+        let src = format!("{}take(2, skip_while(fn n -> true, upto(3)))", UPTO);
+        assert_eq!("[]", format!("{}", eval(&src).unwrap()));
+
+        // The rejected value is held on to rather than made again, so nothing is run twice.
+        let src = format!("{}def twice = {{ yield upto(3); yield upto(3) }}\n\
+            take(10, skip_while(fn n -> n < 1, flat((create twice))))", UPTO);
+        assert_eq!("[1, 2, 0, 1, 2]", format!("{}", eval(&src).unwrap()));
+        let src = format!("{}let inner = upto(5)\n\
+            def wrap = {{ let i = 0; while i < 5 do {{ yield (resume inner); i += 1 }} end }}\n\
+            take(9, skip_while(fn n -> n < 2, (create wrap)))", UPTO);
+        assert_eq!("[2, 3, 4]", format!("{}", eval(&src).unwrap()));
+        let src = "def noisy = { print 1; yield 1; print 2; yield 2 }\n\
+            take(9, skip_while(fn n -> n < 2, (create noisy)))";
+        let (val, out) = CoVM::capture_output(src).unwrap();
+        assert_eq!(("[2]".to_owned(), "1\n2\n".to_owned()), (format!("{}", val), out));
+
+        let err = eval("skip(1.5, infinite(fn n -> [n, n], 0))").unwrap_err();
+        assert_eq!("skip expects a non-negative whole number but got 1.5", err);
+    }
+
+    #[test]
+    fn any_all_count() {
+        // Both stop early, so the rest of the stream is still there.
+        let src = "let nats = infinite(fn n -> [n + 1, n], 0)\n\
+            [any(fn n -> n > 2, nats), take(1, nats), all(fn n -> n < 6, nats), take(1, nats)]";
+        assert_eq!("[true, [4], false, [7]]", format!("{}", eval(src).unwrap()));
+
+        let src = "def upto = { let i = 0; while i < 4 do { yield i; let i = i + 1 } end }\n\
+            let a = create upto\nlet b = create upto\nlet c = create upto\n\
+            [any(fn n -> n > 5, a), all(fn n -> n < 5, b), count(fn n -> n > 1, c)]";
+        assert_eq!("[false, true, 2]", format!("{}", eval(src).unwrap()));
+
+        let err = eval("count(fn n -> n, [])").unwrap_err();
+        assert_eq!("count expects a coroutine but got []", err);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!("[0, 1, 2]", format!("{}", eval("to_list(0..3)").unwrap()));
+        assert_eq!("[10, 7, 4, 1]", format!("{}", eval("to_list(range(10, 0, -3))").unwrap()));
+        assert_eq!("[]", format!("{}", eval("to_list(2..1)").unwrap()));
+        assert_eq!("range(0, 1, 0.5)", format!("{}", eval("range(0, 1, 0.5)").unwrap()));
+
+        // Lists are copied rather than shared.
+        let src = "let a = [1]\nlet b = to_list(a)\nlist_push(b, 2)\n[a, b]";
+        assert_eq!("[[1], [1, 2]]", format!("{}", eval(src).unwrap()));
+
+        let err = eval("range(0, 1, 0)").unwrap_err();
+        assert_eq!("range step must be a non-zero number but got 0", err);
+        let err = eval("to_list(0..100000000)").unwrap_err();
+        assert_eq!("range has more than 16777216 numbers to list", err);
+        assert_eq!("to_list expects a list or a range but got 1", eval("to_list(1)").unwrap_err());
+    }
+
+    #[test]
+    fn format_template() {
+        let src = "format(\"{} + {} = {}\", 1.0, 2.0, 3.0)";
+        assert_eq!(Value::Str("1 + 2 = 3".into()), eval(src).unwrap());
+        let src = "format(\"\\{} is {}, {}\", [1, \"a\"], \"b\")";
+        assert_eq!(Value::Str("{} is [1, \"a\"], b".into()), eval(src).unwrap());
+        assert_eq!(Value::Str("{".into()), eval("format(\"{\")").unwrap());
+
+        let err = eval("format(\"{} {}\", 1)").unwrap_err();
+        assert_eq!("format template has 2 placeholders but got 1 arguments", err);
+        let err = eval("format(\"\\{}\", 1)").unwrap_err();
+        assert_eq!("format template has 0 placeholders but got 1 arguments", err);
+    }
+
+    #[test]
+    fn list_functions() {
+        let src = "let xs = []\nlist_push(xs, 1)\nlist_push(xs, 2)\nlist_push(xs, 3)\n\
+            let last = list_pop(xs)\n[xs, last, list_len(xs), list_pop([])]";
+        assert_eq!("[[1, 2], 3, 2, ()]", format!("{}", eval(src).unwrap()));
+
+        let src = "let xs = [1, 2, 3]\nlist_set(xs, 0, list_get(xs, 2))\nlist_reverse(xs)\nxs";
+        assert_eq!("[3, 2, 3]", format!("{}", eval(src).unwrap()));
+        let err = eval("list_get([1], 1)").unwrap_err();
+        assert_eq!("list_get index 1 is out of bounds for length 1", err);
+        assert!(eval("list_set([], 0, 1)").is_err());
+        assert!(eval("list_get([1], -1)").is_err());
+        assert!(eval("list_get([1], 0.5)").is_err());
+
+        let slice = |args: &str| {
+            let src = format!("list_slice([1, 2, 3], {})", args);
+            format!("{}", eval(&src).unwrap())
+        };
+        assert_eq!("[2, 3]", slice("1, 3"));
+        assert_eq!("[1, 2, 3]", slice("0, 10"));
+        assert_eq!("[2]", slice("-2, -1"));
+        assert_eq!("[]", slice("2, 1"));
+        assert_eq!("[]", slice("3, 3"));
+        assert_eq!("[1, 2]", slice("-10, 2"));
+        assert_eq!("[]", format!("{}", eval("list_slice([], 0, 1)").unwrap()));
+
+        let src = "let xs = [1]\nlet ys = list_concat(xs, [2, 3])\nlist_push(ys, 4)\n[xs, ys]";
+        assert_eq!("[[1], [1, 2, 3, 4]]", format!("{}", eval(src).unwrap()));
+        assert_eq!("[]", format!("{}", eval("list_concat([], [])").unwrap()));
+
+        for name in ["list_pop", "list_len", "list_reverse"] {
+            let err = eval(&format!("{}(\"a\")", name)).unwrap_err();
+            assert_eq!(format!("{} expects a list but got a", name), err);
+        }
+        assert_eq!("list_push expects a list but got 1", eval("list_push(1, 2)").unwrap_err());
+        assert!(eval("list_concat([], 1)").is_err());
+        assert!(eval("list_slice(1, 0, 1)").is_err());
+    }
+
+    #[test]
+    fn map_functions() {
+        // Maps are shared, so changes through one name are seen through the other.
+        let src = "let m = map_new()\nlet n = m\nmap_set(m, \"b\", 2)\nmap_set(n, \"a\", 1)\n\
+            map_set(m, \"c\", [3])\n\
+            [n, map_len(n), map_keys(m), map_values(m), map_has(n, \"b\"), map_has(n, \"z\")]";
+        let exp = "[{\"a\": 1, \"b\": 2, \"c\": [3]}, 3, [\"a\", \"b\", \"c\"], [1, 2, [3]], \
+            true, false]";
+        assert_eq!(exp, format!("{}", eval(src).unwrap()));
+
+        let src = "let m = map_new()\nmap_set(m, \"a\", 1)\nmap_set(m, \"a\", 2)\n\
+            [map_get(m, \"a\"), map_get(m, \"b\"), map_del(m, \"a\"), map_del(m, \"a\"), m]";
+        assert_eq!("[2, (), 2, (), {}]", format!("{}", eval(src).unwrap()));
+
+        assert_eq!("map_len expects a map but got []", eval("map_len([])").unwrap_err());
+        let err = eval("map_get(map_new(), 1)").unwrap_err();
+        assert_eq!("map_get expects a string but got 1", err);
+    }
+
+    #[test]
+    fn contains_and_index_of() {
+        let src = "[contains(\"coroutine\", \"rout\"), contains(\"coroutine\", \"corn\"), \
+            contains(\"coro\", \"coro\"), contains(\"\", \"\")]";
+        assert_eq!("[true, false, true, true]", format!("{}", eval(src).unwrap()));
+
+        let src = "[index_of(\"coroutine\", \"co\"), index_of(\"coroutine\", \"o\"), \
+            index_of(\"coroutine\", \"ine\"), index_of(\"coroutine\", \"x\"), \
+            index_of(\"coro\", \"\"), index_of(\"héllo wörld\", \"wö\")]";
+        assert_eq!("[0, 1, 6, (), 0, 6]", format!("{}", eval(src).unwrap()));
+
+        assert_eq!("index_of expects a string but got 1", eval("index_of(1, \"a\")").unwrap_err());
+    }
+
+    #[test]
+    fn ord_and_chr() {
+        let src = "[ord(\"A\"), ord(\"é\"), chr(97), chr(0x1F600), chr(ord(\"A\")), \
+            chr(ord(\"A\") + 2)]";
+        assert_eq!("[65, 233, \"a\", \"😀\", \"A\", \"C\"]", format!("{}", eval(src).unwrap()));
+
+        let err = eval("ord(\"ab\")").unwrap_err();
+        assert_eq!("ord expects a single char string but got \"ab\"", err);
+        assert_eq!("ord expects a single char string but got \"\"", eval("ord(\"\")").unwrap_err());
+        let err = eval("chr(0xD800)").unwrap_err();
+        assert_eq!("chr expects a valid code point but got 55296", err);
+        assert_eq!("chr expects a valid code point but got -1", eval("chr(0 - 1)").unwrap_err());
+        assert_eq!("chr expects a valid code point but got 1.5", eval("chr(1.5)").unwrap_err());
+    }
+
+    #[test]
+    fn split_and_join() {
+        let val = eval("split(\"a,b,c\", \",\")").unwrap();
+        assert_eq!("[\"a\", \"b\", \"c\"]", format!("{}", val));
+        let val = eval("split(\",a,\", \",\")").unwrap();
+        assert_eq!("[\"\", \"a\", \"\"]", format!("{}", val));
+        let src = "join(split(\"a, b, c\", \", \"), \", \") == \"a, b, c\"";
+        assert_eq!(Value::Bool(true), eval(src).unwrap());
+        assert_eq!(Value::Str("".into()), eval("join([], \"-\")").unwrap());
+
+        assert_eq!("split separator must not be empty", eval("split(\"a\", \"\")").unwrap_err());
+        let err = eval("join([\"a\", 1], \",\")").unwrap_err();
+        assert_eq!("join expects a list of strings but got 1", err);
+    }
+
+    #[test]
+    fn case_and_trim() {
+        assert_eq!(Value::Str("ABC1".into()), eval("upper(\"aBc1\")").unwrap());
+        assert_eq!(Value::Str("abc1".into()), eval("lower(\"aBc1\")").unwrap());
+        assert_eq!(Value::Str("STRASSE".into()), eval("upper(\"straße\")").unwrap());
+        assert_eq!(Value::Str("école".into()), eval("lower(\"ÉCOLE\")").unwrap());
+        assert_eq!(Value::Str("a  b".into()), eval("trim(\" \ta  b\n \")").unwrap());
+        assert_eq!(Value::Str("".into()), eval("trim(\"\")").unwrap());
+        assert_eq!("upper expects a string but got ()", eval("upper(())").unwrap_err());
+    }
+
+    #[test]
+    fn str_functions() {
+        let str_eval = |src: &str| match eval(src) {
+            Ok(Value::Str(s)) => s.to_string(),
+            res => panic!("{:?}", res),
+        };
+        assert_eq!("a b", str_eval("str_trim(\"  a b \n\")"));
+        assert_eq!("a ", str_eval("str_trim_start(\" a \")"));
+        assert_eq!(" a", str_eval("str_trim_end(\" a \")"));
+        assert_eq!("", str_eval("str_trim(\"   \")"));
+        assert_eq!("ÄB1", str_eval("str_to_upper(\"äb1\")"));
+        assert_eq!("ab", str_eval("str_to_lower(\"AB\")"));
+        assert_eq!("ababab", str_eval("str_repeat(\"ab\", 3)"));
+        assert_eq!("", str_eval("str_repeat(\"ab\", 0)"));
+        assert_eq!("", str_eval("str_repeat(\"\", 0x7fffffff)"));
+        assert_eq!("xbab", str_eval("str_replace(\"abab\", \"a\", \"x\")"));
+        assert_eq!("xbxb", str_eval("str_replace_all(\"abab\", \"a\", \"x\")"));
+        assert_eq!("abab", str_eval("str_replace_all(\"abab\", \"c\", \"\")"));
+        assert_eq!("", str_eval("str_replace(\"\", \"a\", \"b\")"));
+
+        let err = eval("str_repeat(\"ab\", 0x1000000)").unwrap_err();
+        assert_eq!("repeated string would be longer than 16777216 bytes", err);
+        let err = eval("str_repeat(\"ab\", 0x1000000000000)").unwrap_err();
+        assert_eq!("repeated string would be longer than 16777216 bytes", err);
+        assert!(eval("str_repeat(\"ab\", -1)").is_err());
+        assert!(eval("str_repeat(\"ab\", 1.5)").is_err());
+        assert_eq!("str_trim expects a string but got 1", eval("str_trim(1)").unwrap_err());
+        let err = eval("str_replace(\"a\", 1, \"b\")").unwrap_err();
+        assert_eq!("str_replace expects a string but got 1", err);
+    }
+
+    #[test]
+    fn file_io_opt_in() {
+        assert_eq!("no binding for name 'read_file'", eval("read_file(\"x\")").unwrap_err());
+        let eval_io = |src: &str| {
+            let mut co = CoVM::with_file_io().build(src).map_err(|e| e.message().to_owned())?;
+            CoVM::run(&mut co).map_err(|e| e.message().to_owned())
+        };
+
+        let path = std::env::temp_dir().join(format!("coro-file-io-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let src = format!("write_file(\"{0}\", \"hi\")\nread_file(\"{0}\")", path);
+        assert_eq!(Value::Str("hi".into()), eval_io(&src).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let err = eval_io(&format!("read_file(\"{}\")", path)).unwrap_err();
+        assert!(err.starts_with("cannot read file"), "{}", err);
+        // Other coroutines still cannot use them.
+        assert_eq!("no binding for name 'read_file'", eval("read_file(\"x\")").unwrap_err());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        assert_eq!("no binding for name 'to_json'", eval("to_json(1)").unwrap_err());
+        let eval = |src: &str| {
+            let mut co = CoVM::with_json().build(src).map_err(|e| e.message().to_owned())?;
+            CoVM::run(&mut co).map_err(|e| e.message().to_owned())
+        };
+
+        // Strings cannot contain quotes, so go through the natives directly.
+        let json = r#"{"name":"coro","tags":["a",{"b":null}],"ver":[0,1.5],"ok":true}"#;
+        let val = parse_json(vec![Value::Str(json.into())]).unwrap();
+        assert_eq!(
+            r#"{"name": "coro", "ok": true, "tags": ["a", {"b": ()}], "ver": [0, 1.5]}"#,
+            format!("{}", val)
+        );
+        let out = to_json(vec![val.clone()]).unwrap();
+        assert_eq!(val, parse_json(vec![out]).unwrap());
+
+        let src = "to_json([1, 2.5, \"x\", (), fn -> 1])";
+        assert_eq!(Value::Str("[1,2.5,\"x\",null,null]".into()), eval(src).unwrap());
+        let err = eval("parse_json(\"[1,\")").unwrap_err();
+        assert!(err.starts_with("cannot parse json"), "{}", err);
+    }
+
+    #[test]
+    fn sort_errors() {
+        assert_eq!("sort expects a list but got 1", eval("sort(1)").unwrap_err());
+        assert_eq!(
+            "expected 1 arguments but got 2 when calling 'sort'",
+            eval("sort([], [])").unwrap_err()
+        );
+    }
+}
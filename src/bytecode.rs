@@ -0,0 +1,299 @@
+//! This module provides a binary form of compiled functions, so that scripts can be compiled once
+//! and run later without their source.
+//!
+//! The format starts with a magic string and a version, followed by the main function. Functions
+//! are written out field by field, with nested functions in place among the constants. Numbers
+//! are little-endian, counts and operands are `u32`, and strings are UTF-8 prefixed by their length.
+//! Built-ins are written by name, and looked up again when reading.
+
+use std::rc::Rc;
+
+use crate::builtins;
+use crate::code::Code;
+use crate::code::Instr;
+use crate::code::Upvalue;
+use crate::value::intern;
+use crate::value::FnDef;
+use crate::value::Value;
+
+/// Extension used by files of compiled bytecode.
+pub const EXT: &str = "cbc";
+
+const MAGIC: &[u8] = b"coro-bc";
+const VERSION: u32 = 2;
+
+const TAG_UNIT: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUM: u8 = 2;
+const TAG_STR: u8 = 3;
+const TAG_FN: u8 = 4;
+const TAG_NATIVE_FN: u8 = 5;
+
+/// Write out the function, along with everything it refers to.
+pub fn write(def: &FnDef) -> Result<Vec<u8>, String> {
+    let mut out = Writer(Vec::new());
+    out.0.extend_from_slice(MAGIC);
+    out.u32(VERSION as usize)?;
+    out.fn_def(def)?;
+    Ok(out.0)
+}
+
+/// Read back a function written by `write`. The function is validated, so running it cannot index
+/// out of bounds even if the bytes were not written by `write`.
+pub fn read(bytes: &[u8]) -> Result<FnDef, String> {
+    let rest = bytes.strip_prefix(MAGIC).ok_or("not a coro bytecode file")?;
+    let mut input = Reader { bytes: rest, pos: 0 };
+    let version = input.u32()?;
+    if version != VERSION as usize {
+        return Err(format!("unsupported bytecode version {}", version));
+    }
+    let def = input.fn_def()?;
+    if input.pos != input.bytes.len() {
+        return Err("unexpected bytes after the end of the bytecode".to_owned());
+    }
+    def.validate()?;
+    Ok(def)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, val: u8) {
+        self.0.push(val);
+    }
+
+    fn u32(&mut self, val: usize) -> Result<(), String> {
+        let val = u32::try_from(val).map_err(|_| format!("{} is too big to write", val))?;
+        self.0.extend_from_slice(&val.to_le_bytes());
+        Ok(())
+    }
+
+    fn str(&mut self, val: &str) -> Result<(), String> {
+        self.u32(val.len())?;
+        self.0.extend_from_slice(val.as_bytes());
+        Ok(())
+    }
+
+    fn strs(&mut self, vals: &[String]) -> Result<(), String> {
+        self.u32(vals.len())?;
+        vals.iter().try_for_each(|val| self.str(val))
+    }
+
+    fn fn_def(&mut self, def: &FnDef) -> Result<(), String> {
+        self.str(def.name())?;
+        self.strs(def.params())?;
+        match def.rest_param() {
+            Some(rest) => {
+                self.u8(1);
+                self.str(rest)?;
+            }
+            None => self.u8(0),
+        }
+        for idx in 0..def.arity() {
+            match def.param_default(idx) {
+                Some(default) => {
+                    self.u8(1);
+                    self.fn_def(default)?;
+                }
+                None => self.u8(0),
+            }
+        }
+        self.code(&def.code)
+    }
+
+    fn code(&mut self, code: &Code) -> Result<(), String> {
+        self.u32(code.constants().len())?;
+        code.constants().iter().try_for_each(|val| self.value(val))?;
+        self.strs(code.locals())?;
+        self.u32(code.upvalues().len())?;
+        for upvalue in code.upvalues() {
+            self.str(&upvalue.name)?;
+            self.u8(upvalue.local as u8);
+        }
+        self.u32(code.len())?;
+        for (_, instr, line) in code {
+            self.u8(instr.opcode() as u8);
+            if let Some(operand) = instr.operand() {
+                self.u32(operand)?;
+            }
+            self.u32(line)?;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, val: &Value) -> Result<(), String> {
+        match val {
+            Value::Unit => self.u8(TAG_UNIT),
+            Value::Bool(b) => {
+                self.u8(TAG_BOOL);
+                self.u8(*b as u8);
+            }
+            Value::Num(n) => {
+                self.u8(TAG_NUM);
+                self.0.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Str(s) => {
+                self.u8(TAG_STR);
+                self.str(s)?;
+            }
+            Value::Fn(def) => {
+                self.u8(TAG_FN);
+                self.fn_def(def)?;
+            }
+            Value::NativeFn(native) => {
+                self.u8(TAG_NATIVE_FN);
+                self.str(native.name())?;
+            }
+            val => return Err(format!("cannot write constant {:?}", val)),
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes;
+        match bytes.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err("unexpected end of bytecode".to_owned()),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn flag(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            val => Err(format!("expected a flag but found {}", val)),
+        }
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| "string is not valid UTF-8".to_owned())
+    }
+
+    fn strs(&mut self) -> Result<Vec<String>, String> {
+        (0..self.u32()?).map(|_| self.str()).collect()
+    }
+
+    fn fn_def(&mut self) -> Result<FnDef, String> {
+        let name = self.str()?;
+        let params = self.strs()?;
+        let rest = if self.flag()? { Some(self.str()?) } else { None };
+        let mut defaults = Vec::new();
+        for _ in 0..params.len() {
+            let default = if self.flag()? { Some(Rc::new(self.fn_def()?)) } else { None };
+            defaults.push(default);
+        }
+        let mut def = FnDef::with(name, params);
+        def.set_rest_param(rest);
+        def.set_defaults(defaults);
+        def.code = self.code()?;
+        // The whole function is validated once it is read, but finding the max depth needs the
+        // jumps to be in bounds already.
+        let max_stack = def.code.verify().and_then(|_| def.code.max_stack());
+        def.set_max_stack(max_stack.map_err(|msg| format!("{}: {}", def.name(), msg))?);
+        Ok(def)
+    }
+
+    fn code(&mut self) -> Result<Code, String> {
+        let consts = (0..self.u32()?).map(|_| self.value()).collect::<Result<_, _>>()?;
+        let locals = self.strs()?;
+        let mut upvalues = Vec::new();
+        for _ in 0..self.u32()? {
+            let name = self.str()?;
+            upvalues.push(Upvalue { name, local: self.flag()? });
+        }
+        let len = self.u32()?;
+        let mut instrs = Vec::new();
+        let mut lines = Vec::new();
+        for _ in 0..len {
+            let opcode = self.u8()? as usize;
+            let instr = Instr::from_opcode(opcode, 0)
+                .ok_or_else(|| format!("unknown opcode {}", opcode))?;
+            let instr = match instr.operand() {
+                Some(_) => {
+                    let operand = self.u32()?;
+                    Instr::from_opcode(opcode, operand)
+                        .ok_or_else(|| format!("operand {} does not fit {:?}", operand, instr))?
+                }
+                None => instr,
+            };
+            instrs.push(instr);
+            lines.push(self.u32()?);
+        }
+        Ok(Code::from_parts(instrs, lines, consts, locals, upvalues))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let val = match self.u8()? {
+            TAG_UNIT => Value::Unit,
+            TAG_BOOL => Value::Bool(self.flag()?),
+            TAG_NUM => Value::Num(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            TAG_STR => Value::Str(intern(&self.str()?)),
+            TAG_FN => Value::Fn(Rc::new(self.fn_def()?)),
+            TAG_NATIVE_FN => {
+                let name = self.str()?;
+                builtins::lookup(&name)
+                    .filter(Value::is_native_fn)
+                    .ok_or_else(|| format!("no built-in named '{}'", name))?
+            }
+            tag => return Err(format!("unknown constant tag {}", tag)),
+        };
+        Ok(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::CoVM;
+
+    #[test]
+    fn round_trip() {
+        let src = "def add a b = a + b\ndef greet name = upper(name)\n\
+            def count ...xs = list_len(xs)\nlet n = 0\ndef inc = { n += 1; n }\n\
+            let f = fn x -> x * 2\nconst flag = true\n\
+            [add(1, 2), greet(\"bo\"), count(1, 2), inc(), inc(), f(4), flag, 1.5]";
+        let def = CoVM::compile(src).unwrap();
+        let bytes = write(&def).unwrap();
+        let loaded = read(&bytes).unwrap();
+        assert_eq!(def.display_verbose().to_string(), loaded.display_verbose().to_string());
+        assert_eq!(def.max_stack(), loaded.max_stack());
+
+        let run = |def: Rc<FnDef>| CoVM::run(&mut CoVM::builder().build_fn(def)).unwrap();
+        let out = "[3, \"BO\", 2, 1, 2, 8, true, 1.5]";
+        assert_eq!(out, format!("{}", run(Rc::new(loaded))));
+    }
+
+    #[test]
+    fn bad_bytecode() {
+        assert_eq!(Err("not a coro bytecode file".to_owned()), read(b"print 1").map(|_| ()));
+        let bytes = write(&CoVM::compile("1 + 2").unwrap()).unwrap();
+        let err = read(&bytes[..bytes.len() - 1]).map(|_| ());
+        assert_eq!(Err("unexpected end of bytecode".to_owned()), err);
+
+        let mut version = bytes.clone();
+        version[MAGIC.len()] = 9;
+        let err = read(&version).map(|_| ());
+        assert_eq!(Err("unsupported bytecode version 9".to_owned()), err);
+    }
+}
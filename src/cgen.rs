@@ -3,174 +3,677 @@
 //!
 //! The main task here is to traverse the syntax tree and "compile" each item to corresponding
 //! instructions. We keep things simple by focusing on individual items at a time to ensure we
-//! get the semantics correct. Therefore, we assume the AST is correct and type-checks.
+//! get the semantics correct. Therefore, we assume the AST is correct and type-checks. The only
+//! exception is a small pass up front that rejects reassigning constants. Another pass gives each
+//! name a function binds a local slot, before any code that reads it is emitted.
 
+use std::collections::HashSet;
 use std::rc::Rc;
 
 use crate::ast::*;
+use crate::builtins;
 use crate::code::Code;
+use crate::code::Instr;
 use crate::code::Instr::*;
 use crate::debug;
+use crate::error::CoError;
+use crate::value::intern;
 use crate::value::FnDef;
 use crate::value::Value;
 
 /// Main entry point to compiling AST to instructions.
-pub fn compile(ast: Ast) -> Code {
+pub fn compile(ast: Ast) -> Result<Code, CoError> {
+    check_consts(&ast.items, &ast.lines, &mut ConstCheck::new(1))?;
+
     let mut code = Code::new();
+    declare_binds(&ast.items, &mut code);
     if !ast.items.is_empty() {
         // An AST is mostly just a block.
-        emit_block(&mut code, ast.items);
-        code.add(OpRet, 1);
+        let mut tracker = LineTracker::new(1);
+        emit_block(&mut code, &mut tracker, ast.items, ast.lines);
+        tracker.emit(&mut code, OpRet);
+    }
+    share_captured(&mut code);
+    thread_jumps(&mut code);
+    Ok(code)
+}
+
+// Constants are tracked per function, since each coroutine has its own env. Blocks do not
+// introduce a new scope, so they share the set of the enclosing function.
+struct ConstCheck {
+    consts: HashSet<String>,
+    line: usize,
+}
+
+impl ConstCheck {
+    fn new(line: usize) -> Self {
+        Self { consts: HashSet::new(), line }
+    }
+}
+
+fn check_consts(binds: &[Bind], lines: &[usize], cx: &mut ConstCheck) -> Result<(), CoError> {
+    for (i, bind) in binds.iter().enumerate() {
+        cx.line = lines.get(i).copied().unwrap_or(cx.line);
+        check_bind(bind, cx)?;
+    }
+    Ok(())
+}
+
+fn check_bind(bind: &Bind, cx: &mut ConstCheck) -> Result<(), CoError> {
+    // Nested blocks move the line along, but errors here belong to the binding itself.
+    let line = cx.line;
+    match bind {
+        Bind::Def(def_bind) => {
+            check_assign(&def_bind.name, cx, line)?;
+            check_fn(def_bind, cx)?;
+        }
+        Bind::Let(let_bind) | Bind::Assign(let_bind) => {
+            check_cmd(&let_bind.init, cx)?;
+            check_assign(&let_bind.name, cx, line)?;
+        }
+        Bind::Const(let_bind) => {
+            check_cmd(&let_bind.init, cx)?;
+            check_assign(&let_bind.name, cx, line)?;
+            cx.consts.insert(let_bind.name.clone());
+        }
+        Bind::Destruct(destruct_bind) => {
+            check_cmd(&destruct_bind.init, cx)?;
+            for name in destruct_bind.names.iter().filter(|name| *name != "_") {
+                check_assign(name, cx, line)?;
+            }
+        }
+        Bind::Cmd(cmd) => check_cmd(cmd, cx)?,
+    }
+    Ok(())
+}
+
+// Nested functions can assign to names of the enclosing function, so its constants carry over,
+// except for the names the nested function binds itself.
+fn check_fn(def_bind: &DefBind, cx: &ConstCheck) -> Result<(), CoError> {
+    for expr in def_bind.defaults.iter().flatten() {
+        check_expr(expr, &mut ConstCheck::new(cx.line))?;
+    }
+    let mut scope = Code::new();
+    scope.set_outer(cx.consts.iter().cloned().collect());
+    declare_fn(def_bind, &mut scope);
+    let mut inner = ConstCheck::new(cx.line);
+    let outer = cx.consts.iter().filter(|name| scope.local_slot(name).is_none());
+    inner.consts = outer.cloned().collect();
+    check_cmd(&def_bind.body, &mut inner)
+}
+
+fn check_assign(name: &str, cx: &ConstCheck, line: usize) -> Result<(), CoError> {
+    if cx.consts.contains(name) {
+        let msg = format!("cannot reassign constant '{}'", name);
+        Err(CoError::compile(msg, line, 0))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_cmd(cmd: &Cmd, cx: &mut ConstCheck) -> Result<(), CoError> {
+    match &cmd.kind {
+        CmdKind::Print(expr) | CmdKind::Yield(expr) | CmdKind::Expr(expr) => check_expr(expr, cx),
+        CmdKind::Create(_) => Ok(()),
+        CmdKind::Resume(expr, args) => {
+            check_expr(expr, cx)?;
+            args.iter().try_for_each(|arg| check_expr(arg, cx))
+        }
+        CmdKind::While(cond, body) => {
+            check_expr(cond, cx)?;
+            check_expr(body, cx)
+        }
+        CmdKind::For(name, items, body) => {
+            check_expr(items, cx)?;
+            check_assign(name, cx, cx.line)?;
+            check_expr(body, cx)
+        }
+        CmdKind::If(cond, then, alt) => {
+            check_expr(cond, cx)?;
+            check_expr(then, cx)?;
+            check_expr(alt, cx)
+        }
+    }
+}
+
+fn check_expr(expr: &Expr, cx: &mut ConstCheck) -> Result<(), CoError> {
+    match &expr.kind {
+        ExprKind::Block(binds, lines) => check_consts(binds, lines, cx),
+        ExprKind::Group(cmd) => check_cmd(cmd, cx),
+        ExprKind::Cond(cond, then, alt) => {
+            check_expr(cond, cx)?;
+            check_expr(then, cx)?;
+            check_expr(alt, cx)
+        }
+        ExprKind::Lt(lhs, rhs)
+        | ExprKind::Gt(lhs, rhs)
+        | ExprKind::Le(lhs, rhs)
+        | ExprKind::Ge(lhs, rhs)
+        | ExprKind::Eq(lhs, rhs)
+        | ExprKind::Range(lhs, rhs)
+        | ExprKind::Index(lhs, rhs)
+        | ExprKind::Add(lhs, rhs)
+        | ExprKind::Sub(lhs, rhs)
+        | ExprKind::Mul(lhs, rhs)
+        | ExprKind::Div(lhs, rhs)
+        | ExprKind::IDiv(lhs, rhs)
+        | ExprKind::BitAnd(lhs, rhs)
+        | ExprKind::BitOr(lhs, rhs)
+        | ExprKind::BitXor(lhs, rhs)
+        | ExprKind::Shl(lhs, rhs)
+        | ExprKind::Shr(lhs, rhs) => {
+            check_expr(lhs, cx)?;
+            check_expr(rhs, cx)
+        }
+        ExprKind::Neg(inner) | ExprKind::Not(inner) | ExprKind::BitNot(inner) => {
+            check_expr(inner, cx)
+        }
+        ExprKind::Slice(list, from, to) => {
+            check_expr(list, cx)?;
+            from.iter().chain(to).try_for_each(|bound| check_expr(bound, cx))
+        }
+        ExprKind::List(items) => items.iter().try_for_each(|item| check_expr(item, cx)),
+        ExprKind::Call(callee, args) => {
+            check_expr(callee, cx)?;
+            args.iter().try_for_each(|arg| check_expr(arg, cx))
+        }
+        ExprKind::Lambda(def_bind) => check_fn(def_bind, cx),
+        ExprKind::Ident(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Num(_)
+        | ExprKind::Str(_)
+        | ExprKind::Unit => Ok(()),
+    }
+}
+
+// Locals are per function, same as constants, and blocks share the slots of the enclosing
+// function. Nested functions are left out, since they get slots of their own.
+fn declare_fn(def_bind: &DefBind, code: &mut Code) {
+    // Parameters take the first slots, in order.
+    for param in def_bind.params.iter().chain(&def_bind.rest) {
+        code.add_local(param);
+    }
+    declare_cmd(&def_bind.body, code);
+}
+
+fn declare_binds(binds: &[Bind], code: &mut Code) {
+    for bind in binds {
+        match bind {
+            Bind::Def(def_bind) => {
+                code.add_local(&def_bind.name);
+            }
+            Bind::Let(let_bind) | Bind::Const(let_bind) => {
+                declare_cmd(&let_bind.init, code);
+                code.add_local(&let_bind.name);
+            }
+            Bind::Assign(let_bind) => {
+                declare_cmd(&let_bind.init, code);
+                // Assigning to a name of an enclosing function updates it there.
+                if !code.outer().contains(&let_bind.name) {
+                    code.add_local(&let_bind.name);
+                }
+            }
+            Bind::Destruct(destruct_bind) => {
+                declare_cmd(&destruct_bind.init, code);
+                for name in destruct_bind.names.iter().filter(|name| *name != "_") {
+                    code.add_local(name);
+                }
+            }
+            Bind::Cmd(cmd) => declare_cmd(cmd, code),
+        }
+    }
+}
+
+fn declare_cmd(cmd: &Cmd, code: &mut Code) {
+    match &cmd.kind {
+        CmdKind::Print(expr) | CmdKind::Yield(expr) | CmdKind::Expr(expr) => {
+            declare_expr(expr, code)
+        }
+        CmdKind::Create(_) => {}
+        CmdKind::Resume(expr, args) => {
+            declare_expr(expr, code);
+            args.iter().for_each(|arg| declare_expr(arg, code));
+        }
+        CmdKind::While(cond, body) => {
+            declare_expr(cond, code);
+            declare_expr(body, code);
+        }
+        CmdKind::For(name, items, body) => {
+            declare_expr(items, code);
+            code.add_local(name);
+            declare_expr(body, code);
+        }
+        CmdKind::If(cond, then, alt) => {
+            declare_expr(cond, code);
+            declare_expr(then, code);
+            declare_expr(alt, code);
+        }
+    }
+}
+
+fn declare_expr(expr: &Expr, code: &mut Code) {
+    match &expr.kind {
+        ExprKind::Block(binds, _) => declare_binds(binds, code),
+        ExprKind::Group(cmd) => declare_cmd(cmd, code),
+        ExprKind::Cond(cond, then, alt) => {
+            declare_expr(cond, code);
+            declare_expr(then, code);
+            declare_expr(alt, code);
+        }
+        ExprKind::Lt(lhs, rhs)
+        | ExprKind::Gt(lhs, rhs)
+        | ExprKind::Le(lhs, rhs)
+        | ExprKind::Ge(lhs, rhs)
+        | ExprKind::Eq(lhs, rhs)
+        | ExprKind::Range(lhs, rhs)
+        | ExprKind::Index(lhs, rhs)
+        | ExprKind::Add(lhs, rhs)
+        | ExprKind::Sub(lhs, rhs)
+        | ExprKind::Mul(lhs, rhs)
+        | ExprKind::Div(lhs, rhs)
+        | ExprKind::IDiv(lhs, rhs)
+        | ExprKind::BitAnd(lhs, rhs)
+        | ExprKind::BitOr(lhs, rhs)
+        | ExprKind::BitXor(lhs, rhs)
+        | ExprKind::Shl(lhs, rhs)
+        | ExprKind::Shr(lhs, rhs) => {
+            declare_expr(lhs, code);
+            declare_expr(rhs, code);
+        }
+        ExprKind::Neg(inner) | ExprKind::Not(inner) | ExprKind::BitNot(inner) => {
+            declare_expr(inner, code)
+        }
+        ExprKind::Slice(list, from, to) => {
+            declare_expr(list, code);
+            from.iter().chain(to).for_each(|bound| declare_expr(bound, code));
+        }
+        ExprKind::List(items) => items.iter().for_each(|item| declare_expr(item, code)),
+        ExprKind::Call(callee, args) => {
+            declare_expr(callee, code);
+            args.iter().for_each(|arg| declare_expr(arg, code));
+        }
+        ExprKind::Lambda(_) => {}
+        ExprKind::Ident(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Num(_)
+        | ExprKind::Str(_)
+        | ExprKind::Unit => {}
+    }
+}
+
+// The source line of the instructions being emitted. Each block item moves it along to its own
+// line, and nested functions start out at the line they are defined on.
+struct LineTracker {
+    line: usize,
+}
+
+impl LineTracker {
+    fn new(line: usize) -> Self {
+        Self { line }
+    }
+
+    fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    // Add an instruction at the current line.
+    fn emit(&self, code: &mut Code, instr: Instr) -> usize {
+        code.add(instr, self.line)
     }
-    code
 }
 
-fn emit_block(code: &mut Code, block: Vec<Bind>) {
+fn emit_block(code: &mut Code, tracker: &mut LineTracker, block: Vec<Bind>, lines: Vec<usize>) {
     let len = block.len();
-    let mut iter = block.into_iter();
+    let mut iter = block.into_iter().enumerate();
 
     for _ in 0..(len - 1) {
         // Compile and discard the value of each item except the last.
-        let bind = iter.next().unwrap();
-        emit_bind(code, bind);
-        code.add(OpPop, 1);
+        let (i, bind) = iter.next().unwrap();
+        if let Some(&line) = lines.get(i) {
+            tracker.set_line(line);
+        }
+        emit_bind(code, tracker, bind);
+        tracker.emit(code, OpPop);
     }
 
     // Block should have at least one item.
-    let last = iter.next().unwrap();
-    emit_bind(code, last);
+    let (i, last) = iter.next().unwrap();
+    if let Some(&line) = lines.get(i) {
+        tracker.set_line(line);
+    }
+    emit_bind(code, tracker, last);
 
     // Last value produced is the value of the block, so no pop.
 }
 
-fn emit_bind(code: &mut Code, bind: Bind) {
+fn emit_bind(code: &mut Code, tracker: &mut LineTracker, bind: Bind) {
     match bind {
         Bind::Def(def_bind) => {
-            emit_def(code, def_bind);
+            emit_def(code, tracker, def_bind);
+            // stack + 1
+        }
+        Bind::Let(let_bind) | Bind::Const(let_bind) => {
+            // Constants are checked up front, so at runtime they are just like let.
+            emit_let(code, tracker, let_bind);
+            // stack + 1
+        }
+        Bind::Assign(let_bind) => {
+            emit_let(code, tracker, let_bind);
             // stack + 1
         }
-        Bind::Let(let_bind) => {
-            emit_let(code, let_bind);
+        Bind::Destruct(destruct_bind) => {
+            emit_destruct(code, tracker, destruct_bind);
             // stack + 1
         }
         Bind::Cmd(cmd) => {
-            emit_cmd(code, cmd);
+            emit_cmd(code, tracker, cmd);
             // stack + 1
         }
     }
 }
 
-fn emit_def(code: &mut Code, def_bind: DefBind) {
-    let mut def = FnDef::with(def_bind.name, def_bind.params);
-    emit_cmd(&mut def.code, def_bind.body);
-    def.code.add(OpRet, 1);
+fn emit_def(code: &mut Code, tracker: &mut LineTracker, def_bind: DefBind) {
+    let name = def_bind.name.clone();
+    let def = compile_fn(def_bind, code, tracker.line);
+    let captures = capture(code, &def);
+    let val = Value::Fn(Rc::new(def));
+    let idx = code.add_const(val);
 
-    if cfg!(feature = "instr") {
-        debug::print(&def.code, def.name());
+    if captures {
+        tracker.emit(code, OpClosure(idx));
+        emit_set(code, tracker, &name);
+    } else {
+        tracker.emit(code, OpDefine(idx));
     }
+}
 
+fn emit_lambda(code: &mut Code, tracker: &mut LineTracker, mut def_bind: DefBind) {
+    def_bind.name = String::from("lambda");
+    let def = compile_fn(def_bind, code, tracker.line);
+    let captures = capture(code, &def);
     let val = Value::Fn(Rc::new(def));
     let idx = code.add_const(val);
 
-    code.add(OpDefine(idx), 1);
+    tracker.emit(code, if captures { OpClosure(idx) } else { OpConst(idx) });
 }
 
-fn emit_let(code: &mut Code, let_bind: LetBind) {
-    emit_cmd(code, let_bind.init);
-    let name = Value::Str(let_bind.name);
-    let idx = code.add_const(name);
-    code.add(OpStore(idx), 1);
+// Give the enclosing function an upvalue for each one the nested function captures, so it can
+// hand over the cells when creating the function. Returns whether there are any.
+fn capture(code: &mut Code, def: &FnDef) -> bool {
+    let mut captures = false;
+    for upvalue in def.code.upvalues().iter().filter(|upvalue| !upvalue.local) {
+        code.add_upvalue(&upvalue.name);
+        captures = true;
+    }
+    captures
+}
+
+// Locals that nested functions captured live in cells instead of slots. Since they can be
+// captured after they are used, the slots are swapped out once the whole function is compiled.
+// The variable of a `for` is set right after `OpIter`, and gets a new cell for each item, so that
+// functions made in the body keep the item they saw.
+fn share_captured(code: &mut Code) {
+    for idx in 0..code.len() {
+        let upvalue = |slot: usize| code.upvalue_slot(&code.locals()[slot]);
+        let binds_item = idx > 0 && matches!(code.instr(idx - 1), OpIter(_));
+        let instr = match *code.instr(idx) {
+            OpGetLocal(slot) => upvalue(slot).map(OpGetUpvalue),
+            OpSetLocal(slot) if binds_item => upvalue(slot).map(OpBindUpvalue),
+            OpSetLocal(slot) => upvalue(slot).map(OpSetUpvalue),
+            OpSetUpvalue(up) if binds_item && code.upvalues()[up].local => Some(OpBindUpvalue(up)),
+            _ => None,
+        };
+        if let Some(instr) = instr {
+            code.patch(idx, instr);
+        }
+    }
+}
+
+// Functions start out at the line they are defined on, and can capture anything bound in the
+// functions they are nested in.
+fn compile_fn(def_bind: DefBind, outer: &Code, line: usize) -> FnDef {
+    let mut def = FnDef::with(def_bind.name.clone(), def_bind.params.clone());
+    def.code.set_outer(outer.outer().iter().chain(outer.locals()).cloned().collect());
+    declare_fn(&def_bind, &mut def.code);
+
+    // Each default value is compiled into its own little function, and evaluated when the
+    // coroutine is first resumed without that argument.
+    let defaults = def_bind
+        .defaults
+        .into_iter()
+        .zip(&def_bind.params)
+        .map(|(expr, param)| {
+            expr.map(|expr| {
+                let name = format!("{}.{}", def_bind.name, param);
+                let mut thunk = FnDef::with(name, Vec::new());
+                let mut tracker = LineTracker::new(line);
+                emit_expr(&mut thunk.code, &mut tracker, expr);
+                tracker.emit(&mut thunk.code, OpRet);
+                thunk.set_max_stack(thunk.code.max_stack().unwrap_or_default());
+                Rc::new(thunk)
+            })
+        })
+        .collect();
+
+    def.set_defaults(defaults);
+    def.set_rest_param(def_bind.rest);
+    let mut tracker = LineTracker::new(line);
+    emit_cmd(&mut def.code, &mut tracker, def_bind.body);
+    tracker.emit(&mut def.code, OpRet);
+    share_captured(&mut def.code);
+    thread_jumps(&mut def.code);
+    // Code with a broken stack is rejected later on by `FnDef::validate`.
+    def.set_max_stack(def.code.max_stack().unwrap_or_default());
+
+    if cfg!(feature = "instr") {
+        debug::print(&def.code, def.name());
+    }
+
+    def
+}
+
+// Nested `if`s end in a chain of jumps, one per level, each skipping the else-expr of its own
+// level. Jumps that land on another jump are sent straight to where the chain ends, which leaves
+// the stack the same since jumps do not touch it. Jumps only go forward, so every chain ends.
+fn thread_jumps(code: &mut Code) {
+    for idx in 0..code.len() {
+        let mut target = match code.instr(idx) {
+            OpJump(offset) => idx + 1 + offset,
+            _ => continue,
+        };
+        while target < code.len() {
+            match code.instr(target) {
+                OpJump(offset) => target += 1 + offset,
+                _ => break,
+            }
+        }
+        code.patch(idx, OpJump(target - idx - 1));
+    }
+}
+
+fn emit_let(code: &mut Code, tracker: &mut LineTracker, let_bind: LetBind) {
+    emit_cmd(code, tracker, let_bind.init);
+    emit_set(code, tracker, &let_bind.name);
+}
+
+// Names are either bound in the function, captured from an enclosing one, or looked up by name,
+// e.g. built-ins.
+fn is_bound(code: &Code, name: &str) -> bool {
+    code.local_slot(name).is_some()
+        || code.upvalue_slot(name).is_some()
+        || code.outer().iter().any(|outer| outer == name)
+}
+
+fn emit_get(code: &mut Code, tracker: &mut LineTracker, name: &str) {
+    if let Some(idx) = code.upvalue_slot(name) {
+        tracker.emit(code, OpGetUpvalue(idx));
+    } else if let Some(slot) = code.local_slot(name) {
+        tracker.emit(code, OpGetLocal(slot));
+    } else if is_bound(code, name) {
+        let idx = code.add_upvalue(name);
+        tracker.emit(code, OpGetUpvalue(idx));
+    } else {
+        let idx = code.add_const(Value::Str(intern(name)));
+        tracker.emit(code, OpLoad(idx));
+    }
+}
+
+// Names that are assigned to without being captured get a slot, see `declare_binds`.
+fn emit_set(code: &mut Code, tracker: &mut LineTracker, name: &str) {
+    if let Some(idx) = code.upvalue_slot(name) {
+        tracker.emit(code, OpSetUpvalue(idx));
+    } else if code.local_slot(name).is_none() && is_bound(code, name) {
+        let idx = code.add_upvalue(name);
+        tracker.emit(code, OpSetUpvalue(idx));
+    } else {
+        let slot = code.add_local(name);
+        tracker.emit(code, OpSetLocal(slot));
+    }
 }
 
-fn emit_cmd(code: &mut Code, cmd: Cmd) {
-    match cmd {
-        Cmd::Print(expr) => {
-            emit_expr(code, expr);
-            code.add(OpPrint, 1);
+fn emit_destruct(code: &mut Code, tracker: &mut LineTracker, destruct_bind: DestructBind) {
+    emit_cmd(code, tracker, destruct_bind.init);
+    let num = destruct_bind.names.len();
+    tracker.emit(code, OpUnpack(num));
+
+    for (i, name) in destruct_bind.names.into_iter().enumerate() {
+        if i > 0 {
+            // Discard the unit from the previous store.
+            tracker.emit(code, OpPop);
+        }
+        if name == "_" {
+            // Drop the value, leaving a unit in its place like a store does.
+            tracker.emit(code, OpPop);
+            tracker.emit(code, OpUnit);
+            continue;
+        }
+        emit_set(code, tracker, &name);
+    }
+
+    // Unit from the last store is the value of the binding.
+}
+
+fn emit_cmd(code: &mut Code, tracker: &mut LineTracker, cmd: Cmd) {
+    match cmd.kind {
+        CmdKind::Print(expr) => {
+            emit_expr(code, tracker, expr);
+            tracker.emit(code, OpPrint);
+            // stack + 1
+        }
+        CmdKind::Create(name) => {
+            emit_create(code, tracker, name);
             // stack + 1
         }
-        Cmd::Create(name) => {
-            emit_create(code, name);
+        CmdKind::Resume(expr, args) => {
+            emit_resume(code, tracker, expr, args);
             // stack + 1
         }
-        Cmd::Resume(expr, args) => {
-            emit_resume(code, expr, args);
+        CmdKind::Yield(expr) => {
+            emit_yield(code, tracker, expr);
             // stack + 1
         }
-        Cmd::Yield(expr) => {
-            emit_yield(code, expr);
+        CmdKind::While(cond, body) => {
+            emit_while(code, tracker, cond, body);
             // stack + 1
         }
-        Cmd::While(cond, body) => {
-            emit_while(code, cond, body);
+        CmdKind::For(name, items, body) => {
+            emit_for(code, tracker, name, items, body);
             // stack + 1
         }
-        Cmd::If(cond, then, alt) => {
-            emit_if(code, cond, then, alt);
+        CmdKind::If(cond, then, alt) => {
+            emit_if(code, tracker, cond, then, alt);
             // stack + 1
         }
-        Cmd::Expr(expr) => {
-            emit_expr(code, expr);
+        CmdKind::Expr(expr) => {
+            emit_expr(code, tracker, expr);
             // stack + 1
         }
     }
 }
 
-fn emit_create(code: &mut Code, name: String) {
-    let name = Value::Str(name);
+fn emit_create(code: &mut Code, tracker: &mut LineTracker, name: String) {
+    // The coroutine looks the function up by name, which covers upvalues as long as there is one.
+    if code.local_slot(&name).is_none() && is_bound(code, &name) {
+        code.add_upvalue(&name);
+    }
+    let name = Value::Str(intern(&name));
     let idx = code.add_const(name);
-    code.add(OpCreate(idx), 1);
+    tracker.emit(code, OpCreate(idx));
 }
 
-fn emit_resume(code: &mut Code, expr: Expr, args: Vec<Expr>) {
-    emit_expr(code, expr);
+fn emit_resume(code: &mut Code, tracker: &mut LineTracker, expr: Expr, args: Vec<Expr>) {
+    emit_expr(code, tracker, expr);
     let num = args.len();
     for arg in args {
-        emit_expr(code, arg);
+        emit_expr(code, tracker, arg);
     }
-    code.add(OpResume(num), 1);
+    tracker.emit(code, OpResume(num));
 }
 
-fn emit_yield(code: &mut Code, expr: Expr) {
-    emit_expr(code, expr);
-    code.add(OpYield, 1);
+fn emit_yield(code: &mut Code, tracker: &mut LineTracker, expr: Expr) {
+    emit_expr(code, tracker, expr);
+    tracker.emit(code, OpYield);
 }
 
-fn emit_while(code: &mut Code, cond: Expr, body: Expr) {
+fn emit_while(code: &mut Code, tracker: &mut LineTracker, cond: Expr, body: Expr) {
     let cond_idx = code.len();
-    emit_expr(code, cond);
-    let exit_idx = code.add(OpBranch(0), 1);
+    emit_expr(code, tracker, cond);
+    let exit_idx = tracker.emit(code, OpBranch(0));
 
     // If cond is true, then pop cond value and do body-expr.
-    code.add(OpPop, 1);
-    emit_expr(code, body);
+    tracker.emit(code, OpPop);
+    emit_expr(code, tracker, body);
     // Discard the value produced by body-expr.
-    code.add(OpPop, 1);
+    tracker.emit(code, OpPop);
     // Loop back up to the cond.
-    emit_loop(code, cond_idx);
+    emit_loop(code, tracker, cond_idx);
 
     // If cond is false, then we jump down here to the pop.
     patch_branch(code, exit_idx);
-    code.add(OpPop, 1);
+    tracker.emit(code, OpPop);
 
     // `while` produces a unit value.
-    code.add(OpUnit, 1);
+    tracker.emit(code, OpUnit);
 }
 
-fn emit_if(code: &mut Code, cond: Expr, then: Expr, alt: Expr) {
-    emit_expr(code, cond);
-    let then_idx = code.add(OpBranch(0), 1);
+fn emit_for(code: &mut Code, tracker: &mut LineTracker, name: String, items: Expr, body: Expr) {
+    // The items and the index of the next one stay on the stack while looping.
+    emit_expr(code, tracker, items);
+    tracker.emit(code, OpZero);
+    let next_idx = tracker.emit(code, OpIter(0));
+
+    // If there is a next item, then bind it and do body-expr.
+    emit_set(code, tracker, &name);
+    tracker.emit(code, OpPop);
+    emit_expr(code, tracker, body);
+    // Discard the value produced by body-expr.
+    tracker.emit(code, OpPop);
+    // Loop back up to get the next item.
+    emit_loop(code, tracker, next_idx);
+
+    // Once there are no more items, we jump down here to pop the items and index.
+    patch_iter(code, next_idx);
+    tracker.emit(code, OpPop);
+    tracker.emit(code, OpPop);
+
+    // `for` produces a unit value, same as `while`.
+    tracker.emit(code, OpUnit);
+}
+
+fn emit_if(code: &mut Code, tracker: &mut LineTracker, cond: Expr, then: Expr, alt: Expr) {
+    emit_expr(code, tracker, cond);
+    let then_idx = tracker.emit(code, OpBranch(0));
 
     // If cond is true, then pop cond value and do then-expr.
-    code.add(OpPop, 1);
-    emit_expr(code, then);
+    tracker.emit(code, OpPop);
+    emit_expr(code, tracker, then);
     // Once then-expr is done, skip over the else-expr.
-    let exit_idx = code.add(OpJump(0), 1);
+    let exit_idx = tracker.emit(code, OpJump(0));
 
     // If cond is false, then we jump down here to else-expr's pop.
     patch_branch(code, then_idx);
-    code.add(OpPop, 1);
-    emit_expr(code, alt);
+    tracker.emit(code, OpPop);
+    emit_expr(code, tracker, alt);
 
     // The skip will come down here.
     patch_jump(code, exit_idx);
@@ -178,117 +681,286 @@ fn emit_if(code: &mut Code, cond: Expr, then: Expr, alt: Expr) {
     // No pop since `if` produces a value.
 }
 
-fn emit_loop(code: &mut Code, target_idx: usize) {
+fn emit_loop(code: &mut Code, tracker: &mut LineTracker, target_idx: usize) {
     // IP will point to next instr, so need one more when going backward.
     let offset = code.len() - target_idx + 1;
-    code.add(OpLoop(offset), 1);
+    tracker.emit(code, OpLoop(offset));
 }
 
 fn patch_jump(code: &mut Code, idx: usize) {
-    backpatch(code, idx, true);
+    backpatch(code, idx, OpJump);
 }
 
 fn patch_branch(code: &mut Code, idx: usize) {
-    backpatch(code, idx, false);
+    backpatch(code, idx, OpBranch);
 }
 
-fn backpatch(code: &mut Code, idx: usize, is_jump: bool) {
+fn patch_iter(code: &mut Code, idx: usize) {
+    backpatch(code, idx, OpIter);
+}
+
+fn backpatch(code: &mut Code, idx: usize, instr: fn(usize) -> Instr) {
     // IP will point to next instr, so do one less when going forward.
     let offset = code.len() - idx - 1;
-    let instr = if is_jump {
-        OpJump(offset)
-    } else {
-        OpBranch(offset)
-    };
-    code.patch(idx, instr);
+    code.patch(idx, instr(offset));
 }
 
-fn emit_expr(code: &mut Code, expr: Expr) {
-    match expr {
-        Expr::Block(binds) => {
-            emit_block(code, binds);
+fn emit_expr(code: &mut Code, tracker: &mut LineTracker, expr: Expr) {
+    match fold_unary(expr).kind {
+        ExprKind::Cond(cond, then, alt) => {
+            emit_if(code, tracker, *cond, *then, *alt);
+            // stack + 1
+        }
+        ExprKind::Block(binds, lines) => {
+            emit_block(code, tracker, binds, lines);
+            // stack + 1
+        }
+        ExprKind::Group(inner) => {
+            emit_cmd(code, tracker, *inner);
+            // stack + 1
+        }
+        ExprKind::List(items) => {
+            let num = items.len();
+            for item in items {
+                emit_expr(code, tracker, item);
+            }
+            tracker.emit(code, OpList(num));
+            // stack + 1
+        }
+        ExprKind::Lambda(def_bind) => {
+            emit_lambda(code, tracker, *def_bind);
+            // stack + 1
+        }
+        ExprKind::Call(callee, args) => {
+            let num = args.len();
+            // Built-ins that are not shadowed are looked up once, here, rather than by name on
+            // every call.
+            let native = match &callee.kind {
+                ExprKind::Ident(name) if !is_bound(code, name) => {
+                    builtins::lookup(name).filter(Value::is_native_fn)
+                }
+                _ => None,
+            };
+            // Converting to a bool has an instruction of its own.
+            let to_bool = match &native {
+                Some(Value::NativeFn(native)) => native.name() == "to_bool",
+                _ => false,
+            };
+            if to_bool && num == 1 {
+                for arg in args {
+                    emit_expr(code, tracker, arg);
+                }
+                tracker.emit(code, OpBool);
+                return;
+            }
+
+            let native_call = native.is_some();
+            match native {
+                Some(native) => emit_const(code, tracker, native),
+                None => emit_expr(code, tracker, *callee),
+            }
+            for arg in args {
+                emit_expr(code, tracker, arg);
+            }
+            tracker.emit(code, if native_call { OpNativeCall(num) } else { OpCall(num) });
+            // stack + 1
+        }
+        ExprKind::Ident(name) => {
+            emit_get(code, tracker, &name);
+            // stack + 1
+        }
+        ExprKind::Lt(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpLt);
+            // stack + 1
+        }
+        ExprKind::Gt(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpGt);
+            // stack + 1
+        }
+        ExprKind::Le(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpLe);
+            // stack + 1
+        }
+        ExprKind::Ge(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpGe);
+            // stack + 1
+        }
+        ExprKind::Eq(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpEq);
+            // stack + 1
+        }
+        ExprKind::Range(start, end) => {
+            emit_expr(code, tracker, *start);
+            emit_expr(code, tracker, *end);
+            tracker.emit(code, OpRange);
+            // stack + 1
+        }
+        ExprKind::Index(list, idx) => {
+            emit_expr(code, tracker, *list);
+            emit_expr(code, tracker, *idx);
+            tracker.emit(code, OpIndex);
+            // stack + 1
+        }
+        ExprKind::Slice(list, from, to) => {
+            emit_expr(code, tracker, *list);
+            // A missing bound is left as unit, which the VM reads as the matching end of the list.
+            for bound in [from, to] {
+                match bound {
+                    Some(bound) => emit_expr(code, tracker, *bound),
+                    None => {
+                        tracker.emit(code, OpUnit);
+                    }
+                }
+            }
+            tracker.emit(code, OpSlice);
+            // stack + 1
+        }
+        ExprKind::Add(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpAdd);
+            // stack + 1
+        }
+        ExprKind::Sub(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpSub);
             // stack + 1
         }
-        Expr::Group(inner) => {
-            emit_cmd(code, *inner);
+        ExprKind::Mul(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpMul);
             // stack + 1
         }
-        Expr::Ident(name) => {
-            let name = Value::Str(name);
-            let idx = code.add_const(name);
-            code.add(OpLoad(idx), 1);
+        ExprKind::Div(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpDiv);
             // stack + 1
         }
-        Expr::Lt(lhs, rhs) => {
-            emit_expr(code, *lhs);
-            emit_expr(code, *rhs);
-            code.add(OpLt, 1);
+        ExprKind::IDiv(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpIDiv);
             // stack + 1
         }
-        Expr::Eq(lhs, rhs) => {
-            emit_expr(code, *lhs);
-            emit_expr(code, *rhs);
-            code.add(OpEq, 1);
+        ExprKind::BitAnd(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpBitAnd);
             // stack + 1
         }
-        Expr::Add(lhs, rhs) => {
-            emit_expr(code, *lhs);
-            emit_expr(code, *rhs);
-            code.add(OpAdd, 1);
+        ExprKind::BitOr(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpBitOr);
             // stack + 1
         }
-        Expr::Sub(lhs, rhs) => {
-            emit_expr(code, *lhs);
-            emit_expr(code, *rhs);
-            code.add(OpSub, 1);
+        ExprKind::BitXor(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpBitXor);
             // stack + 1
         }
-        Expr::Mul(lhs, rhs) => {
-            emit_expr(code, *lhs);
-            emit_expr(code, *rhs);
-            code.add(OpMul, 1);
+        ExprKind::Shl(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpShl);
             // stack + 1
         }
-        Expr::Div(lhs, rhs) => {
-            emit_expr(code, *lhs);
-            emit_expr(code, *rhs);
-            code.add(OpDiv, 1);
+        ExprKind::Shr(lhs, rhs) => {
+            emit_expr(code, tracker, *lhs);
+            emit_expr(code, tracker, *rhs);
+            tracker.emit(code, OpShr);
             // stack + 1
         }
-        Expr::Neg(inner) => {
-            emit_expr(code, *inner);
-            code.add(OpNeg, 1);
+        ExprKind::Neg(inner) => {
+            emit_expr(code, tracker, *inner);
+            tracker.emit(code, OpNeg);
             // stack + 1
         }
-        Expr::Not(inner) => {
-            emit_expr(code, *inner);
-            code.add(OpNot, 1);
+        ExprKind::Not(inner) => {
+            emit_expr(code, tracker, *inner);
+            tracker.emit(code, OpNot);
             // stack + 1
         }
-        Expr::Bool(lit) => {
+        ExprKind::BitNot(inner) => {
+            emit_expr(code, tracker, *inner);
+            tracker.emit(code, OpBitNot);
+            // stack + 1
+        }
+        ExprKind::Bool(lit) => {
             let instr = if lit { OpTrue } else { OpFalse };
-            code.add(instr, 1);
+            tracker.emit(code, instr);
             // stack + 1
         }
-        Expr::Num(lit) => {
-            let val = Value::Num(lit);
-            emit_const(code, val);
+        ExprKind::Num(lit) => {
+            emit_num(code, tracker, lit);
             // stack + 1
         }
-        Expr::Str(lit) => {
-            let val = Value::Str(lit);
-            emit_const(code, val);
+        ExprKind::Str(lit) => {
+            let val = Value::Str(intern(&lit));
+            emit_const(code, tracker, val);
             // stack + 1
         }
-        Expr::Unit => {
-            code.add(OpUnit, 1);
+        ExprKind::Unit => {
+            tracker.emit(code, OpUnit);
             // stack + 1
         }
     }
 }
 
-fn emit_const(code: &mut Code, value: Value) {
+// Work out unary operators applied to a literal, e.g. `-5` or `not true`, so that only the result
+// is pushed. Anything else is given back as it is.
+fn fold_unary(expr: Expr) -> Expr {
+    let span = expr.span;
+    let kind = match expr.kind {
+        ExprKind::Neg(inner) => match fold_unary(*inner) {
+            Expr { kind: ExprKind::Num(lit), .. } => ExprKind::Num(-lit),
+            inner => ExprKind::Neg(Box::new(inner)),
+        },
+        ExprKind::Not(inner) => match fold_unary(*inner) {
+            Expr { kind: ExprKind::Bool(lit), .. } => ExprKind::Bool(!lit),
+            inner => ExprKind::Not(Box::new(inner)),
+        },
+        kind => kind,
+    };
+    Expr::new(kind, span)
+}
+
+/// Emit a breakpoint that notifies the attached debugger. This leaves the stack untouched, so
+/// tools can inject it anywhere between instructions.
+pub fn emit_breakpoint(code: &mut Code, id: u32) {
+    // It belongs to the same line as the instruction before it.
+    let line = if code.is_empty() { 1 } else { code.line(code.len() - 1) };
+    code.add(OpBreakpoint(id), line);
+}
+
+// The most common numbers have their own instructions, so they need no constant. Negative zero
+// is kept apart, same as in the constant pool.
+fn emit_num(code: &mut Code, tracker: &mut LineTracker, num: f64) {
+    if num.to_bits() == 0.0_f64.to_bits() {
+        tracker.emit(code, OpZero);
+    } else if num == 1.0 {
+        tracker.emit(code, OpOne);
+    } else {
+        emit_const(code, tracker, Value::Num(num));
+    }
+}
+
+fn emit_const(code: &mut Code, tracker: &mut LineTracker, value: Value) {
     let idx = code.add_const(value);
     let instr = OpConst(idx);
-    code.add(instr, 1);
+    tracker.emit(code, instr);
 }
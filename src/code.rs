@@ -3,9 +3,17 @@
 //! The `Instr` enum specifies the exact set of instructions the Coro "VM" currently supports. And
 //! the `Code` struct serves as the main container for these instruction code.
 
+use std::fmt;
+use std::rc::Rc;
+use std::slice;
+
+use crate::debug;
 use crate::value::Value;
+use Instr::*;
 
-#[derive(Debug, Clone)]
+/// A single instruction. Operands, if any, are noted in parens at the start of each doc comment.
+/// Operands are plain numbers, so instructions are cheap to copy out of the code when running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instr {
     /// Push a unit value onto stack.
     OpUnit,
@@ -13,22 +21,51 @@ pub enum Instr {
     OpTrue,
     /// Push a false value onto stack.
     OpFalse,
+    /// Push the number 0 onto stack, without a slot in the constant pool.
+    OpZero,
+    /// Push the number 1 onto stack, without a slot in the constant pool.
+    OpOne,
     /// (idx) Lookup constant value using `idx` and push onto stack.
     OpConst(usize),
     /// Pop 2 operands and push sum onto stack.
     OpAdd,
     /// Pop 2 operands and push difference onto stack.
     OpSub,
-    /// Pop 2 operands and push product onto stack.
+    /// Pop 2 operands and push product onto stack. A string times a whole number pushes the string
+    /// repeated that many times.
     OpMul,
     /// Pop 2 operands and push quotient onto stack.
     OpDiv,
+    /// Pop 2 operands and push floored quotient onto stack.
+    OpIDiv,
     /// Pop an operand and push its numeric negation onto stack.
     OpNeg,
     /// Pop an operand and push its boolean negation onto stack.
     OpNot,
-    /// Pop 2 operands, compare less, and push boolean onto stack.
+    /// Pop an operand and push whether it is truthy onto stack.
+    OpBool,
+    /// Pop 2 number operands and push the range from the first up to the second onto stack.
+    OpRange,
+    /// (offset) With a list or range and the index of its next item on the stack, push the item
+    /// and bump the index. If there are no more items, jump forwards with `offset` amount of
+    /// instructions instead.
+    OpIter(usize),
+    /// Pop a list and an index, and push the item at that index onto stack. Negative indexes count
+    /// from the end of the list.
+    OpIndex,
+    /// Pop a list and the two bounds of a slice, and push a new list with the items from the first
+    /// bound up to but not including the second onto stack. Bounds are clamped to the list, and a
+    /// unit bound stands for the matching end.
+    OpSlice,
+    /// Pop 2 operands, compare less, and push boolean onto stack. Operands are either both
+    /// numbers or both strings, and strings are compared lexicographically.
     OpLt,
+    /// Pop 2 operands, compare greater, and push boolean onto stack. Same operands as `OpLt`.
+    OpGt,
+    /// Pop 2 operands, compare less or equal, and push boolean onto stack.
+    OpLe,
+    /// Pop 2 operands, compare greater or equal, and push boolean onto stack.
+    OpGe,
     /// Pop 2 operands, compare equals, and push boolean onto stack.
     OpEq,
     /// (offset) Jump backwards with `offset` amount of instructions.
@@ -45,37 +82,373 @@ pub enum Instr {
     OpDefine(usize),
     /// (idx) Lookup name of function using `idx`, and push a new coroutine onoto stack.
     OpCreate(usize),
+    /// (slot) Push onto stack the value of the local variable in `slot`. If it is not bound yet,
+    /// its name is looked up in env instead, same as `OpLoad`.
+    OpGetLocal(usize),
+    /// (slot) Write top of stack to the local variable in `slot`, and push unit onto stack.
+    OpSetLocal(usize),
+    /// (idx) Push onto stack the value of the upvalue at `idx`. If it is not bound yet, its name is
+    /// looked up in env instead, same as `OpGetLocal`.
+    OpGetUpvalue(usize),
+    /// (idx) Write top of stack to the upvalue at `idx`, and push unit onto stack.
+    OpSetUpvalue(usize),
+    /// (idx) Same as `OpSetUpvalue`, but into a new cell, so that functions that captured the
+    /// upvalue before keep the value they saw.
+    OpBindUpvalue(usize),
+    /// (idx) Lookup function using `idx`, and push a copy of it that holds on to the upvalues it
+    /// captures from this function.
+    OpClosure(usize),
     /// (num) Resume coroutine using `num` arguments from stack. Returned/yielded value will be top of stack.
     OpResume(usize),
+    /// (num) Call function using `num` arguments from stack, and push its result onto stack.
+    OpCall(usize),
+    /// (num) Same as `OpCall`, for a built-in that was looked up when compiling. If its name has
+    /// since been bound in env, or built-ins are turned off, the name is looked up again.
+    OpNativeCall(usize),
+    /// Pop 2 integer operands and push their bitwise and onto stack.
+    OpBitAnd,
+    /// Pop 2 integer operands and push their bitwise or onto stack.
+    OpBitOr,
+    /// Pop 2 integer operands and push their bitwise xor onto stack.
+    OpBitXor,
+    /// Pop an integer operand and push its bitwise negation onto stack.
+    OpBitNot,
+    /// Pop 2 integer operands and push the left shifted by the right onto stack. The shift amount
+    /// must be between 0 and 63, anything else is a runtime error rather than being masked.
+    OpShl,
+    /// Pop 2 integer operands and push the left arithmetically shifted right by the right. The
+    /// shift amount is checked the same as for `OpShl`.
+    OpShr,
     /// Suspend current coroutine and yield top of stack.
     OpYield,
-    /// Pop top of stack, print value, and push unit onto stack.
+    /// Pop top of stack, print value, and push unit onto stack. Values are printed using their
+    /// `Display` form, so strings are written raw without surrounding quotes.
     OpPrint,
+    /// (num) Pop `num` operands and push a list of them onto stack.
+    OpList(usize),
+    /// (num) Pop a list of exactly `num` elements, and push the elements in reverse order so that
+    /// the first element is top of stack.
+    OpUnpack(usize),
     /// Pop the top of stack.
     OpPop,
+    /// Swap the top 2 values on the stack.
+    OpSwap,
+    /// (id) Notify the attached debugger, if any, using an opaque breakpoint `id`.
+    OpBreakpoint(u32),
     /// Exit coroutine, and return top of stack or unit.
     OpRet,
 }
 
+impl Instr {
+    /// Number of distinct opcodes, i.e. instruction variants.
+    pub const NUM_OPS: usize = 53;
+
+    /// A dense index identifying the instruction variant, ignoring its operand. This is always
+    /// less than `NUM_OPS`, so it can be used to index into arrays.
+    pub fn opcode(&self) -> usize {
+        match self {
+            OpUnit => 0,
+            OpTrue => 1,
+            OpFalse => 2,
+            OpConst(_) => 3,
+            OpAdd => 4,
+            OpSub => 5,
+            OpMul => 6,
+            OpDiv => 7,
+            OpIDiv => 8,
+            OpNeg => 9,
+            OpNot => 10,
+            OpLt => 11,
+            OpEq => 12,
+            OpLoop(_) => 13,
+            OpJump(_) => 14,
+            OpBranch(_) => 15,
+            OpLoad(_) => 16,
+            OpStore(_) => 17,
+            OpDefine(_) => 18,
+            OpCreate(_) => 19,
+            OpResume(_) => 20,
+            OpYield => 21,
+            OpPrint => 22,
+            OpList(_) => 23,
+            OpUnpack(_) => 24,
+            OpPop => 25,
+            OpBreakpoint(_) => 26,
+            OpRet => 27,
+            OpCall(_) => 28,
+            OpBitAnd => 29,
+            OpBitOr => 30,
+            OpBitXor => 31,
+            OpBitNot => 32,
+            OpShl => 33,
+            OpShr => 34,
+            OpGt => 35,
+            OpLe => 36,
+            OpGe => 37,
+            OpSwap => 38,
+            OpZero => 39,
+            OpRange => 40,
+            OpIter(_) => 41,
+            OpIndex => 42,
+            OpSlice => 43,
+            OpGetLocal(_) => 44,
+            OpSetLocal(_) => 45,
+            OpNativeCall(_) => 46,
+            OpGetUpvalue(_) => 47,
+            OpSetUpvalue(_) => 48,
+            OpClosure(_) => 49,
+            OpOne => 50,
+            OpBindUpvalue(_) => 51,
+            OpBool => 52,
+        }
+    }
+
+    /// The operand of the instruction, if it has one.
+    pub fn operand(&self) -> Option<usize> {
+        match self {
+            OpConst(operand)
+            | OpLoop(operand)
+            | OpJump(operand)
+            | OpBranch(operand)
+            | OpLoad(operand)
+            | OpStore(operand)
+            | OpDefine(operand)
+            | OpCreate(operand)
+            | OpResume(operand)
+            | OpList(operand)
+            | OpUnpack(operand)
+            | OpCall(operand)
+            | OpIter(operand)
+            | OpGetLocal(operand)
+            | OpSetLocal(operand)
+            | OpNativeCall(operand)
+            | OpGetUpvalue(operand)
+            | OpSetUpvalue(operand)
+            | OpBindUpvalue(operand)
+            | OpClosure(operand) => Some(*operand),
+            OpBreakpoint(id) => Some(*id as usize),
+            _ => None,
+        }
+    }
+
+    /// The instruction with the given opcode, see `opcode`, and operand, which is ignored if it
+    /// takes none. Gives back `None` if there is no such opcode, or the operand does not fit.
+    pub fn from_opcode(opcode: usize, operand: usize) -> Option<Instr> {
+        let instr = match opcode {
+            0 => OpUnit,
+            1 => OpTrue,
+            2 => OpFalse,
+            3 => OpConst(operand),
+            4 => OpAdd,
+            5 => OpSub,
+            6 => OpMul,
+            7 => OpDiv,
+            8 => OpIDiv,
+            9 => OpNeg,
+            10 => OpNot,
+            11 => OpLt,
+            12 => OpEq,
+            13 => OpLoop(operand),
+            14 => OpJump(operand),
+            15 => OpBranch(operand),
+            16 => OpLoad(operand),
+            17 => OpStore(operand),
+            18 => OpDefine(operand),
+            19 => OpCreate(operand),
+            20 => OpResume(operand),
+            21 => OpYield,
+            22 => OpPrint,
+            23 => OpList(operand),
+            24 => OpUnpack(operand),
+            25 => OpPop,
+            26 => OpBreakpoint(u32::try_from(operand).ok()?),
+            27 => OpRet,
+            28 => OpCall(operand),
+            29 => OpBitAnd,
+            30 => OpBitOr,
+            31 => OpBitXor,
+            32 => OpBitNot,
+            33 => OpShl,
+            34 => OpShr,
+            35 => OpGt,
+            36 => OpLe,
+            37 => OpGe,
+            38 => OpSwap,
+            39 => OpZero,
+            40 => OpRange,
+            41 => OpIter(operand),
+            42 => OpIndex,
+            43 => OpSlice,
+            44 => OpGetLocal(operand),
+            45 => OpSetLocal(operand),
+            46 => OpNativeCall(operand),
+            47 => OpGetUpvalue(operand),
+            48 => OpSetUpvalue(operand),
+            49 => OpClosure(operand),
+            50 => OpOne,
+            51 => OpBindUpvalue(operand),
+            52 => OpBool,
+            _ => return None,
+        };
+        Some(instr)
+    }
+
+    /// How many values the instruction pops off the stack, and how many it then pushes. An
+    /// `OpYield` pops the yielded value, and pushes the value it is resumed with.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            OpUnit | OpTrue | OpFalse | OpZero | OpOne | OpConst(_) | OpLoad(_) => (0, 1),
+            OpDefine(_) | OpCreate(_) => (0, 1),
+            OpGetLocal(_) | OpGetUpvalue(_) | OpClosure(_) => (0, 1),
+            OpAdd | OpSub | OpMul | OpDiv | OpIDiv | OpEq => (2, 1),
+            OpLt | OpGt | OpLe | OpGe | OpRange | OpIndex => (2, 1),
+            OpSlice => (3, 1),
+            OpBitAnd | OpBitOr | OpBitXor | OpShl | OpShr => (2, 1),
+            OpNeg | OpNot | OpBool | OpBitNot | OpStore(_) => (1, 1),
+            OpSetLocal(_) | OpSetUpvalue(_) | OpBindUpvalue(_) => (1, 1),
+            OpYield | OpPrint => (1, 1),
+            OpLoop(_) | OpJump(_) | OpBreakpoint(_) => (0, 0),
+            // Branching only peeks at the condition.
+            OpBranch(_) => (1, 1),
+            // When there is an item, that is. The jump leaves the stack as it is.
+            OpIter(_) => (2, 3),
+            OpResume(num) | OpCall(num) | OpNativeCall(num) => (num + 1, 1),
+            OpList(num) => (*num, 1),
+            OpUnpack(num) => (1, *num),
+            OpPop | OpRet => (1, 0),
+            OpSwap => (2, 2),
+        }
+    }
+}
+
+/// A variable that is shared between a function and the functions nested in it, by each holding
+/// on to the same cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upvalue {
+    pub name: String,
+    /// Whether this is a local of the function that nested functions capture, as opposed to one
+    /// the function captures from the function it is nested in.
+    pub local: bool,
+}
+
+/// The instructions of a function, along with its constant pool and the source line of each
+/// instruction. Local variables are given slots by name, so that they can be looked up by index.
+/// The same goes for upvalues.
+#[derive(Debug, Default, Clone)]
 pub struct Code {
     instrs: Vec<Instr>,
     consts: Vec<Value>,
+    locals: Vec<String>,
+    upvalues: Vec<Upvalue>,
+    // Names bound by the functions this one is nested in. Only used when compiling.
+    outer: Vec<String>,
     lines: Vec<usize>,
 }
 
+/// Lists the instructions, in the same format as the disassembler.
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", debug::disassemble(self, "__code__"))
+    }
+}
+
+/// Each instruction along with its index and source line.
+impl<'a> IntoIterator for &'a Code {
+    type Item = (usize, &'a Instr, usize);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter_from(0)
+    }
+}
+
+/// Same as iterating `&Code`, but the instructions can be changed in place, e.g. by an optimizer.
+impl<'a> IntoIterator for &'a mut Code {
+    type Item = (usize, &'a mut Instr, usize);
+    type IntoIter = IterMut<'a>;
+
+    fn into_iter(self) -> IterMut<'a> {
+        IterMut {
+            idx: 0,
+            instrs: self.instrs.iter_mut(),
+            lines: self.lines.iter(),
+        }
+    }
+}
+
+/// Iterator over `(index, instruction, line)` of a `Code`.
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    idx: usize,
+    instrs: slice::Iter<'a, Instr>,
+    lines: slice::Iter<'a, usize>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (usize, &'a Instr, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = (self.idx, self.instrs.next()?, *self.lines.next()?);
+        self.idx += 1;
+        Some(item)
+    }
+}
+
+/// Iterator over `(index, instruction, line)` of a `Code`, with the instructions mutable.
+#[derive(Debug)]
+pub struct IterMut<'a> {
+    idx: usize,
+    instrs: slice::IterMut<'a, Instr>,
+    lines: slice::Iter<'a, usize>,
+}
+
+impl<'a> Iterator for IterMut<'a> {
+    type Item = (usize, &'a mut Instr, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = (self.idx, self.instrs.next()?, *self.lines.next()?);
+        self.idx += 1;
+        Some(item)
+    }
+}
+
 impl Code {
+    /// Most constants one pool can hold. Constant indexes are `usize` in memory, but keeping them
+    /// below this means they always fit in a `u32`, e.g. when code is written out in a compact
+    /// form.
+    pub const MAX_CONSTS: usize = u32::MAX as usize;
+
     pub fn new() -> Self {
         Self {
             instrs: Vec::new(),
             consts: Vec::new(),
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+            outer: Vec::new(),
             lines: Vec::new(),
         }
     }
 
+    /// Put code back together from its parts, e.g. when reading it back in. Nothing is checked, so
+    /// see `verify` before running it.
+    pub fn from_parts(
+        instrs: Vec<Instr>,
+        lines: Vec<usize>,
+        consts: Vec<Value>,
+        locals: Vec<String>,
+        upvalues: Vec<Upvalue>,
+    ) -> Self {
+        Self { instrs, consts, locals, upvalues, outer: Vec::new(), lines }
+    }
+
     pub fn len(&self) -> usize {
         self.instrs.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.instrs.is_empty()
+    }
+
     pub fn line(&self, idx: usize) -> usize {
         self.lines[idx]
     }
@@ -84,10 +457,79 @@ impl Code {
         &self.instrs[idx]
     }
 
+    pub fn iter(&self) -> Iter<'_> {
+        self.into_iter()
+    }
+
+    /// Iterate from the instruction at `start`, which is empty if it is past the end. Indexes are
+    /// still those of the whole code.
+    pub fn iter_from(&self, start: usize) -> Iter<'_> {
+        let start = start.min(self.instrs.len());
+        Iter {
+            idx: start,
+            instrs: self.instrs[start..].iter(),
+            lines: self.lines[start..].iter(),
+        }
+    }
+
     pub fn constant(&self, idx: usize) -> &Value {
         &self.consts[idx]
     }
 
+    pub fn constants(&self) -> &[Value] {
+        &self.consts
+    }
+
+    /// Names of the local variables, indexed by slot.
+    pub fn locals(&self) -> &[String] {
+        &self.locals
+    }
+
+    pub fn local_slot(&self, name: &str) -> Option<usize> {
+        self.locals.iter().position(|local| local == name)
+    }
+
+    /// Give a local variable a slot, reusing its slot if it already has one.
+    pub fn add_local(&mut self, name: &str) -> usize {
+        match self.local_slot(name) {
+            Some(slot) => slot,
+            None => {
+                self.locals.push(name.to_owned());
+                self.locals.len() - 1
+            }
+        }
+    }
+
+    pub fn upvalues(&self) -> &[Upvalue] {
+        &self.upvalues
+    }
+
+    pub fn upvalue_slot(&self, name: &str) -> Option<usize> {
+        self.upvalues.iter().position(|upvalue| upvalue.name == name)
+    }
+
+    /// Give `name` an upvalue, reusing it if there already is one. A name with a local slot is
+    /// shared with nested functions, and anything else is captured from the enclosing function.
+    pub fn add_upvalue(&mut self, name: &str) -> usize {
+        match self.upvalue_slot(name) {
+            Some(idx) => idx,
+            None => {
+                let local = self.local_slot(name).is_some();
+                self.upvalues.push(Upvalue { name: name.to_owned(), local });
+                self.upvalues.len() - 1
+            }
+        }
+    }
+
+    /// Names bound by the enclosing functions, which this one captures when it uses them.
+    pub fn outer(&self) -> &[String] {
+        &self.outer
+    }
+
+    pub fn set_outer(&mut self, outer: Vec<String>) {
+        self.outer = outer;
+    }
+
     pub fn add(&mut self, instr: Instr, line: usize) -> usize {
         let idx = self.instrs.len();
         self.instrs.push(instr);
@@ -95,10 +537,11 @@ impl Code {
         idx
     }
 
+    /// Add a constant to the pool, reusing the slot of an identical constant if there is one.
     pub fn add_const(&mut self, value: Value) -> usize {
         let idx = self.consts.len();
         for (i, val) in self.consts.iter().enumerate() {
-            if val == &value {
+            if same_const(val, &value) {
                 return i;
             }
         }
@@ -106,15 +549,373 @@ impl Code {
         idx
     }
 
+    /// Whether the two are the same code, down to the line numbers, so either can stand in for
+    /// the other.
+    pub fn same_as(&self, other: &Code) -> bool {
+        self.instrs == other.instrs
+            && self.lines == other.lines
+            && self.locals == other.locals
+            && self.upvalues == other.upvalues
+            && self.consts.len() == other.consts.len()
+            && self.consts.iter().zip(&other.consts).all(|(a, b)| same_const(a, b))
+    }
+
     pub fn patch(&mut self, idx: usize, instr: Instr) {
         self.instrs[idx] = instr;
     }
+
+    /// Check that every jump lands on an instruction and every constant index, local slot and
+    /// upvalue is in range, so that running the code cannot index out of bounds. The pool must
+    /// also be within `MAX_CONSTS`.
+    pub fn verify(&self) -> Result<(), String> {
+        check_const_count(self.consts.len())?;
+        for (idx, instr, _) in self {
+            // The IP already points to the next instruction when a jump is taken.
+            let target = match instr {
+                OpJump(offset) | OpBranch(offset) | OpIter(offset) => {
+                    Some((idx + 1).checked_add(*offset))
+                }
+                OpLoop(offset) => Some((idx + 1).checked_sub(*offset)),
+                _ => None,
+            };
+            match target {
+                Some(Some(target)) if target < self.instrs.len() => {}
+                Some(_) => return Err(format!("{:?} at {:04} jumps out of bounds", instr, idx)),
+                None => {}
+            }
+
+            match instr {
+                OpConst(cidx) | OpLoad(cidx) | OpStore(cidx) | OpDefine(cidx) | OpCreate(cidx)
+                | OpClosure(cidx)
+                    if *cidx >= self.consts.len() =>
+                {
+                    return Err(format!("{:?} at {:04} refers to missing constant", instr, idx));
+                }
+                OpGetLocal(slot) | OpSetLocal(slot) if *slot >= self.locals.len() => {
+                    return Err(format!("{:?} at {:04} refers to missing local", instr, idx));
+                }
+                OpGetUpvalue(up) | OpSetUpvalue(up) | OpBindUpvalue(up)
+                    if *up >= self.upvalues.len() =>
+                {
+                    return Err(format!("{:?} at {:04} refers to missing upvalue", instr, idx));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Simulate the height of the stack through every path of the code, and check that no
+    /// instruction pops more values than there are, that paths which meet agree on the height, and
+    /// that exactly one value is left to return. This expects jumps to be in bounds, see `verify`.
+    pub fn check_stack(&self) -> Result<(), String> {
+        self.max_stack().map(|_| ())
+    }
+
+    /// The most values the stack holds at any point while running the code, found the same way
+    /// `check_stack` checks it.
+    pub fn max_stack(&self) -> Result<usize, String> {
+        let mut heights: Vec<Option<usize>> = vec![None; self.instrs.len() + 1];
+        let mut pending = vec![(0, 0)];
+        let mut max = 0;
+        while let Some((idx, height)) = pending.pop() {
+            match heights[idx] {
+                Some(seen) if seen == height => continue,
+                Some(seen) => {
+                    return Err(format!(
+                        "stack height at {:04} is {} on one path but {} on another",
+                        idx, seen, height
+                    ))
+                }
+                None => heights[idx] = Some(height),
+            }
+            // Running out of code returns, same as `OpRet`.
+            let instr = match self.instrs.get(idx) {
+                Some(instr) => instr,
+                None if height <= 1 => continue,
+                None => return Err(format!("stack has {} values left at the end", height)),
+            };
+
+            let (pops, pushes) = instr.stack_effect();
+            if pops > height {
+                return Err(format!("{:?} at {:04} underflows the stack", instr, idx));
+            }
+            let after = height - pops + pushes;
+            max = max.max(after);
+            match instr {
+                OpJump(offset) => pending.push((idx + 1 + offset, after)),
+                OpLoop(offset) => pending.push((idx + 1 - offset, after)),
+                OpBranch(offset) => {
+                    pending.push((idx + 1 + offset, after));
+                    pending.push((idx + 1, after));
+                }
+                OpIter(offset) => {
+                    pending.push((idx + 1 + offset, height));
+                    pending.push((idx + 1, after));
+                }
+                OpRet if after != 0 => {
+                    return Err(format!("stack has {} values left at {:04}", height, idx));
+                }
+                OpRet => {}
+                _ => pending.push((idx + 1, after)),
+            }
+        }
+        Ok(max)
+    }
+}
+
+// Constants are interchangeable if they are identical, which is stricter than `==`. Numbers must
+// have the same bits, so `0` and `-0` stay apart. Functions are immutable, so ones compiled from
+// the same source can share a slot even though `==` compares them by address.
+fn same_const(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Num(n1), Value::Num(n2)) => n1.to_bits() == n2.to_bits(),
+        (Value::Fn(f1), Value::Fn(f2)) => Rc::ptr_eq(f1, f2) || f1.same_as(f2),
+        _ => a == b,
+    }
+}
+
+fn check_const_count(count: usize) -> Result<(), String> {
+    if count > Code::MAX_CONSTS {
+        return Err(format!(
+            "constant pool has {} entries but at most {} are allowed",
+            count,
+            Code::MAX_CONSTS
+        ));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn opcodes_in_range() {
+        assert_eq!(0, OpUnit.opcode());
+        assert_eq!(Instr::NUM_OPS - 1, OpBool.opcode());
+        assert!(OpLoop(3).opcode() == OpLoop(7).opcode());
+    }
+
+    #[test]
+    fn from_opcode_round_trip() {
+        for opcode in 0..Instr::NUM_OPS {
+            let instr = Instr::from_opcode(opcode, 5).unwrap();
+            assert_eq!(opcode, instr.opcode());
+            if let Some(operand) = instr.operand() {
+                assert_eq!(5, operand);
+            }
+        }
+        assert_eq!(None, Instr::from_opcode(Instr::NUM_OPS, 0));
+    }
+
+    #[test]
+    fn verify_ok() {
+        let mut code = Code::new();
+        let idx = code.add_const(Value::Num(1.0));
+        code.add(OpTrue, 1);
+        code.add(OpBranch(2), 1);
+        code.add(OpConst(idx), 1);
+        code.add(OpLoop(4), 1);
+        code.add(OpRet, 1);
+        assert_eq!(Ok(()), code.verify());
+    }
+
+    #[test]
+    fn verify_jump_out_of_bounds() {
+        let mut code = Code::new();
+        code.add(OpJump(1), 1);
+        code.add(OpRet, 1);
+        assert_eq!(Err("OpJump(1) at 0000 jumps out of bounds".to_owned()), code.verify());
+
+        code.patch(0, OpBranch(5));
+        assert!(code.verify().is_err());
+
+        code.patch(0, OpLoop(2));
+        assert_eq!(Err("OpLoop(2) at 0000 jumps out of bounds".to_owned()), code.verify());
+    }
+
+    #[test]
+    fn verify_missing_local() {
+        let mut code = Code::new();
+        assert_eq!(0, code.add_local("a"));
+        assert_eq!(1, code.add_local("b"));
+        assert_eq!(0, code.add_local("a"));
+        code.add(OpGetLocal(1), 1);
+        code.add(OpSetLocal(2), 1);
+        let err = Err("OpSetLocal(2) at 0001 refers to missing local".to_owned());
+        assert_eq!(err, code.verify());
+    }
+
+    #[test]
+    fn verify_missing_upvalue() {
+        let mut code = Code::new();
+        code.add_local("a");
+        assert_eq!(0, code.add_upvalue("a"));
+        assert_eq!(1, code.add_upvalue("b"));
+        assert_eq!(0, code.add_upvalue("a"));
+        // Only the name with a slot belongs to this function.
+        let locals: Vec<bool> = code.upvalues().iter().map(|upvalue| upvalue.local).collect();
+        assert_eq!(vec![true, false], locals);
+        code.add(OpGetUpvalue(1), 1);
+        code.add(OpSetUpvalue(2), 1);
+        let err = Err("OpSetUpvalue(2) at 0001 refers to missing upvalue".to_owned());
+        assert_eq!(err, code.verify());
+    }
+
+    #[test]
+    fn verify_missing_constant() {
+        let mut code = Code::new();
+        code.add_const(Value::Unit);
+        code.add(OpConst(0), 1);
+        code.add(OpLoad(1), 1);
+        assert_eq!(Err("OpLoad(1) at 0001 refers to missing constant".to_owned()), code.verify());
+    }
+
+    #[test]
+    fn verify_const_count() {
+        // Too big to actually allocate, so check the limit on its own.
+        assert_eq!(Ok(()), check_const_count(0));
+        assert_eq!(Ok(()), check_const_count(Code::MAX_CONSTS));
+        let err = check_const_count(Code::MAX_CONSTS + 1).unwrap_err();
+        assert_eq!("constant pool has 4294967296 entries but at most 4294967295 are allowed", err);
+    }
+
+    #[test]
+    fn dedup_fn_consts() {
+        let count_fns = |src: &str| {
+            let code = crate::cgen::compile(crate::parse::parse_ast(src).unwrap()).unwrap();
+            code.constants().iter().filter(|c| c.is_fn()).count()
+        };
+        assert_eq!(1, count_fns("{ def f a = a + 1; def f a = a + 1; f }"));
+        // Different bodies, or the same body on different lines, each get their own slot.
+        assert_eq!(2, count_fns("{ def f a = a + 1; def f a = a + 2; f }"));
+        assert_eq!(2, count_fns("def f a = a + 1\ndef f a = a + 1"));
+
+        let mut code = Code::new();
+        assert_eq!(0, code.add_const(Value::Num(0.0)));
+        assert_eq!(1, code.add_const(Value::Num(-0.0)));
+        assert_eq!(2, code.add_const(Value::Num(f64::NAN)));
+        assert_eq!(2, code.add_const(Value::Num(f64::NAN)));
+    }
+
+    #[test]
+    fn small_numbers() {
+        let ast = crate::parse::parse_ast("[0, 1, -0, 2, 1.0]").unwrap();
+        let code = crate::cgen::compile(ast).unwrap();
+        let instrs: Vec<_> = code.iter().map(|(_, instr, _)| *instr).collect();
+        assert_eq!(vec![OpZero, OpOne, OpConst(0), OpConst(1), OpOne, OpList(5), OpRet], instrs);
+        assert_eq!(2, code.constants().len());
+
+        let val = crate::vm::CoVM::eval_expr("[0 + 1, 1 - 1, -0 == 0]").unwrap();
+        assert_eq!("[1, 0, true]", format!("{}", val));
+    }
+
+    #[test]
+    fn fold_unary_literals() {
+        let instrs = |src: &str| {
+            let code = crate::cgen::compile(crate::parse::parse_ast(src).unwrap()).unwrap();
+            let consts = code.constants().to_vec();
+            (code.iter().map(|(_, instr, _)| *instr).collect::<Vec<_>>(), consts)
+        };
+        assert_eq!((vec![OpConst(0), OpRet], vec![Value::Num(-5.0)]), instrs("-5"));
+        assert_eq!((vec![OpConst(0), OpRet], vec![Value::Num(5.0)]), instrs("- -5"));
+        assert_eq!((vec![OpFalse, OpRet], vec![]), instrs("not true"));
+        assert_eq!((vec![OpTrue, OpRet], vec![]), instrs("not not true"));
+        // Only literals are folded.
+        assert_eq!(vec![OpTrue, OpNot, OpRet], instrs("not (true)").0);
+    }
+
+    #[test]
+    fn display_listing() {
+        let mut code = Code::new();
+        let idx = code.add_const(Value::Num(1.0));
+        code.add(OpConst(idx), 1);
+        code.add(OpConst(idx), 1);
+        code.add(OpAdd, 2);
+        let exp = "== instr: __code__ ==\n\
+            0000    1 OpConst(0) 1\n\
+            0001    | OpConst(0) 1\n\
+            0002    2 OpAdd\n";
+        assert_eq!(exp, format!("{}", code));
+    }
+
+    #[test]
+    fn iterate() {
+        let src = "let a = 1\nprint a + 2\n\"a\"";
+        let mut code = crate::cgen::compile(crate::parse::parse_ast(src).unwrap()).unwrap();
+        let consts: Vec<(usize, usize)> = code
+            .iter()
+            .filter_map(|(idx, instr, line)| match instr {
+                OpConst(_) | OpOne => Some((idx, line)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![(0, 1), (4, 2), (8, 3)], consts, "{}", code);
+
+        let rest: Vec<usize> = code.iter_from(8).map(|(idx, _, _)| idx).collect();
+        assert_eq!(vec![8, 9], rest);
+        assert_eq!(0, code.iter_from(code.len() + 1).count());
+
+        for (_, instr, _) in &mut code {
+            if let OpPrint = instr {
+                *instr = OpPop;
+            }
+        }
+        assert!(!code.iter().any(|(_, instr, _)| *instr == OpPrint));
+    }
+
+    #[test]
+    fn check_stack_compiled() {
+        let src = "def f a (b = 1) = { let c, d = [a, b]; yield c + d }\n\
+            let i = 0\n\
+            while i < 3 do { i += 1; if i == 2 then (print i) else () end } end\n\
+            for x in 0..i do { let y = x; for z in [x] do (print z) end } end\n\
+            [1, 2][0] + [3][:1][0:][0]\n\
+            let co = create f\n\
+            resume co 1";
+        let code = crate::cgen::compile(crate::parse::parse_ast(src).unwrap()).unwrap();
+        assert_eq!(Ok(()), code.check_stack());
+    }
+
+    #[test]
+    fn max_stack() {
+        let src = "let xs = [1, 2 + 3 * 4]\nprint xs[0]";
+        let code = crate::cgen::compile(crate::parse::parse_ast(src).unwrap()).unwrap();
+        assert_eq!(Ok(4), code.max_stack());
+        assert_eq!(Ok(0), Code::new().max_stack());
+    }
+
+    #[test]
+    fn check_stack_underflow() {
+        let mut code = Code::new();
+        code.add(OpTrue, 1);
+        code.add(OpAdd, 1);
+        code.add(OpRet, 1);
+        assert_eq!(Err("OpAdd at 0001 underflows the stack".to_owned()), code.check_stack());
+    }
+
+    #[test]
+    fn check_stack_unbalanced() {
+        // The branch skips popping the condition, so the two paths meet at different heights.
+        let mut code = Code::new();
+        code.add(OpTrue, 1);
+        code.add(OpBranch(1), 1);
+        code.add(OpPop, 1);
+        code.add(OpUnit, 1);
+        code.add(OpRet, 1);
+        assert_eq!(
+            Err("stack height at 0003 is 0 on one path but 1 on another".to_owned()),
+            code.check_stack()
+        );
+
+        let mut code = Code::new();
+        code.add(OpUnit, 1);
+        code.add(OpUnit, 1);
+        code.add(OpRet, 1);
+        assert_eq!(Err("stack has 2 values left at 0002".to_owned()), code.check_stack());
+    }
+
     #[test]
     fn code_add_returns_index() {
         let mut code = Code::new();
@@ -135,9 +936,9 @@ mod tests {
     #[test]
     fn code_add_const_stores_unique() {
         let mut code = Code::new();
-        assert_eq!(0, code.add_const(Value::Str("foo".to_owned())));
-        assert_eq!(0, code.add_const(Value::Str("foo".to_owned())));
-        assert_eq!(1, code.add_const(Value::Str("bar".to_owned())));
+        assert_eq!(0, code.add_const(Value::Str("foo".into())));
+        assert_eq!(0, code.add_const(Value::Str("foo".into())));
+        assert_eq!(1, code.add_const(Value::Str("bar".into())));
         assert_eq!(2, code.consts.len());
     }
 }
@@ -1,51 +1,166 @@
 //! A module that provides helpers to debug Coro.
+//!
+//! Besides the disassembler, this also defines the `Debugger` trait that tools implement in order
+//! to be notified when a coroutine hits an `OpBreakpoint` instruction.
+
+use std::collections::HashMap;
+use std::fmt::Write;
 
 use crate::code::Code;
 use crate::code::Instr::*;
+use crate::value::Value;
 
-pub fn print(code: &Code, name: &str) {
-    eprintln!("== instr: {} ==", name);
-    let mut idx = 0;
-    while idx < code.len() {
-        print_instr(code, idx);
-        idx += 1;
+/// Hooks that the VM calls into while executing code.
+pub trait Debugger: Send {
+    /// Called when an `OpBreakpoint` with the given `id` is executed at `ip`.
+    fn on_breakpoint(&mut self, id: u32, ip: usize, stack: &[Value], env: &HashMap<String, Value>);
+
+    /// Called when the watch expression registered as `name` evaluates to a different value than
+    /// before. The `old` value is `None` the first time the watch evaluates successfully.
+    fn on_watch_changed(&mut self, _name: &str, _old: Option<&Value>, _new: &Value) {}
+}
+
+impl Debugger for Box<dyn Debugger> {
+    fn on_breakpoint(&mut self, id: u32, ip: usize, stack: &[Value], env: &HashMap<String, Value>) {
+        (**self).on_breakpoint(id, ip, stack, env)
+    }
+
+    fn on_watch_changed(&mut self, name: &str, old: Option<&Value>, new: &Value) {
+        (**self).on_watch_changed(name, old, new)
     }
 }
 
+pub fn print(code: &Code, name: &str) {
+    eprint!("{}", disassemble(code, name));
+}
+
 pub fn print_instr(code: &Code, idx: usize) {
+    eprintln!("{}", format_instr(code, idx));
+}
+
+/// Produce a listing of all the instructions in `code`, in the same format as `print`.
+pub fn disassemble(code: &Code, name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "== instr: {} ==", name).unwrap();
+    for (idx, _, _) in code {
+        writeln!(out, "{}", format_instr(code, idx)).unwrap();
+    }
+    out
+}
+
+/// Format the instruction at `idx` as one line of the listing, i.e. its index, its source line,
+/// and the instruction itself along with what it refers to. Constants are shown the way the VM
+/// prints them, except that strings are quoted.
+pub fn format_instr(code: &Code, idx: usize) -> String {
+    let mut out = String::new();
+
     // index
-    eprint!("{:04} ", idx);
+    write!(out, "{:04} ", idx).unwrap();
 
     // line number
     if idx > 0 && code.line(idx) == code.line(idx - 1) {
-        eprint!("   | ");
+        write!(out, "   | ").unwrap();
     } else {
-        eprint!("{:4} ", code.line(idx));
+        write!(out, "{:4} ", code.line(idx)).unwrap();
     }
 
     // instruction
-    let instr = code.instr(idx).clone();
+    let instr = *code.instr(idx);
     match instr {
-        OpConst(idx) => {
-            let val = code.constant(idx);
-            eprintln!("{:?} {:?}", instr, val);
-        }
+        OpConst(idx) => match code.constant(idx) {
+            // Escaped, so that each instruction stays on its own line.
+            Value::Str(s) => write!(out, "{:?} {:?}", instr, &**s).unwrap(),
+            val => write!(out, "{:?} {}", instr, val).unwrap(),
+        },
         OpLoad(idx) => {
             let name = code.constant(idx);
-            eprintln!("{:?} {:?}", instr, name);
+            write!(out, "{:?} {:?}", instr, name).unwrap();
         }
         OpStore(idx) => {
             let name = code.constant(idx);
-            eprintln!("{:?} {:?}", instr, name);
+            write!(out, "{:?} {:?}", instr, name).unwrap();
         }
-        OpDefine(idx) => {
+        OpDefine(idx) | OpClosure(idx) => {
             let def = code.constant(idx);
-            eprintln!("{:?} {:?}", instr, def);
+            write!(out, "{:?} {:?}", instr, def).unwrap();
         }
         OpCreate(idx) => {
             let ident = code.constant(idx);
-            eprintln!("{:?} {:?}", instr, ident);
+            write!(out, "{:?} {:?}", instr, ident).unwrap();
         }
-        _ => eprintln!("{:?}", instr),
+        OpGetLocal(slot) | OpSetLocal(slot) => {
+            let name = &code.locals()[slot];
+            write!(out, "{:?} {:?}", instr, name).unwrap();
+        }
+        OpGetUpvalue(idx) | OpSetUpvalue(idx) | OpBindUpvalue(idx) => {
+            let name = &code.upvalues()[idx].name;
+            write!(out, "{:?} {:?}", instr, name).unwrap();
+        }
+        // Show where jumps land, counting from the next instruction like the VM does.
+        OpJump(offset) | OpBranch(offset) | OpIter(offset) => {
+            write!(out, "{:?} -> {:04}", instr, idx + 1 + offset).unwrap();
+        }
+        OpLoop(offset) => match (idx + 1).checked_sub(offset) {
+            Some(target) => write!(out, "{:?} -> {:04}", instr, target).unwrap(),
+            None => write!(out, "{:?} -> out of bounds", instr).unwrap(),
+        },
+        _ => write!(out, "{:?}", instr).unwrap(),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgen;
+    use crate::parse;
+
+    #[test]
+    fn local_slots() {
+        let ast = parse::parse_ast("let a = 1\nlet b = a").unwrap();
+        let listing = disassemble(&cgen::compile(ast).unwrap(), "main");
+        assert!(listing.contains("| OpSetLocal(1) \"b\"\n"), "{}", listing);
+        assert!(listing.contains("2 OpGetLocal(0) \"a\"\n"), "{}", listing);
+    }
+
+    #[test]
+    fn source_lines() {
+        let src = "let a = 1\n\ndef f x = {\n  let y = x;\n  y * 2\n}\nf(a)";
+        let code = cgen::compile(parse::parse_ast(src).unwrap()).unwrap();
+        let lines: Vec<_> = code.iter().map(|(_, instr, line)| (*instr, line)).collect();
+        assert_eq!((OpOne, 1), lines[0]);
+        assert_eq!(Some(&(OpCall(1), 7)), lines.iter().find(|(instr, _)| *instr == OpCall(1)));
+        let listing = disassemble(&code, "main");
+        assert!(listing.contains("   7 OpGetLocal(1) \"f\"\n"), "{}", listing);
+
+        // The body of a function keeps the lines of its block items.
+        let f = code.constants().iter().find(|v| v.is_fn()).unwrap().clone().into_fn();
+        let mul = f.code.iter().find(|(_, instr, _)| matches!(instr, OpMul)).unwrap();
+        assert_eq!(5, mul.2);
+        let set = f.code.iter().find(|(_, instr, _)| matches!(instr, OpSetLocal(_))).unwrap();
+        assert_eq!(4, set.2);
+    }
+
+    #[test]
+    fn const_values() {
+        let code = cgen::compile(parse::parse_ast("[\"hi\", 2.5, [3]]").unwrap()).unwrap();
+        assert_eq!("0000    1 OpConst(0) \"hi\"", format_instr(&code, 0));
+        assert_eq!("0001    | OpConst(1) 2.5", format_instr(&code, 1));
+
+        let code = cgen::compile(parse::parse_ast("\"a\nb\"").unwrap()).unwrap();
+        assert_eq!("0000    1 OpConst(0) \"a\\nb\"", format_instr(&code, 0));
+    }
+
+    #[test]
+    fn jump_targets() {
+        let ast = parse::parse_ast("if 1 < 2 then 3 else 4 end").unwrap();
+        let listing = disassemble(&cgen::compile(ast).unwrap(), "main");
+        assert!(listing.contains("0003    | OpBranch(3) -> 0007\n"), "{}", listing);
+        assert!(listing.contains("0006    | OpJump(2) -> 0009\n"), "{}", listing);
+
+        let ast = parse::parse_ast("while false do 1 end").unwrap();
+        let code = cgen::compile(ast).unwrap();
+        let (idx, _, _) = code.iter().find(|(_, instr, _)| matches!(instr, OpLoop(_))).unwrap();
+        assert!(format_instr(&code, idx).ends_with("-> 0000"), "{}", disassemble(&code, "main"));
     }
 }
@@ -0,0 +1,100 @@
+//! This module provides the error type shared by the parser, code generator, and VM.
+//!
+//! Errors carry the location they happened at, so embedders can report them nicely or match on
+//! the kind of error. Lines start at 1, and a line of 0 means the location is not known.
+
+use std::error;
+use std::fmt;
+
+/// An error from compiling or running Coro code, located in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoError {
+    /// Source code could not be parsed or compiled.
+    Compile { msg: String, line: usize, col: usize },
+    /// Something went wrong while executing instruction `ip` of a coroutine.
+    Runtime { msg: String, ip: usize, line: usize },
+    /// The script called `exit` with this code, and an exit handler was set.
+    Exit(i32),
+}
+
+impl CoError {
+    pub fn compile(msg: impl Into<String>, line: usize, col: usize) -> Self {
+        Self::Compile { msg: msg.into(), line, col }
+    }
+
+    pub fn runtime(msg: impl Into<String>, ip: usize, line: usize) -> Self {
+        Self::Runtime { msg: msg.into(), ip, line }
+    }
+
+    /// The error message, without any location.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Compile { msg, .. } | Self::Runtime { msg, .. } => msg,
+            Self::Exit(_) => "script exited",
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        match self {
+            Self::Compile { line, .. } | Self::Runtime { line, .. } => *line,
+            Self::Exit(_) => 0,
+        }
+    }
+
+    pub fn is_compile(&self) -> bool {
+        matches!(self, Self::Compile { .. })
+    }
+
+    pub fn is_runtime(&self) -> bool {
+        matches!(self, Self::Runtime { .. })
+    }
+
+    /// The exit code, if the script called `exit`.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Self::Exit(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compile { msg, line: 0, .. } | Self::Runtime { msg, line: 0, .. } => {
+                write!(f, "{}", msg)
+            }
+            Self::Compile { msg, line, col: 0 } => write!(f, "[line {}] {}", line, msg),
+            Self::Compile { msg, line, col } => write!(f, "[line {}:{}] {}", line, col, msg),
+            Self::Runtime { msg, line, .. } => write!(f, "[line {}] {}", line, msg),
+            Self::Exit(code) => write!(f, "script exited with code {}", code),
+        }
+    }
+}
+
+impl error::Error for CoError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!("[line 2:5] oops", CoError::compile("oops", 2, 5).to_string());
+        assert_eq!("[line 2] oops", CoError::compile("oops", 2, 0).to_string());
+        assert_eq!("[line 3] oops", CoError::runtime("oops", 7, 3).to_string());
+        assert_eq!("oops", CoError::runtime("oops", 0, 0).to_string());
+        assert_eq!("script exited with code 3", CoError::Exit(3).to_string());
+    }
+
+    #[test]
+    fn accessors() {
+        let err = CoError::runtime("oops", 7, 3);
+        assert_eq!("oops", err.message());
+        assert_eq!(3, err.line());
+        assert!(err.is_runtime());
+        assert!(!err.is_compile());
+        assert_eq!(None, err.exit_code());
+        assert_eq!(Some(3), CoError::Exit(3).exit_code());
+    }
+}
@@ -0,0 +1,262 @@
+//! This module provides a pretty-printer for the AST.
+//!
+//! The output is valid Coro source, so formatting a parsed AST and parsing it again gives back an
+//! equivalent AST. Since the parser keeps explicit groups around, we never need to insert extra
+//! parens to get the precedence right. Comments are not part of the AST, so they are lost.
+
+use crate::ast::*;
+
+const INDENT: &str = "  ";
+
+/// Format the whole program, with one top-level binding per line.
+pub fn fmt_ast(ast: &Ast) -> String {
+    let mut out = String::new();
+    for bind in &ast.items {
+        out.push_str(&fmt_bind(bind));
+        out.push('\n');
+    }
+    out
+}
+
+pub fn fmt_bind(bind: &Bind) -> String {
+    match bind {
+        Bind::Def(def_bind) => {
+            let mut head = format!("def {}", def_bind.name);
+            head.push_str(&fmt_params(def_bind));
+            format!("{} ={}", head, fmt_body(&fmt_cmd(&def_bind.body)))
+        }
+        Bind::Let(let_bind) => format!("let {} = {}", let_bind.name, fmt_cmd(&let_bind.init)),
+        Bind::Const(let_bind) => format!("const {} = {}", let_bind.name, fmt_cmd(&let_bind.init)),
+        Bind::Assign(let_bind) => fmt_assign(let_bind),
+        Bind::Destruct(destruct_bind) => format!(
+            "let {} = {}",
+            destruct_bind.names.join(", "),
+            fmt_cmd(&destruct_bind.init)
+        ),
+        Bind::Cmd(cmd) => fmt_cmd(cmd),
+    }
+}
+
+// Compound assignment is kept as the operation on the old value, so turn it back into `x += y`.
+fn fmt_assign(let_bind: &LetBind) -> String {
+    let (op, rhs) = match &let_bind.init.kind {
+        CmdKind::Expr(Expr { kind: ExprKind::Add(_, rhs), .. }) => ("+=", rhs),
+        CmdKind::Expr(Expr { kind: ExprKind::Sub(_, rhs), .. }) => ("-=", rhs),
+        CmdKind::Expr(Expr { kind: ExprKind::Mul(_, rhs), .. }) => ("*=", rhs),
+        CmdKind::Expr(Expr { kind: ExprKind::Div(_, rhs), .. }) => ("/=", rhs),
+        _ => return format!("{} = {}", let_bind.name, fmt_cmd(&let_bind.init)),
+    };
+    format!("{} {} {}", let_bind.name, op, fmt_expr(rhs))
+}
+
+pub fn fmt_cmd(cmd: &Cmd) -> String {
+    match &cmd.kind {
+        CmdKind::Print(expr) => format!("print {}", fmt_expr(expr)),
+        CmdKind::Create(name) => format!("create {}", name),
+        CmdKind::Resume(expr, args) => {
+            let mut out = format!("resume {}", fmt_expr(expr));
+            for arg in args {
+                out.push(' ');
+                out.push_str(&fmt_expr(arg));
+            }
+            out
+        }
+        CmdKind::Yield(expr) => format!("yield {}", fmt_expr(expr)),
+        CmdKind::While(cond, body) => format!(
+            "while {} do\n{}\nend",
+            fmt_expr(cond),
+            indent(&fmt_expr(body))
+        ),
+        CmdKind::For(name, items, body) => format!(
+            "for {} in {} do\n{}\nend",
+            name,
+            fmt_expr(items),
+            indent(&fmt_expr(body))
+        ),
+        CmdKind::If(cond, then, alt) => format!(
+            "if {} then\n{}\nelse\n{}\nend",
+            fmt_expr(cond),
+            indent(&fmt_expr(then)),
+            indent(&fmt_expr(alt))
+        ),
+        CmdKind::Expr(expr) => fmt_expr(expr),
+    }
+}
+
+pub fn fmt_expr(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::Cond(cond, then, alt) => {
+            format!("{} ? {} : {}", fmt_expr(cond), fmt_expr(then), fmt_expr(alt))
+        }
+        ExprKind::Lt(lhs, rhs) => fmt_binary(lhs, "<", rhs),
+        ExprKind::Gt(lhs, rhs) => fmt_binary(lhs, ">", rhs),
+        ExprKind::Le(lhs, rhs) => fmt_binary(lhs, "<=", rhs),
+        ExprKind::Ge(lhs, rhs) => fmt_binary(lhs, ">=", rhs),
+        ExprKind::Eq(lhs, rhs) => fmt_binary(lhs, "==", rhs),
+        ExprKind::Range(start, end) => format!("{}..{}", fmt_expr(start), fmt_expr(end)),
+        ExprKind::Add(lhs, rhs) => fmt_binary(lhs, "+", rhs),
+        ExprKind::Sub(lhs, rhs) => fmt_binary(lhs, "-", rhs),
+        ExprKind::Mul(lhs, rhs) => fmt_binary(lhs, "*", rhs),
+        ExprKind::Div(lhs, rhs) => fmt_binary(lhs, "/", rhs),
+        ExprKind::IDiv(lhs, rhs) => fmt_binary(lhs, "//", rhs),
+        ExprKind::BitAnd(lhs, rhs) => fmt_binary(lhs, "&", rhs),
+        ExprKind::BitOr(lhs, rhs) => fmt_binary(lhs, "|", rhs),
+        ExprKind::BitXor(lhs, rhs) => fmt_binary(lhs, "^", rhs),
+        ExprKind::Shl(lhs, rhs) => fmt_binary(lhs, "<<", rhs),
+        ExprKind::Shr(lhs, rhs) => fmt_binary(lhs, ">>", rhs),
+        // Keep a space between repeated negations, so they read as `- -a` rather than `--a`.
+        ExprKind::Neg(inner) => match inner.kind {
+            ExprKind::Neg(_) => format!("- {}", fmt_expr(inner)),
+            _ => format!("-{}", fmt_expr(inner)),
+        },
+        ExprKind::Not(inner) => format!("not {}", fmt_expr(inner)),
+        ExprKind::BitNot(inner) => format!("~{}", fmt_expr(inner)),
+        ExprKind::Index(list, idx) => format!("{}[{}]", fmt_expr(list), fmt_expr(idx)),
+        ExprKind::Slice(list, from, to) => {
+            let bound = |b: &Option<Box<Expr>>| b.as_deref().map(fmt_expr).unwrap_or_default();
+            format!("{}[{}:{}]", fmt_expr(list), bound(from), bound(to))
+        }
+        ExprKind::Block(binds, _) => {
+            let binds: Vec<String> = binds.iter().map(fmt_bind).collect();
+            format!("{{\n{}\n}}", indent(&binds.join(";\n")))
+        }
+        ExprKind::Group(cmd) => format!("({})", fmt_cmd(cmd)),
+        ExprKind::List(items) => {
+            let items: Vec<String> = items.iter().map(fmt_expr).collect();
+            format!("[{}]", items.join(", "))
+        }
+        ExprKind::Lambda(def_bind) => format!(
+            "fn{} ->{}",
+            fmt_params(def_bind),
+            fmt_body(&fmt_cmd(&def_bind.body))
+        ),
+        ExprKind::Call(callee, args) => {
+            let args: Vec<String> = args.iter().map(fmt_expr).collect();
+            format!("{}({})", fmt_expr(callee), args.join(", "))
+        }
+        ExprKind::Ident(name) => name.clone(),
+        ExprKind::Bool(b) => format!("{}", b),
+        // Display never uses exponents, so this always matches the `num` rule.
+        ExprKind::Num(n) => format!("{}", n),
+        ExprKind::Str(s) => format!("\"{}\"", s),
+        ExprKind::Unit => "()".to_owned(),
+    }
+}
+
+fn fmt_binary(lhs: &Expr, op: &str, rhs: &Expr) -> String {
+    format!("{} {} {}", fmt_expr(lhs), op, fmt_expr(rhs))
+}
+
+fn fmt_params(def_bind: &DefBind) -> String {
+    let mut out = String::new();
+    for (param, default) in def_bind.params.iter().zip(&def_bind.defaults) {
+        match default {
+            Some(expr) => out.push_str(&format!(" ({} = {})", param, fmt_expr(expr))),
+            None => out.push_str(&format!(" {}", param)),
+        }
+    }
+    if let Some(rest) = &def_bind.rest {
+        out.push_str(&format!(" ...{}", rest));
+    }
+    out
+}
+
+// Short bodies stay on the same line, longer ones go on the next line and get indented.
+fn fmt_body(body: &str) -> String {
+    if body.contains('\n') {
+        format!("\n{}", indent(body))
+    } else {
+        format!(" {}", body)
+    }
+}
+
+fn indent(src: &str) -> String {
+    let lines: Vec<String> = src.lines().map(|line| format!("{}{}", INDENT, line)).collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_ast;
+
+    // Layout changes, so line numbers of blocks are dropped before comparing,
+    // e.g. `Block([..], [1, 2])` becomes `Block([..])`.
+    fn without_lines(ast: &Ast) -> String {
+        let dbg = format!("{:?}", ast.items);
+        let mut out = String::new();
+        let mut rest = dbg.as_str();
+        while let Some(idx) = rest.find("], [") {
+            out.push_str(&rest[..idx + 1]);
+            let tail = &rest[idx + 4..];
+            let end = tail.find(|c: char| !c.is_ascii_digit() && c != ',' && c != ' ');
+            match end {
+                Some(end) if tail[end..].starts_with("])") => rest = &tail[end + 1..],
+                _ => rest = &rest[idx + 1..],
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn round_trip(src: &str) {
+        let ast = parse_ast(src).unwrap();
+        let out = fmt_ast(&ast);
+        let again = parse_ast(&out).unwrap_or_else(|e| panic!("{}\n{}", out, e));
+        assert_eq!(without_lines(&ast), without_lines(&again), "\n{}", out);
+    }
+
+    #[test]
+    fn pretty() {
+        let src = "def foo a b = if a < b then a else b end";
+        let exp = "def foo a b =\n  if a < b then\n    a\n  else\n    b\n  end\n";
+        assert_eq!(exp, fmt_ast(&parse_ast(src).unwrap()));
+
+        let src = "let x = { let y = 1; y + 2 }";
+        let exp = "let x = {\n  let y = 1;\n  y + 2\n}\n";
+        assert_eq!(exp, fmt_ast(&parse_ast(src).unwrap()));
+    }
+
+    #[test]
+    fn round_trip_literals() {
+        round_trip("()\ntrue\nfalse\n1\n2.5\n\"hi there\"\nx\n[]\n[1, [2, \"a\"]]");
+    }
+
+    #[test]
+    fn round_trip_operators() {
+        round_trip("1 + 2 * 3 - 4 / 5 // 6");
+        round_trip("(1 + 2) * (3 - (4))");
+        round_trip("a < b");
+        round_trip("a > b");
+        round_trip("a <= b");
+        round_trip("a >= b");
+        round_trip("a == not b");
+        round_trip("- -a");
+        round_trip("-(1 + 2)");
+        round_trip("a ? b : c ? d : e");
+        round_trip("1 | 2 & ~3 ^ 4 << 5 >> 6 < 0xFF");
+        round_trip("0..n + 1 == 1..2");
+        round_trip("-xs[i + 1][0] + [1, 2][1:][:-1][:]");
+    }
+
+    #[test]
+    fn round_trip_commands() {
+        round_trip("print \"hi\"");
+        round_trip("create gen");
+        round_trip("resume co 1 (2) [3]");
+        round_trip("f(g(), [1], (2))");
+        round_trip("yield x + 1");
+        round_trip("while i < 10 do { print i; i += 1 } end");
+        round_trip("for x in [1, 2] do (for y in 0..x do (print y) end) end");
+        round_trip("if a then { 1 } else (if b then 2 else 3 end) end");
+    }
+
+    #[test]
+    fn round_trip_bindings() {
+        round_trip("let a = 1\nconst b = 2\nlet c, d = [a, b]\na += 3");
+        round_trip("def nat = { let n = -1; while true do { let n = n + 1; yield n } end }");
+        round_trip("def f a (b = 1) ...rest = { def g = { let x = a; x }; g }");
+        round_trip("let f = fn a (b = [1]) ...c -> fn -> { a }");
+        round_trip("let a = { let b = { let c = 1; c }; { b } }");
+    }
+}
@@ -3,10 +3,18 @@ extern crate pest;
 extern crate pest_derive;
 
 pub mod ast;
+pub mod builtins;
+#[doc(hidden)]
+pub mod bytecode;
+#[doc(hidden)]
 pub mod cgen;
 pub mod code;
+pub mod error;
 pub mod parse;
+pub mod prelude;
 pub mod value;
 pub mod vm;
 
 pub mod debug;
+#[doc(hidden)]
+pub mod fmt;
@@ -2,10 +2,17 @@ use std::env;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process;
 
+use coro::bytecode;
+use coro::debug;
+use coro::value::FnDef;
+use coro::value::Value;
 use coro::vm::CoRes;
 use coro::vm::CoVM;
+use coro::vm::CoVMBuilder;
 
 const STATUS_OK: i32 = 0;
 const STATUS_COMPILE_ERR: i32 = 1;
@@ -13,41 +20,187 @@ const STATUS_RUNTIME_ERR: i32 = 2;
 const STATUS_GENERAL_ERR: i32 = 3;
 const STATUS_USAGE_ERR: i32 = 4;
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 2 {
-        eprintln!("usage: coro [script]");
-        process::exit(STATUS_USAGE_ERR);
+    let mut check = false;
+    let mut disassemble = false;
+    let mut output = None;
+    let mut bytecode = false;
+    let mut script = None;
+    let mut script_args = Vec::new();
+    let mut includes = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--disassemble" | "-d" => disassemble = true,
+            "--include" | "-I" => match args.next() {
+                Some(dir) => includes.push(PathBuf::from(dir)),
+                None => usage(),
+            },
+            "--output" | "-o" => match args.next() {
+                Some(path) => output = Some(path),
+                None => usage(),
+            },
+            "--run" => match args.next() {
+                Some(path) if script.is_none() => {
+                    script = Some(path);
+                    bytecode = true;
+                }
+                _ => usage(),
+            },
+            "--version" | "-V" => {
+                println!("coro {}", VERSION);
+                process::exit(STATUS_OK);
+            }
+            // Everything after this is for the script, not us.
+            "--" => {
+                script_args = args.by_ref().collect();
+                break;
+            }
+            _ if !arg.starts_with('-') && script.is_none() => script = Some(arg),
+            _ => usage(),
+        }
     }
 
-    let status = if args.len() > 1 {
-        run_file(&args[1])
-    } else {
-        run_repl()
+    // Scripts run from the command line are trusted, same as any other program.
+    let builder = CoVM::with_file_io().with_json().with_args(script_args);
+    let builder = includes.into_iter().fold(builder, CoVMBuilder::with_include_path);
+
+    let bytecode = bytecode
+        || script.as_ref().is_some_and(|path| {
+            Path::new(path).extension().is_some_and(|ext| ext == bytecode::EXT)
+        });
+    let status = match script {
+        Some(_) if bytecode && (check || disassemble || output.is_some()) => usage(),
+        Some(path) if disassemble => disassemble_file(&path),
+        Some(path) if check => check_file(&path),
+        Some(path) => match output {
+            Some(out) => compile_file(&path, &out),
+            None if bytecode => run_bytecode(&builder, &path),
+            None => run_file(&builder, &path),
+        },
+        None if check || disassemble || output.is_some() => usage(),
+        None => run_repl(&builder),
     };
 
     process::exit(status);
 }
 
-fn run_file(path: &str) -> i32 {
-    let src = match fs::read_to_string(path) {
+fn usage() -> ! {
+    eprintln!(
+        "usage: coro [--version] [--check] [--disassemble] [--include <dir>]... \
+        [--output <file>.{ext}] [script | --run <file>.{ext}] [-- <args>...]",
+        ext = bytecode::EXT
+    );
+    process::exit(STATUS_USAGE_ERR);
+}
+
+fn read_file(path: &str) -> Result<String, i32> {
+    fs::read_to_string(path).map_err(|e| {
+        eprintln!("[coro] error while reading file: {}", path);
+        eprintln!("[coro] {}", e);
+        STATUS_GENERAL_ERR
+    })
+}
+
+fn check_file(path: &str) -> i32 {
+    let src = match read_file(path) {
+        Ok(s) => s,
+        Err(status) => return status,
+    };
+    match CoVM::compile(&src) {
+        Ok(_) => STATUS_OK,
+        Err(e) => {
+            eprintln!("[coro] compile error:\n{}", e);
+            STATUS_COMPILE_ERR
+        }
+    }
+}
+
+fn disassemble_file(path: &str) -> i32 {
+    let src = match read_file(path) {
+        Ok(s) => s,
+        Err(status) => return status,
+    };
+    match CoVM::compile(&src) {
+        Ok(def) => {
+            print_listing(&def);
+            STATUS_OK
+        }
+        Err(e) => {
+            eprintln!("[coro] compile error:\n{}", e);
+            STATUS_COMPILE_ERR
+        }
+    }
+}
+
+fn compile_file(path: &str, out: &str) -> i32 {
+    let src = match read_file(path) {
         Ok(s) => s,
+        Err(status) => return status,
+    };
+    match CoVM::compile_to_file(&src, Path::new(out)) {
+        Ok(()) => STATUS_OK,
+        Err(e) => {
+            eprintln!("[coro] compile error:\n{}", e);
+            STATUS_COMPILE_ERR
+        }
+    }
+}
+
+fn print_listing(def: &FnDef) {
+    // Nested functions are listed before their parent, same as the `instr` feature.
+    for val in def.code.constants() {
+        if let Value::Fn(inner) = val {
+            print_listing(inner);
+        }
+    }
+    print!("{}", debug::disassemble(&def.code, def.name()));
+}
+
+fn run_file(builder: &CoVMBuilder, path: &str) -> i32 {
+    let src = match read_file(path) {
+        Ok(s) => s,
+        Err(status) => return status,
+    };
+    let builder = script_builder(builder, path);
+    exit_status(builder.eval(&src))
+}
+
+fn run_bytecode(builder: &CoVMBuilder, path: &str) -> i32 {
+    let def = match CoVM::load_from_file(Path::new(path)) {
+        Ok(def) => def,
         Err(e) => {
-            eprintln!("[coro] error while reading file: {}", path);
-            eprintln!("[coro] {}", e);
+            eprintln!("[coro] error while loading bytecode:\n{}", e);
             return STATUS_GENERAL_ERR;
         }
     };
-    match CoVM::eval(&src) {
+    let builder = script_builder(builder, path);
+    exit_status(builder.eval_fn(def))
+}
+
+// Modules next to the script can be found without `--include`.
+fn script_builder(builder: &CoVMBuilder, path: &str) -> CoVMBuilder {
+    match Path::new(path).parent() {
+        Some(dir) => builder.clone().with_script_dir(dir.to_path_buf()),
+        None => builder.clone(),
+    }
+}
+
+fn exit_status(res: CoRes) -> i32 {
+    match res {
         CoRes::Ok => STATUS_OK,
         CoRes::CompileErr => STATUS_COMPILE_ERR,
         CoRes::RuntimeErr => STATUS_RUNTIME_ERR,
+        CoRes::Exit(code) => code,
     }
 }
 
-fn run_repl() -> i32 {
-    let mut main_co = CoVM::build("").unwrap();
-    println!("[coro-lang]");
+fn run_repl(builder: &CoVMBuilder) -> i32 {
+    let mut main_co = builder.build("").unwrap();
+    println!("[coro-lang {}]", VERSION);
 
     loop {
         let src = match repl_read() {
@@ -61,6 +214,14 @@ fn run_repl() -> i32 {
             continue;
         }
 
+        // Meta-command to only compile the input without running it.
+        if let Some(src) = src.strip_prefix(":check") {
+            if let Err(e) = CoVM::compile(src) {
+                eprintln!("[coro] compile error:\n{}", e);
+            }
+            continue;
+        }
+
         let def = match CoVM::compile(&src) {
             Ok(rc) => rc,
             Err(e) => {
@@ -5,253 +5,623 @@
 //! using Pest's PEG.
 //!
 //! The Pest-generated parser does most of the heavy-lifting, and provides us with data structures
-//! which we can traverse to build our own AST. Note that when using Pest, it is considered
-//! idiomatic general practice to use `unwrap()` and `unreachable!()`.
+//! which we can traverse to build our own AST. The grammar decides the shape of the trees, so the
+//! traversal expects certain pairs in certain places. Instead of panicking if they are not there,
+//! e.g. because the grammar changed without the parser, we report an error, since the REPL parses
+//! arbitrary input.
 
+use pest::error::Error;
+use pest::error::ErrorVariant;
+use pest::error::InputLocation;
+use pest::error::LineColLocation;
 use pest::iterators::Pair;
+use pest::iterators::Pairs;
 use pest::Parser;
 
 use crate::ast::*;
+use crate::error::CoError;
+
+/// The pretty-printer lives in its own module, but it is the inverse of `parse_ast`.
+pub use crate::fmt::fmt_ast;
 
 #[derive(Parser)]
 #[grammar = "coro.pest"]
 struct CoroParser;
 
 /// Main entry point to parsing. This produces an AST object, or returns a
-/// parsing error message.
-pub fn parse_ast(src: &str) -> Result<Ast, String> {
+/// compile error with the location of the problem.
+pub fn parse_ast(src: &str) -> Result<Ast, CoError> {
     let mut ast = Ast::new();
     let mut start = match CoroParser::parse(Rule::program, src) {
-        Err(e) => return Err(format!("{}", e)),
+        Err(e) => return Err(pest_error(e)),
         Ok(p) => p,
     };
 
-    let program = start.next().unwrap();
+    let program = next_pair(&mut start)?;
     let iter = program.into_inner();
     for pair in iter {
         match pair.as_rule() {
-            Rule::bind => ast.items.push(parse_bind(pair)?),
+            Rule::bind => {
+                ast.lines.push(line_of(&pair));
+                ast.items.push(parse_bind(pair)?);
+            }
             Rule::EOI => break,
-            _ => unreachable!(),
+            _ => return Err(unexpected(&pair)),
         }
     }
     Ok(ast)
 }
 
-fn parse_bind(pair: Pair<Rule>) -> Result<Bind, String> {
-    let inner = pair.into_inner().next().unwrap();
+/// Parse `src` as exactly one command, e.g. `resume co 1`.
+pub fn parse_cmd_ast(src: &str) -> Result<Cmd, CoError> {
+    let mut start = match CoroParser::parse(Rule::cmd_only, src) {
+        Err(e) => return Err(pest_error(e)),
+        Ok(p) => p,
+    };
+    let cmd = first_pair(next_pair(&mut start)?)?;
+    parse_cmd(cmd)
+}
+
+/// Parse `src` as exactly one expression, e.g. `1 + 2`.
+pub fn parse_expr_ast(src: &str) -> Result<Expr, CoError> {
+    let mut start = match CoroParser::parse(Rule::expr_only, src) {
+        Err(e) => return Err(pest_error(e)),
+        Ok(p) => p,
+    };
+    let expr = first_pair(next_pair(&mut start)?)?;
+    parse_expr(expr)
+}
+
+/// Whether `src` fails to parse only because it ends too early, e.g. an `if` without its `end` or
+/// a string without its closing quote, as opposed to having a syntax error. This lets the REPL
+/// tell when to keep reading more lines.
+pub fn is_incomplete(src: &str) -> bool {
+    let (code, open_string) = strip_literals(src);
+    let err = match CoroParser::parse(Rule::program, src) {
+        // Keywords also match as names, so e.g. `if x then` fits the grammar, and only fails once
+        // it is turned into an AST.
+        Ok(_) => return parse_ast(src).is_err() && has_open_block(&code),
+        Err(e) => e,
+    };
+    // Strings are atomic, so the error points to the start of an unterminated string rather than
+    // the end of input.
+    if open_string {
+        return true;
+    }
+    let pos = match err.location {
+        InputLocation::Pos(pos) => pos,
+        InputLocation::Span((start, _)) => start,
+    };
+    src[pos..]
+        .lines()
+        .map(str::trim)
+        .all(|line| line.is_empty() || line.starts_with('#'))
+}
+
+// The source without comments and the contents of strings, and whether a string is still open at
+// the end.
+fn strip_literals(src: &str) -> (String, bool) {
+    let mut code = String::new();
+    let mut in_str = false;
+    let mut in_comment = false;
+    for c in src.chars() {
+        match c {
+            '\n' if in_comment => {
+                in_comment = false;
+                code.push(c);
+            }
+            _ if in_comment => {}
+            '"' => {
+                in_str = !in_str;
+                code.push(' ');
+            }
+            _ if in_str => {}
+            '#' => in_comment = true,
+            _ => code.push(c),
+        }
+    }
+    (code, in_str)
+}
+
+// Whether there are more `if`s, `while`s and `for`s than `end`s.
+fn has_open_block(code: &str) -> bool {
+    let mut open = 0;
+    for word in code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')) {
+        match word {
+            "if" | "while" | "for" => open += 1,
+            "end" => open -= 1,
+            _ => {}
+        }
+    }
+    open > 0
+}
+
+fn pest_error(e: Error<Rule>) -> CoError {
+    let (line, col) = match e.line_col {
+        LineColLocation::Pos(pos) => pos,
+        LineColLocation::Span(start, _) => start,
+    };
+    let rules = |rules: &[Rule]| {
+        let names: Vec<String> = rules.iter().map(|r| format!("{:?}", r)).collect();
+        names.join(" or ")
+    };
+    let msg = match e.variant {
+        ErrorVariant::ParsingError { positives, negatives } => {
+            match (positives.is_empty(), negatives.is_empty()) {
+                (false, true) => format!("expected {}", rules(&positives)),
+                (true, false) => format!("unexpected {}", rules(&negatives)),
+                (false, false) => format!(
+                    "unexpected {}; expected {}",
+                    rules(&negatives),
+                    rules(&positives)
+                ),
+                (true, true) => String::from("unknown parsing error"),
+            }
+        }
+        ErrorVariant::CustomError { message } => message,
+    };
+    CoError::compile(msg, line, col)
+}
+
+fn error(pair: &Pair<Rule>, msg: &str) -> CoError {
+    let (line, col) = pair.as_span().start_pos().line_col();
+    CoError::compile(msg, line, col)
+}
+
+fn line_of(pair: &Pair<Rule>) -> usize {
+    pair.as_span().start_pos().line_col().0
+}
+
+fn span_of(pair: &Pair<Rule>) -> Span {
+    let span = pair.as_span();
+    Span::new(span.start(), span.end())
+}
+
+fn malformed() -> CoError {
+    CoError::compile("malformed syntax tree", 0, 0)
+}
+
+fn unexpected(pair: &Pair<Rule>) -> CoError {
+    error(pair, &format!("unexpected {:?} in syntax tree", pair.as_rule()))
+}
+
+fn next_pair<'i>(iter: &mut Pairs<'i, Rule>) -> Result<Pair<'i, Rule>, CoError> {
+    iter.next().ok_or_else(malformed)
+}
+
+fn first_pair(pair: Pair<Rule>) -> Result<Pair<Rule>, CoError> {
+    next_pair(&mut pair.into_inner())
+}
+
+fn parse_bind(pair: Pair<Rule>) -> Result<Bind, CoError> {
+    let inner = first_pair(pair)?;
     match inner.as_rule() {
         Rule::bind_def => Ok(Bind::Def(parse_def(inner)?)),
-        Rule::bind_let => Ok(Bind::Let(parse_let(inner)?)),
+        Rule::bind_let => parse_let(inner),
+        Rule::bind_const => Ok(Bind::Const(parse_const(inner)?)),
+        Rule::bind_assign => Ok(Bind::Assign(parse_assign(inner)?)),
         Rule::cmd => Ok(Bind::Cmd(parse_cmd(inner)?)),
-        _ => unreachable!(),
+        _ => Err(unexpected(&inner)),
     }
 }
 
-fn parse_def(pair: Pair<Rule>) -> Result<DefBind, String> {
+fn parse_def(pair: Pair<Rule>) -> Result<DefBind, CoError> {
+    let mut pairs: Vec<Pair<Rule>> = pair.into_inner().collect();
+    let name = String::from(pairs.first().ok_or_else(malformed)?.as_str());
+
+    let last = pairs.pop().ok_or_else(malformed)?;
+    let body = parse_cmd(last)?;
+    parse_params(name, pairs.into_iter().skip(1), body)
+}
+
+fn parse_lambda(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let span = span_of(&pair);
     let mut pairs: Vec<Pair<Rule>> = pair.into_inner().collect();
-    let name = String::from(pairs[0].as_str());
+    let last = pairs.pop().ok_or_else(malformed)?;
+    let body = parse_cmd(last)?;
+    let def_bind = parse_params(String::new(), pairs.into_iter(), body)?;
+    Ok(Expr::new(ExprKind::Lambda(Box::new(def_bind)), span))
+}
 
+fn parse_params<'a>(
+    name: String,
+    pairs: impl Iterator<Item = Pair<'a, Rule>>,
+    body: Cmd,
+) -> Result<DefBind, CoError> {
     let mut params = Vec::new();
-    let num_params = pairs.len() - 2;
-    for i in 1..(num_params + 1) {
-        params.push(String::from(pairs[i].as_str()));
+    let mut defaults = Vec::new();
+    let mut rest = None;
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::ident => {
+                if defaults.iter().any(Option::is_some) {
+                    return Err(error(&pair, "non-default parameter follows default parameter"));
+                }
+                params.push(String::from(pair.as_str()));
+                defaults.push(None);
+            }
+            Rule::param_default => {
+                let mut inner = pair.into_inner();
+                params.push(String::from(next_pair(&mut inner)?.as_str()));
+                defaults.push(Some(parse_expr(next_pair(&mut inner)?)?));
+            }
+            Rule::param_rest => {
+                let inner = first_pair(pair)?;
+                rest = Some(String::from(inner.as_str()));
+            }
+            _ => return Err(unexpected(&pair)),
+        }
     }
 
-    let last = pairs.pop().unwrap();
-    let body = parse_cmd(last)?;
+    let mut def_bind = DefBind::new(name, params, body);
+    def_bind.defaults = defaults;
+    def_bind.rest = rest;
+    Ok(def_bind)
+}
 
-    Ok(DefBind::new(name, params, body))
+fn parse_let(pair: Pair<Rule>) -> Result<Bind, CoError> {
+    let mut pairs: Vec<Pair<Rule>> = pair.into_inner().collect();
+    let last = pairs.pop().ok_or_else(malformed)?;
+    let init = parse_cmd(last)?;
+
+    let mut names: Vec<String> = pairs.iter().map(|p| String::from(p.as_str())).collect();
+    if names.len() == 1 {
+        let name = names.pop().unwrap();
+        Ok(Bind::Let(LetBind::new(name, init)))
+    } else {
+        Ok(Bind::Destruct(DestructBind::new(names, init)))
+    }
 }
 
-fn parse_let(pair: Pair<Rule>) -> Result<LetBind, String> {
+fn parse_const(pair: Pair<Rule>) -> Result<LetBind, CoError> {
     let mut iter = pair.into_inner();
-    let name = String::from(iter.next().unwrap().as_str());
-    let init = parse_cmd(iter.next().unwrap())?;
+    let name = String::from(next_pair(&mut iter)?.as_str());
+    let init = parse_cmd(next_pair(&mut iter)?)?;
     Ok(LetBind::new(name, init))
 }
 
-fn parse_cmd(pair: Pair<Rule>) -> Result<Cmd, String> {
-    let inner = pair.into_inner().next().unwrap();
-    match inner.as_rule() {
-        Rule::cmd_print => parse_print(inner),
-        Rule::cmd_create => parse_create(inner),
-        Rule::cmd_resume => parse_resume(inner),
-        Rule::cmd_yield => parse_yield(inner),
-        Rule::cmd_while => parse_while(inner),
-        Rule::cmd_if => parse_if(inner),
-        Rule::expr => Ok(Cmd::Expr(parse_expr(inner)?)),
-        _ => unreachable!(),
-    }
+// Desugar compound assignment into a binding that reads the old value.
+fn parse_assign(pair: Pair<Rule>) -> Result<LetBind, CoError> {
+    let span = span_of(&pair);
+    let mut iter = pair.into_inner();
+    let lhs = parse_ident(next_pair(&mut iter)?)?;
+    let op = next_pair(&mut iter)?;
+    let rhs = parse_cmd(next_pair(&mut iter)?)?;
+    let rhs_span = rhs.span;
+    let rhs = match rhs.kind {
+        CmdKind::Expr(expr) => expr,
+        kind => Expr::new(ExprKind::Group(Box::new(Cmd::new(kind, rhs_span))), rhs_span),
+    };
+
+    let name = match &lhs.kind {
+        ExprKind::Ident(name) => name.clone(),
+        _ => return Err(malformed()),
+    };
+    let expr = match op.as_str() {
+        "+=" => binary(ExprKind::Add, lhs, rhs),
+        "-=" => binary(ExprKind::Sub, lhs, rhs),
+        "*=" => binary(ExprKind::Mul, lhs, rhs),
+        "/=" => binary(ExprKind::Div, lhs, rhs),
+        _ => return Err(unexpected(&op)),
+    };
+    Ok(LetBind::new(name, Cmd::new(CmdKind::Expr(expr), span)))
+}
+
+fn parse_cmd(pair: Pair<Rule>) -> Result<Cmd, CoError> {
+    let span = span_of(&pair);
+    let inner = first_pair(pair)?;
+    let kind = match inner.as_rule() {
+        Rule::cmd_print => parse_print(inner)?,
+        Rule::cmd_create => parse_create(inner)?,
+        Rule::cmd_resume => parse_resume(inner)?,
+        Rule::cmd_yield => parse_yield(inner)?,
+        Rule::cmd_while => parse_while(inner)?,
+        Rule::cmd_for => parse_for(inner)?,
+        Rule::cmd_if => parse_if(inner)?,
+        Rule::expr => CmdKind::Expr(parse_expr(inner)?),
+        _ => return Err(unexpected(&inner)),
+    };
+    Ok(Cmd::new(kind, span))
 }
 
-fn parse_print(pair: Pair<Rule>) -> Result<Cmd, String> {
-    let inner = pair.into_inner().next().unwrap();
+fn parse_print(pair: Pair<Rule>) -> Result<CmdKind, CoError> {
+    let inner = first_pair(pair)?;
     let expr = parse_expr(inner)?;
-    Ok(Cmd::Print(expr))
+    Ok(CmdKind::Print(expr))
 }
 
-fn parse_create(pair: Pair<Rule>) -> Result<Cmd, String> {
-    let inner = pair.into_inner().next().unwrap();
+fn parse_create(pair: Pair<Rule>) -> Result<CmdKind, CoError> {
+    let inner = first_pair(pair)?;
     let ident = String::from(inner.as_str());
-    Ok(Cmd::Create(ident))
+    Ok(CmdKind::Create(ident))
 }
 
-fn parse_resume(pair: Pair<Rule>) -> Result<Cmd, String> {
+fn parse_resume(pair: Pair<Rule>) -> Result<CmdKind, CoError> {
     let mut iter = pair.into_inner();
-    let co = parse_expr(iter.next().unwrap())?;
+    let co = parse_expr(next_pair(&mut iter)?)?;
 
     let mut args = Vec::new();
     for next in iter {
         args.push(parse_expr(next)?);
     }
 
-    Ok(Cmd::Resume(co, args))
+    Ok(CmdKind::Resume(co, args))
 }
 
-fn parse_yield(pair: Pair<Rule>) -> Result<Cmd, String> {
-    let inner = pair.into_inner().next().unwrap();
+fn parse_yield(pair: Pair<Rule>) -> Result<CmdKind, CoError> {
+    let inner = first_pair(pair)?;
     let expr = parse_expr(inner)?;
-    Ok(Cmd::Yield(expr))
+    Ok(CmdKind::Yield(expr))
 }
 
-fn parse_while(pair: Pair<Rule>) -> Result<Cmd, String> {
+fn parse_while(pair: Pair<Rule>) -> Result<CmdKind, CoError> {
     let mut iter = pair.into_inner();
-    let expr = parse_expr(iter.next().unwrap())?;
-    let body = parse_expr(iter.next().unwrap())?;
-    Ok(Cmd::While(expr, body))
+    let expr = parse_expr(next_pair(&mut iter)?)?;
+    let body = parse_expr(next_pair(&mut iter)?)?;
+    Ok(CmdKind::While(expr, body))
 }
 
-fn parse_if(pair: Pair<Rule>) -> Result<Cmd, String> {
+fn parse_for(pair: Pair<Rule>) -> Result<CmdKind, CoError> {
     let mut iter = pair.into_inner();
-    let cond = parse_expr(iter.next().unwrap())?;
-    let then = parse_expr(iter.next().unwrap())?;
-    let alt = parse_expr(iter.next().unwrap())?;
-    Ok(Cmd::If(cond, then, alt))
+    let name = String::from(next_pair(&mut iter)?.as_str());
+    let items = parse_expr(next_pair(&mut iter)?)?;
+    let body = parse_expr(next_pair(&mut iter)?)?;
+    Ok(CmdKind::For(name, items, body))
 }
 
-fn parse_expr(pair: Pair<Rule>) -> Result<Expr, String> {
-    let inner = pair.into_inner().next().unwrap();
-    parse_relation(inner)
+fn parse_if(pair: Pair<Rule>) -> Result<CmdKind, CoError> {
+    let mut iter = pair.into_inner();
+    let cond = parse_expr(next_pair(&mut iter)?)?;
+    let then = parse_expr(next_pair(&mut iter)?)?;
+    let alt = parse_expr(next_pair(&mut iter)?)?;
+    Ok(CmdKind::If(cond, then, alt))
 }
 
-fn parse_relation(pair: Pair<Rule>) -> Result<Expr, String> {
+fn parse_expr(pair: Pair<Rule>) -> Result<Expr, CoError> {
     let mut iter = pair.into_inner();
-    let mut expr = parse_term(iter.next().unwrap())?;
+    let expr = parse_relation(next_pair(&mut iter)?)?;
+    match iter.next() {
+        Some(next) => parse_cond(expr, next),
+        None => Ok(expr),
+    }
+}
+
+fn parse_cond(cond: Expr, pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let mut iter = pair.into_inner();
+    let then = parse_expr(next_pair(&mut iter)?)?;
+    let alt = parse_expr(next_pair(&mut iter)?)?;
+    let span = cond.span.to(alt.span);
+    Ok(Expr::new(ExprKind::Cond(Box::new(cond), Box::new(then), Box::new(alt)), span))
+}
+
+fn parse_relation(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let mut iter = pair.into_inner();
+    let mut expr = parse_range(next_pair(&mut iter)?)?;
     if let Some(next) = iter.next() {
         let mut rhs_iter = next.into_inner();
-        let op = rhs_iter.next().unwrap();
-        let rhs = parse_term(rhs_iter.next().unwrap())?;
+        let op = next_pair(&mut rhs_iter)?;
+        let rhs = parse_range(next_pair(&mut rhs_iter)?)?;
+        match op.as_str() {
+            "==" => expr = binary(ExprKind::Eq, expr, rhs),
+            "<" => expr = binary(ExprKind::Lt, expr, rhs),
+            ">" => expr = binary(ExprKind::Gt, expr, rhs),
+            "<=" => expr = binary(ExprKind::Le, expr, rhs),
+            ">=" => expr = binary(ExprKind::Ge, expr, rhs),
+            _ => return Err(unexpected(&op)),
+        }
+    }
+    Ok(expr)
+}
+
+fn parse_range(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let mut iter = pair.into_inner();
+    let start = parse_bitwise(next_pair(&mut iter)?)?;
+    match iter.next() {
+        Some(next) => Ok(binary(ExprKind::Range, start, parse_bitwise(next)?)),
+        None => Ok(start),
+    }
+}
+
+fn parse_bitwise(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let mut iter = pair.into_inner();
+    let mut expr = parse_shift(next_pair(&mut iter)?)?;
+    for next in iter {
+        let mut rhs_iter = next.into_inner();
+        let op = next_pair(&mut rhs_iter)?;
+        let rhs = parse_shift(next_pair(&mut rhs_iter)?)?;
+        match op.as_str() {
+            "&" => expr = binary(ExprKind::BitAnd, expr, rhs),
+            "|" => expr = binary(ExprKind::BitOr, expr, rhs),
+            "^" => expr = binary(ExprKind::BitXor, expr, rhs),
+            _ => return Err(unexpected(&op)),
+        }
+    }
+    Ok(expr)
+}
+
+fn parse_shift(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let mut iter = pair.into_inner();
+    let mut expr = parse_term(next_pair(&mut iter)?)?;
+    for next in iter {
+        let mut rhs_iter = next.into_inner();
+        let op = next_pair(&mut rhs_iter)?;
+        let rhs = parse_term(next_pair(&mut rhs_iter)?)?;
         match op.as_str() {
-            "==" => expr = Expr::Eq(Box::new(expr), Box::new(rhs)),
-            "<" => expr = Expr::Lt(Box::new(expr), Box::new(rhs)),
-            _ => unreachable!(),
+            "<<" => expr = binary(ExprKind::Shl, expr, rhs),
+            ">>" => expr = binary(ExprKind::Shr, expr, rhs),
+            _ => return Err(unexpected(&op)),
         }
     }
     Ok(expr)
 }
 
-fn parse_term(pair: Pair<Rule>) -> Result<Expr, String> {
+fn parse_term(pair: Pair<Rule>) -> Result<Expr, CoError> {
     let mut iter = pair.into_inner();
-    let mut expr = parse_factor(iter.next().unwrap())?;
+    let mut expr = parse_factor(next_pair(&mut iter)?)?;
     for next in iter {
         let mut rhs_iter = next.into_inner();
-        let op = rhs_iter.next().unwrap();
-        let rhs = parse_factor(rhs_iter.next().unwrap())?;
+        let op = next_pair(&mut rhs_iter)?;
+        let rhs = parse_factor(next_pair(&mut rhs_iter)?)?;
         match op.as_str() {
-            "+" => expr = Expr::Add(Box::new(expr), Box::new(rhs)),
-            "-" => expr = Expr::Sub(Box::new(expr), Box::new(rhs)),
-            _ => unreachable!(),
+            "+" => expr = binary(ExprKind::Add, expr, rhs),
+            "-" => expr = binary(ExprKind::Sub, expr, rhs),
+            _ => return Err(unexpected(&op)),
         }
     }
     Ok(expr)
 }
 
-fn parse_factor(pair: Pair<Rule>) -> Result<Expr, String> {
+fn parse_factor(pair: Pair<Rule>) -> Result<Expr, CoError> {
     let mut iter = pair.into_inner();
-    let mut expr = parse_unary(iter.next().unwrap())?;
+    let mut expr = parse_unary(next_pair(&mut iter)?)?;
     for next in iter {
         let mut rhs_iter = next.into_inner();
-        let op = rhs_iter.next().unwrap();
-        let rhs = parse_unary(rhs_iter.next().unwrap())?;
+        let op = next_pair(&mut rhs_iter)?;
+        let rhs = parse_unary(next_pair(&mut rhs_iter)?)?;
         match op.as_str() {
-            "*" => expr = Expr::Mul(Box::new(expr), Box::new(rhs)),
-            "/" => expr = Expr::Div(Box::new(expr), Box::new(rhs)),
-            _ => unreachable!(),
+            "*" => expr = binary(ExprKind::Mul, expr, rhs),
+            "/" => expr = binary(ExprKind::Div, expr, rhs),
+            "//" => expr = binary(ExprKind::IDiv, expr, rhs),
+            _ => return Err(unexpected(&op)),
         }
     }
     Ok(expr)
 }
 
-fn parse_unary(pair: Pair<Rule>) -> Result<Expr, String> {
+fn parse_unary(pair: Pair<Rule>) -> Result<Expr, CoError> {
     let mut iter = pair.into_inner();
-    let lhs = iter.next().unwrap();
-    if lhs.as_rule() == Rule::atom {
-        return parse_atom(lhs);
+    let lhs = next_pair(&mut iter)?;
+    if lhs.as_rule() == Rule::expr_sub {
+        return parse_subscript(lhs);
     }
 
     let op = lhs;
-    let rhs = iter.next().unwrap();
+    let rhs = next_pair(&mut iter)?;
     let expr = parse_unary(rhs)?;
-    match op.as_str() {
-        "not" => Ok(Expr::Not(Box::new(expr))),
-        "-" => Ok(Expr::Neg(Box::new(expr))),
-        _ => unreachable!(),
-    }
+    let span = span_of(&op).to(expr.span);
+    let kind = match op.as_str() {
+        "not" => ExprKind::Not(Box::new(expr)),
+        "-" => ExprKind::Neg(Box::new(expr)),
+        "~" => ExprKind::BitNot(Box::new(expr)),
+        _ => return Err(unexpected(&op)),
+    };
+    Ok(Expr::new(kind, span))
 }
 
-fn parse_atom(pair: Pair<Rule>) -> Result<Expr, String> {
-    let inner = pair.into_inner().next().unwrap();
-    match inner.as_rule() {
-        Rule::block => parse_block(inner),
-        Rule::group => parse_group(inner),
-        Rule::ident => parse_ident(inner),
-        Rule::bool => Ok(Expr::Bool(inner.as_str() == "true")),
-        Rule::num => {
-            let res = inner.as_str().parse::<f64>();
-            Ok(Expr::Num(res.unwrap()))
+fn parse_subscript(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let mut iter = pair.into_inner();
+    let mut expr = parse_atom(first_pair(next_pair(&mut iter)?)?)?;
+    for next in iter {
+        // The subscript includes its closing bracket.
+        let span = expr.span.to(span_of(&next));
+        let inner = first_pair(next)?;
+        if inner.as_rule() != Rule::slice {
+            let idx = parse_expr(inner)?;
+            expr = Expr::new(ExprKind::Index(Box::new(expr), Box::new(idx)), span);
+            continue;
         }
-        Rule::str => {
-            let res = inner
-                .as_str()
-                .strip_prefix('"')
-                .unwrap()
-                .strip_suffix('"')
-                .unwrap();
-            Ok(Expr::Str(String::from(res)))
+        let (mut from, mut to) = (None, None);
+        for bound in inner.into_inner() {
+            let rule = bound.as_rule();
+            let bound = Some(Box::new(parse_expr(first_pair(bound)?)?));
+            match rule {
+                Rule::slice_from => from = bound,
+                _ => to = bound,
+            }
         }
-        Rule::unit => Ok(Expr::Unit),
-        _ => unreachable!(),
+        expr = Expr::new(ExprKind::Slice(Box::new(expr), from, to), span);
     }
+    Ok(expr)
 }
 
-fn parse_block(pair: Pair<Rule>) -> Result<Expr, String> {
+fn parse_atom(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let inner = first_pair(pair)?;
+    let span = span_of(&inner);
+    let kind = match inner.as_rule() {
+        Rule::block => return parse_block(inner),
+        Rule::group => return parse_group(inner),
+        Rule::list => return parse_list(inner),
+        Rule::lambda => return parse_lambda(inner),
+        Rule::call => return parse_call(inner),
+        Rule::ident => return parse_ident(inner),
+        Rule::bool => ExprKind::Bool(inner.as_str() == "true"),
+        Rule::num => match inner.as_str().strip_prefix("0x") {
+            Some(hex) => match i64::from_str_radix(hex, 16) {
+                Ok(n) => ExprKind::Num(n as f64),
+                Err(_) => return Err(error(&inner, "hex literal is too large")),
+            },
+            None => match inner.as_str().parse::<f64>() {
+                Ok(n) => ExprKind::Num(n),
+                Err(_) => return Err(unexpected(&inner)),
+            },
+        },
+        Rule::str => match inner.as_str().strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(s) => ExprKind::Str(String::from(s)),
+            None => return Err(unexpected(&inner)),
+        },
+        Rule::unit => ExprKind::Unit,
+        _ => return Err(unexpected(&inner)),
+    };
+    Ok(Expr::new(kind, span))
+}
+
+fn parse_block(pair: Pair<Rule>) -> Result<Expr, CoError> {
     let mut binds = Vec::new();
-    for next in pair.into_inner() {
+    let mut lines = Vec::new();
+    for next in pair.clone().into_inner() {
+        lines.push(line_of(&next));
         binds.push(parse_bind(next)?);
     }
-    assert!(!binds.is_empty(), "block need to be non-empty");
-    Ok(Expr::Block(binds))
+    if binds.is_empty() {
+        return Err(error(&pair, "block must be non-empty"));
+    }
+    Ok(Expr::new(ExprKind::Block(binds, lines), span_of(&pair)))
 }
 
-fn parse_group(pair: Pair<Rule>) -> Result<Expr, String> {
-    let inner = pair.into_inner().next().unwrap();
+fn parse_group(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let span = span_of(&pair);
+    let inner = first_pair(pair)?;
     let cmd = parse_cmd(inner)?;
-    Ok(Expr::Group(Box::new(cmd)))
+    Ok(Expr::new(ExprKind::Group(Box::new(cmd)), span))
+}
+
+fn parse_list(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let span = span_of(&pair);
+    let mut items = Vec::new();
+    for next in pair.into_inner() {
+        items.push(parse_expr(next)?);
+    }
+    Ok(Expr::new(ExprKind::List(items), span))
+}
+
+fn parse_call(pair: Pair<Rule>) -> Result<Expr, CoError> {
+    let span = span_of(&pair);
+    let mut iter = pair.into_inner();
+    let callee = parse_ident(next_pair(&mut iter)?)?;
+    let mut args = Vec::new();
+    for next in next_pair(&mut iter)?.into_inner() {
+        args.push(parse_expr(next)?);
+    }
+    Ok(Expr::new(ExprKind::Call(Box::new(callee), args), span))
 }
 
 // FIXME: Currently, Pest parser seems to be unable to properly deal with
 // keywords. Have not figured out better way to handle all cases yet.
-fn parse_ident(pair: Pair<Rule>) -> Result<Expr, String> {
+fn parse_ident(pair: Pair<Rule>) -> Result<Expr, CoError> {
     match pair.as_str() {
-        "def" | "let" => Err(String::from("expected proper binding")),
-        "print" | "create" | "resume" | "yield" | "while" | "do" | "if" | "then" | "else"
-        | "end" => Err(String::from("expected proper command")),
-        "true" | "false" => Err(String::from("expected proper expression")),
-        name => Ok(Expr::Ident(String::from(name))),
+        "def" | "let" | "const" => Err(error(&pair, "expected proper binding")),
+        "print" | "create" | "resume" | "yield" | "while" | "for" | "in" | "do" | "if"
+        | "then" | "else" | "end" => Err(error(&pair, "expected proper command")),
+        "true" | "false" | "fn" => Err(error(&pair, "expected proper expression")),
+        name => Ok(Expr::new(ExprKind::Ident(String::from(name)), span_of(&pair))),
     }
 }
 
+fn binary(kind: fn(Box<Expr>, Box<Expr>) -> ExprKind, lhs: Expr, rhs: Expr) -> Expr {
+    let span = lhs.span.to(rhs.span);
+    Expr::new(kind(Box::new(lhs), Box::new(rhs)), span)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -259,8 +629,8 @@ mod tests {
     macro_rules! ast_eq {
         ($src:expr, $expected:expr) => {
             let ast = parse_ast($src).unwrap();
-            let dbg = format!("{:?}", ast);
-            let exp = format!("Ast {{ items: [{}] }}", $expected);
+            let dbg = format!("{:?}", ast.items);
+            let exp = format!("[{}]", $expected);
             assert_eq!(dbg, exp);
         };
     }
@@ -331,6 +701,13 @@ mod tests {
         ast_eq!(src, exp);
     }
 
+    #[test]
+    fn binary_floor_div() {
+        let src = "7 // 2 / 3";
+        let exp = "Cmd(Expr(Div(IDiv(Num(7.0), Num(2.0)), Num(3.0))))";
+        ast_eq!(src, exp);
+    }
+
     #[test]
     fn binary_term() {
         let src = "1 + 2 - 3";
@@ -345,6 +722,43 @@ mod tests {
         ast_eq!(src, exp);
     }
 
+    #[test]
+    fn binary_comparison() {
+        ast_eq!("1 > 2", "Cmd(Expr(Gt(Num(1.0), Num(2.0))))");
+        ast_eq!("1 <= 2", "Cmd(Expr(Le(Num(1.0), Num(2.0))))");
+        ast_eq!("1 >= 2 >> 1", "Cmd(Expr(Ge(Num(1.0), Shr(Num(2.0), Num(1.0)))))");
+    }
+
+    #[test]
+    fn binary_range() {
+        ast_eq!("1..5", "Cmd(Expr(Range(Num(1.0), Num(5.0))))");
+        ast_eq!("1.5..n", "Cmd(Expr(Range(Num(1.5), Ident(\"n\"))))");
+        let exp = "Cmd(Expr(Lt(Range(Num(0.0), Add(Num(1.0), Num(2.0))), Num(3.0))))";
+        ast_eq!("0..1 + 2 < 3", exp);
+        assert!(parse_ast("1..2..3").is_err());
+    }
+
+    #[test]
+    fn binary_bitwise() {
+        let src = "1 | 2 & 3 ^ 4";
+        let exp = "Cmd(Expr(BitXor(BitAnd(BitOr(Num(1.0), Num(2.0)), Num(3.0)), Num(4.0))))";
+        ast_eq!(src, exp);
+
+        // Shifts bind tighter than `&`, but looser than arithmetic.
+        let src = "1 << 2 + 3 & ~4 < 5 >> 1";
+        let exp = "Cmd(Expr(Lt(\
+            BitAnd(Shl(Num(1.0), Add(Num(2.0), Num(3.0))), BitNot(Num(4.0))), \
+            Shr(Num(5.0), Num(1.0)))))";
+        ast_eq!(src, exp);
+    }
+
+    #[test]
+    fn hex_literal() {
+        ast_eq!("0xFF", "Cmd(Expr(Num(255.0)))");
+        ast_eq!("0x0f", "Cmd(Expr(Num(15.0)))");
+        assert!(parse_ast("0x10000000000000000").is_err());
+    }
+
     #[test]
     fn precedence() {
         let src = "1 + 2 / 3 - 4 < -5 * 6";
@@ -354,6 +768,51 @@ mod tests {
         ast_eq!(src, exp);
     }
 
+    #[test]
+    fn spans() {
+        let expr = parse_expr_ast("1 + 2").unwrap();
+        assert_eq!(Span::new(0, 5), expr.span);
+        match &expr.kind {
+            ExprKind::Add(lhs, rhs) => {
+                assert_eq!(Span::new(0, 1), lhs.span);
+                assert_eq!(Span::new(4, 5), rhs.span);
+            }
+            kind => panic!("expected an add, found {:?}", kind),
+        }
+
+        let expr = parse_expr_ast("-x * (2 + 3)").unwrap();
+        match &expr.kind {
+            ExprKind::Mul(lhs, rhs) => {
+                assert_eq!(Span::new(0, 2), lhs.span);
+                assert_eq!(Span::new(5, 12), rhs.span);
+            }
+            kind => panic!("expected a mul, found {:?}", kind),
+        }
+
+        let cmd = parse_cmd_ast("print xs[1]").unwrap();
+        assert_eq!(Span::new(0, 11), cmd.span);
+        match &cmd.kind {
+            CmdKind::Print(expr) => assert_eq!(Span::new(6, 11), expr.span),
+            kind => panic!("expected a print, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn conditional() {
+        let src = "1 < 2 ? 3 : 4";
+        let exp = "Cmd(Expr(Cond(Lt(Num(1.0), Num(2.0)), Num(3.0), Num(4.0))))";
+        ast_eq!(src, exp);
+    }
+
+    #[test]
+    fn conditional_nested() {
+        let src = "a ? b ? 1 : 2 : c ? 3 : 4";
+        let exp = "Cmd(Expr(Cond(Ident(\"a\"), \
+            Cond(Ident(\"b\"), Num(1.0), Num(2.0)), \
+            Cond(Ident(\"c\"), Num(3.0), Num(4.0)))))";
+        ast_eq!(src, exp);
+    }
+
     #[test]
     fn command_if() {
         let src = "if true then 1 else 2 end";
@@ -368,6 +827,13 @@ mod tests {
         ast_eq!(src, exp);
     }
 
+    #[test]
+    fn command_for() {
+        let src = "for x in 1..3 do x end";
+        let exp = "Cmd(For(\"x\", Range(Num(1.0), Num(3.0)), Ident(\"x\")))";
+        ast_eq!(src, exp);
+    }
+
     #[test]
     fn command_yield() {
         ast_eq!("yield 1", "Cmd(Yield(Num(1.0)))");
@@ -391,6 +857,22 @@ mod tests {
         ast_eq!("print 1", "Cmd(Print(Num(1.0)))");
     }
 
+    #[test]
+    fn list() {
+        let src = "[1, [], [2,]]";
+        let exp = "Cmd(Expr(List([Num(1.0), List([]), List([Num(2.0)])])))";
+        ast_eq!(src, exp);
+    }
+
+    #[test]
+    fn destruct_binding() {
+        let src = "let a, b = pair";
+        let exp = "Destruct(DestructBind { \
+            names: [\"a\", \"b\"], \
+            init: Expr(Ident(\"pair\")) })";
+        ast_eq!(src, exp);
+    }
+
     #[test]
     fn let_binding() {
         let src = "let a = true";
@@ -400,16 +882,148 @@ mod tests {
         ast_eq!(src, exp);
     }
 
+    #[test]
+    fn compound_assign() {
+        let src = "a += 1 * 2";
+        let exp = "Assign(LetBind { \
+            name: \"a\", \
+            init: Expr(Add(Ident(\"a\"), Mul(Num(1.0), Num(2.0)))) })";
+        ast_eq!(src, exp);
+    }
+
+    #[test]
+    fn compound_assign_cmd() {
+        let src = "a -= resume co";
+        let exp = "Assign(LetBind { \
+            name: \"a\", \
+            init: Expr(Sub(Ident(\"a\"), Group(Resume(Ident(\"co\"), [])))) })";
+        ast_eq!(src, exp);
+    }
+
+    #[test]
+    fn const_binding() {
+        let src = "const a = 1";
+        let exp = "Const(LetBind { \
+            name: \"a\", \
+            init: Expr(Num(1.0)) })";
+        ast_eq!(src, exp);
+    }
+
     #[test]
     fn define_binding() {
         let src = "def fn a b c = true";
         let exp = "Def(DefBind { \
             name: \"fn\", \
             params: [\"a\", \"b\", \"c\"], \
+            defaults: [None, None, None], \
+            rest: None, \
             body: Expr(Bool(true)) })";
         ast_eq!(src, exp);
     }
 
+    #[test]
+    fn define_defaults() {
+        let src = "def fn a (b = 1) (c = 2 + 3) = true";
+        let exp = "Def(DefBind { \
+            name: \"fn\", \
+            params: [\"a\", \"b\", \"c\"], \
+            defaults: [None, Some(Num(1.0)), Some(Add(Num(2.0), Num(3.0)))], \
+            rest: None, \
+            body: Expr(Bool(true)) })";
+        ast_eq!(src, exp);
+
+        let err = parse_ast("def fn (a = 1) b = true").unwrap_err();
+        assert_eq!(CoError::compile("non-default parameter follows default parameter", 1, 16), err);
+    }
+
+    #[test]
+    fn define_variadic() {
+        let src = "def fn a ...rest = true";
+        let exp = "Def(DefBind { \
+            name: \"fn\", \
+            params: [\"a\"], \
+            defaults: [None], \
+            rest: Some(\"rest\"), \
+            body: Expr(Bool(true)) })";
+        ast_eq!(src, exp);
+
+        assert!(parse_ast("def fn ...rest a = true").is_err());
+    }
+
+    #[test]
+    fn expr_only() {
+        let expr = parse_expr_ast("1 + 2 * x").unwrap();
+        assert_eq!("Add(Num(1.0), Mul(Num(2.0), Ident(\"x\")))", format!("{:?}", expr));
+        assert!(parse_expr_ast("let a = 1").is_err());
+        assert!(parse_expr_ast("1 2").is_err());
+        assert!(parse_expr_ast("").is_err());
+    }
+
+    #[test]
+    fn cmd_only() {
+        let cmd = parse_cmd_ast("resume co 1").unwrap();
+        assert_eq!("Resume(Ident(\"co\"), [Num(1.0)])", format!("{:?}", cmd));
+        let cmd = parse_cmd_ast("true").unwrap();
+        assert_eq!("Expr(Bool(true))", format!("{:?}", cmd));
+        assert!(parse_cmd_ast("print 1; print 2").is_err());
+    }
+
+    #[test]
+    fn lambda() {
+        let src = "fn a ...b -> a";
+        let exp = "Cmd(Expr(Lambda(DefBind { \
+            name: \"\", \
+            params: [\"a\"], \
+            defaults: [None], \
+            rest: Some(\"b\"), \
+            body: Expr(Ident(\"a\")) })))";
+        ast_eq!(src, exp);
+
+        let src = "let f = fn -> print 1";
+        let exp = "Let(LetBind { \
+            name: \"f\", \
+            init: Expr(Lambda(DefBind { \
+                name: \"\", \
+                params: [], \
+                defaults: [], \
+                rest: None, \
+                body: Print(Num(1.0)) })) })";
+        ast_eq!(src, exp);
+    }
+
+    #[test]
+    fn call() {
+        ast_eq!("f()", "Cmd(Expr(Call(Ident(\"f\"), [])))");
+        ast_eq!(
+            "f(1, g( x ),)",
+            "Cmd(Expr(Call(Ident(\"f\"), [Num(1.0), Call(Ident(\"g\"), [Ident(\"x\")])])))"
+        );
+        // A space before the parens makes it a resume argument instead.
+        ast_eq!(
+            "resume co (1)",
+            "Cmd(Resume(Ident(\"co\"), [Group(Expr(Num(1.0)))]))"
+        );
+        assert!(parse_ast("print(1, 2)").is_err());
+    }
+
+    #[test]
+    fn subscript() {
+        ast_eq!("xs[0]", "Cmd(Expr(Index(Ident(\"xs\"), Num(0.0))))");
+        let exp = "Cmd(Expr(Neg(Index(Index(Ident(\"xs\"), Ident(\"i\")), Num(0.0)))))";
+        ast_eq!("-xs[ i ][0]", exp);
+        let exp = "Cmd(Expr(Slice(Call(Ident(\"f\"), []), Some(Num(1.0)), Some(Num(2.0)))))";
+        ast_eq!("f()[1:2]", exp);
+        ast_eq!("[1][:-1]", "Cmd(Expr(Slice(List([Num(1.0)]), None, Some(Neg(Num(1.0))))))");
+        ast_eq!("xs[1 :]", "Cmd(Expr(Slice(Ident(\"xs\"), Some(Num(1.0)), None)))");
+        ast_eq!("xs[:]", "Cmd(Expr(Slice(Ident(\"xs\"), None, None)))");
+        let exp = "Cmd(Expr(Index(Ident(\"xs\"), Cond(Ident(\"c\"), Num(1.0), Num(2.0)))))";
+        ast_eq!("xs[c ? 1 : 2]", exp);
+        // A space before the brackets makes it a resume argument instead.
+        ast_eq!("resume co [1]", "Cmd(Resume(Ident(\"co\"), [List([Num(1.0)])]))");
+        assert!(parse_ast("f(xs[])").is_err());
+        assert!(parse_ast("f(xs[1:2:3])").is_err());
+    }
+
     #[test]
     fn group() {
         let src = "(1 + 2) * 3";
@@ -420,14 +1034,21 @@ mod tests {
     #[test]
     fn block() {
         let src = "{ 1; 2; }";
-        let exp = "Cmd(Expr(Block([Cmd(Expr(Num(1.0))), Cmd(Expr(Num(2.0)))])))";
+        let exp = "Cmd(Expr(Block([Cmd(Expr(Num(1.0))), Cmd(Expr(Num(2.0)))], [1, 1])))";
         ast_eq!(src, exp);
     }
 
+    #[test]
+    fn block_empty() {
+        let err = parse_ast("let x = 1\nlet y = {  }").unwrap_err();
+        assert_eq!(CoError::compile("block must be non-empty", 2, 9), err);
+        assert!(parse_ast("{;}").is_err());
+    }
+
     #[test]
     fn block_semi_optional() {
         let src = "{ 1 }";
-        let exp = "Cmd(Expr(Block([Cmd(Expr(Num(1.0)))])))";
+        let exp = "Cmd(Expr(Block([Cmd(Expr(Num(1.0)))], [1])))";
         ast_eq!(src, exp);
     }
 
@@ -445,6 +1066,64 @@ mod tests {
         parse_ast(src).unwrap();
     }
 
+    #[test]
+    fn visit_idents() {
+        struct Idents(Vec<String>);
+        impl AstVisitor for Idents {
+            fn visit_expr(&mut self, expr: &Expr) {
+                if let ExprKind::Ident(name) = &expr.kind {
+                    self.0.push(name.clone());
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        let src = "def f (a = b) = { let c = [d, e[g:]]; if h then fn -> i else j(k) end }\n\
+            print l";
+        let mut idents = Idents(Vec::new());
+        parse_ast(src).unwrap().visit(&mut idents);
+        let names = ["b", "d", "e", "g", "h", "i", "j", "k", "l"];
+        assert_eq!(names.to_vec(), idents.0);
+    }
+
+    #[test]
+    fn keywords_as_names() {
+        assert_eq!("expected proper command", parse_ast("1 + end").unwrap_err().message());
+        assert_eq!("expected proper expression", parse_ast("[fn]").unwrap_err().message());
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert!(is_incomplete("if true then"));
+        assert!(is_incomplete("\"unterminated"));
+        assert!(is_incomplete("while x do {\n  print x;\n  # still going\n"));
+        assert!(is_incomplete("let f = fn a ->"));
+        assert!(is_incomplete("[1, 2,"));
+        assert!(is_incomplete("let x = if y then # \"end\"\n 1"));
+        assert!(is_incomplete("print 1 # \"quoted\"\nprint \"a # b"));
+
+        assert!(!is_incomplete("1 +* 2"));
+        assert!(!is_incomplete("if true then 1 else 2 end"));
+        assert!(!is_incomplete("print \"a # b\""));
+        assert!(!is_incomplete("1 ) + ("));
+        assert!(!is_incomplete("1 + end"));
+    }
+
+    // Pairs that do not have the shape the parser expects used to panic.
+    #[test]
+    fn malformed_tree() {
+        let pair = CoroParser::parse(Rule::num, "1").unwrap().next().unwrap();
+        let err = parse_cmd(pair).unwrap_err();
+        assert_eq!("malformed syntax tree", err.message());
+
+        let pair = CoroParser::parse(Rule::atom, "[1]").unwrap().next().unwrap();
+        let err = parse_cmd(pair).unwrap_err();
+        assert_eq!(CoError::compile("unexpected list in syntax tree", 1, 1), err);
+
+        let pair = CoroParser::parse(Rule::expr_add, "1 + 2").unwrap().next().unwrap();
+        assert!(parse_factor(pair).is_err());
+    }
+
     #[test]
     #[should_panic]
     fn bad_input() {
@@ -0,0 +1,26 @@
+//! Re-exports the types that embedding Coro needs most often, so they can all be imported at once.
+//!
+//! ```
+//! use coro::prelude::*;
+//!
+//! let mut co = CoVM::build("def double a = a * 2\ndouble(21)").unwrap();
+//! assert_eq!(Value::Num(42.0), CoVM::run(&mut co).unwrap());
+//! assert_eq!(&CoStatus::Done, co.status());
+//!
+//! let mut co = CoVM::build("yield 1\nyield 2\n3").unwrap();
+//! let vals: Vec<Value> = co.iter().map(Result::unwrap).collect();
+//! assert_eq!(vec![Value::Num(1.0), Value::Num(2.0)], vals);
+//! ```
+
+pub use crate::ast::AstVisitor;
+pub use crate::code::Code;
+pub use crate::code::Instr;
+pub use crate::debug::Debugger;
+pub use crate::error::CoError;
+pub use crate::value::FnDef;
+pub use crate::value::Value;
+pub use crate::vm::CoRes;
+pub use crate::vm::CoStatus;
+pub use crate::vm::CoVM;
+pub use crate::vm::Coro;
+pub use crate::vm::CoroIter;
@@ -3,31 +3,95 @@
 //! Most Coro data types and values are represented directly in Rust using Rust types. Function and
 //! coroutine objects are more complex and have their own custom representation. Since these two
 //! are objects that can be referenced in a few places, `Rc` and `RefCell` are used as a layer of
-//! indirection to work better with Rust's ownership system.
+//! indirection to work better with Rust's ownership system. Lists and maps are mutable and shared
+//! by reference, so they get the same treatment.
 
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::ops::Range;
 use std::rc::Rc;
 
 use crate::code::Code;
+use crate::code::Instr::*;
 use crate::vm::Coro;
 
+/// Largest integer such that it and every integer closer to zero are exact as a float, i.e.
+/// 2^53 - 1. Integer results further from zero than this would be rounded.
+pub const MAX_SAFE_INT: i64 = (1 << 53) - 1;
+
+/// A value that Coro code works with. Cloning is cheap, since anything bigger than a number is
+/// reference counted.
 #[derive(Clone)]
 pub enum Value {
     Unit,
     Bool(bool),
     Num(f64),
-    Str(String),
+    Str(Rc<str>),
+    List(Rc<RefCell<Vec<Value>>>),
+    /// String keys, kept sorted so that iterating a map is deterministic.
+    Map(Rc<RefCell<BTreeMap<Rc<str>, Value>>>),
+    /// Numbers from `start` up to but not including `end`, counting by `step`. The numbers are
+    /// only worked out as they are needed, see `range_nth`.
+    Range { start: f64, end: f64, step: f64 },
     Fn(Rc<FnDef>),
+    NativeFn(Rc<NativeFn>),
     Co(Rc<RefCell<Coro>>),
+    /// A value that is only worked out when it is first forced, see `Lazy`.
+    Lazy(Rc<RefCell<Lazy>>),
+}
+
+/// The state of a lazy value. Forcing it calls the function once and keeps the result, so later
+/// forces give back the same value.
+#[derive(Debug)]
+pub enum Lazy {
+    /// Not forced yet, holding the function that gives the value.
+    Pending(Value),
+    /// In the middle of calling the function, which must not force the same value again.
+    Forcing,
+    Forced(Value),
 }
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Unit => write!(f, "unit"),
-            Self::Str(s) => write!(f, "\"{}\"", s),
-            _ => fmt::Display::fmt(&self, f),
+            Self::Unit => write!(f, "Unit"),
+            Self::Bool(b) => write!(f, "Bool({:?})", b),
+            Self::Num(n) => write!(f, "Num({:?})", n),
+            Self::Str(s) => write!(f, "Str({:?})", s),
+            Self::List(list) => match list.try_borrow() {
+                Ok(items) => write!(f, "List({:?})", items),
+                Err(_) => write!(f, "List(<borrowed>)"),
+            },
+            Self::Map(map) => match map.try_borrow() {
+                Ok(entries) => write!(f, "Map({:?})", entries),
+                Err(_) => write!(f, "Map(<borrowed>)"),
+            },
+            Self::Range { start, end, step } => f
+                .debug_struct("Range")
+                .field("start", start)
+                .field("end", end)
+                .field("step", step)
+                .finish(),
+            Self::Fn(def) => write!(f, "Fn({})", def),
+            Self::NativeFn(native) => write!(f, "NativeFn({})", native),
+            Self::Co(coro) => {
+                // The coroutine is mutably borrowed while it is running.
+                let desc = match coro.try_borrow() {
+                    Ok(co) => format!("<coro {} at ip {}>", co.status_name(), co.ip()),
+                    Err(_) => "<coro running>".to_owned(),
+                };
+                write!(f, "Co({})", desc)
+            }
+            Self::Lazy(lazy) => match lazy.try_borrow() {
+                Ok(state) => write!(f, "Lazy({:?})", state),
+                Err(_) => write!(f, "Lazy(<borrowed>)"),
+            },
         }
     }
 }
@@ -37,14 +101,133 @@ impl fmt::Display for Value {
         match self {
             Self::Unit => write!(f, "()"),
             Self::Bool(b) => write!(f, "{}", b),
-            Self::Num(n) => write!(f, "{}", n),
+            Self::Num(n) => fmt_num(*n, f),
             Self::Str(s) => write!(f, "{}", s),
+            Self::List(list) => fmt_list(list, f),
+            Self::Map(map) => fmt_map(map, f),
+            Self::Range { start, end, step } => fmt_range(*start, *end, *step, f),
             Self::Fn(def) => def.fmt(f),
-            Self::Co(coro) => coro.borrow().fmt(f),
+            Self::NativeFn(native) => native.fmt(f),
+            // The coroutine is mutably borrowed while it is running.
+            Self::Co(coro) => match coro.try_borrow() {
+                Ok(co) => co.fmt(f),
+                Err(_) => write!(f, "<coro running>"),
+            },
+            Self::Lazy(lazy) => match &*lazy.borrow() {
+                // A lazy value can be forced to something that holds it, or to itself.
+                Lazy::Forced(val) => match Visit::enter(VisitKind::Fmt, &**lazy, &**lazy) {
+                    Some(_visit) => write!(f, "<lazy {}>", val),
+                    None => write!(f, "<lazy ...>"),
+                },
+                _ => write!(f, "<lazy>"),
+            },
         }
     }
 }
 
+/// Numbers are always floats, but whole numbers print without a trailing `.0`, and fractional
+/// numbers print with the fewest digits that still round-trip. This is shared by `print` and the
+/// REPL so both show numbers the same way.
+fn fmt_num(n: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if n.is_nan() {
+        write!(f, "nan")
+    } else if n.is_infinite() {
+        let sign = if n < 0.0 { "-" } else { "" };
+        write!(f, "{}inf", sign)
+    } else if n == 0.0 {
+        // Avoid showing negative zero.
+        write!(f, "0")
+    } else {
+        write!(f, "{}", n)
+    }
+}
+
+/// Lists print their elements comma-separated, with strings quoted so that they are
+/// distinguishable from other values.
+/// A list that is reached again through itself prints as `[...]`.
+fn fmt_list(list: &RefCell<Vec<Value>>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let _visit = match Visit::enter(VisitKind::Fmt, list, list) {
+        Some(visit) => visit,
+        None => return write!(f, "[...]"),
+    };
+    let items = match list.try_borrow() {
+        Ok(items) => items,
+        Err(_) => return write!(f, "[...]"),
+    };
+    write!(f, "[")?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        fmt_item(item, f)?;
+    }
+    write!(f, "]")
+}
+
+/// Maps print like `{"a": 1, "b": "c"}`, with entries in key order.
+fn fmt_map(map: &RefCell<BTreeMap<Rc<str>, Value>>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let _visit = match Visit::enter(VisitKind::Fmt, map, map) {
+        Some(visit) => visit,
+        None => return write!(f, "{{...}}"),
+    };
+    let entries = match map.try_borrow() {
+        Ok(entries) => entries,
+        Err(_) => return write!(f, "{{...}}"),
+    };
+    write!(f, "{{")?;
+    for (i, (key, val)) in entries.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "\"{}\": ", key)?;
+        fmt_item(val, f)?;
+    }
+    write!(f, "}}")
+}
+
+/// Ranges print the same as the code that makes them, e.g. `1..5` or `range(0, 1, 0.5)`.
+fn fmt_range(start: f64, end: f64, step: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if step == 1.0 {
+        fmt_num(start, f)?;
+        write!(f, "..")?;
+        fmt_num(end, f)
+    } else {
+        write!(f, "range(")?;
+        fmt_num(start, f)?;
+        write!(f, ", ")?;
+        fmt_num(end, f)?;
+        write!(f, ", ")?;
+        fmt_num(step, f)?;
+        write!(f, ")")
+    }
+}
+
+/// The number at `idx` of a range, if the range has that many numbers. Ranges that count the
+/// wrong way to reach `end` are empty.
+pub fn range_nth(start: f64, end: f64, step: f64, idx: usize) -> Option<f64> {
+    let num = start + idx as f64 * step;
+    let inside = if step > 0.0 { num < end } else { num > end };
+    inside.then_some(num)
+}
+
+/// The indexes of a list with `len` items from `from` up to but not including `to`. Negative
+/// bounds count from the end, and bounds past either end are clamped, same as in Python. Missing
+/// bounds stand for the start and end of the list.
+pub fn slice_range(len: usize, from: Option<i64>, to: Option<i64>) -> Range<usize> {
+    let len = len as i64;
+    let clamp = |idx: i64| if idx < 0 { (len + idx).max(0) } else { idx.min(len) } as usize;
+    let from = from.map_or(0, clamp);
+    let to = to.map_or(len as usize, clamp).max(from);
+    from..to
+}
+
+fn fmt_item(item: &Value, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match item {
+        Value::Str(s) => write!(f, "\"{}\"", s),
+        _ => write!(f, "{}", item),
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -52,14 +235,212 @@ impl PartialEq for Value {
             (Self::Bool(b1), Self::Bool(b2)) => b1 == b2,
             (Self::Num(n1), Self::Num(n2)) => n1 == n2,
             (Self::Str(s1), Self::Str(s2)) => s1 == s2,
+            (Self::List(l1), Self::List(l2)) => {
+                Rc::ptr_eq(l1, l2)
+                    || match Visit::enter(VisitKind::Eq, &**l1, &**l2) {
+                        Some(_visit) => *l1.borrow() == *l2.borrow(),
+                        None => true,
+                    }
+            }
+            (Self::Map(m1), Self::Map(m2)) => {
+                Rc::ptr_eq(m1, m2)
+                    || match Visit::enter(VisitKind::Eq, &**m1, &**m2) {
+                        Some(_visit) => *m1.borrow() == *m2.borrow(),
+                        None => true,
+                    }
+            }
+            (
+                Self::Range { start: s1, end: e1, step: st1 },
+                Self::Range { start: s2, end: e2, step: st2 },
+            ) => s1 == s2 && e1 == e2 && st1 == st2,
             (Self::Fn(f1), Self::Fn(f2)) => Rc::ptr_eq(f1, f2),
+            (Self::NativeFn(f1), Self::NativeFn(f2)) => Rc::ptr_eq(f1, f2),
             (Self::Co(c1), Self::Co(c2)) => Rc::ptr_eq(c1, c2),
+            (Self::Lazy(l1), Self::Lazy(l2)) => Rc::ptr_eq(l1, l2),
             _ => false,
         }
     }
 }
 
+/// Values are totally ordered so that lists of them can be sorted. Values of different types are
+/// ordered by type: `Unit < Bool < Num < Str < List < Map < Range < Fn < NativeFn < Co < Lazy`.
+/// This is for convenience, and does not mean that comparing across types is meaningful. Numbers
+/// use `f64::total_cmp`, so `NaN` sorts after all other numbers, strings are ordered
+/// lexicographically and lists element-wise. Maps compare their entries in key order, and ranges
+/// their start, end and then step. Functions, coroutines and lazy values are ordered by address.
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Unit, Self::Unit) => Ordering::Equal,
+            (Self::Bool(b1), Self::Bool(b2)) => b1.cmp(b2),
+            (Self::Num(n1), Self::Num(n2)) => cmp_num(*n1, *n2),
+            (Self::Str(s1), Self::Str(s2)) => s1.cmp(s2),
+            (Self::List(l1), Self::List(l2)) if Rc::ptr_eq(l1, l2) => Ordering::Equal,
+            (Self::List(l1), Self::List(l2)) => match Visit::enter(VisitKind::Cmp, &**l1, &**l2) {
+                Some(_visit) => l1.borrow().cmp(&*l2.borrow()),
+                None => Ordering::Equal,
+            },
+            (Self::Map(m1), Self::Map(m2)) if Rc::ptr_eq(m1, m2) => Ordering::Equal,
+            (Self::Map(m1), Self::Map(m2)) => match Visit::enter(VisitKind::Cmp, &**m1, &**m2) {
+                Some(_visit) => m1.borrow().cmp(&*m2.borrow()),
+                None => Ordering::Equal,
+            },
+            (
+                Self::Range { start: s1, end: e1, step: st1 },
+                Self::Range { start: s2, end: e2, step: st2 },
+            ) => cmp_num(*s1, *s2).then(cmp_num(*e1, *e2)).then(cmp_num(*st1, *st2)),
+            (Self::Fn(f1), Self::Fn(f2)) => Rc::as_ptr(f1).cmp(&Rc::as_ptr(f2)),
+            (Self::NativeFn(f1), Self::NativeFn(f2)) => Rc::as_ptr(f1).cmp(&Rc::as_ptr(f2)),
+            (Self::Co(c1), Self::Co(c2)) => Rc::as_ptr(c1).cmp(&Rc::as_ptr(c2)),
+            (Self::Lazy(l1), Self::Lazy(l2)) => Rc::as_ptr(l1).cmp(&Rc::as_ptr(l2)),
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
+    }
+}
+
+// Fold -0 into 0 so that they compare equal, same as `==`.
+fn cmp_num(n1: f64, n2: f64) -> Ordering {
+    (n1 + 0.0).total_cmp(&(n2 + 0.0))
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Values can be used as keys in Rust-side maps. Note that `NaN` is never equal to itself, so a
+/// `NaN` key can be inserted but never found again.
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Unit => state.write_u8(0),
+            Self::Bool(b) => {
+                state.write_u8(1);
+                b.hash(state);
+            }
+            Self::Num(n) => {
+                state.write_u8(2);
+                hash_num(*n, state);
+            }
+            Self::Str(s) => {
+                state.write_u8(3);
+                s.hash(state);
+            }
+            Self::List(list) => {
+                state.write_u8(4);
+                if let Some(_visit) = Visit::enter(VisitKind::Hash, &**list, &**list) {
+                    list.borrow().hash(state);
+                }
+            }
+            Self::Map(map) => {
+                state.write_u8(5);
+                if let Some(_visit) = Visit::enter(VisitKind::Hash, &**map, &**map) {
+                    map.borrow().hash(state);
+                }
+            }
+            Self::Range { start, end, step } => {
+                state.write_u8(6);
+                hash_num(*start, state);
+                hash_num(*end, state);
+                hash_num(*step, state);
+            }
+            Self::Fn(def) => {
+                state.write_u8(7);
+                Rc::as_ptr(def).hash(state);
+            }
+            Self::NativeFn(native) => {
+                state.write_u8(8);
+                Rc::as_ptr(native).hash(state);
+            }
+            Self::Co(coro) => {
+                state.write_u8(9);
+                Rc::as_ptr(coro).hash(state);
+            }
+            Self::Lazy(lazy) => {
+                state.write_u8(10);
+                Rc::as_ptr(lazy).hash(state);
+            }
+        }
+    }
+}
+
+/// What a container is being visited for, see `Visit`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum VisitKind {
+    Fmt,
+    Eq,
+    Cmp,
+    Hash,
+}
+
+thread_local! {
+    static VISITING: RefCell<HashSet<(VisitKind, usize, usize)>> = RefCell::new(HashSet::new());
+}
+
+/// Marks a container, or a pair of containers being compared, as in the middle of being visited
+/// until dropped. Lists and maps can hold themselves, so walking them must stop when a container
+/// is reached again through itself. Such a back-reference prints as `[...]`, compares equal and
+/// adds nothing more to a hash.
+struct Visit((VisitKind, usize, usize));
+
+impl Visit {
+    /// `None` if the containers are already being visited.
+    fn enter<T>(kind: VisitKind, a: *const T, b: *const T) -> Option<Self> {
+        let key = (kind, a as usize, b as usize);
+        VISITING.with(|visiting| visiting.borrow_mut().insert(key)).then_some(Self(key))
+    }
+}
+
+impl Drop for Visit {
+    fn drop(&mut self) {
+        VISITING.with(|visiting| visiting.borrow_mut().remove(&self.0));
+    }
+}
+
+// Equal numbers must hash the same, so fold -0 into 0. All NaNs share one hash.
+fn hash_num<H: Hasher>(n: f64, state: &mut H) {
+    let bits = if n.is_nan() {
+        f64::NAN.to_bits()
+    } else if n == 0.0 {
+        0
+    } else {
+        n.to_bits()
+    };
+    bits.hash(state);
+}
+
 impl Value {
+    /// Hash of the value, consistent with equality.
+    pub fn hash_code(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            Self::Unit => 0,
+            Self::Bool(_) => 1,
+            Self::Num(_) => 2,
+            Self::Str(_) => 3,
+            Self::List(_) => 4,
+            Self::Map(_) => 5,
+            Self::Range { .. } => 6,
+            Self::Fn(_) => 7,
+            Self::NativeFn(_) => 8,
+            Self::Co(_) => 9,
+            Self::Lazy(_) => 10,
+        }
+    }
+
+    /// Whether the value counts as true in a condition, which is anything but unit and `false`.
+    pub fn is_truthy(&self) -> bool {
+        !self.is_falsey()
+    }
+
     pub fn is_falsey(&self) -> bool {
         match self {
             Self::Unit => true,
@@ -79,10 +460,32 @@ impl Value {
         }
     }
 
+    /// The number as an integer, if it is a whole number that fits in an `i64`.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            // The upper bound is exclusive, since `i64::MAX` rounds up to 2^63 as a float.
+            Self::Num(n) if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64 => {
+                Some(*n as i64)
+            }
+            _ => None,
+        }
+    }
+
     pub fn is_str(&self) -> bool {
         matches!(self, Self::Str(..))
     }
 
+    /// Compare values the way `<` and friends do in Coro code: numbers numerically and strings
+    /// lexicographically. Other pairs, e.g. a number and a string, and `NaN` are not comparable.
+    /// Unlike `Ord`, which orders every pair of values so that they can be sorted.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Num(n1), Self::Num(n2)) => n1.partial_cmp(n2),
+            (Self::Str(s1), Self::Str(s2)) => Some(s1.cmp(s2)),
+            _ => None,
+        }
+    }
+
     pub fn as_str_ref(&self) -> &str {
         match self {
             Self::Str(s) => s,
@@ -90,13 +493,59 @@ impl Value {
         }
     }
 
-    pub fn into_str(self) -> String {
+    pub fn into_str(self) -> Rc<str> {
         match self {
             Self::Str(s) => s,
             _ => panic!(),
         }
     }
 
+    pub fn list(items: Vec<Value>) -> Self {
+        Self::List(Rc::new(RefCell::new(items)))
+    }
+
+    /// A range from `start` up to but not including `end`, counting by `step`, which must not be 0.
+    pub fn range(start: f64, end: f64, step: f64) -> Self {
+        Self::Range { start, end, step }
+    }
+
+    /// A lazy value that calls `fun` with no arguments for its value when first forced.
+    pub fn lazy(fun: Value) -> Self {
+        Self::Lazy(Rc::new(RefCell::new(Lazy::Pending(fun))))
+    }
+
+    /// Clone the value, making copies of lists and maps (and any nested inside) instead of sharing
+    /// them. Functions are immutable so they are always shared, and coroutines are shared too.
+    pub fn deep_clone(&self) -> Self {
+        match self {
+            Self::List(list) => Self::list(list.borrow().iter().map(Self::deep_clone).collect()),
+            Self::Map(map) => {
+                let entries = map.borrow();
+                Self::map(entries.iter().map(|(k, v)| (k.clone(), v.deep_clone())).collect())
+            }
+            _ => self.clone(),
+        }
+    }
+
+    pub fn is_list(&self) -> bool {
+        matches!(self, Self::List(..))
+    }
+
+    pub fn into_list(self) -> Rc<RefCell<Vec<Value>>> {
+        match self {
+            Self::List(l) => l,
+            _ => panic!(),
+        }
+    }
+
+    pub fn map(entries: BTreeMap<Rc<str>, Value>) -> Self {
+        Self::Map(Rc::new(RefCell::new(entries)))
+    }
+
+    pub fn is_map(&self) -> bool {
+        matches!(self, Self::Map(..))
+    }
+
     pub fn is_fn(&self) -> bool {
         matches!(self, Self::Fn(..))
     }
@@ -108,6 +557,10 @@ impl Value {
         }
     }
 
+    pub fn is_native_fn(&self) -> bool {
+        matches!(self, Self::NativeFn(..))
+    }
+
     pub fn is_co(&self) -> bool {
         matches!(self, Self::Co(..))
     }
@@ -120,9 +573,138 @@ impl Value {
     }
 }
 
+/// Deduplicates strings, so that equal strings share the same allocation. Cloning an interned
+/// string is just bumping a reference count.
+#[derive(Debug, Default)]
+pub struct StringInterner {
+    strings: HashSet<Rc<str>>,
+}
+
+impl StringInterner {
+    pub fn new() -> Self {
+        Self { strings: HashSet::new() }
+    }
+
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(rc) = self.strings.get(s) {
+            return rc.clone();
+        }
+        let rc: Rc<str> = Rc::from(s);
+        self.strings.insert(rc.clone());
+        rc
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<StringInterner> = RefCell::new(StringInterner::new());
+}
+
+/// Intern `s` in the shared interner. This is used for names and string literals at compile
+/// time, which are fixed and so never need to be freed.
+pub fn intern(s: &str) -> Rc<str> {
+    INTERNER.with(|interner| interner.borrow_mut().intern(s))
+}
+
+type NativeFnBody = dyn Fn(Vec<Value>) -> Result<Value, String>;
+
+/// A function implemented in Rust, e.g. a built-in. Unlike `FnDef`, calling it does not create a
+/// coroutine, so it cannot yield. Errors are plain messages, and get located by the VM.
+pub struct NativeFn {
+    name: String,
+    min_arity: usize,
+    max_arity: usize,
+    fun: Box<NativeFnBody>,
+}
+
+impl fmt::Display for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn name: {}>", self.name)
+    }
+}
+
+impl NativeFn {
+    pub fn new<F>(name: &str, arity: usize, fun: F) -> Self
+    where
+        F: Fn(Vec<Value>) -> Result<Value, String> + 'static,
+    {
+        Self {
+            name: name.to_owned(),
+            min_arity: arity,
+            max_arity: arity,
+            fun: Box::new(fun),
+        }
+    }
+
+    /// A native function taking at least `min_arity` arguments.
+    pub fn variadic<F>(name: &str, min_arity: usize, fun: F) -> Self
+    where
+        F: Fn(Vec<Value>) -> Result<Value, String> + 'static,
+    {
+        Self {
+            name: name.to_owned(),
+            min_arity,
+            max_arity: usize::MAX,
+            fun: Box::new(fun),
+        }
+    }
+
+    /// A native function taking from `min_arity` up to `max_arity` arguments.
+    pub fn ranged<F>(name: &str, min_arity: usize, max_arity: usize, fun: F) -> Self
+    where
+        F: Fn(Vec<Value>) -> Result<Value, String> + 'static,
+    {
+        Self {
+            name: name.to_owned(),
+            min_arity,
+            max_arity,
+            fun: Box::new(fun),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn min_arity(&self) -> usize {
+        self.min_arity
+    }
+
+    /// Most arguments the function takes, which is `usize::MAX` if it is variadic.
+    pub fn max_arity(&self) -> usize {
+        self.max_arity
+    }
+
+    /// Call the function. The caller is expected to have checked the number of arguments.
+    pub fn call(&self, args: Vec<Value>) -> Result<Value, String> {
+        (self.fun)(args)
+    }
+}
+
+/// The cell behind an upvalue, which is shared by every function that captures it. It is empty
+/// until the variable is bound.
+pub type Captured = Rc<RefCell<Option<Value>>>;
+
+/// A compiled function, i.e. its name, parameters and code. The top-level code of a script is a
+/// function too, named `__main__`.
+#[derive(Debug, Default, Clone)]
 pub struct FnDef {
     name: String,
     params: Vec<String>,
+    defaults: Vec<Option<Rc<FnDef>>>,
+    rest_param: Option<String>,
+    // Cells of the upvalues captured from the enclosing function, in order. Locals that nested
+    // functions capture get their cells when the function is run instead.
+    captured: Vec<Captured>,
+    // Most values the code keeps on the stack, so that coroutines can allocate it up front.
+    max_stack: usize,
     pub code: Code,
 }
 
@@ -132,11 +714,25 @@ impl fmt::Display for FnDef {
     }
 }
 
+/// Shows a function header followed by its instructions, see `FnDef::display_verbose`.
+pub struct VerboseFnDef<'a>(&'a FnDef);
+
+impl fmt::Display for VerboseFnDef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.0)?;
+        write!(f, "{}", crate::debug::disassemble(&self.0.code, self.0.name()))
+    }
+}
+
 impl FnDef {
     pub fn new() -> Self {
         Self {
             name: String::new(),
             params: Vec::new(),
+            defaults: Vec::new(),
+            rest_param: None,
+            captured: Vec::new(),
+            max_stack: 0,
             code: Code::new(),
         }
     }
@@ -145,6 +741,10 @@ impl FnDef {
         Self {
             name,
             params,
+            defaults: Vec::new(),
+            rest_param: None,
+            captured: Vec::new(),
+            max_stack: 0,
             code: Code::new(),
         }
     }
@@ -168,6 +768,112 @@ impl FnDef {
     pub fn param(&self, idx: usize) -> &String {
         &self.params[idx]
     }
+
+    /// Name of the parameter that collects extra arguments into a list, if any.
+    pub fn rest_param(&self) -> Option<&String> {
+        self.rest_param.as_ref()
+    }
+
+    pub fn set_rest_param(&mut self, rest: Option<String>) {
+        self.rest_param = rest;
+    }
+
+    /// How many values the stack holds at most while running the code, see `Code::max_stack`.
+    pub fn max_stack(&self) -> usize {
+        self.max_stack
+    }
+
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.max_stack = max_stack;
+    }
+
+    /// Cells of the upvalues this function captured when it was created, see `OpClosure`.
+    pub fn captured(&self) -> &[Captured] {
+        &self.captured
+    }
+
+    /// A copy of this function that holds on to the given cells.
+    pub fn close_over(&self, captured: Vec<Captured>) -> FnDef {
+        FnDef { captured, ..self.clone() }
+    }
+
+    /// Whether the function accepts any number of arguments beyond its `arity`.
+    pub fn is_variadic(&self) -> bool {
+        self.rest_param.is_some()
+    }
+
+    /// Number of arguments that must be given, i.e. the parameters without a default.
+    pub fn min_arity(&self) -> usize {
+        self.params.len() - self.defaults.iter().filter(|d| d.is_some()).count()
+    }
+
+    /// The compiled default value for the parameter at `idx`, if it has one.
+    pub fn param_default(&self, idx: usize) -> Option<&Rc<FnDef>> {
+        self.defaults.get(idx).and_then(Option::as_ref)
+    }
+
+    /// Display the function along with its instructions, rather than just its header.
+    pub fn display_verbose(&self) -> VerboseFnDef<'_> {
+        VerboseFnDef(self)
+    }
+
+    /// Whether the two were compiled from the same source, so either can stand in for the other.
+    pub fn same_as(&self, other: &FnDef) -> bool {
+        let same_default = |(a, b): (&Option<Rc<FnDef>>, &Option<Rc<FnDef>>)| match (a, b) {
+            (Some(a), Some(b)) => a.same_as(b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        self.name == other.name
+            && self.params == other.params
+            && self.rest_param == other.rest_param
+            && self.defaults.len() == other.defaults.len()
+            && self.defaults.iter().zip(&other.defaults).all(same_default)
+            && self.code.same_as(&other.code)
+    }
+
+    /// Check that the code is well-formed, i.e. it passes `Code::verify` and `Code::check_stack`,
+    /// and every instruction refers to a constant of the right type. Nested functions and default
+    /// values are checked as well.
+    pub fn validate(&self) -> Result<(), String> {
+        self.code.verify().map_err(|msg| format!("{}: {}", self.name(), msg))?;
+        self.code.check_stack().map_err(|msg| format!("{}: {}", self.name(), msg))?;
+        let consts = self.code.constants();
+        for (idx, instr, _) in &self.code {
+            let is_str: fn(&Value) -> bool = Value::is_str;
+            let is_fn: fn(&Value) -> bool = Value::is_fn;
+            let (cidx, kind, check) = match instr {
+                OpLoad(cidx) | OpStore(cidx) | OpCreate(cidx) => (*cidx, "string", is_str),
+                OpDefine(cidx) | OpClosure(cidx) => (*cidx, "function", is_fn),
+                _ => continue,
+            };
+            let val = &consts[cidx];
+            if !check(val) {
+                return Err(format!(
+                    "{}: {:?} at {:04} expected a {} constant but found {:?}",
+                    self.name(),
+                    instr,
+                    idx,
+                    kind,
+                    val
+                ));
+            }
+        }
+
+        for val in consts {
+            if let Value::Fn(def) = val {
+                def.validate()?;
+            }
+        }
+        for def in self.defaults.iter().flatten() {
+            def.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Set the compiled default values, one for each parameter.
+    pub fn set_defaults(&mut self, defaults: Vec<Option<Rc<FnDef>>>) {
+        self.defaults = defaults;
+    }
 }
 
 #[cfg(test)]
@@ -178,21 +884,364 @@ mod tests {
     fn false_values() {
         assert!(Value::Unit.is_falsey());
         assert!(Value::Bool(false).is_falsey());
-        assert_eq!(false, Value::Bool(true).is_falsey());
-        assert_eq!(false, Value::Num(1.2).is_falsey());
-        assert_eq!(false, Value::Str("foo".to_owned()).is_falsey());
+        assert!(!Value::Bool(true).is_falsey());
+        assert!(!Value::Num(1.2).is_falsey());
+        assert!(!Value::Str("foo".into()).is_falsey());
+    }
+
+    #[test]
+    fn true_values() {
+        assert!(!Value::Unit.is_truthy());
+        assert!(!Value::Bool(false).is_truthy());
+        assert!(Value::Bool(true).is_truthy());
+        assert!(Value::Num(0.0).is_truthy());
+        assert!(Value::Str("".into()).is_truthy());
+        assert!(Value::list(Vec::new()).is_truthy());
+        assert!(Value::map(BTreeMap::new()).is_truthy());
+        assert!(Value::Fn(Rc::new(FnDef::new())).is_truthy());
+        assert!(Value::NativeFn(Rc::new(NativeFn::new("f", 0, |_| Ok(Value::Unit)))).is_truthy());
+        let co = Coro::new(Rc::new(FnDef::new()));
+        assert!(Value::Co(Rc::new(RefCell::new(co))).is_truthy());
     }
 
     #[test]
     fn num_values() {
         assert!(Value::Num(2.3).is_num());
-        assert_eq!(false, Value::Unit.is_num());
+        assert!(!Value::Unit.is_num());
+    }
+
+    #[test]
+    fn int_values() {
+        assert_eq!(Some(3), Value::Num(3.0).as_int());
+        assert_eq!(Some(-4), Value::Num(-4.0).as_int());
+        assert_eq!(None, Value::Num(1.5).as_int());
+        assert_eq!(None, Value::Num(f64::NAN).as_int());
+        assert_eq!(None, Value::Num(1e20).as_int());
+        assert_eq!(None, Value::Str("1".into()).as_int());
+    }
+
+    #[test]
+    fn map_values() {
+        let entries = vec![("b".into(), Value::Str("x".into())), ("a".into(), Value::Num(1.0))];
+        let map = Value::map(entries.into_iter().collect());
+        assert!(map.is_map());
+        assert_eq!("{\"a\": 1, \"b\": \"x\"}", format!("{}", map));
+
+        let copy = map.deep_clone();
+        assert_eq!(map, copy);
+        assert_eq!(map.hash_code(), copy.hash_code());
+        assert!(Value::list(vec![]) < map && map < Value::Fn(Rc::new(FnDef::new())));
+    }
+
+    #[test]
+    fn range_values() {
+        let range = Value::range(1.0, 5.0, 1.0);
+        assert_eq!("1..5", format!("{}", range));
+        assert_eq!("range(0, 1, 0.25)", format!("{}", Value::range(0.0, 1.0, 0.25)));
+        assert_eq!("Range { start: 1.0, end: 5.0, step: 1.0 }", format!("{:?}", range));
+        assert!(range.is_truthy());
+
+        assert_eq!(range, Value::range(1.0, 5.0, 1.0));
+        assert_eq!(range.hash_code(), Value::range(1.0, 5.0, 1.0).hash_code());
+        assert_ne!(range, Value::range(1.0, 5.0, 2.0));
+        assert!(range < Value::range(1.0, 6.0, 1.0) && range < Value::range(2.0, 3.0, 1.0));
+        assert!(Value::map(BTreeMap::new()) < range && range < Value::Fn(Rc::new(FnDef::new())));
+
+        let nums = |start, end, step| -> Vec<f64> {
+            (0..).map_while(|idx| range_nth(start, end, step, idx)).collect()
+        };
+        assert_eq!(vec![1.0, 2.0, 3.0, 4.0], nums(1.0, 5.0, 1.0));
+        assert_eq!(vec![0.0, 0.5], nums(0.0, 1.0, 0.5));
+        assert_eq!(vec![3.0, 2.0], nums(3.0, 1.0, -1.0));
+        assert!(nums(5.0, 1.0, 1.0).is_empty());
+        assert!(nums(1.0, 1.0, 1.0).is_empty());
+        assert!(nums(0.0, f64::NAN, 1.0).is_empty());
+        assert_eq!(Some(1e6), range_nth(0.0, f64::INFINITY, 1.0, 1_000_000));
     }
 
     #[test]
     fn str_values() {
-        assert!(Value::Str("foo".to_owned()).is_str());
-        assert_eq!(false, Value::Unit.is_str());
+        assert!(Value::Str("foo".into()).is_str());
+        assert!(!Value::Unit.is_str());
+    }
+
+    #[test]
+    fn num_display() {
+        assert_eq!("4", format!("{}", Value::Num(4.0)));
+        assert_eq!("4.5", format!("{}", Value::Num(4.5)));
+        assert_eq!("-12", format!("{}", Value::Num(-12.0)));
+        assert_eq!("0.1", format!("{}", Value::Num(0.1)));
+        assert_eq!("0", format!("{}", Value::Num(-0.0)));
+        assert_eq!("inf", format!("{}", Value::Num(f64::INFINITY)));
+        assert_eq!("-inf", format!("{}", Value::Num(f64::NEG_INFINITY)));
+        assert_eq!("nan", format!("{}", Value::Num(f64::NAN)));
+    }
+
+    #[test]
+    fn debug_structure() {
+        assert_eq!("Unit", format!("{:?}", Value::Unit));
+        assert_eq!("Bool(true)", format!("{:?}", Value::Bool(true)));
+        assert_eq!("Num(2.5)", format!("{:?}", Value::Num(2.5)));
+        assert_eq!("Str(\"hello\")", format!("{:?}", Value::Str("hello".into())));
+
+        let def = Rc::new(FnDef::with("foo".to_owned(), vec!["a".to_owned()]));
+        let dbg = format!("{:?}", Value::Fn(def.clone()));
+        assert_eq!("Fn(<fn name: foo arity: 1>)", dbg);
+
+        let co = Rc::new(RefCell::new(Coro::new(def)));
+        let dbg = format!("{:?}", Value::Co(co.clone()));
+        assert_eq!("Co(<coro suspended at ip 0>)", dbg);
+
+        let _running = co.borrow_mut();
+        let dbg = format!("{:?}", Value::Co(co.clone()));
+        assert_eq!("Co(<coro running>)", dbg);
+    }
+
+    #[test]
+    fn str_raw_and_quoted() {
+        let val = Value::Str("hi".into());
+        assert_eq!("hi", format!("{}", val));
+        assert_eq!("Str(\"hi\")", format!("{:?}", val));
+    }
+
+    #[test]
+    fn num_raw_and_quoted() {
+        let val = Value::Num(42.0);
+        assert_eq!("42", format!("{}", val));
+        assert_eq!("Num(42.0)", format!("{:?}", val));
+    }
+
+    #[test]
+    fn list_values() {
+        let list = Value::list(vec![Value::Num(1.0), Value::Str("a".into())]);
+        assert!(list.is_list());
+        assert!(!list.is_falsey());
+        assert!(!Value::Unit.is_list());
+        assert_eq!("[1, \"a\"]", format!("{}", list));
+        assert_eq!("List([Num(1.0), Str(\"a\")])", format!("{:?}", list));
+        assert_eq!("[]", format!("{}", Value::list(Vec::new())));
+    }
+
+    #[test]
+    fn list_equality() {
+        let list = Value::list(vec![Value::Num(1.0), Value::list(vec![Value::Unit])]);
+        assert!(list == list.clone());
+        assert!(list == Value::list(vec![Value::Num(1.0), Value::list(vec![Value::Unit])]));
+        assert!(list != Value::list(vec![Value::Num(1.0)]));
+        assert!(list != Value::Num(1.0));
+    }
+
+    #[test]
+    fn self_containing_list() {
+        let list = Value::list(vec![Value::Num(1.0)]);
+        let other = Value::list(vec![Value::Num(1.0)]);
+        for val in [&list, &other] {
+            if let Value::List(items) = val {
+                items.borrow_mut().push(val.clone());
+            }
+        }
+        assert_eq!("[1, [...]]", format!("{}", list));
+        assert!(list == other);
+        assert_eq!(Ordering::Equal, list.cmp(&other));
+        assert_eq!(list.hash_code(), other.hash_code());
+        assert!(list != Value::list(vec![Value::Num(1.0), Value::Unit]));
+
+        let map = Value::map(BTreeMap::new());
+        if let Value::Map(entries) = &map {
+            entries.borrow_mut().insert("a".into(), map.clone());
+        }
+        assert_eq!("{\"a\": {...}}", format!("{}", map));
+    }
+
+    #[test]
+    fn self_forced_lazy() {
+        let lazy = Rc::new(RefCell::new(Lazy::Forcing));
+        *lazy.borrow_mut() = Lazy::Forced(Value::Lazy(lazy.clone()));
+        assert_eq!("<lazy <lazy ...>>", format!("{}", Value::Lazy(lazy)));
+
+        let lazy = Rc::new(RefCell::new(Lazy::Forcing));
+        let list = Value::list(vec![Value::Lazy(lazy.clone())]);
+        *lazy.borrow_mut() = Lazy::Forced(list.clone());
+        assert_eq!("[<lazy [...]>]", format!("{}", list));
+        assert_eq!("<lazy [<lazy ...>]>", format!("{}", Value::Lazy(lazy)));
+    }
+
+    #[test]
+    fn fn_display_verbose() {
+        let mut def = FnDef::with("f".to_owned(), vec!["a".to_owned()]);
+        def.code.add(OpUnit, 1);
+        def.code.add(OpRet, 1);
+        assert_eq!("<fn name: f arity: 1>", format!("{}", def));
+        let exp = "<fn name: f arity: 1>\n== instr: f ==\n0000    1 OpUnit\n0001    | OpRet\n";
+        assert_eq!(exp, format!("{}", def.display_verbose()));
+    }
+
+    #[test]
+    fn validate_ok() {
+        let mut def = FnDef::with("f".to_owned(), vec!["a".to_owned()]);
+        let name = def.code.add_const(Value::Str("a".into()));
+        def.code.add(OpLoad(name), 1);
+        def.code.add(OpRet, 1);
+        let mut main = FnDef::new();
+        let fun = main.code.add_const(Value::Fn(Rc::new(def)));
+        main.code.add(OpDefine(fun), 1);
+        assert_eq!(Ok(()), main.validate());
+    }
+
+    #[test]
+    fn validate_const_out_of_bounds() {
+        let mut def = FnDef::new();
+        def.code.add(OpConst(0), 1);
+        assert_eq!(
+            Err("__main__: OpConst(0) at 0000 refers to missing constant".to_owned()),
+            def.validate()
+        );
+    }
+
+    #[test]
+    fn validate_name_not_string() {
+        let mut def = FnDef::new();
+        let idx = def.code.add_const(Value::Num(1.0));
+        def.code.add(OpUnit, 1);
+        def.code.add(OpStore(idx), 1);
+        assert_eq!(
+            Err("__main__: OpStore(0) at 0001 expected a string constant but found Num(1.0)"
+                .to_owned()),
+            def.validate()
+        );
+    }
+
+    #[test]
+    fn validate_define_not_fn() {
+        let mut def = FnDef::new();
+        let idx = def.code.add_const(Value::Str("f".into()));
+        def.code.add(OpDefine(idx), 1);
+        assert!(def.validate().unwrap_err().contains("expected a function constant"));
+    }
+
+    #[test]
+    fn validate_nested() {
+        let mut inner = FnDef::with("inner".to_owned(), Vec::new());
+        inner.code.add(OpLoad(7), 1);
+        let mut def = FnDef::new();
+        let idx = def.code.add_const(Value::Fn(Rc::new(inner)));
+        def.code.add(OpDefine(idx), 1);
+        assert_eq!(
+            Err("inner: OpLoad(7) at 0000 refers to missing constant".to_owned()),
+            def.validate()
+        );
+    }
+
+    #[test]
+    fn interner_dedups() {
+        let mut interner = StringInterner::new();
+        let a = interner.intern("foo");
+        let b = interner.intern(&String::from("foo"));
+        let c = interner.intern("bar");
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &c));
+        assert_eq!(2, interner.len());
+        assert!(Rc::ptr_eq(&intern("baz"), &intern("baz")));
+    }
+
+    #[test]
+    fn equal_values_hash_equal() {
+        let pairs = vec![
+            (Value::Unit, Value::Unit),
+            (Value::Bool(true), Value::Bool(true)),
+            (Value::Num(1.5), Value::Num(1.5)),
+            (Value::Num(0.0), Value::Num(-0.0)),
+            (Value::Str("a".into()), Value::Str(String::from("a").into())),
+            (Value::list(vec![Value::Num(1.0)]), Value::list(vec![Value::Num(1.0)])),
+        ];
+        for (a, b) in pairs {
+            assert!(a == b);
+            assert_eq!(a.hash_code(), b.hash_code(), "{:?}", a);
+        }
+
+        let def = Rc::new(FnDef::new());
+        let f = Value::Fn(def.clone());
+        assert_eq!(f.hash_code(), Value::Fn(def).hash_code());
+        assert_ne!(Value::Unit.hash_code(), Value::Bool(false).hash_code());
+        assert_ne!(Value::Num(1.0).hash_code(), Value::Num(2.0).hash_code());
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn values_as_map_keys() {
+        use std::collections::HashMap;
+
+        let mut map = HashMap::new();
+        map.insert(Value::Num(1.0), "one");
+        map.insert(Value::Str("one".into()), "str");
+        map.insert(Value::Num(-0.0), "zero");
+        assert_eq!(Some(&"one"), map.get(&Value::Num(1.0)));
+        assert_eq!(Some(&"str"), map.get(&Value::Str("one".into())));
+        assert_eq!(Some(&"zero"), map.get(&Value::Num(0.0)));
+
+        // NaN is never equal to itself, so every insert adds a new entry that cannot be found.
+        assert_eq!(Value::Num(f64::NAN).hash_code(), Value::Num(-f64::NAN).hash_code());
+        map.insert(Value::Num(f64::NAN), "nan");
+        map.insert(Value::Num(f64::NAN), "nan");
+        assert_eq!(5, map.len());
+        assert_eq!(None, map.get(&Value::Num(f64::NAN)));
+    }
+
+    #[test]
+    fn ordering() {
+        let mut vals = [
+            Value::Str("b".into()),
+            Value::Num(f64::NAN),
+            Value::Num(2.0),
+            Value::Bool(true),
+            Value::Str("a".into()),
+            Value::Num(-1.5),
+            Value::Unit,
+            Value::Bool(false),
+        ];
+        vals.sort();
+        let out: Vec<String> = vals.iter().map(|v| format!("{:?}", v)).collect();
+        let exp = "Unit Bool(false) Bool(true) Num(-1.5) Num(2.0) Num(NaN) Str(\"a\") Str(\"b\")";
+        assert_eq!(exp, out.join(" "));
+
+        assert_eq!(Ordering::Equal, Value::Num(0.0).cmp(&Value::Num(-0.0)));
+        let l1 = Value::list(vec![Value::Num(1.0), Value::Num(2.0)]);
+        let l2 = Value::list(vec![Value::Num(1.0), Value::Num(3.0)]);
+        assert!(l1 < l2);
+    }
+
+    #[test]
+    fn compare() {
+        let num = |n| Value::Num(n);
+        let s = |s: &str| Value::Str(s.into());
+        assert_eq!(Some(Ordering::Less), num(-1.5).compare(&num(2.0)));
+        assert_eq!(Some(Ordering::Equal), num(0.0).compare(&num(-0.0)));
+        assert_eq!(Some(Ordering::Greater), num(10.0).compare(&num(9.0)));
+        assert_eq!(None, num(f64::NAN).compare(&num(f64::NAN)));
+        assert_eq!(None, num(1.0).compare(&num(f64::NAN)));
+
+        assert_eq!(Some(Ordering::Less), s("abc").compare(&s("abd")));
+        assert_eq!(Some(Ordering::Less), s("ab").compare(&s("abc")));
+        assert_eq!(Some(Ordering::Greater), s("b").compare(&s("abc")));
+        assert_eq!(Some(Ordering::Equal), s("").compare(&s("")));
+
+        // Across types and for other types there is no order, even though `Ord` has one.
+        assert_eq!(None, num(1.0).compare(&s("1")));
+        assert_eq!(None, Value::Unit.compare(&Value::Unit));
+        assert_eq!(None, Value::Bool(false).compare(&Value::Bool(true)));
+        let list = Value::list(vec![num(1.0)]);
+        assert_eq!(None, list.compare(&list));
+        assert!(Value::Bool(false) < Value::Bool(true));
+    }
+
+    #[test]
+    fn list_deep_clone() {
+        let list = Value::list(vec![Value::list(vec![Value::Unit])]);
+        let copy = list.deep_clone();
+        assert!(list == copy);
+        let inner = copy.into_list().borrow()[0].clone().into_list();
+        inner.borrow_mut().push(Value::Unit);
+        assert!(list != Value::list(vec![Value::list(vec![Value::Unit, Value::Unit])]));
     }
 
     #[test]
@@ -206,10 +1255,10 @@ mod tests {
 
         assert!(Value::Num(1.2) == Value::Num(1.2));
         assert!(Value::Num(1.2) != Value::Bool(true));
-        assert!(Value::Num(1.2) != Value::Str("foo".to_owned()));
+        assert!(Value::Num(1.2) != Value::Str("foo".into()));
 
-        assert!(Value::Str("foo".to_owned()) == Value::Str("foo".to_owned()));
-        assert!(Value::Str("foo".to_owned()) != Value::Str("bar".to_owned()));
-        assert!(Value::Str("foo".to_owned()) != Value::Bool(true));
+        assert!(Value::Str("foo".into()) == Value::Str("foo".into()));
+        assert!(Value::Str("foo".into()) != Value::Str("bar".into()));
+        assert!(Value::Str("foo".into()) != Value::Bool(true));
     }
 }
@@ -5,45 +5,232 @@
 //! "self-contained". This means that `Coro` objects manage their own state and executes their own
 //! code. Thus, the "virtual machine" (VM) here is just a wrapper/helper for invoking coroutines.
 
+use std::cell::Cell;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
 use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Instant;
 
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use crate::ast::Ast;
+use crate::ast::Bind;
+use crate::ast::Cmd;
+use crate::ast::CmdKind;
+use crate::ast::DefBind;
+use crate::builtins;
+use crate::builtins::ScriptExit;
+use crate::bytecode;
 use crate::cgen;
+use crate::code::Instr;
 use crate::code::Instr::*;
+use crate::code::Upvalue;
 use crate::debug;
+use crate::debug::Debugger;
+use crate::error::CoError;
 use crate::parse;
+use crate::value::range_nth;
+use crate::value::slice_range;
+use crate::value::Captured;
 use crate::value::FnDef;
+use crate::value::NativeFn;
+use crate::value::MAX_SAFE_INT;
 use crate::value::Value;
 
+/// Outcome of `CoVM::eval`, which reports errors itself.
+#[derive(Debug)]
 pub enum CoRes {
     Ok,
     CompileErr,
     RuntimeErr,
+    /// The script called `exit` with this code, and an exit handler was set.
+    Exit(i32),
 }
 
+/// Entry point for compiling and running Coro code.
+#[derive(Debug)]
 pub struct CoVM;
 
+/// Extension used by Coro scripts, which is appended when resolving module names.
+pub const MODULE_EXT: &str = "co";
+
+thread_local! {
+    // The coroutines that are calling a native function right now, innermost last. Natives only
+    // get their arguments, so this is how the Coro functions they call back into get to run with
+    // the same limits and output as the code that called the native.
+    static CALLERS: RefCell<Vec<Caller>> = const { RefCell::new(Vec::new()) };
+}
+
+// A writer shared by a coroutine and the coroutines it creates.
+type Output = Rc<RefCell<Box<dyn Write>>>;
+
+// The function given to `CoVMBuilder::with_exit_handler`.
+#[derive(Clone)]
+pub(crate) struct ExitHandler(Rc<dyn Fn(i32)>);
+
+impl ExitHandler {
+    pub(crate) fn call(&self, code: i32) {
+        (self.0)(code)
+    }
+}
+
+impl fmt::Debug for ExitHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<exit handler>")
+    }
+}
+
+// A reader shared by the coroutines built from the same config.
+pub(crate) type Input = Rc<RefCell<Box<dyn BufRead>>>;
+
+// The reader given to `CoVMBuilder::with_input`, which has no `Debug` of its own.
+#[derive(Clone)]
+pub(crate) struct ConfigInput(pub(crate) Input);
+
+impl fmt::Debug for ConfigInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<input>")
+    }
+}
+
+// The writer given to `CoVMBuilder::with_stdout`, which has no `Debug` of its own.
+#[derive(Clone)]
+struct ConfigOutput(Output);
+
+impl fmt::Debug for ConfigOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<output>")
+    }
+}
+
+// What a coroutine hands down to the coroutines it creates.
+#[derive(Clone)]
+struct Caller {
+    debugger: Option<Weak<Mutex<dyn Debugger>>>,
+    limits: Rc<Limits>,
+    output: Option<Output>,
+}
+
+impl Caller {
+    fn of(co: &Coro) -> Self {
+        Self { debugger: co.debugger.clone(), limits: co.limits.clone(), output: co.output.clone() }
+    }
+
+    // The innermost coroutine calling a native function, if any.
+    fn current() -> Option<Self> {
+        CALLERS.with(|callers| callers.borrow().last().cloned())
+    }
+
+    fn spawn(self, fun: Rc<FnDef>) -> Coro {
+        let mut coro = Coro::new(fun);
+        coro.debugger = self.debugger;
+        coro.limits = self.limits;
+        coro.output = self.output;
+        coro
+    }
+
+    // Make this the current caller until the guard is dropped, which also happens when `exit`
+    // unwinds through the native.
+    fn enter(self) -> CallerGuard {
+        CALLERS.with(|callers| callers.borrow_mut().push(self));
+        CallerGuard
+    }
+}
+
+struct CallerGuard;
+
+/// Create a coroutine for a function as if the coroutine that is calling a native function right
+/// now had created it, so that it shares the same limits, output and debugger. Outside of any
+/// native, this is the same as `Coro::new`.
+pub(crate) fn spawn(fun: Rc<FnDef>) -> Coro {
+    match Caller::current() {
+        Some(caller) => caller.spawn(fun),
+        None => Coro::new(fun),
+    }
+}
+
+/// The Rust code behind a coroutine made by `spawn_native`. Each resume calls it for the next value
+/// to yield, or `None` once the coroutine is done.
+pub(crate) type NativeStep = Box<dyn FnMut() -> Result<Option<Value>, String>>;
+
+/// Create a coroutine that runs `step` instead of Coro code, e.g. for the built-ins that combine
+/// other coroutines. It is made the same way as with `spawn`, so it shares the same limits.
+pub(crate) fn spawn_native(name: &str, step: NativeStep) -> Coro {
+    let mut coro = spawn(Rc::new(FnDef::with(name.to_owned(), Vec::new())));
+    coro.native = Some(step);
+    coro
+}
+
+/// Write `text` to where `print` writes in the coroutine that is calling a native function right
+/// now, or stdout outside of any native, and flush it, e.g. for a prompt.
+pub(crate) fn write_output(text: &str) -> io::Result<()> {
+    match Caller::current().and_then(|caller| caller.output) {
+        Some(output) => {
+            let mut output = output.borrow_mut();
+            output.write_all(text.as_bytes())?;
+            output.flush()
+        }
+        None => {
+            let mut stdout = io::stdout();
+            stdout.write_all(text.as_bytes())?;
+            stdout.flush()
+        }
+    }
+}
+
+/// The limits of the coroutine that is calling a native function right now, or the defaults when
+/// a native is called from outside of any coroutine. This is how built-ins get at the settings of
+/// the VM that is running them.
+pub(crate) fn caller_limits() -> Rc<Limits> {
+    Caller::current().map_or_else(Rc::default, |caller| caller.limits)
+}
+
+impl Drop for CallerGuard {
+    fn drop(&mut self) {
+        CALLERS.with(|callers| callers.borrow_mut().pop());
+    }
+}
+
 impl CoVM {
-    pub fn build(src: &str) -> Result<Coro, String> {
-        let def = Self::compile(src)?;
-        Ok(Coro::new(def))
+    /// Start configuring how code is run, e.g. to limit how long it can run for.
+    pub fn builder() -> CoVMBuilder {
+        CoVMBuilder(CoVMConfig::default())
     }
 
-    pub fn compile(src: &str) -> Result<Rc<FnDef>, String> {
-        let ast = match parse::parse_ast(src) {
-            Ok(tree) => tree,
-            Err(e) => return Err(format!("{}", e)),
-        };
+    pub fn build(src: &str) -> Result<Coro, CoError> {
+        Self::builder().build(src)
+    }
+
+    pub fn compile(src: &str) -> Result<Rc<FnDef>, CoError> {
+        let ast = parse::parse_ast(src)?;
+        Self::compile_ast(ast)
+    }
 
+    fn compile_ast(ast: Ast) -> Result<Rc<FnDef>, CoError> {
         if cfg!(feature = "ast") {
             eprintln!("{:?}", ast);
         }
 
-        let code = cgen::compile(ast);
+        let code = cgen::compile(ast)?;
         let mut def = FnDef::new();
         def.code = code;
+        def.validate().map_err(|msg| CoError::compile(msg, 0, 0))?;
+        def.set_max_stack(def.code.max_stack().unwrap_or_default());
 
         if cfg!(feature = "dbg") {
             eprintln!("{}", def);
@@ -55,32 +242,400 @@ impl CoVM {
         Ok(Rc::new(def))
     }
 
+    /// Compile the source and write its bytecode to a file, to be run later with `load_from_file`.
+    pub fn compile_to_file(src: &str, path: &Path) -> Result<(), CoError> {
+        let def = Self::compile(src)?;
+        let bytes = bytecode::write(&def).map_err(|msg| CoError::compile(msg, 0, 0))?;
+        std::fs::write(path, bytes).map_err(|e| {
+            CoError::compile(format!("could not write '{}': {}", path.display(), e), 0, 0)
+        })
+    }
+
+    /// Read back bytecode written by `compile_to_file`.
+    pub fn load_from_file(path: &Path) -> Result<Rc<FnDef>, CoError> {
+        let bytes = std::fs::read(path).map_err(|e| {
+            CoError::compile(format!("could not read '{}': {}", path.display(), e), 0, 0)
+        })?;
+        let def = bytecode::read(&bytes).map_err(|msg| {
+            CoError::compile(format!("bad bytecode in '{}': {}", path.display(), msg), 0, 0)
+        })?;
+        Ok(Rc::new(def))
+    }
+
+    /// Compile a single function from its name, parameters and body, e.g. to build up a library
+    /// of functions in Rust. See `inject_fn` for giving it to a coroutine.
+    pub fn compile_fn(name: &str, params: &[&str], body: &str) -> Result<Rc<FnDef>, String> {
+        let body = parse::parse_cmd_ast(body).map_err(|e| e.to_string())?;
+        let params = params.iter().map(|&param| param.to_owned()).collect();
+        let mut ast = Ast::new();
+        ast.items.push(Bind::Def(DefBind::new(name.to_owned(), params, body)));
+        let code = cgen::compile(ast).map_err(|e| e.to_string())?;
+
+        // The code only defines the function, which is its one constant.
+        let def = match code.constants() {
+            [Value::Fn(def)] => def.clone(),
+            _ => return Err(format!("'{}' did not compile to a single function", name)),
+        };
+        def.validate()?;
+        Ok(def)
+    }
+
+    /// Bind a function in the env of the coroutine under its own name, same as a `def` would.
+    pub fn inject_fn(co: &mut Coro, def: Rc<FnDef>) {
+        let name = def.name().to_owned();
+        co.set_var(&name, Value::Fn(def));
+    }
+
+    /// Evaluate a single expression in a fresh coroutine and return its value.
+    pub fn eval_expr(src: &str) -> Result<Value, CoError> {
+        let expr = parse::parse_expr_ast(src)?;
+        let mut ast = Ast::new();
+        let span = expr.span;
+        ast.items.push(Bind::Cmd(Cmd::new(CmdKind::Expr(expr), span)));
+        let mut co = Coro::new(Self::compile_ast(ast)?);
+        Self::run(&mut co)
+    }
+
+    /// Start building coroutines that can use the file built-ins, see
+    /// `CoVMBuilder::with_file_io`.
+    pub fn with_file_io() -> CoVMBuilder {
+        Self::builder().with_file_io()
+    }
+
+    /// Start building coroutines whose `args` built-in gives `args`, see `CoVMBuilder::with_args`.
+    pub fn with_args(args: Vec<String>) -> CoVMBuilder {
+        Self::builder().with_args(args)
+    }
+
+    /// Start building coroutines with a seeded generator, see `CoVMBuilder::with_random_seed`.
+    pub fn with_random_seed(seed: u64) -> CoVMBuilder {
+        Self::builder().with_random_seed(seed)
+    }
+
+    /// Start building coroutines whose `exit` calls `handler`, see
+    /// `CoVMBuilder::with_exit_handler`.
+    pub fn with_exit_handler(handler: Box<dyn Fn(i32)>) -> CoVMBuilder {
+        Self::builder().with_exit_handler(handler)
+    }
+
+    /// Start building coroutines that can use the JSON built-ins, see `CoVMBuilder::with_json`.
+    pub fn with_json() -> CoVMBuilder {
+        Self::builder().with_json()
+    }
+
+    /// Start building coroutines that print to `writer`, see `CoVMBuilder::with_stdout`.
+    pub fn with_stdout(writer: Box<dyn Write>) -> CoVMBuilder {
+        Self::builder().with_stdout(writer)
+    }
+
+    /// Run the source, and return its value along with everything it printed.
+    pub fn capture_output(src: &str) -> Result<(Value, String), CoError> {
+        let buf = SharedBuf::default();
+        let mut co = Self::build(src)?;
+        co.set_output(Box::new(buf.clone()));
+        let val = Self::run(&mut co)?;
+        let out = String::from_utf8_lossy(&buf.0.borrow()).into_owned();
+        Ok((val, out))
+    }
+
+    /// Start building coroutines whose `input` reads from `reader`, see `CoVMBuilder::with_input`.
+    pub fn with_input(reader: Box<dyn BufRead>) -> CoVMBuilder {
+        Self::builder().with_input(reader)
+    }
+
     // Replace with function, reset state, while keeping env.
     // Useful for things like the REPL.
     pub fn rewind(co: &mut Coro, fun: Rc<FnDef>) {
+        // Slots belong to the old function, so move what they hold over to the env.
+        co.env = co.vars();
+        co.locals = vec![None; fun.code.locals().len()];
+        co.upvalues = Coro::new_upvalues(&fun);
         co.ip = 0;
         co.fun = fun;
         co.status = CoStatus::Suspended;
         co.stack.clear();
+        co.stack.reserve(co.fun.max_stack());
+        co.tail_called = false;
+        if let Some(coverage) = &mut co.coverage {
+            *coverage = vec![false; co.fun.code.len()];
+        }
     }
 
-    pub fn run(co: &mut Coro) -> Result<Value, String> {
+    pub fn run(co: &mut Coro) -> Result<Value, CoError> {
         co.resume(Vec::new())
     }
 
+    /// Call a function value with `args`, same as `f(args)` in Coro code. This lets native
+    /// functions call back into Coro functions, which then run with the limits, output and
+    /// debugger of the coroutine that called the native. Errors are not located in the caller's
+    /// code.
+    pub fn call(fun: &Value, args: Vec<Value>) -> Result<Value, CoError> {
+        match fun {
+            Value::Fn(def) => spawn(def.clone()).resume(args),
+            Value::NativeFn(native) => check_native_args(native, args.len())
+                .and_then(|_| native.call(args))
+                .map_err(|msg| CoError::runtime(msg, 0, 0)),
+            _ => Err(CoError::runtime("only functions can be called", 0, 0)),
+        }
+    }
+
+    /// Attach a debugger to the coroutine and any coroutines it creates. The coroutine only holds
+    /// a weak reference, so the returned handle needs to be kept alive for the debugger to work.
+    pub fn attach_debugger(co: &mut Coro, debugger: Box<dyn Debugger>) -> Arc<Mutex<dyn Debugger>> {
+        let debugger: Arc<Mutex<dyn Debugger>> = Arc::new(Mutex::new(debugger));
+        co.set_debugger(Arc::downgrade(&debugger));
+        debugger
+    }
+
+    /// Wrap a coroutine for stepping through it one instruction at a time.
+    pub fn debug_session(coro: Coro) -> DebugSession {
+        DebugSession { coro }
+    }
+
     pub fn eval(src: &str) -> CoRes {
-        let mut co = match Self::build(src) {
-            Ok(co) => co,
+        Self::builder().eval(src)
+    }
+}
+
+// Longest a value is shown by `Coro::format_stack`, in chars.
+const MAX_STACK_VALUE_LEN: usize = 32;
+
+/// How deeply coroutines can be nested by default, i.e. resumed or called from one another. This
+/// leaves room on the 2 MiB stack of a spawned thread, even in debug builds.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Settings for running code, see `CoVM::builder`.
+#[derive(Debug, Clone)]
+pub struct CoVMConfig {
+    max_depth: usize,
+    max_steps: Option<u64>,
+    color_output: bool,
+    includes: Vec<PathBuf>,
+    script_dir: Option<PathBuf>,
+    search_paths: Vec<PathBuf>,
+    initial_env: HashMap<String, Value>,
+    stdlib: bool,
+    file_io: bool,
+    json: bool,
+    args: Vec<String>,
+    stdout: Option<ConfigOutput>,
+    input: Option<ConfigInput>,
+    random_seed: Option<u64>,
+    exit_handler: Option<ExitHandler>,
+    strict_math: bool,
+    started: Instant,
+}
+
+impl Default for CoVMConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_steps: None,
+            color_output: false,
+            includes: Vec::new(),
+            script_dir: None,
+            search_paths: Vec::new(),
+            initial_env: HashMap::new(),
+            stdlib: true,
+            file_io: false,
+            json: false,
+            args: Vec::new(),
+            stdout: None,
+            input: None,
+            random_seed: None,
+            exit_handler: None,
+            strict_math: false,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl CoVMConfig {
+    /// The directories searched for modules, in order:
+    ///
+    /// 1. directories added with `CoVMBuilder::with_include_path` (the `--include` flag)
+    /// 2. the directory of the script, see `CoVMBuilder::with_script_dir`
+    /// 3. entries of the `CORO_PATH` environment variable, which is separated by `:` on Unix and
+    ///    `;` on Windows
+    /// 4. directories added with `CoVMBuilder::with_search_path`
+    pub fn search_path(&self) -> Vec<PathBuf> {
+        self.search_path_with(env::var_os("CORO_PATH"))
+    }
+
+    fn search_path_with(&self, coro_path: Option<OsString>) -> Vec<PathBuf> {
+        let mut dirs = self.includes.clone();
+        dirs.extend(self.script_dir.clone());
+        if let Some(coro_path) = coro_path {
+            dirs.extend(env::split_paths(&coro_path).filter(|p| !p.as_os_str().is_empty()));
+        }
+        dirs.extend(self.search_paths.iter().cloned());
+        dirs
+    }
+
+    /// Find the file for module `name` by appending the script extension, unless the name already
+    /// ends with it, and looking in each directory of the search path. The first match wins.
+    pub fn resolve_module(&self, name: &str) -> Option<PathBuf> {
+        Self::resolve_in(name, &self.search_path())
+    }
+
+    fn resolve_in(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+        // Other extensions are part of the name, e.g. `lib.v1` is `lib.v1.co`.
+        let file = match Path::new(name).extension() {
+            Some(ext) if ext == MODULE_EXT => PathBuf::from(name),
+            _ => PathBuf::from(format!("{}.{}", name, MODULE_EXT)),
+        };
+        dirs.iter().map(|dir| dir.join(&file)).find(|path| path.is_file())
+    }
+}
+
+/// Builds coroutines from source with a `CoVMConfig`. The limits apply to the coroutine and all
+/// the coroutines it creates.
+#[derive(Debug, Clone)]
+pub struct CoVMBuilder(CoVMConfig);
+
+impl CoVMBuilder {
+    /// Limit how deeply coroutines can be nested, i.e. resumed or called from one another.
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.0.max_depth = depth;
+        self
+    }
+
+    /// Limit how many instructions can be executed in total, e.g. to stop runaway loops.
+    pub fn with_max_steps(mut self, steps: u64) -> Self {
+        self.0.max_steps = Some(steps);
+        self
+    }
+
+    /// Highlight errors reported by `eval` with terminal colors.
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.0.color_output = color;
+        self
+    }
+
+    /// Whether names not bound by the code fall back to the built-in functions. On by default.
+    pub fn with_stdlib(mut self, stdlib: bool) -> Self {
+        self.0.stdlib = stdlib;
+        self
+    }
+
+    /// Enable the `read_file` and `write_file` built-ins. These are off by default, so embedders
+    /// that run untrusted scripts do not give them access to the file system.
+    pub fn with_file_io(mut self) -> Self {
+        self.0.file_io = true;
+        self
+    }
+
+    /// Enable the `parse_json` and `to_json` built-ins.
+    pub fn with_json(mut self) -> Self {
+        self.0.json = true;
+        self
+    }
+
+    /// Set the command-line arguments that the `args` built-in gives to scripts.
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.0.args = args;
+        self
+    }
+
+    /// Make the `input` built-in read from `reader` instead of stdin, e.g. to script a session.
+    /// Every coroutine built from this config reads from the same `reader`.
+    pub fn with_input(mut self, reader: Box<dyn BufRead>) -> Self {
+        self.0.input = Some(ConfigInput(Rc::new(RefCell::new(reader))));
+        self
+    }
+
+    /// Seed the `rand` and `rand_int` built-ins, so that scripts using them can be reproduced.
+    /// Each coroutine built from this config starts from the same seed.
+    pub fn with_random_seed(mut self, seed: u64) -> Self {
+        self.0.random_seed = Some(seed);
+        self
+    }
+
+    /// Make the `exit` built-in call `handler` instead of ending the process, e.g. when embedding
+    /// the VM in a larger program. After the handler returns, the run ends with `CoError::Exit`,
+    /// which `eval` turns into `CoRes::Exit`.
+    pub fn with_exit_handler(mut self, handler: Box<dyn Fn(i32)>) -> Self {
+        self.0.exit_handler = Some(ExitHandler(Rc::from(handler)));
+        self
+    }
+
+    /// Make `print` write to `writer` instead of stdout. Every coroutine built from this config
+    /// writes to the same `writer`, see `Coro::set_output` to redirect a single coroutine.
+    pub fn with_stdout(mut self, writer: Box<dyn Write>) -> Self {
+        self.0.stdout = Some(ConfigOutput(Rc::new(RefCell::new(writer))));
+        self
+    }
+
+    /// Make arithmetic that results in infinity or NaN a runtime error, see
+    /// `Coro::set_strict_math`.
+    pub fn with_strict_math(mut self, strict: bool) -> Self {
+        self.0.strict_math = strict;
+        self
+    }
+
+    /// Add a directory to the front of the module search path, e.g. for `--include`. Directories
+    /// added this way are searched in the order they were added, see `CoVMConfig::search_path`.
+    pub fn with_include_path(mut self, path: PathBuf) -> Self {
+        self.0.includes.push(path);
+        self
+    }
+
+    /// Set the directory of the script being executed, so modules next to it can be found.
+    pub fn with_script_dir(mut self, path: PathBuf) -> Self {
+        self.0.script_dir = Some(path);
+        self
+    }
+
+    /// Add a directory to the end of the module search path, see `CoVMConfig::search_path`.
+    pub fn with_search_path(mut self, path: PathBuf) -> Self {
+        self.0.search_paths.push(path);
+        self
+    }
+
+    /// Bind a name in the env of the coroutine before it starts.
+    pub fn with_env_var(mut self, name: &str, val: Value) -> Self {
+        self.0.initial_env.insert(name.to_owned(), val);
+        self
+    }
+
+    pub fn config(&self) -> &CoVMConfig {
+        &self.0
+    }
+
+    /// Compile the source into a coroutine that is ready to run.
+    pub fn build(&self, src: &str) -> Result<Coro, CoError> {
+        Ok(self.build_fn(CoVM::compile(src)?))
+    }
+
+    /// Build a coroutine for a function that was already compiled, e.g. by `CoVM::load_from_file`.
+    pub fn build_fn(&self, def: Rc<FnDef>) -> Coro {
+        let config = &self.0;
+        let mut co = Coro::new(def);
+        co.limits = Rc::new(Limits::new(config));
+        co.output = config.stdout.as_ref().map(|stdout| stdout.0.clone());
+        co.env = config.initial_env.clone();
+        co
+    }
+
+    /// Build and run the source, reporting any errors to stderr.
+    pub fn eval(&self, src: &str) -> CoRes {
+        match CoVM::compile(src) {
+            Ok(def) => self.eval_fn(def),
             Err(e) => {
-                eprintln!("[coro] compile error:\n{}", e);
-                return CoRes::CompileErr;
+                eprintln!("{} compile error:\n{}", self.prefix(), e);
+                CoRes::CompileErr
             }
-        };
+        }
+    }
 
-        let val = match Self::run(&mut co) {
+    /// Run a function that was already compiled, reporting any errors to stderr.
+    pub fn eval_fn(&self, def: Rc<FnDef>) -> CoRes {
+        let mut co = self.build_fn(def);
+        let val = match CoVM::run(&mut co) {
             Ok(val) => val,
+            Err(CoError::Exit(code)) => return CoRes::Exit(code),
             Err(msg) => {
-                eprintln!("[coro] runtime error: {}", msg);
+                eprintln!("{} runtime error: {}", self.prefix(), msg);
                 return CoRes::RuntimeErr;
             }
         };
@@ -91,8 +646,92 @@ impl CoVM {
 
         CoRes::Ok
     }
+
+    fn prefix(&self) -> &'static str {
+        if self.0.color_output {
+            "\x1b[1;31m[coro]\x1b[0m"
+        } else {
+            "[coro]"
+        }
+    }
+}
+
+// Output sink that can still be read after handing it to a coroutine.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The limits from `CoVMConfig`, which a coroutine shares with the coroutines it creates, along
+/// with how much of them has been used up so far.
+#[derive(Debug)]
+pub(crate) struct Limits {
+    max_depth: usize,
+    max_steps: Option<u64>,
+    stdlib: bool,
+    file_io: bool,
+    json: bool,
+    pub(crate) args: Vec<String>,
+    // What the `clock` built-in measures from.
+    pub(crate) started: Instant,
+    // Where `rand` and `rand_int` get numbers from, which is seeded from entropy unless the
+    // config has a seed.
+    pub(crate) rng: RefCell<SmallRng>,
+    // What `exit` calls instead of ending the process.
+    pub(crate) exit_handler: Option<ExitHandler>,
+    // Where `input` reads from, which is stdin unless replaced.
+    pub(crate) input: Option<ConfigInput>,
+    strict_math: Cell<bool>,
+    depth: Cell<usize>,
+    steps: Cell<u64>,
+}
+
+impl Limits {
+    fn new(config: &CoVMConfig) -> Self {
+        Self {
+            max_depth: config.max_depth,
+            max_steps: config.max_steps,
+            stdlib: config.stdlib,
+            file_io: config.file_io,
+            json: config.json,
+            args: config.args.clone(),
+            started: config.started,
+            rng: RefCell::new(match config.random_seed {
+                Some(seed) => SmallRng::seed_from_u64(seed),
+                None => SmallRng::from_entropy(),
+            }),
+            exit_handler: config.exit_handler.clone(),
+            input: config.input.clone(),
+            strict_math: Cell::new(config.strict_math),
+            depth: Cell::new(0),
+            steps: Cell::new(0),
+        }
+    }
+
+    // Whether the built-in with this name can be used, as some have to be opted in to.
+    fn allows(&self, name: &str) -> bool {
+        self.stdlib
+            && (self.file_io || !builtins::FILE_IO.contains(&name))
+            && (self.json || !builtins::JSON.contains(&name))
+    }
 }
 
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new(&CoVMConfig::default())
+    }
+}
+
+/// Where a coroutine is in its life: it can be resumed while suspended, and is done once it
+/// returns.
 #[derive(Debug, PartialEq)]
 pub enum CoStatus {
     Suspended,
@@ -100,19 +739,76 @@ pub enum CoStatus {
     Done,
 }
 
+// What is left of a step once the instruction is decoded. Calls and resumes run a nested coroutine,
+// so they are done after leaving `step_instr`, whose frame is big, to keep deep nesting from
+// running out of Rust stack.
+enum Step {
+    Done(StepResult),
+    Call(usize),
+    Resume(usize),
+}
+
+/// Outcome of executing a single instruction with `Coro::step`.
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    /// The instruction was `OpYield`, and the coroutine is now suspended.
+    Yielded(Value),
+    /// The instruction was `OpRet` (or we ran out of code), and the coroutine is now done.
+    Returned(Value),
+    /// Any other instruction, and execution can continue.
+    Stepped,
+}
+
+/// A coroutine, which runs a function with its own env and stack until it yields or returns.
 pub struct Coro {
     ip: usize,
     fun: Rc<FnDef>,
     status: CoStatus,
     env: HashMap<String, Value>,
+    // Values of the local variables of `fun`, by slot. Names that are not bound in a slot are
+    // looked up in `env`, which holds anything bound from outside, e.g. by `inject_fn`.
+    locals: Vec<Option<Value>>,
+    // Cells of the upvalues of `fun`, which are shared with the functions that capture them.
+    upvalues: Vec<Captured>,
     stack: Vec<Value>,
+    debugger: Option<Weak<Mutex<dyn Debugger>>>,
+    watches: Vec<Watch>,
+    op_counts: Option<Box<[u64; Instr::NUM_OPS]>>,
+    coverage: Option<Vec<bool>>,
+    limits: Rc<Limits>,
+    // Where `print` writes to, which is stdout unless replaced.
+    output: Option<Output>,
+    // Whether this coroutine has taken over a function from a tail call, see `OpCall`.
+    tail_called: bool,
+    // What runs instead of `fun` for a coroutine made by `spawn_native`.
+    native: Option<NativeStep>,
+}
+
+#[derive(Clone)]
+struct Watch {
+    name: String,
+    expr: Rc<FnDef>,
+    last: Option<Value>,
 }
 
 impl fmt::Display for Coro {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let status = format!("{:?}", self.status);
-        let status = status.to_lowercase();
-        write!(f, "<coro fn: {} status: {}>", self.fun.name(), status)
+        write!(f, "<coro fn: {} status: {}>", self.fun.name(), self.status_name())
+    }
+}
+
+impl fmt::Debug for Coro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Sort the env so that the output is stable.
+        let vars = self.vars();
+        let env: BTreeMap<_, _> = vars.iter().collect();
+        f.debug_struct("Coro")
+            .field("fn", &format_args!("{}", self.fun.name()))
+            .field("ip", &self.ip)
+            .field("status", &self.status)
+            .field("stack", &self.stack)
+            .field("env", &env)
+            .finish()
     }
 }
 
@@ -120,24 +816,191 @@ impl Coro {
     pub fn new(fun: Rc<FnDef>) -> Self {
         Self {
             ip: 0,
+            locals: vec![None; fun.code.locals().len()],
+            upvalues: Self::new_upvalues(&fun),
+            stack: Vec::with_capacity(fun.max_stack()),
             fun,
             status: CoStatus::Suspended,
             env: HashMap::new(),
-            stack: Vec::new(),
+            debugger: None,
+            watches: Vec::new(),
+            op_counts: None,
+            coverage: None,
+            limits: Rc::default(),
+            output: None,
+            tail_called: false,
+            native: None,
+        }
+    }
+
+    // The cells the function captured, along with fresh ones for its locals that nested functions
+    // capture.
+    fn new_upvalues(fun: &FnDef) -> Vec<Captured> {
+        let mut captured = fun.captured().iter();
+        let cell = |upvalue: &Upvalue| {
+            if upvalue.local {
+                Captured::default()
+            } else {
+                captured.next().cloned().unwrap_or_default()
+            }
+        };
+        fun.code.upvalues().iter().map(cell).collect()
+    }
+
+    /// Create a new coroutine that shares this coroutine's debugging hooks and limits.
+    fn spawn(&self, fun: Rc<FnDef>) -> Self {
+        Caller::of(self).spawn(fun)
+    }
+
+    // Natives can call back into Coro code with `CoVM::call`, which runs it as if this coroutine
+    // had created it.
+    fn call_native(&self, native: &NativeFn, args: Vec<Value>) -> Result<Value, String> {
+        check_native_args(native, args.len())?;
+        let _caller = Caller::of(self).enter();
+        native.call(args)
+    }
+
+    /// Whether arithmetic that results in infinity or NaN is a runtime error, rather than giving
+    /// back the non-finite number. Off by default. This applies to coroutines created from this one
+    /// as well.
+    pub fn set_strict_math(&mut self, strict: bool) {
+        self.limits.strict_math.set(strict);
+    }
+
+    /// Make `print` write to `output` instead of stdout. Coroutines created from this one write
+    /// there too.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = Some(Rc::new(RefCell::new(output)));
+    }
+
+    pub fn set_debugger(&mut self, debugger: Weak<Mutex<dyn Debugger>>) {
+        self.debugger = Some(debugger);
+    }
+
+    /// Make an independent copy of a suspended coroutine, so that it can be run multiple times from
+    /// the same point. The env and stack are deep-cloned, so lists are not shared between the
+    /// copies. Child coroutines are shared rather than copied, so resuming one from either copy
+    /// will be visible to both.
+    pub fn clone_suspended(&self) -> Result<Coro, String> {
+        if self.status != CoStatus::Suspended {
+            return Err(format!("cannot clone a coroutine that is {}", self.status_name()));
+        }
+        if self.native.is_some() {
+            return Err(format!("cannot clone a built-in coroutine from {}", self.fun.name()));
         }
+        Ok(Self {
+            ip: self.ip,
+            fun: self.fun.clone(),
+            status: CoStatus::Suspended,
+            env: self.env.iter().map(|(k, v)| (k.clone(), v.deep_clone())).collect(),
+            locals: self.locals.iter().map(|v| v.as_ref().map(Value::deep_clone)).collect(),
+            upvalues: self
+                .upvalues
+                .iter()
+                .map(|cell| Rc::new(RefCell::new(cell.borrow().as_ref().map(Value::deep_clone))))
+                .collect(),
+            stack: self.stack.iter().map(Value::deep_clone).collect(),
+            debugger: self.debugger.clone(),
+            watches: self.watches.clone(),
+            op_counts: self.op_counts.clone(),
+            coverage: self.coverage.clone(),
+            limits: self.limits.clone(),
+            output: self.output.clone(),
+            tail_called: self.tail_called,
+            native: None,
+        })
+    }
+
+    /// Register a watch expression, which is evaluated against a copy of the env after every
+    /// instruction. The debugger is notified whenever its value changes. Watches that fail to
+    /// evaluate (e.g. because a name is not bound yet) are skipped.
+    ///
+    /// Only the env is copied, so a watch that resumes a coroutine will affect the original.
+    pub fn add_watch(&mut self, name: &str, expr: Rc<FnDef>) {
+        self.watches.push(Watch { name: name.to_owned(), expr, last: None });
+    }
+
+    /// Start counting how many times each kind of instruction is executed. When not enabled,
+    /// nothing is counted.
+    pub fn enable_profiling(&mut self) {
+        self.op_counts.get_or_insert_with(|| Box::new([0; Instr::NUM_OPS]));
+    }
+
+    /// Execution counts indexed by `Instr::opcode`, if profiling is enabled.
+    pub fn op_counts(&self) -> Option<&[u64]> {
+        self.op_counts.as_ref().map(|counts| &counts[..])
+    }
+
+    /// Start recording which instructions of the function are executed.
+    pub fn enable_coverage(&mut self) {
+        let len = self.fun.code.len();
+        self.coverage.get_or_insert_with(|| vec![false; len]);
+    }
+
+    /// For each instruction index, whether it was executed. Empty if coverage is not enabled.
+    pub fn coverage(&self) -> Vec<bool> {
+        self.coverage.clone().unwrap_or_default()
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn status(&self) -> &CoStatus {
+        &self.status
+    }
+
+    pub fn status_name(&self) -> String {
+        format!("{:?}", self.status).to_lowercase()
     }
 
-    pub fn resume(&mut self, args: Vec<Value>) -> Result<Value, String> {
+    pub fn resume(&mut self, args: Vec<Value>) -> Result<Value, CoError> {
         self.check_status()?;
         self.handle_inputs(args)?;
+        self.run_resumed()
+    }
 
+    /// Iterate over the values the coroutine yields, see `CoroIter`.
+    pub fn iter(&mut self) -> CoroIter<'_> {
+        CoroIter { coro: self, failed: false }
+    }
+
+    // The rest of `resume`, once the inputs are handled.
+    fn run_resumed(&mut self) -> Result<Value, CoError> {
+        // Each nested resume takes up space on the Rust stack, so bound how deep they can go.
+        let depth = self.limits.depth.get();
+        if depth >= self.limits.max_depth {
+            let msg = format!("maximum depth of {} nested coroutines exceeded", depth);
+            return Err(self.error(msg));
+        }
         self.status = CoStatus::Running;
         if cfg!(feature = "dbg") {
             println!("{}", self);
         }
 
-        let res = self.exec()?;
-        if self.ip >= self.fun.code.len() {
+        self.limits.depth.set(depth + 1);
+        // `exit` unwinds through here, out of any natives on the way, until it reaches the
+        // outermost resume, which gives it back as an error. The VM can be used again after, so
+        // leave the depth as it was and this coroutine finished.
+        let res = panic::catch_unwind(AssertUnwindSafe(|| match self.native {
+            Some(_) => self.exec_native(),
+            None => self.exec(),
+        }));
+        let res = match res {
+            Ok(res) => res,
+            Err(payload) => {
+                self.limits.depth.set(depth);
+                self.status = CoStatus::Done;
+                match payload.downcast::<ScriptExit>() {
+                    Ok(exit) if depth == 0 => return Err(CoError::Exit(exit.0)),
+                    Ok(exit) => panic::resume_unwind(exit),
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            }
+        };
+        self.limits.depth.set(depth);
+        let res = res?;
+        if self.native.is_none() && self.ip >= self.fun.code.len() {
             self.status = CoStatus::Done;
         }
 
@@ -152,232 +1015,749 @@ impl Coro {
     }
 
     pub fn debug_stack(&self) {
-        eprint!("<ip: {:04} stack: [", self.ip);
+        eprintln!("{}", self.format_stack());
+    }
+
+    /// The IP and the values on the stack, with strings quoted and long values cut short.
+    pub fn format_stack(&self) -> String {
+        let mut out = format!("<ip: {:04} stack: [", self.ip);
         for value in &self.stack {
-            match value {
-                Value::Str(_) => eprint!(" <str>"),
-                Value::Fn(_) => eprint!(" <fn>"),
-                Value::Co(_) => eprint!(" <co>"),
-                _ => eprint!(" {:?}", value),
-            }
-        }
-        eprintln!(" ]>");
-    }
-
-    fn exec(&mut self) -> Result<Value, String> {
-        let code_len = self.fun.code.len();
-        while self.ip < code_len {
-            if cfg!(feature = "stack") {
-                self.debug_stack();
-            }
-            let instr = self.fun.code.instr(self.ip);
-            let instr = instr.clone();
-            self.ip += 1;
-            match instr {
-                OpUnit => self.stack.push(Value::Unit),
-                OpTrue => self.stack.push(Value::Bool(true)),
-                OpFalse => self.stack.push(Value::Bool(false)),
-                OpConst(idx) => {
-                    let val = self.fun.code.constant(idx);
-                    self.stack.push(val.clone());
-                }
-                OpAdd => {
-                    self.check_bin_operands()?;
-                    let rhs = self.stack.pop().unwrap().into_num();
-                    let lhs = self.stack.pop().unwrap().into_num();
-                    let val = Value::Num(lhs + rhs);
-                    self.stack.push(val);
-                }
-                OpSub => {
-                    self.check_bin_operands()?;
-                    let rhs = self.stack.pop().unwrap().into_num();
-                    let lhs = self.stack.pop().unwrap().into_num();
-                    let val = Value::Num(lhs - rhs);
-                    self.stack.push(val);
-                }
-                OpMul => {
-                    self.check_bin_operands()?;
-                    let rhs = self.stack.pop().unwrap().into_num();
-                    let lhs = self.stack.pop().unwrap().into_num();
-                    let val = Value::Num(lhs * rhs);
-                    self.stack.push(val);
-                }
-                OpDiv => {
-                    self.check_bin_operands()?;
-                    let rhs = self.stack.pop().unwrap().into_num();
-                    let lhs = self.stack.pop().unwrap().into_num();
-                    if rhs == 0.0 {
-                        return Err("cannot divide by zero".to_owned());
-                    }
-                    let val = Value::Num(lhs / rhs);
-                    self.stack.push(val);
-                }
-                OpNeg => {
-                    self.check_uni_operands()?;
-                    let val = self.stack.pop().unwrap().into_num();
-                    let val = Value::Num(-val);
-                    self.stack.push(val);
-                }
-                OpNot => {
-                    let val = self.stack.pop().unwrap();
-                    let val = Value::Bool(val.is_falsey());
-                    self.stack.push(val);
-                }
-                OpLt => {
-                    self.check_bin_operands()?;
-                    let rhs = self.stack.pop().unwrap().into_num();
-                    let lhs = self.stack.pop().unwrap().into_num();
-                    let val = Value::Bool(lhs < rhs);
-                    self.stack.push(val);
-                }
-                OpEq => {
-                    let rhs = self.stack.pop().unwrap();
-                    let lhs = self.stack.pop().unwrap();
-                    let val = Value::Bool(lhs == rhs);
-                    self.stack.push(val);
-                }
-                OpLoop(offset) => {
-                    self.ip -= offset;
-                }
-                OpJump(offset) => {
-                    self.ip += offset;
-                }
-                OpBranch(offset) => {
-                    if self.peek(0).is_falsey() {
-                        self.ip += offset;
-                    }
-                }
-                OpLoad(idx) => {
-                    let name = self.fun.code.constant(idx);
-                    let name = name.as_str_ref();
-                    match self.env.get(name) {
-                        Some(val) => self.stack.push(val.clone()),
-                        None => return Err(format!("no binding for name '{}'", name)),
-                    }
-                }
-                OpStore(idx) => {
-                    let name = self.fun.code.constant(idx);
-                    let name = name.clone().into_str();
-                    let val = self.stack.pop().unwrap();
-                    self.env.insert(name, val);
-                    self.stack.push(Value::Unit);
-                }
-                OpDefine(idx) => {
-                    let def = self.fun.code.constant(idx);
-                    let def = def.clone().into_fn();
-                    let name = def.name().to_owned();
-                    let val = Value::Fn(def);
-                    self.env.insert(name, val);
-                    self.stack.push(Value::Unit);
+            let desc = match value {
+                Value::Str(s) => format!("\"{}\"", s),
+                _ => format!("{}", value),
+            };
+            out.push(' ');
+            if desc.chars().count() > MAX_STACK_VALUE_LEN {
+                out.extend(desc.chars().take(MAX_STACK_VALUE_LEN - 3));
+                out.push_str("...");
+            } else {
+                out.push_str(&desc);
+            }
+        }
+        out.push_str(" ]>");
+        out
+    }
+
+    fn exec(&mut self) -> Result<Value, CoError> {
+        loop {
+            let res = self.step()?;
+            if !self.watches.is_empty() {
+                self.eval_watches();
+            }
+            match res {
+                StepResult::Stepped => continue,
+                StepResult::Yielded(val) | StepResult::Returned(val) => return Ok(val),
+            }
+        }
+    }
+
+    // Run the step of a coroutine made by `spawn_native`, as the caller of anything it calls back
+    // into. Errors leave it running, same as with `exec`.
+    fn exec_native(&mut self) -> Result<Value, CoError> {
+        let res = {
+            let _caller = Caller::of(self).enter();
+            self.native.as_mut().unwrap()()
+        };
+        match res.map_err(|msg| self.error(msg))? {
+            Some(val) => {
+                self.status = CoStatus::Suspended;
+                Ok(val)
+            }
+            None => {
+                self.status = CoStatus::Done;
+                self.native = None;
+                Ok(Value::Unit)
+            }
+        }
+    }
+
+    fn eval_watches(&mut self) {
+        let debugger = self.debugger.as_ref().and_then(|d| d.upgrade());
+        let vars = self.vars();
+        for watch in self.watches.iter_mut() {
+            let mut coro = Coro::new(watch.expr.clone());
+            coro.env = vars.clone();
+            coro.status = CoStatus::Running;
+            let val = match coro.exec() {
+                Ok(val) => val,
+                Err(_) => continue,
+            };
+            if watch.last.as_ref() == Some(&val) {
+                continue;
+            }
+            if let Some(debugger) = &debugger {
+                if let Ok(mut debugger) = debugger.lock() {
+                    debugger.on_watch_changed(&watch.name, watch.last.as_ref(), &val);
                 }
-                OpCreate(idx) => {
-                    let name = self.fun.code.constant(idx);
-                    let name = name.as_str_ref();
-                    let val = match self.env.get(name) {
-                        Some(val) => val,
-                        None => return Err(format!("no binding for name '{}'", name)),
-                    };
-                    if !val.is_fn() {
-                        return Err(format!("'{}' is not a function", name));
+            }
+            watch.last = Some(val);
+        }
+    }
+
+    /// Execute exactly one instruction. Running off the end of the code is treated as returning
+    /// unit, same as `resume` does.
+    pub fn step(&mut self) -> Result<StepResult, CoError> {
+        match self.step_instr()? {
+            Step::Done(res) => Ok(res),
+            Step::Call(num) => self.call(num),
+            Step::Resume(num) => self.resume_child(num),
+        }
+    }
+
+    fn step_instr(&mut self) -> Result<Step, CoError> {
+        if self.ip >= self.fun.code.len() {
+            self.status = CoStatus::Done;
+            return Ok(Step::Done(StepResult::Returned(Value::Unit)));
+        }
+        if cfg!(feature = "stack") {
+            self.debug_stack();
+        }
+        let instr = *self.fun.code.instr(self.ip);
+        self.ip += 1;
+        if let Some(max_steps) = self.limits.max_steps {
+            let steps = self.limits.steps.get() + 1;
+            if steps > max_steps {
+                return Err(self.error(format!("step limit of {} exceeded", max_steps)));
+            }
+            self.limits.steps.set(steps);
+        }
+        if let Some(counts) = &mut self.op_counts {
+            counts[instr.opcode()] += 1;
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage[self.ip - 1] = true;
+        }
+        match instr {
+            OpUnit => self.stack.push(Value::Unit),
+            OpTrue => self.stack.push(Value::Bool(true)),
+            OpZero => self.stack.push(Value::Num(0.0)),
+            OpOne => self.stack.push(Value::Num(1.0)),
+            OpFalse => self.stack.push(Value::Bool(false)),
+            OpConst(idx) => {
+                let val = self.fun.code.constant(idx);
+                self.stack.push(val.clone());
+            }
+            OpAdd => {
+                self.check_bin_operands()?;
+                let rhs = self.stack.pop().unwrap().into_num();
+                let lhs = self.stack.pop().unwrap().into_num();
+                let val = Value::Num(self.check_finite(lhs + rhs)?);
+                self.stack.push(val);
+            }
+            OpSub => {
+                self.check_bin_operands()?;
+                let rhs = self.stack.pop().unwrap().into_num();
+                let lhs = self.stack.pop().unwrap().into_num();
+                let val = Value::Num(self.check_finite(lhs - rhs)?);
+                self.stack.push(val);
+            }
+            // A string times a whole number repeats the string.
+            OpMul if self.peek(1).is_str() && self.peek(0).is_num() => {
+                let times = match self.peek(0).as_int() {
+                    Some(times) if times >= 0 => times as usize,
+                    _ => {
+                        let msg = "string repeat count must be a non-negative integer";
+                        return Err(self.error(msg));
                     }
-                    let def = val.clone().into_fn();
-                    let coro = Self::new(def);
-                    let coro = Rc::new(RefCell::new(coro));
-                    self.stack.push(Value::Co(coro))
+                };
+                self.stack.pop();
+                let s = self.stack.pop().unwrap().into_str();
+                let s = builtins::repeat_str(&s, times).map_err(|msg| self.error(msg))?;
+                self.stack.push(Value::Str(s.into()));
+            }
+            OpMul => {
+                self.check_bin_operands()?;
+                let rhs = self.stack.pop().unwrap().into_num();
+                let lhs = self.stack.pop().unwrap().into_num();
+                let val = Value::Num(self.check_finite(lhs * rhs)?);
+                self.stack.push(val);
+            }
+            OpDiv => {
+                self.check_bin_operands()?;
+                let rhs = self.stack.pop().unwrap().into_num();
+                let lhs = self.stack.pop().unwrap().into_num();
+                if rhs == 0.0 {
+                    return Err(self.error("cannot divide by zero"));
+                }
+                let val = Value::Num(self.check_finite(lhs / rhs)?);
+                self.stack.push(val);
+            }
+            OpIDiv => {
+                self.check_bin_operands()?;
+                let rhs = self.stack.pop().unwrap().into_num();
+                let lhs = self.stack.pop().unwrap().into_num();
+                if rhs == 0.0 {
+                    return Err(self.error("cannot divide by zero"));
                 }
-                OpResume(num) => {
-                    let mut args = Vec::with_capacity(num);
-                    for _ in 0..num {
-                        let val = self.stack.pop().unwrap();
-                        args.insert(0, val);
+                let val = Value::Num(self.check_finite((lhs / rhs).floor())?);
+                self.stack.push(val);
+            }
+            OpNeg => {
+                self.check_uni_operands()?;
+                let val = self.stack.pop().unwrap().into_num();
+                let val = Value::Num(-val);
+                self.stack.push(val);
+            }
+            OpNot => {
+                let val = self.stack.pop().unwrap();
+                let val = Value::Bool(val.is_falsey());
+                self.stack.push(val);
+            }
+            OpBool => {
+                let val = self.stack.pop().unwrap();
+                let val = Value::Bool(val.is_truthy());
+                self.stack.push(val);
+            }
+            OpRange => {
+                let (start, end) = match (self.peek(1), self.peek(0)) {
+                    (Value::Num(start), Value::Num(end)) => (*start, *end),
+                    _ => return Err(self.error("range bounds must be numbers")),
+                };
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(Value::range(start, end, 1.0));
+            }
+            OpIter(offset) => {
+                let idx = match self.peek(0).as_int().and_then(|idx| usize::try_from(idx).ok()) {
+                    Some(idx) => idx,
+                    None => return Err(self.error("loop index must be a whole number")),
+                };
+                // Ranges work out each number as it is needed, rather than making a list.
+                let item = match self.peek(1) {
+                    Value::List(list) => list.borrow().get(idx).cloned(),
+                    Value::Range { start, end, step } => {
+                        range_nth(*start, *end, *step, idx).map(Value::Num)
                     }
-                    let coro = self.stack.pop().unwrap();
-                    if !coro.is_co() {
-                        return Err(format!("only coroutines can be resumed"));
+                    _ => return Err(self.error("only lists and ranges can be looped over")),
+                };
+                match item {
+                    Some(item) => {
+                        let len = self.stack.len();
+                        self.stack[len - 1] = Value::Num((idx + 1) as f64);
+                        self.stack.push(item);
                     }
-                    let coro = coro.into_co();
-                    self.status = CoStatus::Suspended;
-                    let val = coro.borrow_mut().resume(args)?;
-                    self.status = CoStatus::Running;
-                    self.stack.push(val);
+                    None => self.ip += offset,
                 }
-                OpYield => {
-                    let val = self.stack.pop().unwrap();
-                    self.status = CoStatus::Suspended;
-                    return Ok(val);
+            }
+            OpIndex => self.index()?,
+            OpSlice => self.slice()?,
+            OpBitAnd | OpBitOr | OpBitXor | OpShl | OpShr => {
+                let (lhs, rhs) = self.check_int_operands()?;
+                let val = match instr {
+                    OpBitAnd => lhs & rhs,
+                    OpBitOr => lhs | rhs,
+                    OpBitXor => lhs ^ rhs,
+                    _ if !(0..64).contains(&rhs) => {
+                        return Err(self.error("shift amount must be between 0 and 63"));
+                    }
+                    OpShl => lhs << rhs,
+                    _ => lhs >> rhs,
+                };
+                let val = self.int_result(val)?;
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(val);
+            }
+            OpBitNot => {
+                let val = match self.peek(0).as_int() {
+                    Some(val) if is_safe_int(val) => val,
+                    Some(_) => return Err(self.error("operand must fit in 53 bits")),
+                    None => return Err(self.error("operand must be an integer")),
+                };
+                let val = self.int_result(!val)?;
+                self.stack.pop();
+                self.stack.push(val);
+            }
+            OpLt | OpGt | OpLe | OpGe => {
+                self.check_cmp_operands()?;
+                let rhs = self.stack.pop().unwrap();
+                let lhs = self.stack.pop().unwrap();
+                // Comparing with NaN is always false.
+                let val = match (instr, lhs.compare(&rhs)) {
+                    (_, None) => false,
+                    (OpLt, Some(ord)) => ord.is_lt(),
+                    (OpGt, Some(ord)) => ord.is_gt(),
+                    (OpLe, Some(ord)) => ord.is_le(),
+                    (_, Some(ord)) => ord.is_ge(),
+                };
+                self.stack.push(Value::Bool(val));
+            }
+            OpEq => {
+                let rhs = self.stack.pop().unwrap();
+                let lhs = self.stack.pop().unwrap();
+                let val = Value::Bool(lhs == rhs);
+                self.stack.push(val);
+            }
+            OpLoop(offset) => {
+                self.ip -= offset;
+            }
+            OpJump(offset) => {
+                self.ip += offset;
+            }
+            OpBranch(offset) => {
+                if self.peek(0).is_falsey() {
+                    self.ip += offset;
                 }
-                OpPrint => {
-                    let val = self.stack.pop().unwrap();
-                    self.stack.push(Value::Unit);
-                    println!("{}", val);
+            }
+            OpLoad(idx) => {
+                let name = self.fun.code.constant(idx).clone();
+                let val = self.load(name.as_str_ref())?;
+                self.stack.push(val);
+            }
+            OpGetLocal(slot) => {
+                let val = match &self.locals[slot] {
+                    Some(val) => val.clone(),
+                    None => self.load(&self.fun.code.locals()[slot])?,
+                };
+                self.stack.push(val);
+            }
+            OpStore(idx) => {
+                let name = self.fun.code.constant(idx).clone();
+                let val = self.stack.pop().unwrap();
+                self.set_var(name.as_str_ref(), val);
+                self.stack.push(Value::Unit);
+            }
+            OpSetLocal(slot) => {
+                let val = self.stack.pop().unwrap();
+                self.locals[slot] = Some(val);
+                self.stack.push(Value::Unit);
+            }
+            OpGetUpvalue(idx) => {
+                let val = self.upvalues[idx].borrow().clone();
+                let val = match val {
+                    Some(val) => val,
+                    None => self.load(&self.fun.code.upvalues()[idx].name)?,
+                };
+                self.stack.push(val);
+            }
+            OpSetUpvalue(idx) => {
+                let val = self.stack.pop().unwrap();
+                *self.upvalues[idx].borrow_mut() = Some(val);
+                self.stack.push(Value::Unit);
+            }
+            OpBindUpvalue(idx) => {
+                let val = self.stack.pop().unwrap();
+                self.upvalues[idx] = Rc::new(RefCell::new(Some(val)));
+                self.stack.push(Value::Unit);
+            }
+            OpClosure(idx) => {
+                let def = self.fun.code.constant(idx).clone().into_fn();
+                let val = Value::Fn(Rc::new(self.close_over(&def)?));
+                self.stack.push(val);
+            }
+            OpDefine(idx) => {
+                let def = self.fun.code.constant(idx);
+                let def = def.clone().into_fn();
+                let name = def.name().to_owned();
+                self.set_var(&name, Value::Fn(def));
+                self.stack.push(Value::Unit);
+            }
+            OpCreate(idx) => {
+                let name = self.fun.code.constant(idx);
+                let name = name.as_str_ref();
+                let val = match self.get_var(name) {
+                    Some(val) => val,
+                    None => return Err(self.error(format!("no binding for name '{}'", name))),
+                };
+                if !val.is_fn() {
+                    return Err(self.error(format!("'{}' is not a function", name)));
                 }
-                OpPop => {
-                    self.stack.pop();
+                let def = val.into_fn();
+                let coro = self.spawn(def);
+                let coro = Rc::new(RefCell::new(coro));
+                self.stack.push(Value::Co(coro))
+            }
+            OpResume(num) => return Ok(Step::Resume(num)),
+            OpCall(num) | OpNativeCall(num) => {
+                if let OpNativeCall(_) = instr {
+                    self.resolve_native(num)?;
                 }
-                OpRet => {
-                    let val = if !self.stack.is_empty() {
-                        self.stack.pop().unwrap()
-                    } else {
-                        Value::Unit
-                    };
+                return Ok(Step::Call(num));
+            }
+            OpYield => {
+                let val = self.stack.pop().unwrap();
+                // A called function ends when it first yields, so the same goes for one that was
+                // tail called.
+                if self.tail_called {
                     self.status = CoStatus::Done;
-                    return Ok(val);
+                    return Ok(Step::Done(StepResult::Returned(val)));
+                }
+                self.status = CoStatus::Suspended;
+                return Ok(Step::Done(StepResult::Yielded(val)));
+            }
+            OpPrint => {
+                let val = self.stack.pop().unwrap();
+                self.stack.push(Value::Unit);
+                let res = match &self.output {
+                    Some(output) => writeln!(output.borrow_mut(), "{}", val),
+                    None => writeln!(io::stdout(), "{}", val),
+                };
+                res.map_err(|e| self.error(format!("cannot write output: {}", e)))?;
+            }
+            OpList(num) => {
+                let items = self.stack.split_off(self.stack.len() - num);
+                self.stack.push(Value::list(items));
+            }
+            OpUnpack(num) => {
+                let val = self.stack.pop().unwrap();
+                if !val.is_list() {
+                    return Err(self.error("only lists can be destructured"));
+                }
+                let list = val.into_list();
+                let items = list.borrow();
+                if items.len() != num {
+                    return Err(self.error(format!(
+                        "expected {} values to destructure but got {}",
+                        num,
+                        items.len()
+                    )));
                 }
+                self.stack.extend(items.iter().rev().cloned());
+            }
+            OpPop => {
+                self.stack.pop();
+            }
+            OpSwap => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(self.error("not enough values on the stack to swap"));
+                }
+                self.stack.swap(len - 1, len - 2);
+            }
+            OpBreakpoint(id) => {
+                let debugger = self.debugger.as_ref().and_then(|d| d.upgrade());
+                if let Some(debugger) = debugger {
+                    if let Ok(mut debugger) = debugger.lock() {
+                        debugger.on_breakpoint(id, self.ip - 1, &self.stack, &self.vars());
+                    }
+                }
+            }
+            OpRet => {
+                let val = if !self.stack.is_empty() {
+                    self.stack.pop().unwrap()
+                } else {
+                    Value::Unit
+                };
+                self.status = CoStatus::Done;
+                return Ok(Step::Done(StepResult::Returned(val)));
             }
         }
-        Ok(Value::Unit)
+        Ok(Step::Done(StepResult::Stepped))
+    }
+
+    // Whether the next instruction returns, possibly after a jump, same as running off the end.
+    // Coverage is recorded per function, so tail calls are not reused while it is enabled.
+    fn in_tail_position(&self) -> bool {
+        let code = &self.fun.code;
+        let returns = |idx| idx >= code.len() || *code.instr(idx) == OpRet;
+        let next = match code.iter_from(self.ip).next() {
+            Some((idx, OpJump(offset), _)) => idx + 1 + offset,
+            _ => self.ip,
+        };
+        self.coverage.is_none() && returns(next)
+    }
+
+    /// Make a runtime error located at the instruction that was just executed.
+    fn error(&self, msg: impl Into<String>) -> CoError {
+        let ip = self.ip.saturating_sub(1);
+        let line = if ip < self.fun.code.len() { self.fun.code.line(ip) } else { 0 };
+        CoError::runtime(msg, ip, line)
     }
 
     fn peek(&self, distance: usize) -> &Value {
         &self.stack[self.stack.len() - distance - 1]
     }
 
-    fn check_status(&self) -> Result<(), String> {
+    // In strict mode, arithmetic must give back a finite number.
+    fn check_finite(&self, num: f64) -> Result<f64, CoError> {
+        if num.is_finite() || !self.limits.strict_math.get() {
+            Ok(num)
+        } else if num.is_nan() {
+            Err(self.error("not a number"))
+        } else {
+            Err(self.error("numeric overflow"))
+        }
+    }
+
+    fn check_status(&self) -> Result<(), CoError> {
         if self.status != CoStatus::Suspended {
-            Err(format!("tried to resume a non-suspended coroutine"))
+            Err(self.error("tried to resume a non-suspended coroutine"))
         } else {
             Ok(())
         }
     }
 
-    fn check_arity(&self, arity: usize, args_len: usize) -> Result<(), String> {
-        if arity != args_len {
-            Err(format!(
+    fn check_arity(&self, arity: usize, args_len: usize) -> Result<(), CoError> {
+        self.check_arity_range(arity, arity, args_len)
+    }
+
+    fn check_arity_range(&self, min: usize, max: usize, args_len: usize) -> Result<(), CoError> {
+        if args_len < min || args_len > max {
+            Err(self.error(format!(
                 "expected {} arguments but got {} when resuming coroutine",
-                arity, args_len
-            ))
+                expected_args(min, max),
+                args_len
+            )))
         } else {
             Ok(())
         }
     }
 
-    fn check_uni_operands(&self) -> Result<(), String> {
+    fn check_uni_operands(&self) -> Result<(), CoError> {
         let val = self.peek(0);
         if !val.is_num() {
-            Err("operand must be a number".to_owned())
+            Err(self.error("operand must be a number"))
         } else {
             Ok(())
         }
     }
 
-    fn check_bin_operands(&self) -> Result<(), String> {
+    fn check_bin_operands(&self) -> Result<(), CoError> {
         let lhs = self.peek(1);
         let rhs = self.peek(0);
         if !lhs.is_num() || !rhs.is_num() {
-            Err("operands must be numbers".to_owned())
+            Err(self.error("operands must be numbers"))
         } else {
             Ok(())
         }
     }
 
-    fn handle_inputs(&mut self, args: Vec<Value>) -> Result<(), String> {
+    fn check_cmp_operands(&self) -> Result<(), CoError> {
+        let lhs = self.peek(1);
+        let rhs = self.peek(0);
+        if (lhs.is_num() && rhs.is_num()) || (lhs.is_str() && rhs.is_str()) {
+            Ok(())
+        } else {
+            Err(self.error("operands must both be numbers or both be strings"))
+        }
+    }
+
+    // Resume the coroutine below `num` arguments on the stack, and push what it gives back.
+    fn resume_child(&mut self, num: usize) -> Result<StepResult, CoError> {
+        let mut args = Vec::with_capacity(num);
+        for _ in 0..num {
+            let val = self.stack.pop().unwrap();
+            args.insert(0, val);
+        }
+        let coro = self.stack.pop().unwrap();
+        if !coro.is_co() {
+            return Err(self.error("only coroutines can be resumed"));
+        }
+        let coro = coro.into_co();
+        // The coroutine is borrowed while it runs, so this is itself or one that resumed us.
+        let mut coro = match coro.try_borrow_mut() {
+            Ok(coro) => coro,
+            Err(_) => return Err(self.error("cannot resume a running coroutine")),
+        };
+        // Resuming with bad inputs is the fault of this coroutine, so report it here.
+        if let Err(err) = coro.check_status().and_then(|_| coro.handle_inputs(args)) {
+            return Err(self.error(err.message()));
+        }
+        self.status = CoStatus::Suspended;
+        let val = coro.run_resumed()?;
+        self.status = CoStatus::Running;
+        self.stack.push(val);
+        Ok(StepResult::Stepped)
+    }
+
+    // Call the function below `num` arguments on the stack, and push what it gives back.
+    fn call(&mut self, num: usize) -> Result<StepResult, CoError> {
+        let args = self.stack.split_off(self.stack.len() - num);
+        let val = match self.stack.pop().unwrap() {
+            Value::NativeFn(native) => {
+                self.call_native(&native, args).map_err(|msg| self.error(msg))?
+            }
+            // A function calling itself as the last thing it does runs the call here,
+            // instead of in a fresh coroutine. This keeps deep tail recursion from growing
+            // the Rust stack.
+            Value::Fn(def) if Rc::ptr_eq(&def, &self.fun) && self.in_tail_position() => {
+                let mut coro = self.spawn(def);
+                if let Err(err) = coro.handle_inputs(args) {
+                    return Err(self.error(err.message()));
+                }
+                self.env = coro.env;
+                self.locals = coro.locals;
+                self.upvalues = coro.upvalues;
+                self.ip = 0;
+                self.stack.clear();
+                self.tail_called = true;
+                return Ok(StepResult::Stepped);
+            }
+            // Functions run in a fresh coroutine, until they first yield or return.
+            Value::Fn(def) => {
+                let mut coro = self.spawn(def);
+                if let Err(err) = coro.handle_inputs(args) {
+                    return Err(self.error(err.message()));
+                }
+                coro.run_resumed()?
+            }
+            _ => return Err(self.error("only functions can be called")),
+        };
+        self.stack.push(val);
+        Ok(StepResult::Stepped)
+    }
+
+    // A built-in called by name is picked when compiling, but names bound from outside still
+    // shadow it, same as they would when loading it by name.
+    fn resolve_native(&mut self, num: usize) -> Result<(), CoError> {
+        let callee = self.stack.len() - num - 1;
+        if let Value::NativeFn(native) = &self.stack[callee] {
+            if !self.limits.allows(native.name()) || self.env.contains_key(native.name()) {
+                let name = native.name().to_owned();
+                self.stack[callee] = self.load(&name)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Hand the nested function the cells of the upvalues it captures from this function.
+    fn close_over(&self, def: &FnDef) -> Result<FnDef, CoError> {
+        let mut captured = Vec::new();
+        for upvalue in def.code.upvalues().iter().filter(|upvalue| !upvalue.local) {
+            match self.fun.code.upvalue_slot(&upvalue.name) {
+                Some(idx) => captured.push(self.upvalues[idx].clone()),
+                None => {
+                    let msg = format!("no upvalue for name '{}' to capture", upvalue.name);
+                    return Err(self.error(msg));
+                }
+            }
+        }
+        Ok(def.close_over(captured))
+    }
+
+    // Bind a name in its upvalue or local slot, or in the env if the function has neither.
+    pub(crate) fn set_var(&mut self, name: &str, val: Value) {
+        if let Some(idx) = self.fun.code.upvalue_slot(name) {
+            *self.upvalues[idx].borrow_mut() = Some(val);
+            return;
+        }
+        match self.fun.code.local_slot(name) {
+            Some(slot) => self.locals[slot] = Some(val),
+            None => {
+                self.env.insert(name.to_owned(), val);
+            }
+        }
+    }
+
+    fn get_var(&self, name: &str) -> Option<Value> {
+        let code = &self.fun.code;
+        let upvalue = code.upvalue_slot(name).and_then(|idx| self.upvalues[idx].borrow().clone());
+        let local = || code.local_slot(name).and_then(|slot| self.locals[slot].clone());
+        upvalue.or_else(local).or_else(|| self.env.get(name).cloned())
+    }
+
+    // Names that are not bound fall back to the built-ins, so they can be shadowed.
+    fn load(&self, name: &str) -> Result<Value, CoError> {
+        let builtin = || builtins::lookup(name).filter(|_| self.limits.allows(name));
+        match self.get_var(name).or_else(builtin) {
+            Some(val) => Ok(val),
+            None => Err(self.error(format!("no binding for name '{}'", name))),
+        }
+    }
+
+    // Everything bound by name, i.e. the env along with the locals and upvalues that are bound.
+    fn vars(&self) -> HashMap<String, Value> {
+        let mut vars = self.env.clone();
+        for (name, val) in self.fun.code.locals().iter().zip(&self.locals) {
+            if let Some(val) = val {
+                vars.insert(name.clone(), val.clone());
+            }
+        }
+        for (upvalue, cell) in self.fun.code.upvalues().iter().zip(&self.upvalues) {
+            if let Some(val) = &*cell.borrow() {
+                vars.insert(upvalue.name.clone(), val.clone());
+            }
+        }
+        vars
+    }
+
+    // Index into the list below the index on the stack, counting from the end if negative.
+    fn index(&mut self) -> Result<(), CoError> {
+        let list = match self.peek(1) {
+            Value::List(list) => list,
+            _ => return Err(self.error("only lists can be indexed")),
+        };
+        let idx = match self.peek(0).as_int() {
+            Some(idx) => idx,
+            None => return Err(self.error("list index must be a whole number")),
+        };
+        let len = list.borrow().len();
+        let pos = if idx < 0 { idx.checked_add(len as i64) } else { Some(idx) };
+        let item = match pos.and_then(|pos| usize::try_from(pos).ok()) {
+            Some(pos) if pos < len => list.borrow()[pos].clone(),
+            _ => {
+                let msg = format!("list index {} is out of bounds for length {}", idx, len);
+                return Err(self.error(msg));
+            }
+        };
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(item);
+        Ok(())
+    }
+
+    // Slice the list below the two bounds on the stack, where a unit bound is left open.
+    fn slice(&mut self) -> Result<(), CoError> {
+        let list = match self.peek(2) {
+            Value::List(list) => list,
+            _ => return Err(self.error("only lists can be sliced")),
+        };
+        let mut bounds = [None, None];
+        for (bound, val) in bounds.iter_mut().zip([self.peek(1), self.peek(0)]) {
+            *bound = match val {
+                Value::Unit => None,
+                _ => match val.as_int() {
+                    Some(idx) => Some(idx),
+                    None => return Err(self.error("slice bounds must be whole numbers")),
+                },
+            };
+        }
+        let items = list.borrow();
+        let range = slice_range(items.len(), bounds[0], bounds[1]);
+        let val = Value::list(items[range].to_vec());
+        drop(items);
+        self.stack.truncate(self.stack.len() - 3);
+        self.stack.push(val);
+        Ok(())
+    }
+
+    // Bitwise operations work on numbers that are whole, treating them as 64-bit integers.
+    fn check_int_operands(&self) -> Result<(i64, i64), CoError> {
+        match (self.peek(1).as_int(), self.peek(0).as_int()) {
+            (Some(lhs), Some(rhs)) if is_safe_int(lhs) && is_safe_int(rhs) => Ok((lhs, rhs)),
+            (Some(_), Some(_)) => Err(self.error("operands must fit in 53 bits")),
+            _ => Err(self.error("operands must be integers")),
+        }
+    }
+
+    /// Numbers are floats, so an integer result is an error rather than rounded if it is too big
+    /// to be exact.
+    fn int_result(&self, val: i64) -> Result<Value, CoError> {
+        if is_safe_int(val) {
+            Ok(Value::Num(val as f64))
+        } else {
+            Err(self.error("integer result does not fit in 53 bits"))
+        }
+    }
+
+    fn handle_inputs(&mut self, mut args: Vec<Value>) -> Result<(), CoError> {
         if self.ip == 0 {
             // First time calling coroutine, so setup the function arguments.
-            let arity = self.fun.arity();
-            self.check_arity(arity, args.len())?;
+            let fun = self.fun.clone();
+            let max = if fun.is_variadic() { usize::MAX } else { fun.arity() };
+            self.check_arity_range(fun.min_arity(), max, args.len())?;
+            let extra = args.split_off(args.len().min(fun.arity()));
+            if let Some(rest) = fun.rest_param() {
+                self.set_var(rest, Value::list(extra));
+            }
+            let given = args.len();
             for (i, arg) in args.into_iter().enumerate() {
-                let param = self.fun.param(i).clone();
-                self.env.insert(param, arg);
+                self.set_var(fun.param(i), arg);
+            }
+            // Fill in the rest from the default values.
+            for i in given..fun.arity() {
+                let thunk = fun.param_default(i).unwrap().clone();
+                let mut coro = self.spawn(thunk);
+                coro.status = CoStatus::Running;
+                let val = coro.exec()?;
+                self.set_var(fun.param(i), val);
             }
         } else {
             // At most one value (unit if none), and we push this onto the stack.
@@ -392,3 +1772,1263 @@ impl Coro {
         Ok(())
     }
 }
+
+fn expected_args(min: usize, max: usize) -> String {
+    if max == usize::MAX {
+        format!("at least {}", min)
+    } else if min == max {
+        format!("{}", max)
+    } else {
+        format!("{} to {}", min, max)
+    }
+}
+
+fn check_native_args(native: &NativeFn, args_len: usize) -> Result<(), String> {
+    if args_len < native.min_arity() || args_len > native.max_arity() {
+        Err(format!(
+            "expected {} arguments but got {} when calling '{}'",
+            expected_args(native.min_arity(), native.max_arity()),
+            args_len,
+            native.name()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Iterates over the values a coroutine yields, by resuming it without arguments until it is done.
+/// The value it returns at the end is left out, and an error is the last item.
+pub struct CoroIter<'a> {
+    coro: &'a mut Coro,
+    failed: bool,
+}
+
+impl Iterator for CoroIter<'_> {
+    type Item = Result<Value, CoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || *self.coro.status() == CoStatus::Done {
+            return None;
+        }
+        match self.coro.resume(Vec::new()) {
+            Ok(_) if *self.coro.status() == CoStatus::Done => None,
+            Ok(val) => Some(Ok(val)),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A coroutine being driven one instruction at a time, e.g. by a debugger.
+pub struct DebugSession {
+    coro: Coro,
+}
+
+impl DebugSession {
+    pub fn step(&mut self) -> Result<StepResult, CoError> {
+        match self.coro.status {
+            CoStatus::Done => return Err(self.coro.error("tried to step a finished coroutine")),
+            // Stepping into a suspended coroutine behaves like resuming it with no arguments.
+            CoStatus::Suspended => self.coro.handle_inputs(Vec::new())?,
+            CoStatus::Running => {}
+        }
+        self.coro.status = CoStatus::Running;
+        self.coro.step()
+    }
+
+    pub fn peek_stack(&self) -> &[Value] {
+        &self.coro.stack
+    }
+
+    pub fn current_instr(&self) -> Option<&Instr> {
+        if self.coro.ip < self.coro.fun.code.len() {
+            Some(self.coro.fun.code.instr(self.coro.ip))
+        } else {
+            None
+        }
+    }
+
+    pub fn env_snapshot(&self) -> HashMap<String, Value> {
+        self.coro.vars()
+    }
+
+    pub fn coro(&self) -> &Coro {
+        &self.coro
+    }
+
+    pub fn into_coro(self) -> Coro {
+        self.coro
+    }
+}
+
+/// Whether `val` is exact as a float, along with every integer closer to zero. Floats further out
+/// also stand for their neighbours, e.g. 2^53 + 1 reads as 2^53.
+fn is_safe_int(val: i64) -> bool {
+    (-MAX_SAFE_INT..=MAX_SAFE_INT).contains(&val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Errors are compared by message, since most tests do not care about the location.
+    fn eval(src: &str) -> Result<Value, String> {
+        let mut co = CoVM::build(src).map_err(|e| e.message().to_owned())?;
+        CoVM::run(&mut co).map_err(|e| e.message().to_owned())
+    }
+
+    #[test]
+    fn conditional() {
+        assert_eq!(Value::Num(1.0), eval("1 < 2 ? 1 : 2").unwrap());
+        assert_eq!(Value::Num(2.0), eval("2 < 1 ? 1 : 2").unwrap());
+    }
+
+    #[test]
+    fn conditional_nested() {
+        let src = "let n = 5\n n < 3 ? \"small\" : n < 10 ? \"medium\" : \"large\"";
+        assert_eq!(Value::Str("medium".into()), eval(src).unwrap());
+    }
+
+    #[test]
+    fn coro_debug() {
+        let mut co = CoVM::build("def foo = 1\nlet a = 1\nyield \"x\"\nlet b = 2").unwrap();
+        CoVM::run(&mut co).unwrap();
+        let dbg = format!("{:#?}", co);
+        let exp = "Coro {
+    fn: __main__,
+    ip: 7,
+    status: Suspended,
+    stack: [],
+    env: {
+        \"a\": Num(1.0),
+        \"foo\": Fn(<fn name: foo arity: 0>),
+    },
+}";
+        assert_eq!(exp, dbg);
+    }
+
+    #[test]
+    fn iterate_yields() {
+        let mut co = CoVM::build("for i in 0..3 do { yield i * 2 } end\n\"done\"").unwrap();
+        let vals: Vec<Value> = co.iter().map(Result::unwrap).collect();
+        assert_eq!(vec![Value::Num(0.0), Value::Num(2.0), Value::Num(4.0)], vals);
+        assert_eq!(0, co.iter().count());
+
+        let mut co = CoVM::build("yield 1\nyield 1 + \"a\"\nyield 3").unwrap();
+        let mut iter = co.iter();
+        assert_eq!(Value::Num(1.0), iter.next().unwrap().unwrap());
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn step_results() {
+        let co = CoVM::build("let a = 1\nyield a\n2").unwrap();
+        let mut dbg = CoVM::debug_session(co);
+
+        assert!(matches!(dbg.current_instr(), Some(OpOne)));
+        assert_eq!(StepResult::Stepped, dbg.step().unwrap());
+        assert_eq!(&[Value::Num(1.0)], dbg.peek_stack());
+        assert_eq!(StepResult::Stepped, dbg.step().unwrap());
+        assert_eq!(Some(&Value::Num(1.0)), dbg.env_snapshot().get("a"));
+
+        // pop, load, yield
+        dbg.step().unwrap();
+        dbg.step().unwrap();
+        assert!(matches!(dbg.current_instr(), Some(OpYield)));
+        assert_eq!(StepResult::Yielded(Value::Num(1.0)), dbg.step().unwrap());
+        assert_eq!(&CoStatus::Suspended, dbg.coro().status());
+
+        // Stepping again resumes with unit; then pop, const, ret.
+        assert_eq!(StepResult::Stepped, dbg.step().unwrap());
+        assert_eq!(StepResult::Stepped, dbg.step().unwrap());
+        assert_eq!(StepResult::Returned(Value::Num(2.0)), dbg.step().unwrap());
+        assert!(dbg.current_instr().is_none());
+        assert_eq!(&CoStatus::Done, dbg.coro().status());
+        assert!(dbg.step().is_err());
+    }
+
+    #[test]
+    fn stack_within_max() {
+        let src = "let xs = [1, 2 + 3 * 4]\nlet i = 0\n\
+            while i < 3 do { i += xs[0] } end\nyield [i, xs[1]]";
+        let co = CoVM::build(src).unwrap();
+        assert_eq!(4, co.fun.max_stack());
+        assert!(co.stack.capacity() >= 4);
+
+        let mut dbg = CoVM::debug_session(co);
+        let mut max = 0;
+        while dbg.coro().status() != &CoStatus::Done {
+            dbg.step().unwrap();
+            max = max.max(dbg.peek_stack().len());
+        }
+        assert_eq!(4, max);
+    }
+
+    #[test]
+    fn bool_coercion() {
+        let def = CoVM::compile("to_bool(\"a\")").unwrap();
+        let listing = format!("{}", def.code);
+        assert!(listing.contains("OpBool"), "{}", listing);
+        assert!(!listing.contains("OpNativeCall"), "{}", listing);
+        assert_eq!(Value::Bool(true), CoVM::run(&mut Coro::new(def)).unwrap());
+
+        // Shadowed names are called like any other function.
+        let def = CoVM::compile("def to_bool x = 5\nto_bool(1)").unwrap();
+        assert!(!format!("{}", def.code).contains("OpBool"));
+        assert_eq!(Value::Num(5.0), CoVM::run(&mut Coro::new(def)).unwrap());
+
+        assert_eq!(Value::Bool(true), eval("to_bool(\"\")").unwrap());
+        assert_eq!(Value::Bool(true), eval("to_bool(0)").unwrap());
+        assert_eq!(Value::Bool(false), eval("to_bool(())").unwrap());
+    }
+
+    #[test]
+    fn get_set_locals() {
+        let mut def = FnDef::new();
+        let code = &mut def.code;
+        let (a, b, c) = (code.add_local("a"), code.add_local("b"), code.add_local("c"));
+        for (slot, num) in [(a, 1.0), (b, 2.0), (c, 3.0)] {
+            let idx = code.add_const(Value::Num(num));
+            code.add(OpConst(idx), 1);
+            code.add(OpSetLocal(slot), 1);
+            code.add(OpPop, 1);
+        }
+        // Overwrite the middle slot with the sum of the others.
+        code.add(OpGetLocal(a), 1);
+        code.add(OpGetLocal(c), 1);
+        code.add(OpAdd, 1);
+        code.add(OpSetLocal(b), 1);
+        code.add(OpPop, 1);
+        code.add(OpGetLocal(c), 1);
+        code.add(OpGetLocal(b), 1);
+        code.add(OpGetLocal(a), 1);
+        code.add(OpList(3), 1);
+        code.add(OpRet, 1);
+        let mut co = Coro::new(Rc::new(def));
+        assert_eq!("[3, 4, 1]", format!("{}", CoVM::run(&mut co).unwrap()));
+        let env = CoVM::debug_session(co).env_snapshot();
+        assert_eq!(Some(&Value::Num(4.0)), env.get("b"));
+
+        // Reading a slot before it is set looks up its name instead.
+        let mut def = FnDef::new();
+        let slot = def.code.add_local("nope");
+        def.code.add(OpGetLocal(slot), 1);
+        let mut co = Coro::new(Rc::new(def));
+        assert_eq!("no binding for name 'nope'", CoVM::run(&mut co).unwrap_err().message());
+    }
+
+    #[test]
+    fn closures() {
+        // Captured locals are shared, so changes on either side show up across resumes.
+        let src = "let n = 0\ndef gen = { n += 1; yield n; n += 10; n }\nlet c = create gen\n\
+            let a = (resume c)\nn += 100\nlet b = (resume c)\n[a, b, n]";
+        assert_eq!("[1, 111, 111]", format!("{}", eval(src).unwrap()));
+        let def = CoVM::compile(src).unwrap();
+        let instrs: Vec<&Instr> = def.code.iter().map(|(_, instr, _)| instr).collect();
+        assert!(instrs.iter().any(|instr| matches!(instr, OpClosure(_))));
+        assert!(instrs.contains(&&OpSetUpvalue(0)));
+        assert!(!instrs.contains(&&OpGetLocal(0)) && !instrs.contains(&&OpSetLocal(0)));
+
+        // Each call makes new cells, so closures from different calls do not share.
+        let src = "def make start = fn -> { start += 1; start }\nlet a = make(0)\n\
+            let b = make(10)\na()\n[a(), b(), a()]";
+        assert_eq!("[2, 11, 3]", format!("{}", eval(src).unwrap()));
+        // Captures reach through more than one level.
+        let src = "def outer = { let n = 1; let f = fn -> (fn -> { n *= 2; n }); \
+            let g = f(); g(); [g(), n] }\nouter()";
+        assert_eq!("[4, 4]", format!("{}", eval(src).unwrap()));
+        // Each item of a `for` gets its own cell, and the variable holds the last one after.
+        let src = "let fs = []\nfor i in 0..3 do list_push(fs, fn -> i) end\n\
+            [map(fs, fn f -> f()), i]";
+        assert_eq!("[[0, 1, 2], 2]", format!("{}", eval(src).unwrap()));
+        let src = "def make = { let fs = []; for i in 0..2 do { list_push(fs, fn -> i); i *= 10 } \
+            end; map(fs, fn f -> f()) }\nmake()";
+        assert_eq!("[0, 10]", format!("{}", eval(src).unwrap()));
+
+        // Functions can call themselves by name, and `let` binds a new name rather than capture.
+        let src = "def fact n = n < 2 ? 1 : n * fact(n - 1)\nfact(5)";
+        assert_eq!(Value::Num(120.0), eval(src).unwrap());
+        let src = "let x = 1\ndef f = { let x = 2; x }\n[f(), x]";
+        assert_eq!("[2, 1]", format!("{}", eval(src).unwrap()));
+    }
+
+    #[test]
+    fn native_calls() {
+        let instrs = |src| {
+            let def = CoVM::compile(src).unwrap();
+            def.code.iter().map(|(_, instr, _)| *instr).collect::<Vec<Instr>>()
+        };
+        assert!(instrs("upper(\"a\")").contains(&OpNativeCall(1)));
+        assert_eq!(Value::Str("A".into()), eval("upper(\"a\")").unwrap());
+        // A function that binds the name calls whatever it holds instead.
+        let src = "upper(\"a\")\ndef upper s = s\nupper(\"b\")";
+        assert!(!instrs(src).contains(&OpNativeCall(1)));
+        assert_eq!(Value::Str("b".into()), eval(src).unwrap());
+
+        // Names bound from outside still shadow the built-in, and turning the built-ins off
+        // turns off the ones picked when compiling too.
+        let builder = CoVM::builder().with_env_var("upper", Value::Num(1.0));
+        let err = CoVM::run(&mut builder.build("upper(\"a\")").unwrap()).unwrap_err();
+        assert_eq!("only functions can be called", err.message());
+        let builder = CoVM::builder().with_stdlib(false);
+        let err = CoVM::run(&mut builder.build("upper(\"a\")").unwrap()).unwrap_err();
+        assert_eq!("no binding for name 'upper'", err.message());
+    }
+
+    #[test]
+    fn swap() {
+        let mut def = FnDef::new();
+        let code = &mut def.code;
+        let one = code.add_const(Value::Num(1.0));
+        let two = code.add_const(Value::Num(2.0));
+        code.add(OpConst(one), 1);
+        code.add(OpConst(two), 1);
+        code.add(OpSwap, 1);
+        code.add(OpList(2), 1);
+        code.add(OpRet, 1);
+        let mut co = Coro::new(Rc::new(def));
+        assert_eq!("[2, 1]", format!("{}", CoVM::run(&mut co).unwrap()));
+
+        let mut def = FnDef::new();
+        def.code.add(OpUnit, 1);
+        def.code.add(OpSwap, 1);
+        let mut co = Coro::new(Rc::new(def));
+        let err = CoVM::run(&mut co).unwrap_err();
+        assert_eq!("not enough values on the stack to swap", err.message());
+    }
+
+    #[test]
+    fn step_past_end() {
+        let mut co = Coro::new(Rc::new(FnDef::new()));
+        co.status = CoStatus::Running;
+        assert_eq!(StepResult::Returned(Value::Unit), co.step().unwrap());
+    }
+
+    #[test]
+    fn compound_assign() {
+        assert_eq!(Value::Num(6.0), eval("let x = 1\nx += 5\nx").unwrap());
+        assert_eq!(Value::Num(4.0), eval("let x = 10\nx -= 2\nx /= 4\nx *= 2\nx").unwrap());
+    }
+
+    #[test]
+    fn compound_assign_unbound() {
+        assert_eq!("no binding for name 'y'", eval("y += 1").unwrap_err());
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        hits: Vec<(u32, usize, Vec<String>, Option<String>)>,
+    }
+
+    impl Debugger for Recorder {
+        fn on_breakpoint(&mut self, id: u32, ip: usize, stack: &[Value], env: &HashMap<String, Value>) {
+            let stack = stack.iter().map(|v| format!("{:?}", v)).collect();
+            let a = env.get("a").map(|v| format!("{:?}", v));
+            self.hits.push((id, ip, stack, a));
+        }
+    }
+
+    fn breakpoint_fn() -> Rc<FnDef> {
+        let mut def = FnDef::with("bp".to_owned(), Vec::new());
+        let code = &mut def.code;
+        let one = code.add_const(Value::Num(1.0));
+        let name = code.add_const(Value::Str("a".into()));
+        cgen::emit_breakpoint(code, 1);
+        code.add(OpConst(one), 1);
+        cgen::emit_breakpoint(code, 2);
+        code.add(OpStore(name), 1);
+        cgen::emit_breakpoint(code, 3);
+        code.add(OpRet, 1);
+        Rc::new(def)
+    }
+
+    #[test]
+    fn breakpoints() {
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        let weak: Weak<Mutex<dyn Debugger>> = Arc::downgrade(&recorder) as _;
+        let mut co = Coro::new(breakpoint_fn());
+        co.set_debugger(weak);
+        CoVM::run(&mut co).unwrap();
+
+        let hits = &recorder.lock().unwrap().hits;
+        assert_eq!(3, hits.len());
+        assert_eq!((1, 0, vec![], None), hits[0]);
+        assert_eq!((2, 2, vec!["Num(1.0)".to_owned()], None), hits[1]);
+        assert_eq!((3, 4, vec!["Unit".to_owned()], Some("Num(1.0)".to_owned())), hits[2]);
+    }
+
+    #[test]
+    fn breakpoints_without_debugger() {
+        let mut co = Coro::new(breakpoint_fn());
+        assert_eq!(Value::Unit, CoVM::run(&mut co).unwrap());
+
+        // Debugger is dropped right away, so the weak reference is dead.
+        let mut co = Coro::new(breakpoint_fn());
+        drop(CoVM::attach_debugger(&mut co, Box::new(Recorder::default())));
+        assert_eq!(Value::Unit, CoVM::run(&mut co).unwrap());
+    }
+
+    #[test]
+    fn breakpoints_in_child_coroutine() {
+        struct Counter(Arc<Mutex<usize>>);
+        impl Debugger for Counter {
+            fn on_breakpoint(&mut self, _: u32, _: usize, _: &[Value], _: &HashMap<String, Value>) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let mut def = FnDef::new();
+        let name = def.code.add_const(Value::Str("bp".into()));
+        let fun = def.code.add_const(Value::Fn(breakpoint_fn()));
+        def.code.add(OpDefine(fun), 1);
+        def.code.add(OpCreate(name), 1);
+        def.code.add(OpResume(0), 1);
+        def.code.add(OpRet, 1);
+        let mut co = Coro::new(Rc::new(def));
+
+        let count = Arc::new(Mutex::new(0));
+        let _handle = CoVM::attach_debugger(&mut co, Box::new(Counter(count.clone())));
+        CoVM::run(&mut co).unwrap();
+        assert_eq!(3, *count.lock().unwrap());
+    }
+
+    #[test]
+    fn watches() {
+        #[derive(Default)]
+        struct Watcher(Vec<(String, Option<String>, String)>);
+        impl Debugger for Watcher {
+            fn on_breakpoint(&mut self, _: u32, _: usize, _: &[Value], _: &HashMap<String, Value>) {}
+            fn on_watch_changed(&mut self, name: &str, old: Option<&Value>, new: &Value) {
+                let old = old.map(|v| format!("{:?}", v));
+                self.0.push((name.to_owned(), old, format!("{:?}", new)));
+            }
+        }
+
+        let mut co = CoVM::build("let x = 0\nwhile x < 3 do { x += 1 } end").unwrap();
+        co.add_watch("x", CoVM::compile("x").unwrap());
+        let watcher = Arc::new(Mutex::new(Watcher::default()));
+        co.set_debugger(Arc::downgrade(&watcher) as Weak<Mutex<dyn Debugger>>);
+        CoVM::run(&mut co).unwrap();
+
+        let changes = &watcher.lock().unwrap().0;
+        let change = |old: Option<&str>, new: &str| {
+            ("x".to_owned(), old.map(str::to_owned), new.to_owned())
+        };
+        assert_eq!(
+            &vec![
+                change(None, "Num(0.0)"),
+                change(Some("Num(0.0)"), "Num(1.0)"),
+                change(Some("Num(1.0)"), "Num(2.0)"),
+                change(Some("Num(2.0)"), "Num(3.0)"),
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn clone_suspended() {
+        let src = "let i = 0\nlet seen = []\nwhile i < 5 do { yield i; i += 1 } end\nseen";
+        let mut co = CoVM::build(src).unwrap();
+        assert_eq!(Value::Num(0.0), CoVM::run(&mut co).unwrap());
+        assert_eq!(Value::Num(1.0), CoVM::run(&mut co).unwrap());
+
+        let mut copy = co.clone_suspended().unwrap();
+        let drain = |co: &mut Coro| {
+            let mut out = Vec::new();
+            while co.status() != &CoStatus::Done {
+                out.push(CoVM::run(co).unwrap());
+            }
+            out
+        };
+        let first = drain(&mut co);
+        let second = drain(&mut copy);
+        assert_eq!(first, second);
+        assert_eq!(Value::Num(2.0), first[0]);
+        assert!(co.clone_suspended().is_err());
+    }
+
+    #[test]
+    fn clone_suspended_copies_lists() {
+        let mut co = CoVM::build("let xs = [1]\nyield xs\nxs").unwrap();
+        let yielded = CoVM::run(&mut co).unwrap();
+        let mut copy = co.clone_suspended().unwrap();
+        yielded.into_list().borrow_mut().push(Value::Num(2.0));
+        assert_eq!(Value::list(vec![Value::Num(1.0)]), CoVM::run(&mut copy).unwrap());
+        let mut fresh = CoVM::build("1").unwrap();
+        fresh.status = CoStatus::Running;
+        assert!(fresh.clone_suspended().is_err());
+    }
+
+    #[test]
+    fn default_params() {
+        let src = "def f a (b = 10) = a + b\nlet c = create f\n";
+        assert_eq!(Value::Num(11.0), eval(&format!("{}resume c 1", src)).unwrap());
+        assert_eq!(Value::Num(3.0), eval(&format!("{}resume c 1 2", src)).unwrap());
+        assert_eq!(
+            "expected 1 to 2 arguments but got 0 when resuming coroutine",
+            eval(&format!("{}resume c", src)).unwrap_err()
+        );
+        assert_eq!(
+            "expected 1 to 2 arguments but got 3 when resuming coroutine",
+            eval(&format!("{}resume c 1 2 3", src)).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn default_params_all() {
+        let src = "def f (a = 1) (b = 2 * 3) = a + b\nlet c = create f\nresume c";
+        assert_eq!(Value::Num(7.0), eval(src).unwrap());
+    }
+
+    #[test]
+    fn default_params_call() {
+        let src = "def f a (b = 0) = a + b\n[f(1), f(1, 2)]";
+        assert_eq!("[1, 3]", format!("{}", eval(src).unwrap()));
+        let err = eval("def f a (b = 0) = a + b\nf()").unwrap_err();
+        assert_eq!("expected 1 to 2 arguments but got 0 when resuming coroutine", err);
+
+        // Defaults are worked out on each call, so they are not shared between calls.
+        let src = "def f (xs = []) = { list_push(xs, 1); xs }\n[f(), f()]";
+        assert_eq!("[[1], [1]]", format!("{}", eval(src).unwrap()));
+    }
+
+    #[test]
+    fn variadic_params() {
+        let src = "def f a ...rest = [a, rest]\nlet c = create f\n";
+        let run = |args: &str| eval(&format!("{}resume c {}", src, args));
+        let num = Value::Num;
+        let expect = |a: f64, rest: Vec<Value>| Value::list(vec![num(a), Value::list(rest)]);
+        assert_eq!(expect(1.0, vec![]), run("1").unwrap());
+        assert_eq!(expect(1.0, vec![num(2.0)]), run("1 2").unwrap());
+        assert_eq!(expect(1.0, vec![num(2.0), num(3.0), num(4.0)]), run("1 2 3 4").unwrap());
+        assert_eq!(
+            "expected at least 1 arguments but got 0 when resuming coroutine",
+            run("").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn variadic_sum() {
+        let src = "def sum ...nums = fold(nums, 0, fn acc x -> acc + x)\n\
+            [sum(), sum(5), sum(1, 2, 3, 4)]";
+        assert_eq!("[0, 5, 10]", format!("{}", eval(src).unwrap()));
+    }
+
+    #[test]
+    fn variadic_with_defaults() {
+        let src = "def f (a = 1) ...rest = [a, rest]\nlet c = create f\nresume c";
+        let exp = Value::list(vec![Value::Num(1.0), Value::list(Vec::new())]);
+        assert_eq!(exp, eval(src).unwrap());
+    }
+
+    #[test]
+    fn eval_expr() {
+        assert_eq!(Value::Num(7.0), CoVM::eval_expr("1 + 2 * 3").unwrap());
+        assert_eq!(Value::Num(2.0), CoVM::eval_expr("{ let a = 1; a + 1 }").unwrap());
+        assert!(CoVM::eval_expr("let a = 1").is_err());
+        assert_eq!("no binding for name 'a'", CoVM::eval_expr("a").unwrap_err().message());
+    }
+
+    #[test]
+    fn lambda() {
+        let src = "let f = fn a (b = 1) -> a + b\nlet c = create f\nresume c 2";
+        assert_eq!(Value::Num(3.0), eval(src).unwrap());
+
+        let src = "let f = fn -> { yield 1; 2 }\nlet c = create f\nlet x = (resume c)\nresume c";
+        assert_eq!(Value::Num(2.0), eval(src).unwrap());
+
+        let f = CoVM::eval_expr("fn a b -> a").unwrap();
+        assert_eq!("<fn name: lambda arity: 2>", format!("{}", f));
+    }
+
+    #[test]
+    fn resume_running() {
+        let src = "def foo c = { yield 1; resume c }\nlet co = create foo\n\
+            let x = (resume co co)\nresume co";
+        assert_eq!("cannot resume a running coroutine", eval(src).unwrap_err());
+
+        // Resuming a coroutine further up the chain fails the same way.
+        let src = "def inner c = (resume c)\ndef outer = { let i = create inner; resume i top }\n\
+            let top = create outer\nresume top";
+        assert_eq!("cannot resume a running coroutine", eval(src).unwrap_err());
+    }
+
+    #[test]
+    fn comparison() {
+        assert_eq!(Value::Bool(true), eval("1 < 2").unwrap());
+        assert_eq!(Value::Bool(false), eval("1 > 2").unwrap());
+        assert_eq!(Value::Bool(true), eval("2 <= 2").unwrap());
+        assert_eq!(Value::Bool(false), eval("1 >= 2").unwrap());
+        assert_eq!(Value::Bool(true), eval("\"apple\" < \"banana\"").unwrap());
+        assert_eq!(Value::Bool(true), eval("\"z\" > \"a\"").unwrap());
+        assert_eq!(Value::Bool(true), eval("\"ab\" <= \"ab\"").unwrap());
+        assert_eq!(Value::Bool(false), eval("\"B\" >= \"a\"").unwrap());
+        assert_eq!(
+            "operands must both be numbers or both be strings",
+            eval("1 < \"a\"").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn string_repeat() {
+        assert_eq!(Value::Str("ababab".into()), eval("\"ab\" * 3").unwrap());
+        assert_eq!(Value::Str("".into()), eval("\"ab\" * 0").unwrap());
+        let err = "string repeat count must be a non-negative integer";
+        assert_eq!(err, eval("\"ab\" * -1").unwrap_err());
+        assert_eq!(err, eval("\"ab\" * 1.5").unwrap_err());
+        assert_eq!("operands must be numbers", eval("3 * \"ab\"").unwrap_err());
+        let err = eval("\"ab\" * 0x1000000").unwrap_err();
+        assert_eq!("repeated string would be longer than 16777216 bytes", err);
+    }
+
+    #[test]
+    fn for_loops() {
+        let src = "let seen = []\nfor i in 1..5 do list_push(seen, i) end\nseen";
+        assert_eq!("[1, 2, 3, 4]", format!("{}", eval(src).unwrap()));
+        let src = "let sum = 0\nfor x in [1, 2, 3] do { sum += x } end\nsum";
+        assert_eq!(Value::Num(6.0), eval(src).unwrap());
+        let src = "let n = 0\nfor x in 3..1 do { n += 1 } end\nfor x in [] do { n += 1 } end\nn";
+        assert_eq!(Value::Num(0.0), eval(src).unwrap());
+        assert_eq!(Value::Unit, eval("for x in 0..2 do x end").unwrap());
+
+        // Ranges are lazy, so looping stops without making the rest of the numbers.
+        let src = "def gen = for i in 0..1000000000000 do (yield i * i) end\nlet c = create gen\n\
+            [(resume c), (resume c), (resume c)]";
+        assert_eq!("[0, 1, 4]", format!("{}", eval(src).unwrap()));
+
+        let src = "let pairs = []\nfor i in 0..2 do (for j in [\"a\", \"b\"] do \
+            list_push(pairs, format(\"{}{}\", i, j)) end) end\npairs";
+        let exp = "[\"0a\", \"0b\", \"1a\", \"1b\"]";
+        assert_eq!(exp, format!("{}", eval(src).unwrap()));
+
+        let err = eval("for c in \"ab\" do c end").unwrap_err();
+        assert_eq!("only lists and ranges can be looped over", err);
+        assert_eq!("range bounds must be numbers", eval("1..\"a\"").unwrap_err());
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(Value::Bool(true), eval("0xFF & 0x0F == 15").unwrap());
+        assert_eq!(Value::Num(2.0), eval("6 & 3").unwrap());
+        assert_eq!(Value::Num(5.0), eval("4 | 1").unwrap());
+        assert_eq!(Value::Num(7.0), eval("6 | 3").unwrap());
+        assert_eq!(Value::Num(5.0), eval("6 ^ 3").unwrap());
+        assert_eq!(Value::Num(-1.0), eval("~0").unwrap());
+        assert_eq!(Value::Num(8.0), eval("1 << 3").unwrap());
+        assert_eq!(Value::Num(16.0), eval("1 << 4").unwrap());
+        assert_eq!(Value::Num(64.0), eval("256 >> 2").unwrap());
+        assert_eq!(Value::Num(-2.0), eval("-8 >> 2").unwrap());
+        assert_eq!("operands must be integers", eval("1.5 & 1").unwrap_err());
+        assert_eq!("operand must be an integer", eval("~\"a\"").unwrap_err());
+        assert_eq!("operand must be an integer", eval("~0.5").unwrap_err());
+        assert_eq!("shift amount must be between 0 and 63", eval("1 << 64").unwrap_err());
+        assert_eq!("shift amount must be between 0 and 63", eval("1 >> -1").unwrap_err());
+        assert_eq!(Value::Num(4503599627370496.0), eval("1 << 52").unwrap());
+        let msg = "integer result does not fit in 53 bits";
+        assert_eq!(msg, eval("1 << 53").unwrap_err());
+        assert_eq!(msg, eval("(1 << 62) | 1").unwrap_err());
+        // 2^53 + 1 has no exact float, so it reads as 2^53.
+        assert_eq!("operands must fit in 53 bits", eval("9007199254740993 & 1").unwrap_err());
+        assert_eq!("operand must fit in 53 bits", eval("~9007199254740993").unwrap_err());
+        assert_eq!(Value::Num(1.0), eval("9007199254740991 & 1").unwrap());
+    }
+
+    #[test]
+    fn exit_leaves_coroutines_done() {
+        let builder = CoVM::with_exit_handler(Box::new(|_| {}));
+        let seen = Value::list(Vec::new());
+        let src = "def gen = { yield 1; exit(5) }\nlet co = create gen\nlist_push(seen, co)\n\
+                   (resume co)\nresume co";
+        let mut co = builder.with_env_var("seen", seen.clone()).build(src).unwrap();
+        assert_eq!(Err(CoError::Exit(5)), CoVM::run(&mut co));
+        assert_eq!("done", co.status_name());
+        assert_eq!(0, co.limits.depth.get());
+        assert_eq!("[<coro fn: gen status: done>]", format!("{}", seen));
+    }
+
+    #[test]
+    fn call() {
+        let src = "def add a (b = 1) = a + b\nadd(2) + add(2, 3)";
+        assert_eq!(Value::Num(8.0), eval(src).unwrap());
+
+        // Calling runs until the first yield, in a fresh coroutine each time.
+        let src = "def gen = { yield 1; 2 }\ngen() + gen()";
+        assert_eq!(Value::Num(2.0), eval(src).unwrap());
+
+        let src = "let f = fn a -> a * 2\nf(f(3))";
+        assert_eq!(Value::Num(12.0), eval(src).unwrap());
+
+        assert_eq!("only functions can be called", eval("let x = 1\nx()").unwrap_err());
+        assert_eq!(
+            "expected 1 arguments but got 0 when resuming coroutine",
+            eval("def f a = a\nf()").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn callbacks_share_limits() {
+        // Recursing through a built-in still counts towards the depth.
+        let err = eval("def f g = map([g], g)\nf(f)").unwrap_err();
+        assert!(err.ends_with("maximum depth of 128 nested coroutines exceeded"), "{}", err);
+
+        let mut co = CoVM::builder()
+            .with_max_steps(1000)
+            .build("map([1], fn x -> { while true do () end })")
+            .unwrap();
+        let err = CoVM::run(&mut co).unwrap_err();
+        assert!(err.message().ends_with("step limit of 1000 exceeded"), "{}", err);
+
+        let (_, out) = CoVM::capture_output("map([1, 2], fn x -> print x)").unwrap();
+        assert_eq!("1\n2\n", out);
+    }
+
+    #[test]
+    fn subscripts() {
+        let src = "let xs = [1, 2, 3]\n[xs[0], xs[2], xs[-1], xs[-3]]";
+        assert_eq!("[1, 3, 3, 1]", format!("{}", eval(src).unwrap()));
+        assert_eq!(Value::Num(4.0), eval("[[1, 2], [3, 4]][1][1]").unwrap());
+
+        let src = "let xs = [1, 2, 3, 4]\n[xs[1:3], xs[:2], xs[2:], xs[:], xs[-2:], xs[:-1]]";
+        let exp = "[[2, 3], [1, 2], [3, 4], [1, 2, 3, 4], [3, 4], [1, 2, 3]]";
+        assert_eq!(exp, format!("{}", eval(src).unwrap()));
+
+        // Slices going past either end are clamped, and ones that cross over are empty.
+        let src = "let xs = [1, 2, 3]\n[xs[-10:10], xs[2:1], xs[5:], xs[:-5], [][0:1]]";
+        assert_eq!("[[1, 2, 3], [], [], [], []]", format!("{}", eval(src).unwrap()));
+
+        // Slices are copies, so changing one leaves the original as it was.
+        let src = "let xs = [1, 2]\nlet ys = xs[:]\nlist_push(ys, 3)\n[xs, ys]";
+        assert_eq!("[[1, 2], [1, 2, 3]]", format!("{}", eval(src).unwrap()));
+
+        let err = eval("[1, 2][2]").unwrap_err();
+        assert_eq!("list index 2 is out of bounds for length 2", err);
+        let err = eval("[1, 2][-3]").unwrap_err();
+        assert_eq!("list index -3 is out of bounds for length 2", err);
+        assert_eq!("list index must be a whole number", eval("[1][0.5]").unwrap_err());
+        assert_eq!("slice bounds must be whole numbers", eval("[1][\"a\":]").unwrap_err());
+        assert_eq!("only lists can be indexed", eval("let s = \"ab\"\ns[0]").unwrap_err());
+        assert_eq!("only lists can be sliced", eval("(1)[0:1]").unwrap_err());
+    }
+
+    #[test]
+    fn format_stack() {
+        let mut co = CoVM::build("def f = 1").unwrap();
+        let inner = Rc::new(RefCell::new(Coro::new(Rc::new(FnDef::new()))));
+        // The coroutine is being resumed, so it is borrowed and cannot be looked into.
+        let running = inner.clone();
+        let _guard = running.borrow_mut();
+        co.stack = vec![
+            Value::Num(1.0),
+            Value::Str("hi".into()),
+            Value::Str("a very long string that does not fit".into()),
+            Value::list(vec![Value::Unit, Value::Bool(true)]),
+            builtins::lookup("sort").unwrap(),
+            Value::Co(inner),
+        ];
+        assert_eq!(
+            "<ip: 0000 stack: [ 1 \"hi\" \"a very long string that does... \
+            [(), true] <native fn name: sort> <coro running> ]>",
+            co.format_stack()
+        );
+    }
+
+    #[test]
+    fn print_to_output() {
+        let buf = SharedBuf::default();
+        let mut co = CoVM::build("print 42\ndef f = print \"hi\"\nf()").unwrap();
+        co.set_output(Box::new(buf.clone()));
+        CoVM::run(&mut co).unwrap();
+        assert_eq!(b"42\nhi\n", &buf.0.borrow()[..]);
+    }
+
+    #[test]
+    fn capture_output() {
+        let src = "let xs = [1, \"a\"]\nprint xs\nprint \"a\"\nprint 1.5\n2";
+        let (val, out) = CoVM::capture_output(src).unwrap();
+        assert_eq!(Value::Num(2.0), val);
+        assert_eq!("[1, \"a\"]\na\n1.5\n", out);
+
+        let err = CoVM::capture_output("print x").unwrap_err();
+        assert_eq!("no binding for name 'x'", err.message());
+    }
+
+    #[test]
+    fn with_stdout() {
+        let buf = SharedBuf::default();
+        let builder = CoVM::with_stdout(Box::new(buf.clone()));
+        CoVM::run(&mut builder.build("print 1\nprint 2").unwrap()).unwrap();
+        CoVM::run(&mut builder.build("print 3").unwrap()).unwrap();
+        assert_eq!(b"1\n2\n3\n", &buf.0.borrow()[..]);
+
+        // Only coroutines built from that config write there.
+        CoVM::run(&mut CoVM::build("print 4").unwrap()).unwrap();
+        assert_eq!(b"1\n2\n3\n", &buf.0.borrow()[..]);
+    }
+
+    #[test]
+    fn builder_limits() {
+        let run = |builder: CoVMBuilder, src: &str| {
+            let mut co = builder.build(src).map_err(|e| e.message().to_owned())?;
+            CoVM::run(&mut co).map_err(|e| e.message().to_owned())
+        };
+
+        let src = "let i = 0\nwhile true do { i += 1 } end";
+        let err = run(CoVM::builder().with_max_steps(100), src).unwrap_err();
+        assert_eq!("step limit of 100 exceeded", err);
+        let src = "let i = 0\nwhile i < 3 do { i += 1 } end\ni";
+        assert_eq!(Value::Num(3.0), run(CoVM::builder().with_max_steps(100), src).unwrap());
+
+        // The function is passed itself, which a call by name would also do. Tail calls do not
+        // nest, so add to the result after the call.
+        let src = "def f g n = 1 + g(g, n + 1)\nf(f, 0)";
+        let err = run(CoVM::builder().with_max_depth(10), src).unwrap_err();
+        assert_eq!("maximum depth of 10 nested coroutines exceeded", err);
+        let err = eval(src).unwrap_err();
+        assert_eq!("maximum depth of 128 nested coroutines exceeded", err);
+        let src = "def f g n = n < 5 ? g(g, n + 1) : n\nf(f, 0)";
+        assert_eq!(Value::Num(5.0), run(CoVM::builder().with_max_depth(10), src).unwrap());
+    }
+
+    #[test]
+    fn local_slots() {
+        let src = "def f a ...more = { let b = a + 1; [b, more, upper(\"x\")] }";
+        let def = CoVM::compile(src).unwrap();
+        let f = def.code.constants()[0].clone().into_fn();
+        assert_eq!(["a", "more", "b"], f.code.locals());
+        // Neither locals nor built-ins are looked up by name, which leaves only the string.
+        let instrs: Vec<&Instr> = f.code.iter().map(|(_, instr, _)| instr).collect();
+        assert!(instrs.contains(&&OpGetLocal(0)) && instrs.contains(&&OpSetLocal(2)));
+        let names: Vec<&Value> = f.code.constants().iter().filter(|v| v.is_str()).collect();
+        assert_eq!(vec![&Value::Str("x".into())], names);
+
+        let src = "def f a ...more = { let b = a + 1; [b, more] }\nf(1, 2, 3)";
+        assert_eq!("[2, [2, 3]]", format!("{}", eval(src).unwrap()));
+        // A name read in a loop before it is bound further down still gets the same slot.
+        let src = "let out = []\nfor i in 0..3 do { \
+            if i > 0 then list_push(out, prev) else () end; let prev = i } end\nout";
+        assert_eq!("[0, 1]", format!("{}", eval(src).unwrap()));
+    }
+
+    #[test]
+    fn local_shadowing() {
+        let src = "let x = 1\nlet x = x + 1\nx";
+        assert_eq!(Value::Num(2.0), eval(src).unwrap());
+        let src = "def f x = { let x = x * 10; x }\nf(2)";
+        assert_eq!(Value::Num(20.0), eval(src).unwrap());
+        // Blocks share the slots of their function.
+        let src = "let x = 1\n{ let x = 2; x }\nx";
+        assert_eq!(Value::Num(2.0), eval(src).unwrap());
+        // Each function has its own slots, so the same name in another function is a different
+        // variable.
+        let src = "def f = { let x = 5; x }\nlet x = 1\n[f(), x]";
+        assert_eq!("[5, 1]", format!("{}", eval(src).unwrap()));
+
+        // Locals shadow built-ins, but only once they are bound.
+        let src = "let a = list_len([1])\nlet list_len = fn xs -> 0\n[a, list_len([1])]";
+        assert_eq!("[1, 0]", format!("{}", eval(src).unwrap()));
+        assert_eq!("no binding for name 'y'", eval("let x = y\nlet y = 1").unwrap_err());
+
+        // Until then, the env is used, e.g. for names bound from outside.
+        let builder = CoVM::builder().with_env_var("n", Value::Num(4.0));
+        let mut co = builder.build("let m = n * 2\nlet n = 1\n[m, n]").unwrap();
+        assert_eq!("[8, 1]", format!("{}", CoVM::run(&mut co).unwrap()));
+    }
+
+    #[test]
+    fn compile_and_inject_fn() {
+        let def = CoVM::compile_fn("scale", &["x", "by"], "{ let y = x * by; yield y; y + 1 }");
+        let def = def.unwrap();
+        assert_eq!("scale", def.name());
+        assert_eq!(2, def.arity());
+
+        let src = "let c = create scale\nlet a = (resume c 3 2)\n[a, (resume c)]";
+        let mut co = CoVM::build(src).unwrap();
+        CoVM::inject_fn(&mut co, def.clone());
+        assert_eq!("[6, 7]", format!("{}", CoVM::run(&mut co).unwrap()));
+
+        // Scripts that bind the same name shadow it.
+        let mut co = CoVM::build("def scale x = x\nscale(5)").unwrap();
+        CoVM::inject_fn(&mut co, def);
+        assert_eq!(Value::Num(5.0), CoVM::run(&mut co).unwrap());
+
+        let err = CoVM::compile_fn("bad", &["x"], "x +").unwrap_err();
+        assert!(err.starts_with("[line 1:"), "{}", err);
+    }
+
+    #[test]
+    fn mixed_dispatch() {
+        // Touches most kinds of instruction, including each kind of jump and operand.
+        let src = "def gen n = for i in 0..n do (yield i) end\n\
+            let c = create gen\n\
+            let xs = [(resume c 3), (resume c), (resume c)]\n\
+            let total = 0\n\
+            while total < 20 do { total += fold(xs, 1, fn a x -> a + x) } end\n\
+            let a, b = [total // 3, -total]\n\
+            [a, b, xs[1:], not (a < b) ? \"ok\" : \"no\", 6 & 3 | 1 << 2]";
+        let exp = "[6, -20, [1, 2], \"ok\", 6]";
+        assert_eq!(exp, format!("{}", eval(src).unwrap()));
+    }
+
+    #[test]
+    fn tail_calls() {
+        // Without reusing the coroutine, this would go way past the default depth limit.
+        let src = "def count g n acc = if n == 0 then acc else g(g, n - 1, acc + 1) end\n\
+            count(count, 10000, 0)";
+        assert_eq!(Value::Num(10000.0), eval(src).unwrap());
+        let src = "def count g n = n > 0 ? g(g, n - 1) : \"done\"\ncount(count, 10000)";
+        assert_eq!(Value::Str("done".into()), eval(src).unwrap());
+        let src = "def count g n = 0 + (n > 0 ? g(g, n - 1) : 0)\ncount(count, 10000)";
+        let err = eval(src).unwrap_err();
+        assert_eq!("maximum depth of 128 nested coroutines exceeded", err);
+
+        // A called function ends when it yields, including after a tail call.
+        let src = "def f g n = if n == 0 then { yield \"first\"; \"second\" } \
+            else g(g, n - 1) end\nf(f, 3)";
+        assert_eq!(Value::Str("first".into()), eval(src).unwrap());
+
+        let src = "def f g n = { yield n; n > 0 ? g(g, n - 1) : \"done\" }\n\
+            let c = create f\n[(resume c f 2), (resume c)]";
+        assert_eq!("[2, 1]", format!("{}", eval(src).unwrap()));
+        let src = "def f g n = { yield n; n > 0 ? g(g, n - 1) : \"done\" }\n\
+            let c = create f\n(resume c f 2)\n(resume c)\nresume c";
+        assert_eq!("tried to resume a non-suspended coroutine", eval(src).unwrap_err());
+    }
+
+    #[test]
+    fn strict_math() {
+        // Squaring 2 ten times gives 2^1024, which is too big for a float.
+        let big = "let x = 2\nlet i = 0\nwhile i < 10 do { x *= x; i += 1 } end\n";
+        assert_eq!("inf", format!("{}", eval(&format!("{}x", big)).unwrap()));
+
+        let strict = CoVM::builder().with_strict_math(true);
+        let err = CoVM::run(&mut strict.build(&format!("{}x", big)).unwrap()).unwrap_err();
+        assert_eq!("numeric overflow", err.message());
+        assert_eq!(Value::Num(3.0), CoVM::run(&mut strict.build("1 + 2").unwrap()).unwrap());
+
+        // Coroutines created by a strict one are strict too.
+        let src = "def f a = { let i = 0; while i < 10 do { a *= a; i += 1 } end; a }\nf(2)";
+        let mut co = CoVM::build(src).unwrap();
+        co.set_strict_math(true);
+        assert_eq!("numeric overflow", CoVM::run(&mut co).unwrap_err().message());
+    }
+
+    #[test]
+    fn builder_env_and_stdlib() {
+        let builder = CoVM::builder().with_env_var("x", Value::Num(2.0));
+        assert_eq!(Value::Num(3.0), CoVM::run(&mut builder.build("x + 1").unwrap()).unwrap());
+
+        let builder = CoVM::builder().with_stdlib(false);
+        let err = CoVM::run(&mut builder.build("{ def f = sort; f() }").unwrap()).unwrap_err();
+        assert_eq!("no binding for name 'sort'", err.message());
+    }
+
+    #[test]
+    fn builtins_can_be_shadowed() {
+        assert!(eval("sort").unwrap().is_native_fn());
+        assert_eq!(Value::Num(1.0), eval("def sort xs = 1\nsort([2, 1])").unwrap());
+        let f = CoVM::eval_expr("sort").unwrap();
+        let list = Value::list(vec![Value::Num(2.0), Value::Num(1.0)]);
+        CoVM::call(&f, vec![list.clone()]).unwrap();
+        assert_eq!("[1, 2]", format!("{}", list));
+    }
+
+    #[test]
+    fn op_counts() {
+        let mut co = CoVM::build("let i = 0\nwhile i < 5 do { i += 1 } end").unwrap();
+        assert!(co.op_counts().is_none());
+        co.enable_profiling();
+        CoVM::run(&mut co).unwrap();
+
+        let counts = co.op_counts().unwrap();
+        assert_eq!(5, counts[OpLoop(0).opcode()]);
+        assert_eq!(6, counts[OpLt.opcode()]);
+        assert_eq!(1, counts[OpRet.opcode()]);
+    }
+
+    #[test]
+    fn nested_if_jumps() {
+        let src = "let a = true\nlet b = true\nif a then (if b then 1 else 2 end) else 3 end";
+        let mut co = CoVM::build(src).unwrap();
+        co.enable_profiling();
+        assert_eq!(Value::Num(1.0), CoVM::run(&mut co).unwrap());
+        // The jump out of the inner `if` skips the one out of the outer `if`, which would
+        // otherwise run too and make 16.
+        let counts = co.op_counts().unwrap();
+        assert_eq!(1, counts[OpJump(0).opcode()]);
+        assert_eq!(15, counts.iter().sum::<u64>());
+
+        let src = "def f a = { let x = if a then (if a then 1 else 2 end) else 3 end; x + 1 }\n\
+            [f(true), f(false)]";
+        let def = CoVM::compile(src).unwrap();
+        let f = def.code.constants().iter().find(|val| val.is_fn()).unwrap().clone().into_fn();
+        let targets: Vec<_> = f
+            .code
+            .iter()
+            .filter_map(|(idx, instr, _)| match instr {
+                OpJump(offset) => Some(idx + 1 + offset),
+                _ => None,
+            })
+            .collect();
+        // Both jumps skip to the same place, rather than the inner one landing on the outer one.
+        assert_eq!(2, targets.len());
+        assert_eq!(targets[0], targets[1]);
+        assert_eq!("[2, 4]", format!("{}", CoVM::run(&mut Coro::new(def)).unwrap()));
+    }
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("coro-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn module(&self, sub: &str, name: &str) -> PathBuf {
+            let dir = self.0.join(sub);
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(format!("{}.{}", name, MODULE_EXT));
+            std::fs::write(&path, "1").unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn search_path_order() {
+        let tmp = TempDir::new("order");
+        let builder = CoVM::builder()
+            .with_search_path(tmp.0.join("extra"))
+            .with_script_dir(tmp.0.join("script"))
+            .with_include_path(tmp.0.join("inc1"))
+            .with_include_path(tmp.0.join("inc2"));
+        let coro_path = env::join_paths([tmp.0.join("env1"), tmp.0.join("env2")]).unwrap();
+
+        let dirs = builder.config().search_path_with(Some(coro_path));
+        let names: Vec<_> = dirs.iter().map(|d| d.file_name().unwrap().to_owned()).collect();
+        assert_eq!(vec!["inc1", "inc2", "script", "env1", "env2", "extra"], names);
+    }
+
+    #[test]
+    fn resolve_module() {
+        let tmp = TempDir::new("resolve");
+        let utils = tmp.module("lib", "utils");
+        let shadowed = tmp.module("script", "utils");
+        let nested = tmp.module("lib/sub", "deep");
+        let dirs = vec![tmp.0.join("missing"), tmp.0.join("lib"), tmp.0.join("script")];
+
+        assert_eq!(Some(utils.clone()), CoVMConfig::resolve_in("utils", &dirs));
+        assert_eq!(Some(nested), CoVMConfig::resolve_in("sub/deep", &dirs));
+        assert_eq!(None, CoVMConfig::resolve_in("nope", &dirs));
+        assert_eq!(Some(utils), CoVMConfig::resolve_in("utils.co", &dirs));
+        let versioned = tmp.module("lib", "utils.v1");
+        assert_eq!(Some(versioned), CoVMConfig::resolve_in("utils.v1", &dirs));
+
+        let builder = CoVM::builder()
+            .with_script_dir(tmp.0.join("script"))
+            .with_search_path(tmp.0.join("lib"));
+        assert_eq!(Some(shadowed), builder.config().resolve_module("utils"));
+        let builder = CoVM::builder().with_search_path(tmp.0.join("lib"));
+        assert_eq!(Some(tmp.0.join("lib/utils.co")), builder.config().resolve_module("utils"));
+    }
+
+    #[test]
+    fn coverage() {
+        let mut co = CoVM::build("if 1 < 2 then 3 else 4 end").unwrap();
+        assert!(co.coverage().is_empty());
+        co.enable_coverage();
+        CoVM::run(&mut co).unwrap();
+
+        let code = &co.fun.code;
+        let coverage = co.coverage();
+        assert_eq!(code.len(), coverage.len());
+        let uncovered: Vec<usize> = (0..code.len()).filter(|&i| !coverage[i]).collect();
+        // Only the else branch is skipped, which pops the condition and loads the constant.
+        assert_eq!(2, uncovered.len());
+        assert!(matches!(code.instr(uncovered[0]), OpPop));
+        let four = code.constants().iter().position(|v| v == &Value::Num(4.0)).unwrap();
+        assert!(matches!(code.instr(uncovered[1]), OpConst(idx) if *idx == four));
+    }
+
+    #[test]
+    fn interned_names() {
+        // Locals live in slots and built-ins are resolved when compiling, so this uses a name
+        // that is only bound from outside.
+        let src = "def f = n\nlet x = 0\nwhile x < 10000 do { x += 1 } end\nx + n";
+        let def = CoVM::compile(src).unwrap();
+        let name = |code: &crate::code::Code| match code.constants().iter().find(|v| v.is_str()) {
+            Some(Value::Str(s)) => s.clone(),
+            _ => unreachable!(),
+        };
+        let inner = match &def.code.constants()[0] {
+            Value::Fn(f) => name(&f.code),
+            _ => unreachable!(),
+        };
+        // Names are shared between functions instead of each having its own copy.
+        assert!(Rc::ptr_eq(&inner, &name(&def.code)));
+
+        let mut co = CoVM::builder().with_env_var("n", Value::Num(0.0)).build(src).unwrap();
+        assert_eq!(Value::Num(10000.0), CoVM::run(&mut co).unwrap());
+    }
+
+    #[test]
+    fn shared_strings() {
+        let big = "x".repeat(1 << 20);
+        let src = format!("let s = \"{}\"\nlet out = []\n\
+            for i in 0..1000 do list_push(out, s) end\nout", big);
+        let out = eval(&src).unwrap().into_list();
+        let out = out.borrow();
+        assert_eq!(1000, out.len());
+        // Loading the string again bumps a count rather than copying it.
+        let first = out[0].as_str_ref();
+        assert!(out.iter().all(|s| std::ptr::eq(first, s.as_str_ref())));
+    }
+
+    #[test]
+    fn shared_constants() {
+        let src = "let out = []\nfor i in 0..3 do list_push(out, [\"hi\", fn -> 1]) end\nout";
+        let def = CoVM::compile(src).unwrap();
+        let mut co = Coro::new(def.clone());
+        let out = CoVM::run(&mut co).unwrap().into_list();
+        let out = out.borrow();
+        assert_eq!(3, out.len());
+
+        // Every load of a constant hands out the allocation held by the code.
+        let consts = def.code.constants();
+        let hi = consts.iter().find(|v| v.is_str() && v.as_str_ref() == "hi").unwrap();
+        let lambda = consts.iter().find_map(|v| match v {
+            Value::Fn(def) => Some(def),
+            _ => None,
+        });
+        for pair in out.iter() {
+            let pair = pair.clone().into_list();
+            let pair = pair.borrow();
+            assert!(std::ptr::eq(hi.as_str_ref(), pair[0].as_str_ref()));
+            match &pair[1] {
+                Value::Fn(def) => assert!(Rc::ptr_eq(lambda.unwrap(), def)),
+                val => panic!("expected a function but got {}", val),
+            }
+        }
+    }
+
+    #[test]
+    fn runtime_error_location() {
+        let mut co = CoVM::build("let a = 1\nlet b = 0\nprint a / b").unwrap();
+        let err = CoVM::run(&mut co).unwrap_err();
+        assert_eq!(CoError::runtime("cannot divide by zero", 8, 3), err);
+
+        // Errors inside a function point into the function, and keep the line of the block item.
+        let src = "def f = {\n  let x = 1;\n  x // 0\n}\nlet c = create f\nresume c";
+        let mut co = CoVM::build(src).unwrap();
+        match CoVM::run(&mut co).unwrap_err() {
+            CoError::Runtime { msg, line, .. } => {
+                assert_eq!("cannot divide by zero", msg);
+                assert_eq!(3, line);
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        // Bad resumes are reported where the resume happens.
+        let src = "def f = 1\nlet c = create f\nlet x = (resume c)\nresume c";
+        let mut co = CoVM::build(src).unwrap();
+        assert_eq!(4, CoVM::run(&mut co).unwrap_err().line());
+    }
+
+    #[test]
+    fn compile_error_location() {
+        let err = CoVM::compile("let a = 1\nlet b = (1 +)").unwrap_err();
+        assert!(matches!(err, CoError::Compile { line: 2, col: 13, .. }), "{:?}", err);
+        let err = CoVM::compile("const a = 1\n\nlet a = 2").unwrap_err();
+        assert_eq!(CoError::compile("cannot reassign constant 'a'", 3, 0), err);
+    }
+
+    #[test]
+    fn list_literal() {
+        let exp = Value::list(vec![Value::Num(1.0), Value::list(Vec::new())]);
+        assert_eq!(exp, eval("let a = 1\n[a, []]").unwrap());
+    }
+
+    #[test]
+    fn destructure() {
+        let src = "let pair = [1, 2]\nlet a, b = pair\n[b, a]";
+        let exp = Value::list(vec![Value::Num(2.0), Value::Num(1.0)]);
+        assert_eq!(exp, eval(src).unwrap());
+        assert_eq!(Value::Bool(true), eval("let a, b = [1, 2]\na + b == 3").unwrap());
+    }
+
+    #[test]
+    fn destructure_wildcard() {
+        let src = "let _, b, _ = [1, 2, 3]\nb";
+        assert_eq!(Value::Num(2.0), eval(src).unwrap());
+        assert_eq!(Value::Unit, eval("let a, _ = [1, 2]").unwrap());
+
+        // Wildcards are never bound, so they do not clash with constants either.
+        let src = "const _ = 0\nlet a, _ = [1, 2]\n_";
+        assert_eq!(Value::Num(0.0), eval(src).unwrap());
+        let err = eval("let a, _ = [1, 2]\n_").unwrap_err();
+        assert_eq!("no binding for name '_'", err);
+        let err = eval("let _, _ = [1]").unwrap_err();
+        assert_eq!("expected 2 values to destructure but got 1", err);
+    }
+
+    #[test]
+    fn destructure_mismatch() {
+        let err = eval("let a, b = [1, 2, 3]").unwrap_err();
+        assert_eq!("expected 2 values to destructure but got 3", err);
+        let err = eval("let a, b = 1").unwrap_err();
+        assert_eq!("only lists can be destructured", err);
+    }
+
+    #[test]
+    fn const_binding() {
+        assert_eq!(Value::Num(3.0), eval("const a = 1\nconst b = 2\na + b").unwrap());
+    }
+
+    #[test]
+    fn const_reassign() {
+        let err = CoVM::compile("const a = 1\nlet a = 2").unwrap_err();
+        assert_eq!("cannot reassign constant 'a'", err.message());
+        let err = CoVM::compile("const a = 1\nwhile true do { a += 1 } end").unwrap_err();
+        assert_eq!("cannot reassign constant 'a'", err.message());
+        let err = CoVM::compile("const a = 1\nlet b, a = [1, 2]").unwrap_err();
+        assert_eq!("cannot reassign constant 'a'", err.message());
+        let err = CoVM::compile("const a = 1\nconst a = 2").unwrap_err();
+        assert_eq!("cannot reassign constant 'a'", err.message());
+    }
+
+    #[test]
+    fn let_reassign() {
+        assert_eq!(Value::Num(2.0), eval("let a = 1\nlet a = 2\na").unwrap());
+    }
+
+    #[test]
+    fn const_scoped_to_function() {
+        // Functions can bind the name for themselves, but not assign to the enclosing one.
+        assert!(CoVM::compile("const a = 1\ndef f = { let a = 2; a += 1 }").is_ok());
+        let err = CoVM::compile("def f = { const a = 1; let a = 2 }").unwrap_err();
+        assert_eq!("cannot reassign constant 'a'", err.message());
+        let err = CoVM::compile("const a = 1\ndef f = fn -> { a += 1 }").unwrap_err();
+        assert_eq!("cannot reassign constant 'a'", err.message());
+    }
+
+    #[test]
+    fn floor_div() {
+        assert_eq!(Value::Num(3.0), eval("7 // 2").unwrap());
+        assert_eq!(Value::Num(-4.0), eval("-7 // 2").unwrap());
+        assert_eq!(Value::Num(2.0), eval("6 // 3").unwrap());
+    }
+
+    #[test]
+    fn floor_div_by_zero() {
+        let err = eval("1 // 0").unwrap_err();
+        assert_eq!("cannot divide by zero", err);
+    }
+}
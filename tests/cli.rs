@@ -0,0 +1,122 @@
+use std::process::Command;
+use std::process::Output;
+
+fn coro(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_coro"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check_valid_file() {
+    // Script has a runtime error, but it should never be executed.
+    let out = coro(&["--check", "tests/binary.co"]);
+    assert_eq!(Some(0), out.status.code());
+    assert!(out.stdout.is_empty());
+    assert!(out.stderr.is_empty());
+}
+
+#[test]
+fn check_invalid_file() {
+    let out = coro(&["--check", "tests/fixtures/syntax_error.co"]);
+    assert_eq!(Some(1), out.status.code());
+    assert!(out.stdout.is_empty());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.contains("compile error"));
+}
+
+#[test]
+fn disassemble_file() {
+    let out = coro(&["--disassemble", "tests/fixtures/arith.co"]);
+    assert_eq!(Some(0), out.status.code());
+    let listing = String::from_utf8(out.stdout).unwrap();
+    assert!(listing.contains("== instr: add =="));
+    assert!(listing.contains("== instr: __main__ =="));
+    assert!(listing.contains("OpAdd"));
+    // Script is not executed, so the printed result should be absent.
+    assert!(!listing.lines().any(|line| line == "7"));
+}
+
+#[test]
+fn version() {
+    let out = coro(&["--version"]);
+    assert_eq!(Some(0), out.status.code());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let version = stdout.strip_prefix("coro ").unwrap().trim_end();
+    let parts: Vec<&str> = version.split('.').collect();
+    assert_eq!(3, parts.len());
+    assert!(parts.iter().all(|p| p.parse::<u32>().is_ok()));
+
+    let out = coro(&["-V"]);
+    assert_eq!(stdout, String::from_utf8(out.stdout).unwrap());
+}
+
+#[test]
+fn include_flag() {
+    let out = coro(&["-I", "tests/fixtures", "--include", "tests", "--check", "tests/binary.co"]);
+    assert_eq!(Some(0), out.status.code());
+
+    let out = coro(&["--check", "tests/binary.co", "--include"]);
+    assert_eq!(Some(4), out.status.code());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.starts_with("usage: coro"));
+}
+
+#[test]
+fn file_io() {
+    let dir = std::env::temp_dir().join(format!("coro-cli-io-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let data = dir.join("data.txt");
+    let script = dir.join("io.co");
+    let src = format!(
+        "write_file(\"{0}\", \"hello\")\nprint read_file(\"{0}\")",
+        data.display()
+    );
+    std::fs::write(&script, src).unwrap();
+
+    let out = coro(&[script.to_str().unwrap()]);
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(Some(0), out.status.code());
+    assert_eq!("hello\n", String::from_utf8(out.stdout).unwrap());
+}
+
+#[test]
+fn script_args() {
+    let out = coro(&["tests/fixtures/args.co", "--", "-x", "y"]);
+    assert_eq!(Some(0), out.status.code());
+    assert_eq!("[\"-x\", \"y\"]\n", String::from_utf8(out.stdout).unwrap());
+}
+
+#[test]
+fn script_exit() {
+    let out = coro(&["tests/fixtures/exit.co"]);
+    assert_eq!(Some(7), out.status.code());
+    assert_eq!("before\n", String::from_utf8(out.stdout).unwrap());
+}
+
+#[test]
+fn precompiled_bytecode() {
+    let dir = std::env::temp_dir().join(format!("coro-cli-bc-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("arith.cbc");
+    let file = file.to_str().unwrap();
+
+    let out = coro(&["--output", file, "tests/fixtures/arith.co"]);
+    assert_eq!(Some(0), out.status.code());
+    assert!(out.stdout.is_empty());
+
+    let run = coro(&["--run", file]);
+    let by_ext = coro(&[file]);
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(Some(0), run.status.code());
+    assert_eq!("7\n", String::from_utf8(run.stdout).unwrap());
+    assert_eq!(Some(0), by_ext.status.code());
+    assert_eq!("7\n", String::from_utf8(by_ext.stdout).unwrap());
+
+    let out = coro(&["--run", "tests/fixtures/arith.co"]);
+    assert_eq!(Some(3), out.status.code());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.contains("not a coro bytecode file"));
+}